@@ -35,16 +35,21 @@ según la EN ISO 52000-1.
 
 use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
+use std::hash::Hash;
+use std::ops::AddAssign;
 
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    components::production_by_generator,
     error::{EpbdError, Result},
     types::{
-        CSubtype, CType, Carrier, Component, Dest, Factor, RenNrenCo2, Service, Source, Step,
+        Battery, BatteryFlows, CSubtype, CType, Carrier, CogenWFactors, Component, CostForCarrier,
+        Costs, Dest, EnergyRating, ExportCredit, Factor, LoadMatching, Perimeter, RenNrenCo2,
+        Service, ServiceAllocation, Source, Step, Tariff, TimeResolution, Warning, WarningCode,
         SERVICES,
     },
-    vecops::{veckmul, vecsum, vecvecdif, vecvecmin, vecvecmul, vecvecsum},
+    vecops::{vecsum, vecvecdif, vecvecmin, vecvecmul, vecvecsum},
     Components, Factors,
 };
 
@@ -63,16 +68,51 @@ pub struct Balance {
     pub k_exp: f32,
     /// Reference area used for energy performance ratios (>1e-3)
     pub arearef: f32,
+    /// Time resolution of the calculation timesteps, if known
+    pub resolution: Option<TimeResolution>,
+    /// Assessment perimeter of the weighting factors (see `Factors::to_perimeter`)
+    #[serde(default)]
+    pub perimeter: Perimeter,
+    /// Weighting factors of cogenerated electricity and heat, if a cogeneration unit is defined
+    pub cogeneration: Option<CogenWFactors>,
+    /// Energy costs, if tariffs are defined for any carrier
+    pub costs: Option<Costs>,
     /// Energy balance results by carrier
     pub balance_cr: HashMap<Carrier, BalanceForCarrier>,
     /// Global energy balance results
     pub balance: BalanceTotal,
     /// Global energy balance results expressed as area ratios
     pub balance_m2: BalanceTotal,
+    /// Energy rating, if computed (see `cte::incorpora_calificacion`)
+    #[serde(default)]
+    pub rating: Option<EnergyRating>,
+    /// Automatic corrections of the declared components and weighting factors
+    #[serde(default)]
+    pub warnings: Vec<Warning>,
     /// Generic miscelaneous user provided data
     pub misc: Option<HashMap<String, String>>,
 }
 
+/// Unidad de un edificio (p.e. una vivienda de un bloque) para la evaluación de varias unidades
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildingUnit {
+    /// Unit identifier
+    pub id: String,
+    /// Energy components of the unit (produced and consumed energy data + metadata)
+    pub components: Components,
+    /// Reference area of the unit (>1e-3)
+    pub arearef: f32,
+}
+
+/// Resultados de la evaluación de un edificio con varias unidades
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnitsBalance {
+    /// Energy balance of each unit (unit id, balance), including its share of the shared production
+    pub units: Vec<(String, Balance)>,
+    /// Energy balance of the whole building, as the sum of the unit balances, with the total reference area
+    pub building: Balance,
+}
+
 /// Resultados del balance global (todos los vectores), en valor absoluto o por m2.
 #[allow(non_snake_case)]
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
@@ -93,6 +133,50 @@ pub struct BalanceTotal {
     pub we_exp_A: RenNrenCo2,
     /// Weighted exported energy for calculation step A+B
     pub we_exp: RenNrenCo2,
+    /// Balance result for calculation step A, in each timestep
+    pub A_t: Vec<RenNrenCo2>,
+    /// Balance result for calculation step A+B, in each timestep
+    pub B_t: Vec<RenNrenCo2>,
+    /// Weighted delivered energy, in each timestep
+    pub we_del_t: Vec<RenNrenCo2>,
+    /// Weighted exported energy for calculation step A, in each timestep
+    pub we_exp_A_t: Vec<RenNrenCo2>,
+    /// Weighted exported energy for calculation step A+B, in each timestep
+    pub we_exp_t: Vec<RenNrenCo2>,
+    /// Weighted energy for calculation step A+B, by assessment perimeter (see `rer`)
+    #[serde(default)]
+    pub B_perimeter: HashMap<Perimeter, RenNrenCo2>,
+    /// Weighted energy for calculation step A+B, by assessment perimeter and use (for EPB services)
+    #[serde(default)]
+    pub B_byuse_perimeter: HashMap<Perimeter, HashMap<Service, RenNrenCo2>>,
+    /// Renewable energy ratio (RER) for calculation step A+B, by assessment perimeter
+    /// (perimeters wider than the perimeter of the weighting factors, or the nearby perimeter with a wrong
+    /// definition of the nearby carriers, are not included)
+    #[serde(default)]
+    pub rer: HashMap<Perimeter, f32>,
+    /// Renewable energy ratio (RER) for calculation step A+B, by assessment perimeter and use (for EPB services)
+    #[serde(default)]
+    pub rer_byuse: HashMap<Perimeter, HashMap<Service, f32>>,
+    /// Balance of the extra weighting indicators (e.g. energy cost), in the order declared
+    /// in the weighting factors (see `Factors::indicators`)
+    #[serde(default)]
+    pub extra: Vec<ExtraIndicatorBalance>,
+}
+
+/// Resultados del balance de un indicador de ponderación adicional (p.e. coste de la energía)
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExtraIndicatorBalance {
+    /// Indicator name, as declared in the weighting factors (`CTE_INDICADORES` metadata)
+    pub name: String,
+    /// Balance result for calculation step A
+    pub A: f32,
+    /// Balance result for calculation step A, by use (for EPB services)
+    pub A_byuse: HashMap<Service, f32>,
+    /// Balance result for calculation step A+B
+    pub B: f32,
+    /// Balance result for calculation step A+B, by use (for EPB services)
+    pub B_byuse: HashMap<Service, f32>,
 }
 
 /// Calcula enficiencia energética agregando resultados por vector energético
@@ -104,10 +188,19 @@ pub struct BalanceTotal {
 /// * `k_exp` - exported energy factor [0, 1]
 /// * `arearef` - reference area used for computing energy performance ratios
 ///
+/// The load matching factor for electricity is taken from the `CTE_FMATCH` components metadata
+/// (see `LoadMatching`). Other carriers use a unit load matching factor.
+///
+/// The priority of on-site energy production is taken from the `CTE_PRIORIDAD_PRODUCCION` components metadata
+/// (see `Components::production_priority`). Without priorities produced energy is shared by generators
+/// in proportion to their production.
+///
 /// # Errors
 ///
 /// * Use of an `arearef` less than 1e-3 raises an error
 /// * Missing weighting factors needed for balance computation
+/// * Wrong definition of the load matching factor
+/// * Wrong definition of the production priority
 ///
 #[allow(non_snake_case)]
 pub fn energy_performance(
@@ -123,11 +216,36 @@ pub fn energy_performance(
         )));
     };
 
+    // Los consumos de servicios no declarados como EPB se consideran consumos no EPB
+    let components = &components.with_nepb_services()?;
     let carriers: HashSet<_> = components.cdata.iter().map(|e| e.carrier).collect();
+    // Los vectores de usuario de los componentes deben estar declarados en los factores de paso
+    wfactors.check_user_carriers(&carriers)?;
+    let f_match = components.load_matching()?;
+    let priority = components.production_priority()?;
+    let battery = components.battery()?;
+    let allocation = components.service_allocation()?;
+    let tariffs = components.tariffs()?;
+    // Comprueba la coherencia de los componentes con la resolución temporal
+    let resolution = components.time_resolution()?;
+    // Perímetro de evaluación de los factores de paso
+    let perimeter = wfactors.perimeter()?;
+    // Factores de paso de la electricidad cogenerada según el reparto del combustible de la cogeneración
+    let mut wfactors = wfactors.clone();
+    let cogeneration = components.cogen_wfactors(&wfactors)?;
+    if let Some(fp) = &cogeneration {
+        wfactors.set_cogen_wfactors(fp);
+    }
+    // Correcciones automáticas de los componentes y factores de paso declarados
+    let mut warnings: Vec<Warning> = components
+        .warnings
+        .iter()
+        .chain(&wfactors.warnings)
+        .cloned()
+        .collect();
 
-    // Compute balance for each carrier
-    let mut balance_cr: HashMap<Carrier, BalanceForCarrier> = HashMap::new();
-    for &carrier in &carriers {
+    // Balance for a carrier, using the given weighting factors
+    let carrier_balance = |carrier: Carrier, wfactors: &Factors| -> Result<BalanceForCarrier> {
         let components_cr: Vec<Component> = components
             .cdata
             .iter()
@@ -140,10 +258,32 @@ pub fn energy_performance(
             .filter(|e| e.carrier == carrier)
             .cloned()
             .collect();
-        let bal = balance_for_carrier(carrier, &components_cr, &fp_cr, k_exp)?;
-        balance_cr.insert(carrier, bal);
+        let (f_match_cr, battery_cr) = if carrier == Carrier::ELECTRICIDAD {
+            (f_match.clone(), battery.as_ref())
+        } else {
+            (LoadMatching::default(), None)
+        };
+        balance_for_carrier(
+            carrier,
+            &components_cr,
+            &fp_cr,
+            k_exp,
+            &f_match_cr,
+            &priority,
+            battery_cr,
+            allocation,
+        )
+    };
+
+    // Compute balance for each carrier
+    let mut balance_cr: HashMap<Carrier, BalanceForCarrier> = HashMap::new();
+    for &carrier in &carriers {
+        balance_cr.insert(carrier, carrier_balance(carrier, &wfactors)?);
     }
 
+    // Energy costs for carriers with a tariff
+    let costs = energy_costs(&tariffs, components, &balance_cr)?;
+
     // Accumulate partial balance values for total balance
     let balance: BalanceTotal = carriers
         .iter()
@@ -156,6 +296,12 @@ pub fn energy_performance(
             acc.we_del += balance_cr[cr].we_delivered_an;
             acc.we_exp_A += balance_cr[cr].we_exported_an_A;
             acc.we_exp += balance_cr[cr].we_exported_an;
+            // Weighted energy for each timestep
+            add_t(&mut acc.A_t, &balance_cr[cr].we_A);
+            add_t(&mut acc.B_t, &balance_cr[cr].we);
+            add_t(&mut acc.we_del_t, &balance_cr[cr].we_delivered);
+            add_t(&mut acc.we_exp_A_t, &balance_cr[cr].we_exported_A);
+            add_t(&mut acc.we_exp_t, &balance_cr[cr].we_exported);
             // Weighted energy for each use item (EPB services)
             for &service in &SERVICES {
                 // Energy use
@@ -174,7 +320,402 @@ pub fn energy_performance(
             acc
         });
 
+    // Renewable energy ratio (RER) for each assessment perimeter, and by use (ISO 52000-1, 10 and Annex B)
+    // Perimeters wider than the perimeter of the weighting factors can't be evaluated, and the nearby perimeter
+    // is not evaluated with a wrong definition of the nearby carriers
+    let nearby = match wfactors.nearby_carriers() {
+        Ok(nearby) => Some(nearby),
+        Err(e) => {
+            if perimeter <= Perimeter::NEARBY {
+                warnings.push(Warning::new(
+                    WarningCode::RER_PROXIMO_NO_EVALUADO,
+                    format!(
+                        "No se evalúa la fracción renovable en el perímetro próximo: {}",
+                        e
+                    ),
+                    None,
+                ));
+            }
+            None
+        }
+    };
+    let mut B_perimeter = HashMap::new();
+    let mut B_byuse_perimeter = HashMap::new();
+    for &perimeter_p in &[Perimeter::DISTANT, Perimeter::NEARBY, Perimeter::ONSITE] {
+        if perimeter_p < perimeter {
+            continue;
+        }
+        let wfactors_p = match (perimeter_p, &nearby) {
+            (Perimeter::NEARBY, Some(nearby)) => wfactors.to_perimeter(perimeter_p, nearby)?,
+            (Perimeter::NEARBY, None) => continue,
+            _ => wfactors.to_perimeter(perimeter_p, &[])?,
+        };
+        let mut B_p = RenNrenCo2::default();
+        let mut B_byuse_p: HashMap<Service, RenNrenCo2> = HashMap::new();
+        for cr in &carriers {
+            // Only carriers with modified weighting factors need a new balance
+            let fp_changed = wfactors_p
+                .wdata
+                .iter()
+                .filter(|fp_p| fp_p.carrier == *cr)
+                .any(|fp_p| {
+                    wfactors
+                        .wdata
+                        .iter()
+                        .find(|fp| {
+                            fp.source == fp_p.source
+                                && fp.dest == fp_p.dest
+                                && fp.step == fp_p.step
+                                && fp.carrier == fp_p.carrier
+                        })
+                        .map(|fp| {
+                            fp.factors() != fp_p.factors() || fp.values_t() != fp_p.values_t()
+                        })
+                        .unwrap_or(true)
+                });
+            let bal_cr_p;
+            let bal_cr = if fp_changed {
+                bal_cr_p = carrier_balance(*cr, &wfactors_p)?;
+                &bal_cr_p
+            } else {
+                &balance_cr[cr]
+            };
+            B_p += bal_cr.we_an;
+            for (service, value) in &bal_cr.we_an_byuse {
+                *B_byuse_p.entry(*service).or_default() += *value;
+            }
+        }
+        B_perimeter.insert(perimeter_p, B_p);
+        B_byuse_perimeter.insert(perimeter_p, B_byuse_p);
+    }
+    let (rer, rer_byuse) = rer_by_perimeter(&B_perimeter, &B_byuse_perimeter);
+
+    // Extra weighting indicators (e.g. energy cost), from the balance with the weighting factors of each indicator.
+    // The balance is linear in the weighting factors, including those of cogenerated electricity
+    let mut extra = Vec::new();
+    for (idx, name) in wfactors.indicators()?.into_iter().enumerate() {
+        let mut wfactors_i = wfactors.indicator_wfactors(idx);
+        if let Some(fp) = components.cogen_wfactors(&wfactors_i)? {
+            wfactors_i.set_cogen_wfactors(&fp);
+        }
+        let mut indicator = ExtraIndicatorBalance {
+            name,
+            ..Default::default()
+        };
+        for &cr in &carriers {
+            let bal_cr = carrier_balance(cr, &wfactors_i)?;
+            indicator.A += bal_cr.we_an_A.ren;
+            indicator.B += bal_cr.we_an.ren;
+            for (service, value) in &bal_cr.we_an_A_byuse {
+                *indicator.A_byuse.entry(*service).or_default() += value.ren;
+            }
+            for (service, value) in &bal_cr.we_an_byuse {
+                *indicator.B_byuse.entry(*service).or_default() += value.ren;
+            }
+        }
+        extra.push(indicator);
+    }
+
+    let balance = BalanceTotal {
+        B_perimeter,
+        B_byuse_perimeter,
+        rer,
+        rer_byuse,
+        extra,
+        ..balance
+    };
+
     // Compute area weighted total balance
+    let balance_m2 = balance_m2(&balance, arearef);
+
+    // Global data and results
+    Ok(Balance {
+        components: components.clone(),
+        wfactors,
+        k_exp,
+        arearef,
+        resolution,
+        perimeter,
+        cogeneration,
+        costs,
+        balance_cr,
+        balance,
+        balance_m2,
+        rating: None,
+        warnings,
+        misc: None,
+    })
+}
+
+/// Coste de la energía de los vectores con tarifa
+///
+/// Energy costs of the carriers with a tariff, using the metered energy of each carrier, that is, the energy
+/// delivered by the grid to EPB and non EPB uses and the energy exported to the grid. Returns None if no tariffs are defined.
+fn energy_costs(
+    tariffs: &[Tariff],
+    components: &Components,
+    balance_cr: &HashMap<Carrier, BalanceForCarrier>,
+) -> Result<Option<Costs>> {
+    if tariffs.is_empty() {
+        return Ok(None);
+    }
+    let mut costs = Costs::default();
+    for tariff in tariffs {
+        let cost = match balance_cr.get(&tariff.carrier) {
+            // Vector sin consumo ni producción: solamente el cargo fijo
+            None => CostForCarrier {
+                fixed: tariff.fixed,
+                total: tariff.fixed,
+                ..Default::default()
+            },
+            Some(bal_cr) => {
+                // Consumo de cada servicio EPB en cada paso de cálculo
+                let mut used_byservice: HashMap<Service, Vec<f32>> = HashMap::new();
+                for c in components.cdata.iter().filter(|c| {
+                    c.carrier == tariff.carrier
+                        && c.ctype == CType::CONSUMO
+                        && c.csubtype == CSubtype::EPB
+                }) {
+                    used_byservice
+                        .entry(c.service)
+                        .and_modify(|v| *v = vecvecsum(v, &c.values))
+                        .or_insert_with(|| c.values.clone());
+                }
+                // Energía medida: suministrada por la red a usos EPB y no EPB
+                let delivered = vecvecsum(&bal_cr.delivered_grid, &bal_cr.delivered_grid_nEPB);
+                tariff.costs(
+                    &delivered,
+                    &bal_cr.exported_grid,
+                    &used_byservice,
+                    &bal_cr.used_nEPB,
+                )?
+            }
+        };
+        costs.total += cost.total;
+        costs.nepb += cost.nepb;
+        for (service, value) in &cost.total_byservice {
+            *costs.total_byservice.entry(*service).or_default() += *value;
+        }
+        costs.bycarrier.insert(tariff.carrier, cost);
+    }
+    Ok(Some(costs))
+}
+
+/// Calcula la eficiencia energética de un edificio con varias unidades
+///
+/// Energy performance of a building with several units (e.g. dwellings in a block) and shared energy
+/// production (e.g. a common PV system), that is allocated to the units using the allocation rule of
+/// the shared components metadata (`CTE_REPARTO_PRODUCCION_COMUN`).
+///
+/// Returns the energy balance of each unit, with its share of the shared production and its own components
+/// metadata (battery, tariffs, priorities...), and the energy balance of the whole building, that aggregates
+/// the unit balances, so that its area ratios are the area weighted aggregate of the units.
+///
+/// * `shared` - shared energy production components and building level metadata
+/// * `units` - building units, with their energy components and reference area
+/// * `wfactors` - weighting factors
+/// * `k_exp` - exported energy factor [0, 1]
+///
+/// # Errors
+///
+/// * No units or shared components other than energy production
+/// * Components with a different number of timesteps
+/// * Incorrect allocation rule for the shared production or any error in the energy balance of a unit
+pub fn energy_performance_units(
+    shared: &Components,
+    units: &[BuildingUnit],
+    wfactors: &Factors,
+    k_exp: f32,
+) -> Result<UnitsBalance> {
+    if units.is_empty() {
+        return Err(EpbdError::WrongInput(
+            "No se han definido unidades para la evaluación del edificio".to_string(),
+        ));
+    }
+    if shared.cdata.iter().any(|c| c.ctype != CType::PRODUCCION) {
+        return Err(EpbdError::WrongInput(
+            "Los componentes comunes del edificio solo pueden ser de producción de energía"
+                .to_string(),
+        ));
+    }
+    let num_steps = units
+        .iter()
+        .flat_map(|u| u.components.cdata.iter())
+        .chain(&shared.cdata)
+        .map(|c| c.values.len())
+        .collect::<HashSet<_>>();
+    if num_steps.len() > 1 {
+        return Err(EpbdError::WrongInput(
+            "Los componentes de las unidades y comunes tienen distinto número de pasos de cálculo"
+                .to_string(),
+        ));
+    }
+    let num_steps = num_steps.into_iter().next().unwrap_or(0);
+
+    // Reparto de la producción común entre unidades
+    let allocation = shared.shared_production_allocation()?;
+    let areas: Vec<f32> = units.iter().map(|u| u.arearef).collect();
+    let mut units_cdata: Vec<Vec<Component>> =
+        units.iter().map(|u| u.components.cdata.clone()).collect();
+    for c in &shared.cdata {
+        // Consumo EPB de cada unidad del vector producido
+        let used: Vec<Vec<f32>> = units
+            .iter()
+            .map(|u| {
+                u.components
+                    .cdata
+                    .iter()
+                    .filter(|e| {
+                        e.carrier == c.carrier
+                            && e.ctype == CType::CONSUMO
+                            && e.csubtype == CSubtype::EPB
+                    })
+                    .fold(vec![0.0; num_steps], |acc, e| vecvecsum(&acc, &e.values))
+            })
+            .collect();
+        let shares = allocation.shares(&areas, &used)?;
+        for (cdata, share) in units_cdata.iter_mut().zip(&shares) {
+            cdata.push(Component {
+                values: vecvecmul(&c.values, share),
+                ..c.clone()
+            });
+        }
+    }
+
+    let units = units
+        .iter()
+        .zip(units_cdata)
+        .map(|(unit, cdata)| {
+            let components = Components {
+                cmeta: unit.components.cmeta.clone(),
+                cdata,
+                warnings: unit.components.warnings.clone(),
+            };
+            let balance = energy_performance(&components, wfactors, k_exp, unit.arearef)?;
+            Ok((unit.id.clone(), balance))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // Edificio completo: agregado de los balances de las unidades
+    let building = units_aggregate(shared, &units, wfactors, k_exp);
+
+    Ok(UnitsBalance { units, building })
+}
+
+/// Balance agregado de un edificio a partir de los balances de sus unidades
+///
+/// Aggregated energy balance of a building from the (non empty) energy balances of its units.
+///
+/// Energy, weighted energy and costs are the sum of the unit values, area ratios use the total reference area
+/// and the renewable energy ratios are weighted by the total weighted energy of each unit (the perimeter conversion
+/// of the weighting factors keeps the total weighting factor). The components are those of all units,
+/// with their share of the shared production, and the shared components metadata.
+/// Cogeneration weighting factors are only available in the balance of each unit.
+#[allow(non_snake_case)]
+fn units_aggregate(
+    shared: &Components,
+    units: &[(String, Balance)],
+    wfactors: &Factors,
+    k_exp: f32,
+) -> Balance {
+    let arearef = units.iter().map(|(_, b)| b.arearef).sum();
+    let components = Components {
+        cmeta: shared.cmeta.clone(),
+        cdata: units
+            .iter()
+            .flat_map(|(_, b)| b.components.cdata.iter())
+            .cloned()
+            .collect(),
+        warnings: shared.warnings.clone(),
+    };
+    let mut warnings = Vec::new();
+    for warning in units.iter().flat_map(|(_, b)| &b.warnings) {
+        if !warnings.contains(warning) {
+            warnings.push(warning.clone());
+        }
+    }
+
+    let mut balance_cr: HashMap<Carrier, BalanceForCarrier> = HashMap::new();
+    let mut balance = BalanceTotal::default();
+    let mut costs: Option<Costs> = None;
+    for (_, unit) in units {
+        for (cr, bal_cr) in &unit.balance_cr {
+            match balance_cr.get_mut(cr) {
+                Some(acc) => add_balance_cr(acc, bal_cr),
+                None => {
+                    balance_cr.insert(*cr, bal_cr.clone());
+                }
+            }
+        }
+        add_balance(&mut balance, &unit.balance);
+        if let Some(unit_costs) = &unit.costs {
+            add_costs(costs.get_or_insert_with(Costs::default), unit_costs);
+        }
+    }
+
+    // Effective load matching factor of the aggregated production and use (formula 32)
+    for bal_cr in balance_cr.values_mut() {
+        bal_cr.f_match = vecvecmin(&bal_cr.used_EPB, &bal_cr.produced)
+            .iter()
+            .zip(&bal_cr.produced_used_EPus)
+            .map(|(min, used)| if *min > 0.0 { used / min } else { 1.0 })
+            .collect();
+    }
+
+    // Renewable energy ratios of the aggregated weighted energy
+    let (rer, rer_byuse) = rer_by_perimeter(&balance.B_perimeter, &balance.B_byuse_perimeter);
+    balance.rer = rer;
+    balance.rer_byuse = rer_byuse;
+
+    let balance_m2 = balance_m2(&balance, arearef);
+
+    Balance {
+        components,
+        wfactors: wfactors.clone(),
+        k_exp,
+        arearef,
+        resolution: units.iter().find_map(|(_, b)| b.resolution),
+        perimeter: units[0].1.perimeter,
+        cogeneration: None,
+        costs,
+        balance_cr,
+        balance,
+        balance_m2,
+        rating: None,
+        warnings,
+        misc: None,
+    }
+}
+
+// Renewable energy ratios (RER) from the weighted energy by assessment perimeter, and by perimeter and use
+#[allow(non_snake_case)]
+fn rer_by_perimeter(
+    B_perimeter: &HashMap<Perimeter, RenNrenCo2>,
+    B_byuse_perimeter: &HashMap<Perimeter, HashMap<Service, RenNrenCo2>>,
+) -> (
+    HashMap<Perimeter, f32>,
+    HashMap<Perimeter, HashMap<Service, f32>>,
+) {
+    let rer = B_perimeter
+        .iter()
+        .map(|(perimeter, value)| (*perimeter, value.rer()))
+        .collect();
+    let rer_byuse = B_byuse_perimeter
+        .iter()
+        .map(|(perimeter, byuse)| {
+            let byuse = byuse
+                .iter()
+                .map(|(service, value)| (*service, value.rer()))
+                .collect();
+            (*perimeter, byuse)
+        })
+        .collect();
+    (rer, rer_byuse)
+}
+
+// Global balance expressed as area ratios
+#[allow(non_snake_case)]
+fn balance_m2(balance: &BalanceTotal, arearef: f32) -> BalanceTotal {
     let k_area = 1.0 / arearef;
     let mut used_EPB_byuse = balance.used_EPB_byuse.clone();
     used_EPB_byuse.values_mut().for_each(|v| *v *= k_area);
@@ -185,7 +726,7 @@ pub fn energy_performance(
     let mut B_byuse = balance.B_byuse.clone();
     B_byuse.values_mut().for_each(|v| *v *= k_area);
 
-    let balance_m2 = BalanceTotal {
+    BalanceTotal {
         used_EPB_byuse,
         A: k_area * balance.A,
         A_byuse,
@@ -194,19 +735,221 @@ pub fn energy_performance(
         we_del: k_area * balance.we_del,
         we_exp_A: k_area * balance.we_exp_A,
         we_exp: k_area * balance.we_exp,
-    };
+        A_t: mul_t(&balance.A_t, k_area),
+        B_t: mul_t(&balance.B_t, k_area),
+        we_del_t: mul_t(&balance.we_del_t, k_area),
+        we_exp_A_t: mul_t(&balance.we_exp_A_t, k_area),
+        we_exp_t: mul_t(&balance.we_exp_t, k_area),
+        B_perimeter: balance
+            .B_perimeter
+            .iter()
+            .map(|(perimeter, value)| (*perimeter, k_area * *value))
+            .collect(),
+        B_byuse_perimeter: balance
+            .B_byuse_perimeter
+            .iter()
+            .map(|(perimeter, byuse)| {
+                let byuse = byuse
+                    .iter()
+                    .map(|(service, value)| (*service, k_area * *value))
+                    .collect();
+                (*perimeter, byuse)
+            })
+            .collect(),
+        rer: balance.rer.clone(),
+        rer_byuse: balance.rer_byuse.clone(),
+        extra: balance
+            .extra
+            .iter()
+            .map(|e| {
+                let byuse = |values: &HashMap<Service, f32>| {
+                    values
+                        .iter()
+                        .map(|(service, value)| (*service, value * k_area))
+                        .collect()
+                };
+                ExtraIndicatorBalance {
+                    name: e.name.clone(),
+                    A: e.A * k_area,
+                    A_byuse: byuse(&e.A_byuse),
+                    B: e.B * k_area,
+                    B_byuse: byuse(&e.B_byuse),
+                }
+            })
+            .collect(),
+    }
+}
 
-    // Global data and results
-    Ok(Balance {
-        components: components.clone(),
-        wfactors: wfactors.clone(),
-        k_exp,
-        arearef,
-        balance_cr,
-        balance,
-        balance_m2,
-        misc: None,
-    })
+// Sum of global balance values (without renewable energy ratios, see `rer_by_perimeter`), acc += other
+fn add_balance(acc: &mut BalanceTotal, other: &BalanceTotal) {
+    add_map(&mut acc.used_EPB_byuse, &other.used_EPB_byuse);
+    acc.A += other.A;
+    add_map(&mut acc.A_byuse, &other.A_byuse);
+    acc.B += other.B;
+    add_map(&mut acc.B_byuse, &other.B_byuse);
+    acc.we_del += other.we_del;
+    acc.we_exp_A += other.we_exp_A;
+    acc.we_exp += other.we_exp;
+    add_t(&mut acc.A_t, &other.A_t);
+    add_t(&mut acc.B_t, &other.B_t);
+    add_t(&mut acc.we_del_t, &other.we_del_t);
+    add_t(&mut acc.we_exp_A_t, &other.we_exp_A_t);
+    add_t(&mut acc.we_exp_t, &other.we_exp_t);
+    // All units share the weighting factors, and thus the evaluated perimeters
+    add_map(&mut acc.B_perimeter, &other.B_perimeter);
+    for (perimeter, byuse) in &other.B_byuse_perimeter {
+        add_map(acc.B_byuse_perimeter.entry(*perimeter).or_default(), byuse);
+    }
+    if acc.extra.is_empty() {
+        acc.extra = other.extra.clone();
+    } else {
+        for (e, o) in acc.extra.iter_mut().zip(&other.extra) {
+            e.A += o.A;
+            add_map(&mut e.A_byuse, &o.A_byuse);
+            e.B += o.B;
+            add_map(&mut e.B_byuse, &o.B_byuse);
+        }
+    }
+}
+
+// Sum of energy balance values for a carrier, acc += other
+//
+// The load matching factor is not additive and has to be computed from the aggregated values
+fn add_balance_cr(acc: &mut BalanceForCarrier, other: &BalanceForCarrier) {
+    let add_v = |acc: &mut Vec<f32>, values: &[f32]| *acc = vecvecsum(acc, values);
+    add_v(&mut acc.used_EPB, &other.used_EPB);
+    add_map(&mut acc.used_EPB_an_byuse, &other.used_EPB_an_byuse);
+    add_v(&mut acc.used_nEPB, &other.used_nEPB);
+    add_v(&mut acc.produced, &other.produced);
+    acc.produced_an += other.produced_an;
+    for (gen, csubtype) in &other.generators {
+        acc.generators.entry(gen.clone()).or_insert(*csubtype);
+    }
+    add_map_t(&mut acc.produced_bygen, &other.produced_bygen);
+    add_map(&mut acc.produced_bygen_an, &other.produced_bygen_an);
+    add_v(&mut acc.produced_used_EPus, &other.produced_used_EPus);
+    add_map_t(
+        &mut acc.produced_used_EPus_bygen,
+        &other.produced_used_EPus_bygen,
+    );
+    add_v(&mut acc.stored, &other.stored);
+    acc.stored_an += other.stored_an;
+    add_v(&mut acc.discharged, &other.discharged);
+    acc.discharged_an += other.discharged_an;
+    add_v(&mut acc.storage_losses, &other.storage_losses);
+    acc.storage_losses_an += other.storage_losses_an;
+    add_v(&mut acc.exported, &other.exported);
+    acc.exported_an += other.exported_an;
+    add_map_t(&mut acc.exported_bygen, &other.exported_bygen);
+    add_map(&mut acc.exported_bygen_an, &other.exported_bygen_an);
+    add_v(&mut acc.exported_grid, &other.exported_grid);
+    acc.exported_grid_an += other.exported_grid_an;
+    add_v(&mut acc.exported_nEPB, &other.exported_nEPB);
+    acc.exported_nEPB_an += other.exported_nEPB_an;
+    add_v(&mut acc.delivered_grid, &other.delivered_grid);
+    acc.delivered_grid_an += other.delivered_grid_an;
+    add_v(&mut acc.delivered_grid_nEPB, &other.delivered_grid_nEPB);
+    acc.delivered_grid_nEPB_an += other.delivered_grid_nEPB_an;
+    acc.we_delivered_grid_an += other.we_delivered_grid_an;
+    acc.we_delivered_prod_an += other.we_delivered_prod_an;
+    acc.we_delivered_an += other.we_delivered_an;
+    acc.we_exported_an_A += other.we_exported_an_A;
+    acc.we_exported_nEPB_an_AB += other.we_exported_nEPB_an_AB;
+    acc.we_exported_grid_an_AB += other.we_exported_grid_an_AB;
+    acc.we_exported_an_AB += other.we_exported_an_AB;
+    acc.we_exported_an += other.we_exported_an;
+    add_t(&mut acc.we_delivered, &other.we_delivered);
+    add_t(&mut acc.we_exported_A, &other.we_exported_A);
+    add_t(&mut acc.we_exported, &other.we_exported);
+    add_t(&mut acc.we_A, &other.we_A);
+    add_t(&mut acc.we, &other.we);
+    for (gen, o) in &other.we_exported_bygen {
+        let e = acc
+            .we_exported_bygen
+            .entry(gen.clone())
+            .or_insert_with(|| WeightedExportedByGen {
+                fpA_nEPB: o.fpA_nEPB,
+                fpA_grid: o.fpA_grid,
+                fpB_nEPB: o.fpB_nEPB,
+                fpB_grid: o.fpB_grid,
+                ..Default::default()
+            });
+        e.exported_nEPB_an += o.exported_nEPB_an;
+        e.exported_grid_an += o.exported_grid_an;
+        e.we_exported_nEPB_an_A += o.we_exported_nEPB_an_A;
+        e.we_exported_grid_an_A += o.we_exported_grid_an_A;
+        e.we_exported_nEPB_an_AB += o.we_exported_nEPB_an_AB;
+        e.we_exported_grid_an_AB += o.we_exported_grid_an_AB;
+        // Averaged weighting factors of the aggregated exported energy
+        if e.exported_nEPB_an > 0.0 {
+            let k = 1.0 / e.exported_nEPB_an;
+            e.fpA_nEPB = k * e.we_exported_nEPB_an_A;
+            e.fpB_nEPB = k * (e.we_exported_nEPB_an_A + e.we_exported_nEPB_an_AB);
+        }
+        if e.exported_grid_an > 0.0 {
+            let k = 1.0 / e.exported_grid_an;
+            e.fpA_grid = k * e.we_exported_grid_an_A;
+            e.fpB_grid = k * (e.we_exported_grid_an_A + e.we_exported_grid_an_AB);
+        }
+    }
+    add_map(&mut acc.we_an_A_byuse, &other.we_an_A_byuse);
+    acc.we_an_A += other.we_an_A;
+    add_map(&mut acc.we_an_byuse, &other.we_an_byuse);
+    acc.we_an += other.we_an;
+}
+
+// Sum of energy costs, acc += other
+fn add_costs(acc: &mut Costs, other: &Costs) {
+    for (cr, o) in &other.bycarrier {
+        let e = acc.bycarrier.entry(*cr).or_default();
+        e.fixed += o.fixed;
+        e.energy_t = if e.energy_t.is_empty() {
+            o.energy_t.clone()
+        } else {
+            vecvecsum(&e.energy_t, &o.energy_t)
+        };
+        e.energy += o.energy;
+        e.compensation += o.compensation;
+        e.total += o.total;
+        add_map(&mut e.total_byservice, &o.total_byservice);
+        e.nepb += o.nepb;
+    }
+    acc.total += other.total;
+    add_map(&mut acc.total_byservice, &other.total_byservice);
+    acc.nepb += other.nepb;
+}
+
+// Sum of values by key, acc[k] += values[k]
+fn add_map<K: Clone + Eq + Hash, V: Copy + Default + AddAssign>(
+    acc: &mut HashMap<K, V>,
+    values: &HashMap<K, V>,
+) {
+    for (k, v) in values {
+        *acc.entry(k.clone()).or_default() += *v;
+    }
+}
+
+// Elementwise sum of timestep values by key, acc[k][t] += values[k][t]
+fn add_map_t(acc: &mut HashMap<String, Vec<f32>>, values: &HashMap<String, Vec<f32>>) {
+    for (k, v) in values {
+        acc.entry(k.clone())
+            .and_modify(|a| *a = vecvecsum(a, v))
+            .or_insert_with(|| v.clone());
+    }
+}
+
+// Elementwise sum of weighted energy for each timestep, acc[t] += values[t]
+fn add_t(acc: &mut Vec<RenNrenCo2>, values: &[RenNrenCo2]) {
+    if acc.is_empty() {
+        acc.extend_from_slice(values);
+    } else {
+        acc.iter_mut().zip(values).for_each(|(a, v)| *a += *v);
+    }
+}
+
+// Weighted energy for each timestep multiplied by a scalar
+fn mul_t(values: &[RenNrenCo2], k: f32) -> Vec<RenNrenCo2> {
+    values.iter().map(|v| k * *v).collect()
 }
 
 // Energy balance by carrier
@@ -230,24 +973,38 @@ pub struct BalanceForCarrier {
     pub produced: Vec<f32>,
     /// Produced energy (from all sources)
     pub produced_an: f32,
-    /// Produced energy in each timestep by non grid source (COGENERACION / INSITU)
-    pub produced_bygen: HashMap<CSubtype, Vec<f32>>,
-    /// Produced energy by non grid source (COGENERACION / INSITU)
-    pub produced_bygen_an: HashMap<CSubtype, f32>,
+    /// Origin (COGENERACION / INSITU) of each generator
+    pub generators: HashMap<String, CSubtype>,
+    /// Produced energy in each timestep by generator
+    pub produced_bygen: HashMap<String, Vec<f32>>,
+    /// Produced energy by generator
+    pub produced_bygen_an: HashMap<String, f32>,
     /// Produced energy from all origins and used for EPB services
     pub produced_used_EPus: Vec<f32>,
     /// Produced energy with origin in generator i and used for EPB services
-    pub produced_used_EPus_bygen: HashMap<CSubtype, Vec<f32>>,
+    pub produced_used_EPus_bygen: HashMap<String, Vec<f32>>,
     /// Load matching factor
     pub f_match: Vec<f32>,
+    /// Produced energy stored in the battery in each timestep
+    pub stored: Vec<f32>,
+    /// Produced energy stored in the battery
+    pub stored_an: f32,
+    /// Energy delivered by the battery to EPB uses in each timestep
+    pub discharged: Vec<f32>,
+    /// Energy delivered by the battery to EPB uses
+    pub discharged_an: f32,
+    /// Battery charge and discharge losses in each timestep
+    pub storage_losses: Vec<f32>,
+    /// Battery charge and discharge losses
+    pub storage_losses_an: f32,
     /// Exported energy to the grid and non EPB uses in each timestep
     pub exported: Vec<f32>, // exp_used_nEPus + exp_grid
     /// Exported energy to the grid and non EPB uses
     pub exported_an: f32,
-    /// Exported energy to the grid and non EPB uses in each timestep, by generation source
-    pub exported_bygen: HashMap<CSubtype, Vec<f32>>, // cambiado origin -> gen
-    /// Exported energy to the grid and non EPB uses, by generation source
-    pub exported_bygen_an: HashMap<CSubtype, f32>, // cambiado origin -> gen
+    /// Exported energy to the grid and non EPB uses in each timestep, by generator
+    pub exported_bygen: HashMap<String, Vec<f32>>,
+    /// Exported energy to the grid and non EPB uses, by generator
+    pub exported_bygen_an: HashMap<String, f32>,
     /// Exported energy to the grid in each timestep
     pub exported_grid: Vec<f32>,
     /// Exported energy to the grid
@@ -260,6 +1017,12 @@ pub struct BalanceForCarrier {
     pub delivered_grid: Vec<f32>,
     /// Delivered energy by the grid
     pub delivered_grid_an: f32,
+    /// Delivered energy by the grid to non EPB uses in each timestep (not included in the EPB balance)
+    #[serde(default)]
+    pub delivered_grid_nEPB: Vec<f32>,
+    /// Delivered energy by the grid to non EPB uses (not included in the EPB balance)
+    #[serde(default)]
+    pub delivered_grid_nEPB_an: f32,
     /// Weighted delivered energy by the grid
     pub we_delivered_grid_an: RenNrenCo2,
     /// Weighted delivered energy by any energy production sources
@@ -276,6 +1039,18 @@ pub struct BalanceForCarrier {
     pub we_exported_an_AB: RenNrenCo2,
     /// Weighted exported energy for calculation step A+B
     pub we_exported_an: RenNrenCo2,
+    /// Weighted delivered energy by the grid and any energy production sources, in each timestep
+    pub we_delivered: Vec<RenNrenCo2>,
+    /// Weighted exported energy for calculation step A, in each timestep
+    pub we_exported_A: Vec<RenNrenCo2>,
+    /// Weighted exported energy for calculation step A+B, in each timestep
+    pub we_exported: Vec<RenNrenCo2>,
+    /// Weighted energy for calculation step A, in each timestep
+    pub we_A: Vec<RenNrenCo2>,
+    /// Weighted energy, in each timestep
+    pub we: Vec<RenNrenCo2>,
+    /// Weighted exported energy for calculation steps A and AB, by generator and destination
+    pub we_exported_bygen: HashMap<String, WeightedExportedByGen>,
     /// Weighted energy for calculation step A
     pub we_an_A: RenNrenCo2,
     /// Weighted energy for calculation step A, by use (for EPB services)
@@ -286,6 +1061,36 @@ pub struct BalanceForCarrier {
     pub we_an_byuse: HashMap<Service, RenNrenCo2>,
 }
 
+/// Energía exportada ponderada de un generador, por destino y paso de cálculo
+///
+/// Weighted exported energy with origin in a generator, by destination (non EPB uses and grid).
+/// Includes the weighting factors applied, averaged along timesteps when they are not constant,
+/// so that formulas 23-26 can be reconstructed for each generator.
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WeightedExportedByGen {
+    /// Exported energy to non EPB uses
+    pub exported_nEPB_an: f32,
+    /// Exported energy to the grid
+    pub exported_grid_an: f32,
+    /// Weighting factor applied to the energy exported to non EPB uses, calculation step A
+    pub fpA_nEPB: RenNrenCo2,
+    /// Weighting factor applied to the energy exported to the grid, calculation step A
+    pub fpA_grid: RenNrenCo2,
+    /// Weighting factor applied to the energy exported to non EPB uses, calculation step B
+    pub fpB_nEPB: RenNrenCo2,
+    /// Weighting factor applied to the energy exported to the grid, calculation step B
+    pub fpB_grid: RenNrenCo2,
+    /// Weighted exported energy to non EPB uses for calculation step A (formula 24)
+    pub we_exported_nEPB_an_A: RenNrenCo2,
+    /// Weighted exported energy to the grid for calculation step A (formula 25)
+    pub we_exported_grid_an_A: RenNrenCo2,
+    /// Weighted exported energy to non EPB uses for calculation step AB (formula 26)
+    pub we_exported_nEPB_an_AB: RenNrenCo2,
+    /// Weighted exported energy to the grid for calculation step AB (formula 26)
+    pub we_exported_grid_an_AB: RenNrenCo2,
+}
+
 // --------------------------------------------------------------------
 // Energy calculation functions
 // --------------------------------------------------------------------
@@ -302,24 +1107,28 @@ pub struct BalanceForCarrier {
 /// * `cr_list` - list of components for carrier
 /// * `k_exp` - exported energy factor [0, 1]
 /// * `fp_cr` - weighting factors for carrier
+/// * `f_match` - load matching factor definition
+/// * `priority` - generators (ids or origins) in decreasing order of priority for the use of produced energy.
+///   When empty, produced energy is shared in proportion to the production of each generator
+/// * `battery` - electrical storage system, if any
+///
+/// Each production component is a generator i, identified as described in `production_by_generator`.
 ///
 /// # Errors
 ///
 /// * Missing weighting factors for a carrier, origin type, destination or calculation step
-///
-/// TODO:
-/// - Ahora mismo nosotros consideramos únicamente la existencia de dos orígenes de generación:
-///   insitu y cogeneración, sin diferenciar generadores individuales. Es decir, consideramos que hay
-///   dos generadores como mucho. La norma hace el reparto de la energía producida (14) por generador i
-///   y si implementamos el soporte generador a generador habría que revisar esto. En particular,
-///   cómo se calcula f_pr_cr_i.
-/// - Implementar factor de reparto de carga f_match_t
-#[allow(non_snake_case)]
+/// * Wrong definition of the load matching factor
+/// * Generators with the same id and different origins
+#[allow(non_snake_case, clippy::too_many_arguments)]
 fn balance_for_carrier(
     carrier: Carrier,
     cr_list: &[Component],
     fp_cr: &[Factor],
     k_exp: f32,
+    f_match: &LoadMatching,
+    priority: &[String],
+    battery: Option<&Battery>,
+    allocation: ServiceAllocation,
 ) -> Result<BalanceForCarrier> {
     // We know all carriers have the same timesteps (see FromStr for Components)
     let num_steps = cr_list[0].values.len();
@@ -336,47 +1145,78 @@ fn balance_for_carrier(
         .filter(|e| e.ctype == CType::CONSUMO && e.csubtype == CSubtype::NEPB)
         .fold(vec![0.0; num_steps], |acc, e| vecvecsum(&acc, &e.values));
 
-    // * Produced on-site energy and inside the assessment boundary, by generator i (origin i)
-    let mut E_pr_cr_i_t = HashMap::<CSubtype, Vec<f32>>::new();
-    for comp in cr_list
-        .iter()
-        .filter(|comp| comp.ctype == CType::PRODUCCION)
-    {
+    // * Produced on-site energy and inside the assessment boundary, by generator i
+    // PRODUCED ENERGY GENERATORS, with origin CSubtype::INSITU or CSubtype::COGENERACION
+    // generators are unique in this list and keep the order of the components
+    let mut E_pr_cr_i_t = HashMap::<String, Vec<f32>>::new();
+    let mut pr_generators: Vec<(String, CSubtype)> = Vec::new();
+    for (gen, comp) in production_by_generator(cr_list) {
+        match pr_generators.iter().find(|(g, _)| *g == gen) {
+            Some((_, source)) if *source != comp.csubtype => {
+                return Err(EpbdError::WrongInput(format!(
+                    "El generador {} tiene componentes con orígenes distintos ({}, {})",
+                    gen, source, comp.csubtype
+                )))
+            }
+            Some(_) => (),
+            None => pr_generators.push((gen.clone(), comp.csubtype)),
+        }
         E_pr_cr_i_t
-            .entry(comp.csubtype)
+            .entry(gen)
             .and_modify(|e| *e = vecvecsum(e, &comp.values))
             .or_insert_with(|| comp.values.clone());
     }
 
-    // PRODUCED ENERGY GENERATORS (CSubtype::INSITU or CSubtype::COGENERACION)
-    // generators are unique in this list
-    let pr_generators: Vec<CSubtype> = E_pr_cr_i_t.keys().cloned().collect(); // INSITU, COGENERACION
-
-    // Annually produced on-site energy from generator i (origin i)
-    let mut E_pr_cr_i_an = HashMap::<CSubtype, f32>::new();
-    for gen in &pr_generators {
-        E_pr_cr_i_an.insert(*gen, vecsum(&E_pr_cr_i_t[gen]));
+    // Annually produced on-site energy from generator i
+    let mut E_pr_cr_i_an = HashMap::<String, f32>::new();
+    for (gen, _) in &pr_generators {
+        E_pr_cr_i_an.insert(gen.clone(), vecsum(&E_pr_cr_i_t[gen]));
     }
 
     // * Energy produced on-site and inside the assessment boundary (formula 30)
     let mut E_pr_cr_t = vec![0.0; num_steps];
-    for gen in &pr_generators {
+    for (gen, _) in &pr_generators {
         E_pr_cr_t = vecvecsum(&E_pr_cr_t, &E_pr_cr_i_t[gen])
     }
     let E_pr_cr_an = vecsum(&E_pr_cr_t);
 
+    // * Produced energy used for EPB services, by generator i (formulas 14, 15 and 31)
+    // Production assigned to a service is used first by that service and the remainder is shared
+    // among all EPB services, with or without priorities on energy use (9.6.6.2.4)
+    let E_pr_cr_used_EPus = ProducedUsedEPus::new(cr_list, f_match, priority)?;
+    let E_pr_cr_i_used_EPus_t = E_pr_cr_used_EPus.bygen();
+
     // * Produced energy from all origins for EPB services for each time step (formula 31)
-    // TODO: f_match_t constant for electricity (formula 32)
-    // TODO: let f_match_t = fmatch(E_pr_cr_t / E_EPus_cr_t)
-    let f_match_t = vec![1.0; num_steps];
+    let E_pr_cr_used_EPus_t = E_pr_cr_used_EPus.used.clone();
 
-    let E_pr_cr_used_EPus_t = vecvecmul(&f_match_t, &vecvecmin(&E_EPus_cr_t, &E_pr_cr_t));
+    // * Load matching factor for each time step (formula 32, B.32)
+    // Effective value, that also includes the use of production assigned to services
+    let f_match_t = f_match
+        .values(&E_EPus_cr_t, &E_pr_cr_t)?
+        .iter()
+        .zip(vecvecmin(&E_EPus_cr_t, &E_pr_cr_t))
+        .zip(&E_pr_cr_used_EPus_t)
+        .map(|((f, min), used)| if min > 0.0 { used / min } else { *f })
+        .collect();
+
+    // * Electrical storage: produced energy not used for EPB services charges the battery
+    // and the stored energy is used for EPB services in later timesteps
+    let E_pr_cr_surplus_t = vecvecdif(&E_pr_cr_t, &E_pr_cr_used_EPus_t);
+    let E_EPus_cr_uncovered_t = vecvecdif(&E_EPus_cr_t, &E_pr_cr_used_EPus_t);
+    let E_sto_cr = match battery {
+        Some(battery) => battery.dispatch(&E_pr_cr_surplus_t, &E_EPus_cr_uncovered_t),
+        None => BatteryFlows {
+            charged: vec![0.0; num_steps],
+            discharged: vec![0.0; num_steps],
+            losses: vec![0.0; num_steps],
+        },
+    };
 
     // * Exported energy for each time step (produced energy not consumed in EPB uses) (formula 33)
-    // E_pr_cr_t = E_pr_cr_used_EPus_t + E_exp_cr_used_nEPus_t + E_exp_cr_grid_t
+    // E_pr_cr_t = E_pr_cr_used_EPus_t + E_sto_cr_in_t + E_exp_cr_used_nEPus_t + E_exp_cr_grid_t
     // E_exp_cr_t = E_exp_cr_used_nEPus_t + E_exp_cr_grid_t
-    // -> E_exp_cr_t = E_pr_cr_t - E_pr_cr_used_EPus_t
-    let E_exp_cr_t = vecvecdif(&E_pr_cr_t, &E_pr_cr_used_EPus_t);
+    // -> E_exp_cr_t = E_pr_cr_t - E_pr_cr_used_EPus_t - E_sto_cr_in_t
+    let E_exp_cr_t = vecvecdif(&E_pr_cr_surplus_t, &E_sto_cr.charged);
 
     // * Exported energy used for non-EPB uses for each time step (formula 34)
     let E_exp_cr_used_nEPus_t = vecvecmin(&E_exp_cr_t, &E_nEPus_cr_t);
@@ -391,53 +1231,53 @@ fn balance_for_carrier(
     let E_exp_cr_grid_an = vecsum(&E_exp_cr_grid_t);
 
     // * Delivered energy (by the grid) for EP uses for each interval (formula 37)
-    let E_del_cr_t = vecvecdif(&E_EPus_cr_t, &E_pr_cr_used_EPus_t);
+    // Energy used for EPB services not covered by produced energy or by the battery
+    let E_del_cr_t = vecvecdif(&E_EPus_cr_uncovered_t, &E_sto_cr.discharged);
 
     // * Annualy delivered energy (by the grid) for EP uses for carrier (formula 38)
     let E_del_cr_an = vecsum(&E_del_cr_t);
 
-    // ** Weighting depending on energy generator **
-
-    // Exported energy by generator i (origin) (9.6.6.2)
-    // Implementation WITHOUT priorities on energy use
-
-    // * Fraction of produced energy of type i (origin from generator i) (formula 14)
-    // FIXME: ¿El factor de reparto debería hacerse para cada componente de ese tipo y no agrupado por tipo
-    // FIXME: aunque la agrupación por tipo sea útil para ver orígenes?
-    let mut f_pr_cr_i = HashMap::<CSubtype, f32>::new();
-    for gen in &pr_generators {
-        let f = if E_pr_cr_an > 1e-3 {
-            E_pr_cr_i_an[gen] / E_pr_cr_an
-        } else {
-            0.0
-        };
-        f_pr_cr_i.insert(*gen, f);
-    }
+    // * Delivered energy (by the grid) for non EPB uses for each interval
+    // Energy used for non EPB services not covered by exported energy. Not part of the EPB balance, but metered
+    let E_del_cr_nEPus_t = vecvecdif(&E_nEPus_cr_t, &E_exp_cr_used_nEPus_t);
+    let E_del_cr_nEPus_an = vecsum(&E_del_cr_nEPus_t);
 
-    // * Produced energy with origin from generator i and used for EPB services (formula 15)
-    let mut E_pr_cr_i_used_EPus_t = HashMap::<CSubtype, Vec<f32>>::new();
-    for gen in &pr_generators {
-        E_pr_cr_i_used_EPus_t.insert(*gen, veckmul(&E_pr_cr_used_EPus_t, f_pr_cr_i[gen]));
-    }
+    // ** Weighting depending on energy generator **
 
-    // * Exported energy from generator i (origin i) (formula 16)
-    let mut E_exp_cr_i_t = HashMap::<CSubtype, Vec<f32>>::new();
-    for gen in &pr_generators {
-        E_exp_cr_i_t.insert(
-            *gen,
-            vecvecdif(&E_pr_cr_i_t[gen], &E_pr_cr_i_used_EPus_t[gen]),
-        );
+    // Exported energy by generator i (9.6.6.2)
+    // Implementation with or without priorities on energy use (9.6.6.2.4)
+
+    // * Exported energy from generator i (formula 16)
+    // Energy stored in the battery comes from each generator i in proportion to its surplus
+    let mut E_exp_cr_i_t = HashMap::<String, Vec<f32>>::new();
+    for (gen, _) in &pr_generators {
+        let mut E_exp_cr_i = vecvecdif(&E_pr_cr_i_t[gen], &E_pr_cr_i_used_EPus_t[gen]);
+        if battery.is_some() {
+            E_exp_cr_i = E_exp_cr_i
+                .iter()
+                .zip(&E_pr_cr_surplus_t)
+                .zip(&E_sto_cr.charged)
+                .map(|((exp_i, surplus), charged)| {
+                    if *surplus > 0.0 {
+                        exp_i - charged * exp_i / surplus
+                    } else {
+                        *exp_i
+                    }
+                })
+                .collect();
+        }
+        E_exp_cr_i_t.insert(gen.clone(), E_exp_cr_i);
     }
 
-    // * Annually exported energy from generator i (origin i)
-    let mut E_exp_cr_i_an = HashMap::<CSubtype, f32>::new();
-    for gen in &pr_generators {
-        E_exp_cr_i_an.insert(*gen, vecsum(&E_exp_cr_i_t[gen]));
+    // * Annually exported energy from generator i
+    let mut E_exp_cr_i_an = HashMap::<String, f32>::new();
+    for (gen, _) in &pr_generators {
+        E_exp_cr_i_an.insert(gen.clone(), vecsum(&E_exp_cr_i_t[gen]));
     }
 
     // -------- Weighted delivered and exported energy (11.6.2.1, 11.6.2.2, 11.6.2.3 + eq 2, 3)
-    // NOTE: All weighting factors have been considered constant through all timesteps
-    // NOTE: This allows using annual quantities and not timestep expressions
+    // NOTE: Constant weighting factors allow using annual quantities and not timestep expressions
+    // NOTE: Weighting factors with values for each timestep are applied timestep by timestep and then summed
 
     // Find weighting factor for 'step' of energy exported to 'dest' from the given energy 'source'.
     //
@@ -457,23 +1297,77 @@ fn balance_for_carrier(
             })
     }
 
+    // Weighted energy for the energy `E_t` of each timestep, using weighting factor `fp`
+    fn weighted_t(E_t: &[f32], fp: &Factor) -> Result<RenNrenCo2> {
+        Ok(fp
+            .factors_t(E_t.len())?
+            .iter()
+            .zip(E_t)
+            .fold(RenNrenCo2::default(), |acc, (fp_t, E)| acc + *E * *fp_t))
+    }
+
     // * Weighted energy for delivered energy: the cost of producing that energy
     let fpA_grid = fp_find(fp_cr, Source::RED, Dest::SUMINISTRO, Step::A)?;
-    let E_we_del_cr_grid_an = E_del_cr_an * fpA_grid.factors(); // formula 19, 39
-
-    // 2) Delivered energy from non cogeneration on-site sources (origin i)
-    let E_we_del_cr_onsite_an = E_pr_cr_i_an
-        .get(&CSubtype::INSITU)
-        .and_then(|E_pr_cr_i| {
-            fp_find(fp_cr, Source::INSITU, Dest::SUMINISTRO, Step::A)
-                .and_then(|fpA_pr_cr_i| Ok(E_pr_cr_i * fpA_pr_cr_i.factors()))
-                .ok()
-        })
-        .unwrap_or_default();
+    let E_we_del_cr_grid_an = if fpA_grid.is_constant() {
+        E_del_cr_an * fpA_grid.factors() // formula 19, 39
+    } else {
+        weighted_t(&E_del_cr_t, fpA_grid)?
+    };
+
+    // 2) Delivered energy from non cogeneration on-site sources (generators with origin INSITU)
+    let onsite_generators: Vec<_> = pr_generators
+        .iter()
+        .filter(|(_, source)| *source == CSubtype::INSITU)
+        .collect();
+    let E_pr_cr_onsite_t = onsite_generators
+        .iter()
+        .fold(vec![0.0; num_steps], |acc, (gen, _)| {
+            vecvecsum(&acc, &E_pr_cr_i_t[gen])
+        });
+    // District heating and user defined carriers need a factor for on-site produced energy (e.g. solar thermal field)
+    let fpA_pr_cr_onsite = if onsite_generators.is_empty() {
+        None
+    } else {
+        match fp_find(fp_cr, Source::INSITU, Dest::SUMINISTRO, Step::A) {
+            Ok(fpA_pr_cr_i) => Some(fpA_pr_cr_i),
+            Err(e) if matches!(carrier, Carrier::RED1 | Carrier::RED2 | Carrier::USUARIO(_)) => {
+                return Err(e)
+            }
+            Err(_) => None,
+        }
+    };
+    let E_we_del_cr_onsite_an = match fpA_pr_cr_onsite {
+        None => RenNrenCo2::default(),
+        Some(fpA_pr_cr_i) if fpA_pr_cr_i.is_constant() => {
+            let E_pr_cr_onsite_an: f32 = onsite_generators
+                .iter()
+                .map(|(gen, _)| E_pr_cr_i_an[gen])
+                .sum();
+            E_pr_cr_onsite_an * fpA_pr_cr_i.factors()
+        }
+        Some(fpA_pr_cr_i) => weighted_t(&E_pr_cr_onsite_t, fpA_pr_cr_i)?,
+    };
 
     // 3) Total delivered energy: grid + all onsite (but non cogeneration)
     let E_we_del_cr_an = E_we_del_cr_grid_an + E_we_del_cr_onsite_an; // formula 19, 39
 
+    // * Weighted delivered energy for each timestep: grid + all onsite (but non cogeneration)
+    let mut E_we_del_cr_t: Vec<RenNrenCo2> = fpA_grid
+        .factors_t(num_steps)?
+        .iter()
+        .zip(&E_del_cr_t)
+        .map(|(fp, E)| *E * *fp)
+        .collect();
+    if let Some(fpA_pr_cr_i) = fpA_pr_cr_onsite {
+        for ((we, fp), E) in E_we_del_cr_t
+            .iter_mut()
+            .zip(fpA_pr_cr_i.factors_t(num_steps)?)
+            .zip(&E_pr_cr_onsite_t)
+        {
+            *we += *E * fp;
+        }
+    }
+
     // // * Weighted energy for exported energy: depends on step A or B
 
     let mut E_we_exp_cr_an_A = RenNrenCo2::default();
@@ -481,6 +1375,9 @@ fn balance_for_carrier(
     let mut E_we_exp_cr_an = RenNrenCo2::default();
     let mut E_we_exp_cr_used_nEPus_an_AB = RenNrenCo2::default();
     let mut E_we_exp_cr_grid_an_AB = RenNrenCo2::default();
+    let mut we_exported_bygen = HashMap::<String, WeightedExportedByGen>::new();
+    let mut E_we_exp_cr_t_A = vec![RenNrenCo2::default(); num_steps];
+    let mut E_we_exp_cr_t_AB = vec![RenNrenCo2::default(); num_steps];
 
     let E_exp_cr_an = E_exp_cr_used_nEPus_an + E_exp_cr_grid_an;
 
@@ -489,48 +1386,104 @@ fn balance_for_carrier(
         // If there's no exportation, it's either because the carrier cannot be exported
         // or becuause there's no effective exportation
         // * Step A: weighting depends on exported energy generation (origin generator)
-        // Factors are averaged weighting by production for each origin (no priority, 9.6.6.2.4)
+        // Factors are averaged weighting by the exported energy of each origin (9.6.6.2.4)
+        // With priorities, exported energy comes mostly from the lowest priority generators
 
-        // * Fraction of produced energy tipe i (origin from generator i) that is exported (formula 14)
+        // * Fraction of exported energy with origin from generator i
         // NOTE: simplified for annual computations (not valid for timestep calculation)
-        let mut f_pr_cr_i = HashMap::<CSubtype, f32>::new();
-        for gen in &pr_generators {
-            // Do not store generators without generation
+        let E_exp_cr_gen_an: f32 = E_exp_cr_i_an.values().sum();
+        let mut f_pr_cr_i = HashMap::<&str, f32>::new();
+        for (gen, _) in &pr_generators {
+            // Do not store generators without exported energy
             if E_exp_cr_i_an[gen] != 0.0 {
-                f_pr_cr_i.insert(*gen, vecsum(&E_exp_cr_i_t[gen]) / E_exp_cr_i_an[gen]);
+                f_pr_cr_i.insert(gen, E_exp_cr_i_an[gen] / E_exp_cr_gen_an);
             }
         }
         // Generators (produced energy sources) that are exporting some energy (!= 0)
-        let exp_generators: Vec<_> = f_pr_cr_i.keys().collect();
+        let exp_generators: Vec<_> = pr_generators
+            .iter()
+            .filter(|(gen, _)| f_pr_cr_i.contains_key(gen.as_str()))
+            .collect();
 
-        // Weighting factors for energy exported to nEP uses (step A) (~formula 24)
-        let f_we_exp_cr_stepA_nEPus: RenNrenCo2 = if E_exp_cr_used_nEPus_an == 0.0 {
-            // No exported energy to nEP uses
-            RenNrenCo2::default() // ren: 0.0, nren: 0.0, co2: 0.0
-        } else {
-            exp_generators.iter().fold(
-                Ok(RenNrenCo2::default()),
-                |acc: Result<RenNrenCo2>, &gen| {
-                    let fp = fp_find(fp_cr, (*gen).try_into()?, Dest::A_NEPB, Step::A)?;
-                    Ok(acc? + (fp.factors() * f_pr_cr_i[gen]))
-                },
-            )? // sum all i (non grid sources): fpA_nEPus_i[gen] * f_pr_cr_i[gen]
+        // Exported energy from the exporting generators, for each timestep
+        let E_exp_cr_gen_t = exp_generators
+            .iter()
+            .fold(vec![0.0; num_steps], |acc, (gen, _)| {
+                vecvecsum(&acc, &E_exp_cr_i_t[gen])
+            });
+
+        // Weighting factors applied to the energy exported to `dest` from each generator i, in `step`
+        // Returns, for each exporting generator, the fraction of the energy exported to `dest` with origin in
+        // generator i (f_pr_cr_i) and the weighting factor applied (averaged along timesteps, if not constant)
+        //
+        // * `E_exp_dest_t` - exported energy to `dest`, for each timestep
+        // * `E_exp_dest_an` - annual exported energy to `dest`
+        let fp_exp_bygen = |dest: Dest,
+                            step: Step,
+                            E_exp_dest_t: &[f32],
+                            E_exp_dest_an: f32|
+         -> Result<Vec<(&str, f32, RenNrenCo2)>> {
+            if E_exp_dest_an == 0.0 {
+                // No exported energy to dest
+                return Ok(Vec::new());
+            }
+            let fps = exp_generators
+                .iter()
+                .map(|(gen, source)| Ok((gen, fp_find(fp_cr, (*source).try_into()?, dest, step)?)))
+                .collect::<Result<Vec<_>>>()?;
+            if fps.iter().all(|(_, fp)| fp.is_constant()) {
+                Ok(fps
+                    .iter()
+                    .map(|(gen, fp)| (gen.as_str(), f_pr_cr_i[gen.as_str()], fp.factors()))
+                    .collect())
+            } else {
+                // Timestep computation: sum all t: E_exp_dest_t * fp_i_t[gen] * f_pr_cr_i_t[gen]
+                let mut res = Vec::new();
+                for (gen, fp) in &fps {
+                    let fp_t = fp.factors_t(num_steps)?;
+                    let mut E_exp_dest_i_an = 0.0;
+                    let mut E_we_exp_dest_i_an = RenNrenCo2::default();
+                    for t in 0..num_steps {
+                        if E_exp_cr_gen_t[t] > 0.0 {
+                            let f_pr_cr_i_t = E_exp_cr_i_t[*gen][t] / E_exp_cr_gen_t[t];
+                            E_exp_dest_i_an += E_exp_dest_t[t] * f_pr_cr_i_t;
+                            E_we_exp_dest_i_an += (E_exp_dest_t[t] * f_pr_cr_i_t) * fp_t[t];
+                        }
+                    }
+                    let fp_i = if E_exp_dest_i_an > 0.0 {
+                        E_we_exp_dest_i_an * (1.0 / E_exp_dest_i_an)
+                    } else {
+                        fp.factors()
+                    };
+                    res.push((gen.as_str(), E_exp_dest_i_an / E_exp_dest_an, fp_i));
+                }
+                Ok(res)
+            }
         };
 
-        // Weighting factors for energy exported to the grid (step A) (~formula 25)
-        let f_we_exp_cr_stepA_grid: RenNrenCo2 = if E_exp_cr_grid_an == 0.0 {
-            // No energy exported to grid
-            RenNrenCo2::default() // ren: 0.0, nren: 0.0, co2: 0.0
-        } else {
-            exp_generators.iter().fold(
-                Ok(RenNrenCo2::default()),
-                |acc: Result<RenNrenCo2>, &gen| {
-                    let fp = fp_find(fp_cr, (*gen).try_into()?, Dest::A_RED, Step::A)?;
-                    Ok(acc? + (fp.factors() * f_pr_cr_i[gen]))
-                },
-            )? // sum all i (non grid sources): fpA_grid_i[gen] * f_pr_cr_i[gen];
+        // Weighting factors for exported energy, averaged by the exported energy from each generator
+        // sum all i (non grid sources): fp_i[gen] * f_pr_cr_i[gen]
+        let f_we_exp = |fp_bygen: &[(&str, f32, RenNrenCo2)]| {
+            fp_bygen
+                .iter()
+                .fold(RenNrenCo2::default(), |acc, (_, f_pr_cr_i, fp)| {
+                    acc + (*fp * *f_pr_cr_i)
+                })
         };
 
+        // Weighting factors for energy exported to nEP uses (step A) (~formula 24)
+        let fpA_nEPus_i = fp_exp_bygen(
+            Dest::A_NEPB,
+            Step::A,
+            &E_exp_cr_used_nEPus_t,
+            E_exp_cr_used_nEPus_an,
+        )?;
+        let f_we_exp_cr_stepA_nEPus: RenNrenCo2 = f_we_exp(&fpA_nEPus_i);
+
+        // Weighting factors for energy exported to the grid (step A) (~formula 25)
+        let fpA_grid_i = fp_exp_bygen(Dest::A_RED, Step::A, &E_exp_cr_grid_t, E_exp_cr_grid_an)?;
+        let f_we_exp_cr_stepA_grid: RenNrenCo2 = f_we_exp(&fpA_grid_i);
+
         // Weighted exported energy according to resources used to generate that energy (formula 23)
         E_we_exp_cr_an_A = (E_exp_cr_used_nEPus_an * f_we_exp_cr_stepA_nEPus) // formula 24
             + (E_exp_cr_grid_an * f_we_exp_cr_stepA_grid); // formula 25
@@ -538,32 +1491,17 @@ fn balance_for_carrier(
         // * Step B: weighting depends on exported energy generation and avoided resources on the grid
 
         // Factors of contribution for energy exported to nEP uses (step B)
-        let f_we_exp_cr_used_nEPus = if E_exp_cr_used_nEPus_an == 0.0 {
-            // No energy exported to nEP uses
-            RenNrenCo2::default() // ren: 0.0, nren: 0.0, co2: 0.0
-        } else {
-            exp_generators.iter().fold(
-                Ok(RenNrenCo2::default()),
-                |acc: Result<RenNrenCo2>, &gen| {
-                    let fp = fp_find(fp_cr, (*gen).try_into()?, Dest::A_NEPB, Step::B)?;
-                    Ok(acc? + (fp.factors() * f_pr_cr_i[gen]))
-                },
-            )? // sum all i (non grid sources): fpB_nEPus_i[gen] * f_pr_cr_i[gen]
-        };
+        let fpB_nEPus_i = fp_exp_bygen(
+            Dest::A_NEPB,
+            Step::B,
+            &E_exp_cr_used_nEPus_t,
+            E_exp_cr_used_nEPus_an,
+        )?;
+        let f_we_exp_cr_used_nEPus = f_we_exp(&fpB_nEPus_i);
 
         // Weighting factors for energy exported to the grid (step B)
-        let f_we_exp_cr_grid = if E_exp_cr_grid_an == 0.0 {
-            // No energy exported to grid
-            RenNrenCo2::default() // ren: 0.0, nren: 0.0, co2: 0.0
-        } else {
-            exp_generators.iter().fold(
-                Ok(RenNrenCo2::default()),
-                |acc: Result<RenNrenCo2>, &gen| {
-                    let fp = fp_find(fp_cr, (*gen).try_into()?, Dest::A_RED, Step::B)?;
-                    Ok(acc? + (fp.factors() * f_pr_cr_i[gen]))
-                },
-            )? // sum all i (non grid sources): fpB_grid_i[gen] * f_pr_cr_i[gen];
-        };
+        let fpB_grid_i = fp_exp_bygen(Dest::A_RED, Step::B, &E_exp_cr_grid_t, E_exp_cr_grid_an)?;
+        let f_we_exp_cr_grid = f_we_exp(&fpB_grid_i);
 
         // Effect of exported energy on weighted energy performance (step B) (formula 26)
 
@@ -572,6 +1510,94 @@ fn balance_for_carrier(
         E_we_exp_cr_grid_an_AB = E_exp_cr_grid_an * (f_we_exp_cr_grid - f_we_exp_cr_stepA_grid);
         E_we_exp_cr_an_AB = E_we_exp_cr_used_nEPus_an_AB + E_we_exp_cr_grid_an_AB;
 
+        // * Weighted exported energy for each timestep
+        // Weighted exported energy to `dest` in `step` for each timestep, using the averaged weighting factor `f_we`
+        // or the weighting factors of each generator for each timestep, if they are not constant
+        let we_exp_t = |dest: Dest,
+                        step: Step,
+                        E_exp_dest_t: &[f32],
+                        f_we: RenNrenCo2|
+         -> Result<Vec<RenNrenCo2>> {
+            if E_exp_dest_t.iter().all(|E| *E == 0.0) {
+                return Ok(vec![RenNrenCo2::default(); num_steps]);
+            }
+            let fps = exp_generators
+                .iter()
+                .map(|(gen, source)| Ok((gen, fp_find(fp_cr, (*source).try_into()?, dest, step)?)))
+                .collect::<Result<Vec<_>>>()?;
+            if fps.iter().all(|(_, fp)| fp.is_constant()) {
+                return Ok(E_exp_dest_t.iter().map(|E| *E * f_we).collect());
+            }
+            let mut E_we_exp_dest_t = vec![RenNrenCo2::default(); num_steps];
+            for (gen, fp) in &fps {
+                let fp_t = fp.factors_t(num_steps)?;
+                for t in 0..num_steps {
+                    if E_exp_cr_gen_t[t] > 0.0 {
+                        let f_pr_cr_i_t = E_exp_cr_i_t[*gen][t] / E_exp_cr_gen_t[t];
+                        E_we_exp_dest_t[t] += (E_exp_dest_t[t] * f_pr_cr_i_t) * fp_t[t];
+                    }
+                }
+            }
+            Ok(E_we_exp_dest_t)
+        };
+        let E_we_exp_cr_used_nEPus_t_A = we_exp_t(
+            Dest::A_NEPB,
+            Step::A,
+            &E_exp_cr_used_nEPus_t,
+            f_we_exp_cr_stepA_nEPus,
+        )?;
+        let E_we_exp_cr_grid_t_A = we_exp_t(
+            Dest::A_RED,
+            Step::A,
+            &E_exp_cr_grid_t,
+            f_we_exp_cr_stepA_grid,
+        )?;
+        let E_we_exp_cr_used_nEPus_t_B = we_exp_t(
+            Dest::A_NEPB,
+            Step::B,
+            &E_exp_cr_used_nEPus_t,
+            f_we_exp_cr_used_nEPus,
+        )?;
+        let E_we_exp_cr_grid_t_B =
+            we_exp_t(Dest::A_RED, Step::B, &E_exp_cr_grid_t, f_we_exp_cr_grid)?;
+        for t in 0..num_steps {
+            E_we_exp_cr_t_A[t] = E_we_exp_cr_used_nEPus_t_A[t] + E_we_exp_cr_grid_t_A[t];
+            E_we_exp_cr_t_AB[t] = (E_we_exp_cr_used_nEPus_t_B[t] - E_we_exp_cr_used_nEPus_t_A[t])
+                + (E_we_exp_cr_grid_t_B[t] - E_we_exp_cr_grid_t_A[t]);
+        }
+
+        // * Weighted exported energy by generator i and destination (formulas 23-26 for each generator)
+        // Factors for each generator: (fraction of the energy exported to dest, weighting factor)
+        let bygen = |fp_i: &[(&str, f32, RenNrenCo2)], gen: &str| {
+            fp_i.iter()
+                .find(|(g, _, _)| *g == gen)
+                .map(|(_, f, fp)| (*f, *fp))
+                .unwrap_or_default()
+        };
+        for (gen, _) in &exp_generators {
+            let (f_nEPus_i, fpA_nEPus) = bygen(&fpA_nEPus_i, gen);
+            let (f_grid_i, fpA_grid) = bygen(&fpA_grid_i, gen);
+            let (_, fpB_nEPus) = bygen(&fpB_nEPus_i, gen);
+            let (_, fpB_grid) = bygen(&fpB_grid_i, gen);
+            let E_exp_nEPus_i_an = E_exp_cr_used_nEPus_an * f_nEPus_i;
+            let E_exp_grid_i_an = E_exp_cr_grid_an * f_grid_i;
+            we_exported_bygen.insert(
+                gen.clone(),
+                WeightedExportedByGen {
+                    exported_nEPB_an: E_exp_nEPus_i_an,
+                    exported_grid_an: E_exp_grid_i_an,
+                    fpA_nEPB: fpA_nEPus,
+                    fpA_grid,
+                    fpB_nEPB: fpB_nEPus,
+                    fpB_grid,
+                    we_exported_nEPB_an_A: E_exp_nEPus_i_an * fpA_nEPus, // formula 24
+                    we_exported_grid_an_A: E_exp_grid_i_an * fpA_grid,   // formula 25
+                    we_exported_nEPB_an_AB: E_exp_nEPus_i_an * (fpB_nEPus - fpA_nEPus), // formula 26
+                    we_exported_grid_an_AB: E_exp_grid_i_an * (fpB_grid - fpA_grid), // formula 26
+                },
+            );
+        }
+
         // Contribution of exported energy to the annual weighted energy performance
         // 11.6.2.1, 11.6.2.2, 11.6.2.3
         E_we_exp_cr_an = E_we_exp_cr_an_A + (k_exp * E_we_exp_cr_an_AB); // (formula 20)
@@ -585,10 +1611,24 @@ fn balance_for_carrier(
     // Partial result for carrier (formula 2)
     let E_we_cr_an: RenNrenCo2 = E_we_del_cr_an - E_we_exp_cr_an;
 
+    // * Weighted exported energy and total results for steps A and B, for each timestep
+    let E_we_exp_cr_t: Vec<RenNrenCo2> = E_we_exp_cr_t_A
+        .iter()
+        .zip(&E_we_exp_cr_t_AB)
+        .map(|(A, AB)| *A + (k_exp * *AB))
+        .collect();
+    let E_we_cr_t_A: Vec<RenNrenCo2> = E_we_del_cr_t
+        .iter()
+        .zip(&E_we_exp_cr_t_A)
+        .map(|(del, exp)| *del - *exp)
+        .collect();
+    let E_we_cr_t: Vec<RenNrenCo2> = E_we_del_cr_t
+        .iter()
+        .zip(&E_we_exp_cr_t)
+        .map(|(del, exp)| *del - *exp)
+        .collect();
+
     // ================ Compute values by use ===============
-    // Compute fraction of used energy by use (for EPB services):
-    // used energy for service_i / used energy for all services)
-    let f_us_cr = compute_factors_by_use_cr(cr_list);
     // Annual energy use for carrier
     let E_EPus_cr_an: f32 = E_EPus_cr_t.iter().sum();
 
@@ -596,15 +1636,111 @@ fn balance_for_carrier(
     let mut E_Epus_cr_an_byuse: HashMap<Service, f32> = HashMap::new();
     let mut E_we_cr_an_A_byuse: HashMap<Service, RenNrenCo2> = HashMap::new();
     let mut E_we_cr_an_byuse: HashMap<Service, RenNrenCo2> = HashMap::new();
-    for service in &SERVICES {
-        let f_us_k_cr = *f_us_cr.get(service).unwrap_or(&0.0f32);
-        if f_us_k_cr != 0.0 {
-            // Used energy
-            E_Epus_cr_an_byuse.insert(*service, E_EPus_cr_an * f_us_k_cr);
-            // Step A
-            E_we_cr_an_A_byuse.insert(*service, E_we_cr_an_A * f_us_k_cr);
-            // Step B (E.2.6)
-            E_we_cr_an_byuse.insert(*service, E_we_cr_an * f_us_k_cr);
+    match allocation {
+        // Reverse calculation method (E.3.6): share of the weighted energy of the carrier
+        ServiceAllocation::INVERSO => {
+            // Compute fraction of used energy by use (for EPB services):
+            // used energy for service_i / used energy for all services)
+            let f_us_cr = compute_factors_by_use_cr(cr_list);
+            for service in &SERVICES {
+                let f_us_k_cr = *f_us_cr.get(service).unwrap_or(&0.0f32);
+                if f_us_k_cr != 0.0 {
+                    // Used energy
+                    E_Epus_cr_an_byuse.insert(*service, E_EPus_cr_an * f_us_k_cr);
+                    // Step A
+                    E_we_cr_an_A_byuse.insert(*service, E_we_cr_an_A * f_us_k_cr);
+                    // Step B (E.2.6)
+                    E_we_cr_an_byuse.insert(*service, E_we_cr_an * f_us_k_cr);
+                }
+            }
+        }
+        // Forward calculation method: weighted delivered energy and produced energy used by each service
+        // and a share of the exported energy (and of the weighted on-site production not used by EPB services)
+        ServiceAllocation::DIRECTO(credit) => {
+            let fpA_grid_t = fpA_grid.factors_t(num_steps)?;
+            let fpA_pr_cr_onsite_t = match fpA_pr_cr_onsite {
+                Some(fpA_pr_cr_i) => Some(fpA_pr_cr_i.factors_t(num_steps)?),
+                None => None,
+            };
+            let mut E_we_del_cr_an_byuse: HashMap<Service, RenNrenCo2> = HashMap::new();
+            let mut E_pr_cr_used_an_byuse: HashMap<Service, f32> = HashMap::new();
+            for service in &SERVICES {
+                let E_srv_cr_t = cr_list
+                    .iter()
+                    .filter(|c| {
+                        c.ctype == CType::CONSUMO
+                            && c.csubtype == CSubtype::EPB
+                            && c.service == *service
+                    })
+                    .fold(vec![0.0; num_steps], |acc, e| vecvecsum(&acc, &e.values));
+                let E_srv_cr_an = vecsum(&E_srv_cr_t);
+                if E_srv_cr_an == 0.0 {
+                    continue;
+                }
+                // Produced energy used by the service, by generator
+                let E_pr_cr_i_used_srv_t =
+                    E_pr_cr_used_EPus.byservice_bygen(*service, &E_EPus_cr_t, &E_srv_cr_t);
+                let E_pr_cr_used_srv_t = E_pr_cr_i_used_srv_t
+                    .values()
+                    .fold(vec![0.0; num_steps], |acc, e| vecvecsum(&acc, e));
+                // Delivered energy (by the grid) to the service, in proportion to its energy use not covered
+                // by produced energy (includes the energy discharged from the battery)
+                let E_del_srv_cr_t: Vec<f32> = E_srv_cr_t
+                    .iter()
+                    .zip(&E_pr_cr_used_srv_t)
+                    .zip(E_EPus_cr_uncovered_t.iter().zip(&E_del_cr_t))
+                    .map(|((srv, used), (uncovered, del))| {
+                        if *uncovered > 0.0 {
+                            del * (srv - used).max(0.0) / uncovered
+                        } else {
+                            0.0
+                        }
+                    })
+                    .collect();
+                // Weighted delivered energy: grid + produced energy from non cogeneration sources
+                let mut E_we_del_srv_cr_an = E_del_srv_cr_t
+                    .iter()
+                    .zip(&fpA_grid_t)
+                    .fold(RenNrenCo2::default(), |acc, (E, fp)| acc + *E * *fp);
+                if let Some(fpA_pr_cr_onsite_t) = &fpA_pr_cr_onsite_t {
+                    for (gen, _) in &onsite_generators {
+                        if let Some(E_pr_cr_i_used_srv) = E_pr_cr_i_used_srv_t.get(gen) {
+                            E_we_del_srv_cr_an += E_pr_cr_i_used_srv
+                                .iter()
+                                .zip(fpA_pr_cr_onsite_t)
+                                .fold(RenNrenCo2::default(), |acc, (E, fp)| acc + *E * *fp);
+                        }
+                    }
+                }
+                E_Epus_cr_an_byuse.insert(*service, E_srv_cr_an);
+                E_pr_cr_used_an_byuse.insert(*service, vecsum(&E_pr_cr_used_srv_t));
+                E_we_del_cr_an_byuse.insert(*service, E_we_del_srv_cr_an);
+            }
+            // Weighted delivered energy not used by EPB services (on-site production exported or stored)
+            // is shared along with the exported energy
+            let E_we_del_cr_rest_an = E_we_del_cr_an
+                - E_we_del_cr_an_byuse
+                    .values()
+                    .fold(RenNrenCo2::default(), |acc, e| acc + *e);
+            let E_pr_cr_used_an: f32 = E_pr_cr_used_an_byuse.values().sum();
+            for (service, E_we_del_srv_cr_an) in &E_we_del_cr_an_byuse {
+                let f_exp_srv_cr = match credit {
+                    ExportCredit::PRODUCCION if E_pr_cr_used_an > 0.0 => {
+                        E_pr_cr_used_an_byuse[service] / E_pr_cr_used_an
+                    }
+                    _ => E_Epus_cr_an_byuse[service] / E_EPus_cr_an,
+                };
+                // Step A
+                E_we_cr_an_A_byuse.insert(
+                    *service,
+                    *E_we_del_srv_cr_an + f_exp_srv_cr * (E_we_del_cr_rest_an - E_we_exp_cr_an_A),
+                );
+                // Step B
+                E_we_cr_an_byuse.insert(
+                    *service,
+                    *E_we_del_srv_cr_an + f_exp_srv_cr * (E_we_del_cr_rest_an - E_we_exp_cr_an),
+                );
+            }
         }
     }
 
@@ -615,11 +1751,18 @@ fn balance_for_carrier(
         used_nEPB: E_nEPus_cr_t,
         produced: E_pr_cr_t,
         produced_an: E_pr_cr_an,
+        generators: pr_generators.into_iter().collect(),
         produced_bygen: E_pr_cr_i_t,
         produced_bygen_an: E_pr_cr_i_an,
         produced_used_EPus: E_pr_cr_used_EPus_t,
         produced_used_EPus_bygen: E_pr_cr_i_used_EPus_t,
-        f_match: f_match_t,   // load matching factor
+        f_match: f_match_t, // load matching factor
+        stored_an: vecsum(&E_sto_cr.charged),
+        stored: E_sto_cr.charged,
+        discharged_an: vecsum(&E_sto_cr.discharged),
+        discharged: E_sto_cr.discharged,
+        storage_losses_an: vecsum(&E_sto_cr.losses),
+        storage_losses: E_sto_cr.losses,
         exported: E_exp_cr_t, // exp_used_nEPus + exp_grid
         exported_an: E_exp_cr_an,
         exported_bygen: E_exp_cr_i_t,
@@ -630,6 +1773,8 @@ fn balance_for_carrier(
         exported_nEPB_an: E_exp_cr_used_nEPus_an,
         delivered_grid: E_del_cr_t,
         delivered_grid_an: E_del_cr_an,
+        delivered_grid_nEPB: E_del_cr_nEPus_t,
+        delivered_grid_nEPB_an: E_del_cr_nEPus_an,
         // Weighted energy: { ren, nren }
         we_delivered_grid_an: E_we_del_cr_grid_an,
         we_delivered_prod_an: E_we_del_cr_onsite_an,
@@ -639,6 +1784,12 @@ fn balance_for_carrier(
         we_exported_grid_an_AB: E_we_exp_cr_grid_an_AB,
         we_exported_an_AB: E_we_exp_cr_an_AB,
         we_exported_an: E_we_exp_cr_an,
+        we_exported_bygen,
+        we_delivered: E_we_del_cr_t,
+        we_exported_A: E_we_exp_cr_t_A,
+        we_exported: E_we_exp_cr_t,
+        we_A: E_we_cr_t_A,
+        we: E_we_cr_t,
         we_an_A: E_we_cr_an_A,
         we_an_A_byuse: E_we_cr_an_A_byuse,
         we_an: E_we_cr_an,
@@ -646,6 +1797,301 @@ fn balance_for_carrier(
     })
 }
 
+/// Energía producida usada en servicios EPB, por generador
+///
+/// Produced energy used for EPB services, by generator (formulas 14, 15 and 31)
+///
+/// Production components assigned to a service (other than NDEF) are used first by the consumption
+/// of that service and the remaining production is shared among all EPB services (generic share-out).
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ProducedUsedEPus {
+    /// Produced energy used by services with assigned production, by generator, for each timestep
+    pub byservice: HashMap<Service, HashMap<String, Vec<f32>>>,
+    /// Produced energy used for EPB services in the generic share-out, by generator, for each timestep
+    pub generic: HashMap<String, Vec<f32>>,
+    /// Produced energy from all origins used for EPB services, for each timestep
+    pub used: Vec<f32>,
+}
+
+impl ProducedUsedEPus {
+    /// Reparto de la energía producida usada en servicios EPB para los componentes de un vector
+    ///
+    /// * `cr_list` - components of a carrier
+    /// * `f_match` - load matching factor definition
+    /// * `priority` - generators (ids or origins) in decreasing order of priority for the use of produced energy
+    ///
+    /// The load matching factor applies both to the production assigned to services and to the generic share-out.
+    #[allow(non_snake_case)]
+    pub fn new<'a, I>(cr_list: I, f_match: &LoadMatching, priority: &[String]) -> Result<Self>
+    where
+        I: IntoIterator<Item = &'a Component>,
+    {
+        let cr_list: Vec<&Component> = cr_list.into_iter().collect();
+        if cr_list.is_empty() {
+            return Ok(Self::default());
+        }
+        let num_steps = cr_list[0].values.len();
+
+        // Generators and priority levels
+        let pr_list = production_by_generator(cr_list.iter().cloned());
+        let mut generators: Vec<(String, CSubtype)> = Vec::new();
+        for (gen, comp) in &pr_list {
+            if !generators.iter().any(|(g, _)| g == gen) {
+                generators.push((gen.clone(), comp.csubtype));
+            }
+        }
+        let levels = priority_levels(priority, &generators);
+
+        // Energy used for EPB services, total and by service
+        let mut E_EPus_t = vec![0.0; num_steps];
+        let mut E_EPus_srv_t = HashMap::<Service, Vec<f32>>::new();
+        for comp in cr_list
+            .iter()
+            .filter(|c| c.ctype == CType::CONSUMO && c.csubtype == CSubtype::EPB)
+        {
+            E_EPus_t = vecvecsum(&E_EPus_t, &comp.values);
+            E_EPus_srv_t
+                .entry(comp.service)
+                .and_modify(|e| *e = vecvecsum(e, &comp.values))
+                .or_insert_with(|| comp.values.clone());
+        }
+
+        // Produced energy by generator, total and for each service with assigned production (or NDEF)
+        let mut E_pr_i_t = HashMap::<String, Vec<f32>>::new();
+        let mut E_pr_srv_i_t = HashMap::<Service, HashMap<String, Vec<f32>>>::new();
+        for (gen, comp) in &pr_list {
+            E_pr_i_t
+                .entry(gen.clone())
+                .and_modify(|e| *e = vecvecsum(e, &comp.values))
+                .or_insert_with(|| comp.values.clone());
+            E_pr_srv_i_t
+                .entry(comp.service)
+                .or_default()
+                .entry(gen.clone())
+                .and_modify(|e| *e = vecvecsum(e, &comp.values))
+                .or_insert_with(|| comp.values.clone());
+        }
+
+        // 1. Production assigned to services is used first by that service
+        let mut byservice = HashMap::<Service, HashMap<String, Vec<f32>>>::new();
+        let mut E_pr_used_srv_tot_t = vec![0.0; num_steps];
+        for (service, E_pr_srv_i) in &E_pr_srv_i_t {
+            if *service == Service::NDEF {
+                continue;
+            }
+            let E_pr_srv_t = E_pr_srv_i
+                .values()
+                .fold(vec![0.0; num_steps], |acc, e| vecvecsum(&acc, e));
+            let E_srv_t = E_EPus_srv_t
+                .get(service)
+                .cloned()
+                .unwrap_or_else(|| vec![0.0; num_steps]);
+            let E_pr_used_srv_t = f_match.produced_used(&E_srv_t, &E_pr_srv_t)?;
+            E_pr_used_srv_tot_t = vecvecsum(&E_pr_used_srv_tot_t, &E_pr_used_srv_t);
+            byservice.insert(
+                *service,
+                share_produced_used_bygen(&E_pr_used_srv_t, E_pr_srv_i, &levels),
+            );
+        }
+
+        // 2. Remaining production is shared among all EPB services
+        let E_EPus_rem_t = vecvecdif(&E_EPus_t, &E_pr_used_srv_tot_t);
+        let mut E_pr_rem_i_t = E_pr_i_t;
+        for (gen, used_t) in byservice.values().flatten() {
+            let E_pr_rem = E_pr_rem_i_t.get_mut(gen).unwrap();
+            *E_pr_rem = vecvecdif(E_pr_rem, used_t);
+        }
+        let E_pr_rem_t = generators
+            .iter()
+            .fold(vec![0.0; num_steps], |acc, (gen, _)| {
+                vecvecsum(&acc, &E_pr_rem_i_t[gen])
+            });
+        let E_pr_used_rem_t = f_match.produced_used(&E_EPus_rem_t, &E_pr_rem_t)?;
+        let generic = share_produced_used_bygen(&E_pr_used_rem_t, &E_pr_rem_i_t, &levels);
+
+        // Produced energy from all origins used for EPB services
+        let used = vecvecsum(&E_pr_used_srv_tot_t, &E_pr_used_rem_t);
+
+        Ok(Self {
+            byservice,
+            generic,
+            used,
+        })
+    }
+
+    /// Energía producida usada en servicios EPB, por generador
+    ///
+    /// Produced energy used for EPB services (assigned and generic), by generator, for each timestep
+    pub fn bygen(&self) -> HashMap<String, Vec<f32>> {
+        let mut bygen = self.generic.clone();
+        for (gen, used_t) in self.byservice.values().flatten() {
+            bygen
+                .entry(gen.clone())
+                .and_modify(|e| *e = vecvecsum(e, used_t))
+                .or_insert_with(|| used_t.clone());
+        }
+        bygen
+    }
+
+    /// Fracción del reparto genérico que corresponde a un servicio EPB
+    ///
+    /// Share of the generic share-out of produced energy used by `service`, for each timestep.
+    /// It is the energy use of the service not covered by its assigned production relative to
+    /// the energy use of all EPB services not covered by assigned production.
+    ///
+    /// * `service` - EPB service
+    /// * `E_EPus_t` - energy used for all EPB services, for each timestep
+    /// * `E_srv_t` - energy used for `service`, for each timestep
+    #[allow(non_snake_case)]
+    pub fn generic_share(&self, service: Service, E_EPus_t: &[f32], E_srv_t: &[f32]) -> Vec<f32> {
+        let mut E_EPus_rem_t = E_EPus_t.to_vec();
+        for used_t in self.byservice.values().flat_map(|m| m.values()) {
+            E_EPus_rem_t = vecvecdif(&E_EPus_rem_t, used_t);
+        }
+        let mut E_srv_rem_t = E_srv_t.to_vec();
+        if let Some(used_srv) = self.byservice.get(&service) {
+            for used_t in used_srv.values() {
+                E_srv_rem_t = vecvecdif(&E_srv_rem_t, used_t);
+            }
+        }
+        E_srv_rem_t
+            .iter()
+            .zip(&E_EPus_rem_t)
+            .map(|(v, t)| if v.abs() < f32::EPSILON { 0.0 } else { v / t })
+            .collect()
+    }
+
+    /// Energía producida usada por un servicio EPB, por generador
+    ///
+    /// Produced energy used by `service` (assigned production and its share of the generic share-out),
+    /// by generator, for each timestep
+    ///
+    /// * `service` - EPB service
+    /// * `E_EPus_t` - energy used for all EPB services, for each timestep
+    /// * `E_srv_t` - energy used for `service`, for each timestep
+    #[allow(non_snake_case)]
+    pub fn byservice_bygen(
+        &self,
+        service: Service,
+        E_EPus_t: &[f32],
+        E_srv_t: &[f32],
+    ) -> HashMap<String, Vec<f32>> {
+        let f_srv_t = self.generic_share(service, E_EPus_t, E_srv_t);
+        let mut bygen: HashMap<String, Vec<f32>> = self
+            .generic
+            .iter()
+            .map(|(gen, used_t)| (gen.clone(), vecvecmul(used_t, &f_srv_t)))
+            .collect();
+        if let Some(used_srv) = self.byservice.get(&service) {
+            for (gen, used_t) in used_srv {
+                bygen
+                    .entry(gen.clone())
+                    .and_modify(|e| *e = vecvecsum(e, used_t))
+                    .or_insert_with(|| used_t.clone());
+            }
+        }
+        bygen
+    }
+}
+
+/// Niveles de prioridad de los generadores para el uso de la energía producida
+///
+/// Priority levels of the generators for the use of produced energy, in decreasing order of priority
+///
+/// * `priority` - list of generator ids or origins (`INSITU`, `COGENERACION`) in decreasing order of priority
+/// * `generators` - generator ids and their origin
+///
+/// An origin includes in the same level all the generators with that origin that are not in a previous level.
+/// Items that do not match any of the generators are skipped.
+pub(crate) fn priority_levels(
+    priority: &[String],
+    generators: &[(String, CSubtype)],
+) -> Vec<Vec<String>> {
+    let mut assigned = HashSet::<String>::new();
+    let mut levels = Vec::new();
+    for item in priority {
+        let level: Vec<String> = generators
+            .iter()
+            .filter(|(gen, source)| {
+                !assigned.contains(gen) && (gen == item || source.to_string() == *item)
+            })
+            .map(|(gen, _)| gen.clone())
+            .collect();
+        if !level.is_empty() {
+            assigned.extend(level.iter().cloned());
+            levels.push(level);
+        }
+    }
+    levels
+}
+
+/// Reparto entre generadores de la energía producida usada en servicios EPB (9.6.6.2.4)
+///
+/// Share of the produced energy used for EPB services among generators (9.6.6.2.4)
+///
+/// * `E_pr_cr_used_EPus_t` - produced energy from all origins used for EPB services, for each timestep
+/// * `E_pr_cr_i_t` - produced energy by generator i, for each timestep
+/// * `priority` - levels of generators in decreasing order of priority (see `priority_levels`)
+///
+/// Without priorities (empty list), produced energy used for EPB services is shared among all generators
+/// in proportion to their production in each timestep, so that no generator supplies more than its production
+/// in a timestep. With priorities, produced energy is used in each timestep from the generators in each level,
+/// in the given order, up to their production. Generators in the same level, or not included in any level
+/// (used last), share the energy in the same way, in proportion to their production in the timestep.
+#[allow(non_snake_case)]
+pub(crate) fn share_produced_used_bygen<K: Clone + Eq + Hash>(
+    E_pr_cr_used_EPus_t: &[f32],
+    E_pr_cr_i_t: &HashMap<K, Vec<f32>>,
+    priority: &[Vec<K>],
+) -> HashMap<K, Vec<f32>> {
+    let mut E_pr_cr_i_used_EPus_t = HashMap::<K, Vec<f32>>::new();
+
+    // Generators without a defined priority are used last (all generators without priorities)
+    let non_prioritized: Vec<K> = E_pr_cr_i_t
+        .keys()
+        .filter(|gen| !priority.iter().any(|level| level.contains(gen)))
+        .cloned()
+        .collect();
+
+    // Produced energy used for EPB services and not yet assigned to a generator
+    let mut remaining_t = E_pr_cr_used_EPus_t.to_vec();
+
+    for level in priority.iter().chain(std::iter::once(&non_prioritized)) {
+        let level: Vec<&K> = level
+            .iter()
+            .filter(|gen| E_pr_cr_i_t.contains_key(gen))
+            .collect();
+        // Production of the generators in this level and energy used from them
+        let E_pr_cr_lvl_t = level.iter().fold(vec![0.0; remaining_t.len()], |acc, gen| {
+            vecvecsum(&acc, &E_pr_cr_i_t[gen])
+        });
+        let E_pr_cr_lvl_used_t = vecvecmin(&remaining_t, &E_pr_cr_lvl_t);
+        remaining_t = vecvecdif(&remaining_t, &E_pr_cr_lvl_used_t);
+        // Share among the generators of the level, in proportion to their production
+        for gen in level {
+            let used_t = E_pr_cr_i_t[gen]
+                .iter()
+                .zip(&E_pr_cr_lvl_t)
+                .zip(&E_pr_cr_lvl_used_t)
+                .map(|((pr_i, pr_lvl), used_lvl)| {
+                    if used_lvl >= pr_lvl {
+                        // All the production of the level is used
+                        *pr_i
+                    } else if *pr_lvl > 0.0 {
+                        used_lvl * pr_i / pr_lvl
+                    } else {
+                        0.0
+                    }
+                })
+                .collect();
+            E_pr_cr_i_used_EPus_t.insert(gen.clone(), used_t);
+        }
+    }
+
+    E_pr_cr_i_used_EPus_t
+}
+
 /// Calcula fracción de cada uso EPB para un vector energético i
 ///
 /// Compute share of each EPB use for a given carrier i
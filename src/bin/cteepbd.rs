@@ -32,13 +32,27 @@ cteepbd - Implementation of the ISO EN 52000-1 standard
 
   Energy performance of buildings - Overarching EPB assessment - General framework and procedures
   This implementation has used the following assumptions:
-  - weighting factors are constant for all timesteps
-  - no priority is set for energy production (average step A weighting factor f_we_el_stepA)
+  - weighting factors are constant for all timesteps, unless values for each timestep are given
+  - no priority is set for energy production (average step A weighting factor f_we_el_stepA), unless
+    a priority is set for the production origins (CTE_PRIORIDAD_PRODUCCION metadata)
   - all on-site produced energy from non cogeneration sources is considered as delivered
-  - on-site produced energy is not compensated on a service by service basis, but on a by carrier basis
-  - the load matching factor is constant and equal to 1.0
-  TODO:
-  - allow other values of the load matching factor (or usign functions) f_match_t (formula 32, B.32)
+  - there is no electrical storage, unless a battery is defined (CTE_BATERIA metadata). The battery
+    is discharged to cover EPB uses and then charged with the surplus production, in each timestep
+  - on-site produced energy assigned to a service is used first by that service, and the rest
+    is compensated on a by carrier basis
+  - cogenerated electricity used on-site has a zero step A supply factor, as the fuel is charged to the
+    fuel carrier, and exported cogenerated electricity uses user defined factors, unless a cogeneration
+    unit is defined (CTE_COGENERADOR metadata) and the fuel is allocated to electricity and heat
+  - the load matching factor is constant and equal to 1.0, unless a constant value, a value for each
+    timestep or the use of formula B.32 is selected (formula 32, B.32)
+  - results by EPB service use the reverse calculation method (E.3.6), unless the forward method
+    is selected (CTE_REPARTO_SERVICIOS metadata)
+  - user defined energy carriers (USUARIO_ name prefix) must be declared in the weighting factors file
+    (CTE_VECTOR_USUARIO metadata), along with their nearby and exportable flags
+  - electric vehicle charging (VE), people transport (TRA) and built-in appliances (EQU) are only
+    EPB services when declared as such (CTE_SERVICIOS_EPB metadata)
+  - energy costs are computed for carriers with a tariff (CTE_TARIFA metadata), using the energy
+    delivered by the grid to EPB and non EPB uses and exported to the grid in each timestep
 
 */
 
@@ -50,7 +64,7 @@ use std::str::FromStr;
 
 use cteepbd::{
     cte, energy_performance,
-    types::{MetaVec, RenNrenCo2, Service},
+    types::{LoadMatching, MetaVec, Perimeter, RenNrenCo2, Service},
     Balance, Components, UserWF,
 };
 
@@ -213,8 +227,11 @@ fn get_factor(
 fn get_components(archivo: Option<&str>) -> Components {
     if let Some(archivo_componentes) = archivo {
         println!("Componentes energéticos: \"{}\"", archivo_componentes);
-        readfile(archivo_componentes)
-            .parse::<Components>()
+        let data = readfile(archivo_componentes);
+        for warning in Components::validate(&data).warnings() {
+            eprintln!("{}", warning);
+        }
+        data.parse::<Components>()
             .unwrap_or_else(|e| {
                 eprintln!(
                     "ERROR: formato incorrecto del archivo de componentes \"{}\": {}",
@@ -251,6 +268,12 @@ fn start_app_and_get_matches() -> clap::ArgMatches<'static> {
             .help("Factor de exportación (k_exp)")
             .takes_value(true)
             .display_order(2))
+        .arg(Arg::with_name("fmatch")
+            .long("fmatch")
+            .value_name("FMATCH")
+            .help("Factor de coincidencia de cargas de la electricidad (f_match_t).\nValor constante, lista de valores para cada paso de cálculo o B32.\nP.e.: --fmatch B32")
+            .takes_value(true)
+            .display_order(3))
         .arg(Arg::with_name("archivo_componentes")
             .short("c")
             .long("archivo_componentes")
@@ -258,7 +281,7 @@ fn start_app_and_get_matches() -> clap::ArgMatches<'static> {
             .help("Archivo de definición de los componentes energéticos")
             .takes_value(true)
             //.validator(clap_validators::fs::is_file))
-            .display_order(3))
+            .display_order(4))
         .arg(Arg::with_name("archivo_factores")
             .short("f")
             .long("archivo_factores")
@@ -268,7 +291,7 @@ fn start_app_and_get_matches() -> clap::ArgMatches<'static> {
             .help("Archivo de definición de los componentes energéticos")
             .takes_value(true)
             //.validator(clap_validators::fs::is_file))
-            .display_order(4))
+            .display_order(5))
         .arg(Arg::with_name("fps_loc")
             .short("l")
             .value_name("LOCALIZACION")
@@ -276,7 +299,21 @@ fn start_app_and_get_matches() -> clap::ArgMatches<'static> {
             .required_unless_one(&["archivo_factores", "archivo_componentes"])
             .help("Localización que define los factores de paso\n")
             .takes_value(true)
-            .display_order(5))
+            .display_order(6))
+        .arg(Arg::with_name("biblioteca_factores")
+            .long("biblioteca_factores")
+            .value_name("BIBLIOTECA")
+            .conflicts_with("archivo_factores")
+            .help("Biblioteca de factores de paso reglamentarios usada con la localización (p.e. RITE2014)")
+            .takes_value(true)
+            .display_order(6))
+        .arg(Arg::with_name("dir_factores")
+            .long("dir_factores")
+            .value_name("DIR_FACTORES")
+            .conflicts_with("archivo_factores")
+            .help("Directorio con bibliotecas adicionales de factores de paso reglamentarios (archivos .csv\ncon los metadatos CTE_FUENTE y CTE_LOCALIZACION)")
+            .takes_value(true)
+            .display_order(6))
         // Archivos de salida
         .arg(Arg::with_name("gen_archivo_componentes")
             .long("oc")
@@ -333,6 +370,20 @@ fn start_app_and_get_matches() -> clap::ArgMatches<'static> {
             .long("demanda_anual_acs")
             .value_name("DEM_ACS")
             .help("Demanda anual de ACS [kWh]"))
+        // Perímetro de evaluación y servicios del balance
+        .arg(Arg::with_name("perimetro")
+            .long("perimetro")
+            .value_name("PERIMETRO")
+            .possible_values(&["DISTANT", "NEARBY", "ONSITE"])
+            .requires("archivo_componentes")
+            .help("Perímetro de evaluación de la energía renovable (DISTANT, NEARBY u ONSITE)")
+            .takes_value(true))
+        .arg(Arg::with_name("servicios")
+            .long("servicios")
+            .value_name("SERVICIOS")
+            .requires("archivo_componentes")
+            .help("Realiza el balance considerando solo los servicios indicados.\nP.e.: --servicios ACS,CAL")
+            .takes_value(true))
         .arg(Arg::with_name("acsnrb") // Obsoleto
             .short("N")
             .long("acs_nearby")
@@ -380,10 +431,106 @@ fn main() {
 
     // Componentes energéticos ---------------------------------------------------------------------
     let mut components = get_components(matches.value_of("archivo_componentes"));
+    if let Some(component) = components.cdata.first() {
+        let resolution = components
+            .time_resolution()
+            .ok()
+            .flatten()
+            .map(|r| r.to_string())
+            .unwrap_or_else(|| "-".to_string());
+        println!(
+            "Resolución temporal: {}, pasos de cálculo: {}",
+            resolution,
+            component.values.len()
+        );
+    }
 
-    // Cálculo para servicio de ACS en nearby
-    if matches.is_present("acsnrb") {
-        components = components.filter_by_epb_service(Service::ACS);
+    // Factor de coincidencia de cargas -----------------------------------------------------------
+    // Se obtiene antes de filtrar por servicio, ya que el reparto de la producción usa este factor
+    // CLI > Metadatos de componentes > Valor por defecto (1.0)
+    let fmatch_cli = matches.value_of("fmatch").map(|fmatchstr| {
+        fmatchstr.parse::<LoadMatching>().unwrap_or_else(|e| {
+            eprintln!("ERROR: factor de coincidencia de cargas incorrecto: {}", e);
+            exit(exitcode::DATAERR);
+        })
+    });
+    let orig_fmatch = if let Some(fmatch) = fmatch_cli {
+        // Actualiza metadato CTE_FMATCH al valor seleccionado
+        components.set_meta("CTE_FMATCH", &fmatch.to_string());
+        "usuario"
+    } else if components.has_meta("CTE_FMATCH") {
+        "metadatos"
+    } else {
+        "predefinido"
+    };
+    let fmatch = components.load_matching().unwrap_or_else(|e| {
+        eprintln!("ERROR: factor de coincidencia de cargas incorrecto: {}", e);
+        exit(exitcode::DATAERR);
+    });
+
+    println!(
+        "Factor de coincidencia de cargas ({}) [-]: {}",
+        orig_fmatch, fmatch
+    );
+
+    // Almacenamiento eléctrico (batería) -----------------------------------------------------------
+    let battery = components.battery().unwrap_or_else(|e| {
+        eprintln!("ERROR: definición incorrecta de la batería: {}", e);
+        exit(exitcode::DATAERR);
+    });
+    if let Some(battery) = battery {
+        println!(
+            "Batería (capacidad [kWh], rendimiento de carga y descarga [-]): {}",
+            battery
+        );
+    }
+
+    // Unidad de cogeneración -----------------------------------------------------------------------
+    let cogeneration = components.cogeneration().unwrap_or_else(|e| {
+        eprintln!("ERROR: definición incorrecta de la cogeneración: {}", e);
+        exit(exitcode::DATAERR);
+    });
+    if let Some(cogeneration) = cogeneration {
+        println!(
+            "Cogeneración (combustible, consumo, producción eléctrica y térmica [kWh], reparto): {}",
+            cogeneration
+        );
+    }
+
+    // Tarifas energéticas ---------------------------------------------------------------------------
+    let tariffs = components.tariffs().unwrap_or_else(|e| {
+        eprintln!("ERROR: definición incorrecta de las tarifas: {}", e);
+        exit(exitcode::DATAERR);
+    });
+    for tariff in tariffs {
+        println!(
+            "Tarifa (vector, cargo fijo [€], precios [€/kWh], compensación [€/kWh]): {}",
+            tariff
+        );
+    }
+
+    // Cálculo para un conjunto de servicios (ACS en nearby con --acs_nearby)
+    let services: Option<Vec<Service>> = if matches.is_present("acsnrb") {
+        Some(vec![Service::ACS])
+    } else {
+        matches.value_of("servicios").map(|list| {
+            list.split(',')
+                .map(|s| {
+                    s.trim().parse().unwrap_or_else(|_| {
+                        eprintln!("ERROR: servicio desconocido \"{}\"", s.trim());
+                        exit(exitcode::USAGE);
+                    })
+                })
+                .collect()
+        })
+    };
+    if let Some(services) = &services {
+        components = components
+            .filter_by_epb_services(services)
+            .unwrap_or_else(|e| {
+                eprintln!("ERROR: no se han podido filtrar los servicios: {}", e);
+                exit(exitcode::DATAERR);
+            });
     }
 
     if verbosity > 1 && !components.cmeta.is_empty() {
@@ -406,7 +553,24 @@ fn main() {
     // Factores de paso ---------------------------------------------------------------------------
 
     // 0. Factores por defecto, según modo
-    let default_locwf = &cte::CTE_LOCWF_RITE2014;
+    let mut registry = cte::WFactorsRegistry::new();
+    if let Some(dir) = matches.value_of("dir_factores") {
+        let ids = registry.load_dir(dir).unwrap_or_else(|e| {
+            eprintln!(
+                "ERROR: carga incorrecta de las bibliotecas de factores de paso de \"{}\": {}",
+                dir, e
+            );
+            exit(exitcode::DATAERR);
+        });
+        println!(
+            "Bibliotecas de factores de paso (\"{}\"): {}",
+            dir,
+            ids.join(", ")
+        );
+    }
+    let library = matches
+        .value_of("biblioteca_factores")
+        .unwrap_or(cte::CTE_WF_LIBRARY_DEFAULT);
     let default_userwf = cte::CTE_USERWF;
 
     // 1. Factores de paso definibles por el usuario (a través de la CLI o de metadatos)
@@ -434,12 +598,13 @@ fn main() {
             ("archivo", fp_cli.to_string(), fp)
         }
         (None, Some(l_cli), _) => {
-            let fp = cte::wfactors_from_loc(&l_cli, &default_locwf, user_wf, default_userwf);
-            ("usuario", l_cli.to_string(), fp)
+            let fp = cte::wfactors_from_library(library, l_cli, &registry, user_wf, default_userwf);
+            ("usuario", format!("{} ({})", l_cli, library), fp)
         }
         (None, None, Some(l_meta)) => {
-            let fp = cte::wfactors_from_loc(&l_meta, &default_locwf, user_wf, default_userwf);
-            ("metadatos", l_meta, fp)
+            let fp =
+                cte::wfactors_from_library(library, &l_meta, &registry, user_wf, default_userwf);
+            ("metadatos", format!("{} ({})", l_meta, library), fp)
         }
         _ => {
             eprintln!("ERROR: datos insuficientes para determinar los factores de paso");
@@ -470,10 +635,28 @@ fn main() {
         }
     }
 
-    // Transformación a factores de paso en nearby
-    if matches.is_present("acsnrb") {
-        // Estamos en cálculo de ACS en nearby
-        fpdata = cte::wfactors_to_nearby(&fpdata);
+    // Transformación a factores de paso en el perímetro de evaluación (ACS en nearby con --acs_nearby)
+    let perimeter = if matches.is_present("acsnrb") {
+        Perimeter::NEARBY
+    } else {
+        matches
+            .value_of("perimetro")
+            .map(|p| p.parse().unwrap())
+            .unwrap_or_default()
+    };
+    if perimeter != Perimeter::DISTANT {
+        fpdata = cte::nearby_carriers(&fpdata)
+            .and_then(|nearby| cte::wfactors_to_perimeter(&fpdata, perimeter, &nearby))
+            .unwrap_or_else(|e| {
+                eprintln!(
+                    "ERROR: no se han podido obtener los factores de paso en el perímetro {}: {}",
+                    perimeter, e
+                );
+                exit(exitcode::DATAERR);
+            });
+        if verbosity > 0 {
+            println!("Perímetro de evaluación: {}", perimeter);
+        }
     }
 
     // Área de referencia -------------------------------------------------------------------------
@@ -564,6 +747,7 @@ fn main() {
     let balance: Option<Balance> = if !components.cdata.is_empty() {
         let balance = energy_performance(&components, &fpdata, kexp, arearef)
             .map(|b| cte::incorpora_demanda_renovable_acs_nrb(b, maybe_demanda_anual_acs))
+            .map(cte::incorpora_calificacion)
             .unwrap_or_else(|e| {
                 eprintln!(
                     "ERROR: no se ha podido calcular el balance energético: {}",
@@ -585,6 +769,13 @@ fn main() {
 
     // Salida de resultados -----------------------------------------------------------------------
     if let Some(balance) = balance {
+        // Correcciones automáticas de los datos de entrada
+        if verbosity > 0 && !balance.warnings.is_empty() {
+            println!("Correcciones automáticas de los datos de entrada:");
+            for warning in &balance.warnings {
+                println!("- {}", warning);
+            }
+        }
         // Guardar balance en formato json
         if matches.is_present("archivo_salida_json") {
             let path = matches.value_of_os("archivo_salida_json").unwrap();
@@ -612,12 +803,41 @@ fn main() {
         // Mostrar siempre en formato de texto plano
         if matches.is_present("acsnrb") {
             println!("** Balance energético (servicio de ACS, perímetro próximo)");
+        } else if let Some(services) = &services {
+            let services = services
+                .iter()
+                .map(Service::to_string)
+                .collect::<Vec<_>>()
+                .join(", ");
+            println!(
+                "** Balance energético (servicios: {}, perímetro: {})",
+                services, balance.perimeter
+            );
+        } else if balance.perimeter != Perimeter::DISTANT {
+            println!("** Balance energético (perímetro: {})", balance.perimeter);
         } else {
             println!("** Balance energético");
         }
         let plain = cte::balance_to_plain(&balance);
         println!("{}", plain);
 
+        // Verificación del cumplimiento del DB-HE 2019, si se define la zona climática
+        match cte::ComplianceInput::from_components(&balance.components) {
+            Ok(Some(input)) => {
+                let input = cte::ComplianceInput {
+                    demanda_anual_acs: maybe_demanda_anual_acs,
+                    ..input
+                };
+                println!("** Verificación del DB-HE 2019 (HE0, HE4)");
+                println!("{}", cte::check_compliance(&balance, input));
+            }
+            Ok(None) => (),
+            Err(e) => eprintln!(
+                "ERROR: datos incorrectos para la verificación del DB-HE 2019: {}",
+                e
+            ),
+        }
+
         // Guardar balance en formato de texto plano
         if matches.is_present("archivo_salida_txt") {
             let path = matches.value_of_os("archivo_salida_txt").unwrap();
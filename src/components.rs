@@ -34,19 +34,26 @@ Los componentes modelizan el uso y producción de energía en el periodo de cál
 Hipótesis:
 
 - Se completa automáticamente el consumo de energía procedente del medioambiente con una producción
-- No se permite la producción de electricidad a usos concretos (se asume NDEF) (XXX: se podría eliminar)
+- Todos los componentes tienen el mismo número de pasos de cálculo, que corresponde a la resolución temporal
+  declarada en los metadatos (`CTE_RESOLUCION`), si existe
 */
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::str;
 
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    balance::ProducedUsedEPus,
     error::EpbdError,
-    types::{CSubtype, CType, Carrier, Component, Meta, MetaVec, Service},
-    vecops::{veclistsum, vecvecdif, vecvecmin, vecvecmul, vecvecsum},
+    types::{
+        Battery, CSubtype, CType, Carrier, CogenWFactors, Cogeneration, Component, LoadMatching,
+        Meta, MetaVec, Service, ServiceAllocation, Severity, SharedProductionAllocation, StartDate,
+        Tariff, TimeResolution, ValidationReport, Warning, WarningCode, SERVICES,
+    },
+    vecops::{veckmul, veclistsum, vecsum, vecvecdif, vecvecsum},
+    Factors,
 };
 
 /// Lista de datos de componentes con sus metadatos
@@ -62,6 +69,9 @@ pub struct Components {
     pub cmeta: Vec<Meta>,
     /// Metadata
     pub cdata: Vec<Component>,
+    /// Automatic corrections of the declared components (see `normalize`)
+    #[serde(skip)]
+    pub warnings: Vec<Warning>,
 }
 
 impl MetaVec for Components {
@@ -95,58 +105,540 @@ impl str::FromStr for Components {
     type Err = EpbdError;
 
     fn from_str(s: &str) -> Result<Components, Self::Err> {
-        let s_nobom = if s.starts_with("\u{feff}") {
-            &s[3..]
-        } else {
-            s
-        };
-        let lines: Vec<&str> = s_nobom.lines().map(str::trim).collect();
-        let metalines = lines
-            .iter()
-            .filter(|l| l.starts_with("#META") || l.starts_with("#CTE_"));
-        let datalines = lines
+        let (components, report, _) = parse_components(s);
+        if !report.is_ok() {
+            let errors = report
+                .errors()
+                .map(|e| e.to_string())
+                .collect::<Vec<_>>()
+                .join("\n");
+            return Err(EpbdError::ParseError(format!(
+                "los componentes energéticos:\n{}",
+                errors
+            )));
+        }
+        // Comprueba la coherencia con la resolución temporal declarada
+        components.time_resolution()?;
+        Ok(components)
+    }
+}
+
+/// Interpreta los componentes y recoge los problemas detectados en cada línea
+///
+/// Las líneas con errores no se incluyen en los componentes devueltos.
+/// Devuelve también la línea de la primera aparición de cada clave de metadatos.
+fn parse_components(s: &str) -> (Components, ValidationReport, HashMap<String, usize>) {
+    let s_nobom = s.strip_prefix('\u{feff}').unwrap_or(s);
+    let mut report = ValidationReport::default();
+    let mut cmeta = Vec::new();
+    let mut meta_lines = HashMap::new();
+    let mut cdata = Vec::new();
+    // Número de pasos de cálculo y línea del primer componente
+    let mut num_steps: Option<(usize, usize)> = None;
+
+    for (idx, rawline) in s_nobom.lines().enumerate() {
+        let nline = idx + 1;
+        let line = rawline.trim();
+        if line.starts_with("#META") || line.starts_with("#CTE_") {
+            match line.parse::<Meta>() {
+                Ok(meta) if !meta.key.is_empty() => {
+                    meta_lines.entry(meta.key.clone()).or_insert(nline);
+                    cmeta.push(meta)
+                }
+                Ok(_) => report.error(nline, None, "metadato sin clave"),
+                Err(_) => report.error(
+                    nline,
+                    None,
+                    "metadato mal formado, debe tener el formato \"#META CLAVE: valor\"",
+                ),
+            }
+            continue;
+        }
+        if line.starts_with('#') || line.starts_with("vector,") || line.is_empty() {
+            continue;
+        }
+        let nissues = report.issues.len();
+        validate_component_line(rawline, nline, &mut report);
+        if report.issues[nissues..]
             .iter()
-            .filter(|l| !(l.starts_with('#') || l.starts_with("vector,") || l.is_empty()));
-        let cmeta = metalines
-            .map(|e| e.parse())
-            .collect::<Result<Vec<Meta>, _>>()?;
-        let cdata = datalines
-            .map(|e| e.parse())
-            .collect::<Result<Vec<Component>, _>>()?;
+            .any(|i| i.severity == Severity::ERROR)
         {
-            let cdata_lens: Vec<_> = cdata.iter().map(|e| e.values.len()).collect();
-            if cdata_lens.iter().max().unwrap() != cdata_lens.iter().min().unwrap() {
-                return Err(EpbdError::ParseError(s.into()));
+            continue;
+        }
+        let component = match line.parse::<Component>() {
+            Ok(component) => component,
+            Err(e) => {
+                report.error(nline, None, e.to_string());
+                continue;
             }
+        };
+        match num_steps {
+            None => num_steps = Some((component.values.len(), nline)),
+            Some((steps, firstline)) if steps != component.values.len() => report.error(
+                nline,
+                None,
+                format!(
+                    "el componente tiene {} pasos de cálculo y el de la línea {} tiene {}",
+                    component.values.len(),
+                    firstline,
+                    steps
+                ),
+            ),
+            _ => (),
+        }
+        cdata.push(component);
+    }
+
+    if cdata.is_empty() && report.is_ok() {
+        report.warning(
+            s_nobom.lines().count().max(1),
+            None,
+            "no se ha definido ningún componente energético",
+        );
+    }
+
+    (
+        Components {
+            cmeta,
+            cdata,
+            ..Default::default()
+        },
+        report,
+        meta_lines,
+    )
+}
+
+/// Comprueba los campos de una línea de componente e informa de su columna
+///
+/// Detecta vectores energéticos, tipos y subtipos incorrectos o no admitidos en combinación,
+/// servicios desconocidos y valores no numéricos (errores), así como valores negativos y líneas
+/// en formato antiguo, sin servicio (avisos).
+fn validate_component_line(line: &str, nline: usize, report: &mut ValidationReport) {
+    // Campos de la línea, sin el comentario, con su columna (empezando en 1)
+    let data = line.split('#').next().unwrap_or("");
+    let mut fields = Vec::new();
+    let mut offset = 0;
+    for field in data.split(',') {
+        let lead = field.len() - field.trim_start().len();
+        let column = line[..offset + lead].chars().count() + 1;
+        fields.push((column, field.trim()));
+        offset += field.len() + 1;
+    }
+    if fields.len() < 4 {
+        report.error(
+            nline,
+            None,
+            "el componente debe tener al menos vector energético, tipo, subtipo y un valor",
+        );
+        return;
+    }
+
+    let (col, value) = fields[0];
+    let carrier = match value.parse::<Carrier>() {
+        Ok(carrier) => Some(carrier),
+        Err(EpbdError::ParseError(msg)) => {
+            report.error(nline, Some(col), msg);
+            None
+        }
+        Err(_) => {
+            report.error(
+                nline,
+                Some(col),
+                format!("vector energético \"{}\" desconocido", value),
+            );
+            None
+        }
+    };
+    let (col, value) = fields[1];
+    let ctype = value.parse::<CType>().ok();
+    if ctype.is_none() {
+        report.error(
+            nline,
+            Some(col),
+            format!(
+                "tipo \"{}\" desconocido, debe ser CONSUMO o PRODUCCION",
+                value
+            ),
+        );
+    }
+    let (col, value) = fields[2];
+    let csubtype = value.parse::<CSubtype>().ok();
+    if csubtype.is_none() {
+        report.error(
+            nline,
+            Some(col),
+            format!(
+                "subtipo \"{}\" desconocido, debe ser EPB, NEPB, INSITU o COGENERACION",
+                value
+            ),
+        );
+    }
+    if let (Some(carrier), Some(ctype), Some(csubtype)) = (carrier, ctype, csubtype) {
+        if let Err(msg) = Component::check_kind(ctype, csubtype, carrier) {
+            report.error(nline, Some(fields[0].0), msg);
+        }
+    }
+
+    // Servicio (opcional en el formato antiguo) y valores
+    let (col, value) = fields[3];
+    let valuesidx = if value.parse::<Service>().is_ok() {
+        4
+    } else if value.parse::<f32>().is_ok() {
+        report.warning(
+            nline,
+            Some(col),
+            "componente sin servicio (formato antiguo), se asigna al servicio NDEF",
+        );
+        3
+    } else {
+        report.error(
+            nline,
+            Some(col),
+            format!("servicio \"{}\" desconocido", value),
+        );
+        return;
+    };
+    if fields.len() == valuesidx {
+        report.error(nline, None, "el componente no tiene valores");
+    }
+    for &(col, value) in &fields[valuesidx..] {
+        match value.parse::<f32>() {
+            Ok(v) if v < 0.0 => {
+                report.warning(nline, Some(col), format!("valor negativo ({})", value))
+            }
+            Ok(_) => (),
+            Err(_) => report.error(nline, Some(col), format!("valor \"{}\" no numérico", value)),
         }
-        Ok(Components { cmeta, cdata })
     }
 }
 
 impl Components {
+    /// Valida los datos de componentes y devuelve un informe con todos los problemas detectados
+    ///
+    /// A diferencia de la interpretación con `from_str`, que se detiene con el primer error, se
+    /// recogen todos los errores y avisos, indicando la línea y, si es posible, la columna de cada uno.
+    ///
+    /// Se comprueban los metadatos mal formados, los vectores energéticos, tipos y subtipos
+    /// desconocidos o no admitidos en combinación, los servicios desconocidos, los valores no numéricos
+    /// o negativos, la coincidencia del número de pasos de cálculo de todos los componentes y su
+    /// coherencia con la resolución temporal declarada (`CTE_RESOLUCION`).
+    ///
+    /// La declaración de los vectores de usuario se comprueba con los factores de paso al calcular
+    /// el balance, donde un vector no declarado es un error (ver `Factors::check_user_carriers`).
+    pub fn validate(s: &str) -> ValidationReport {
+        let (components, mut report, meta_lines) = parse_components(s);
+        if report.is_ok() {
+            if let Err(e) = components.time_resolution() {
+                // Línea del metadato de la resolución temporal o, si esta es correcta, de la fecha de inicio incorrecta
+                let key = match components
+                    .get_meta("CTE_RESOLUCION")
+                    .map(|v| v.parse::<TimeResolution>())
+                {
+                    Some(Ok(_)) if components.start_date().is_err() => "CTE_FECHA_INICIO",
+                    _ => "CTE_RESOLUCION",
+                };
+                let nline = meta_lines.get(key).copied().unwrap_or(1);
+                report.error(nline, None, e.to_string());
+            }
+        }
+        report
+    }
+
     /// Corrige los componentes de consumo y producción
     ///
     /// - Asegura que la energía MEDIOAMBIENTE consumida tiene su producción correspondiente
-    /// - Asegura que la energía eléctrica producida no tiene un uso que no sea NDEF
+    ///
+    /// Las correcciones aplicadas se registran como avisos (campo `warnings`).
     ///
     /// Los metadatos, servicios y coherencia de los vectores se aseguran ya en el parsing
     pub fn normalize(mut self) -> Self {
-        self.force_ndef_use_for_electricity_production();
         self.compensate_env_use();
         self
     }
 
+    /// Factor de coincidencia de cargas definido en los metadatos (clave `CTE_FMATCH`)
+    ///
+    /// Si no se define se usa un valor constante igual a 1.0
+    pub fn load_matching(&self) -> Result<LoadMatching, EpbdError> {
+        self.get_meta("CTE_FMATCH")
+            .map(|v| v.parse())
+            .unwrap_or_else(|| Ok(LoadMatching::default()))
+    }
+
+    /// Método de reparto de la energía ponderada entre servicios EPB (clave `CTE_REPARTO_SERVICIOS`)
+    ///
+    /// Si no se define se usa el método inverso (E.3.6)
+    pub fn service_allocation(&self) -> Result<ServiceAllocation, EpbdError> {
+        self.get_meta("CTE_REPARTO_SERVICIOS")
+            .map(|v| v.parse())
+            .unwrap_or_else(|| Ok(ServiceAllocation::default()))
+    }
+
+    /// Unidad de cogeneración definida en los metadatos (clave `CTE_COGENERADOR`)
+    ///
+    /// Si no se define se devuelve None. En ese caso, todo el combustible se imputa al vector que alimenta
+    /// la cogeneración y la electricidad cogenerada exportada usa los factores de paso definidos por el usuario
+    pub fn cogeneration(&self) -> Result<Option<Cogeneration>, EpbdError> {
+        self.get_meta("CTE_COGENERADOR")
+            .map(|v| v.parse())
+            .transpose()
+    }
+
+    /// Factores de paso de la electricidad y del calor cogenerados (ver método cogeneration)
+    ///
+    /// # Errors
+    ///
+    /// * Definición incorrecta de la unidad de cogeneración
+    /// * Factores de paso de suministro del combustible o de la electricidad no definidos
+    pub fn cogen_wfactors(&self, wfactors: &Factors) -> Result<Option<CogenWFactors>, EpbdError> {
+        self.cogeneration()?
+            .map(|cogen| wfactors.cogen_wfactors(&cogen))
+            .transpose()
+    }
+
+    /// Sistema de almacenamiento eléctrico (batería) definido en los metadatos (clave `CTE_BATERIA`)
+    ///
+    /// Si no se define se devuelve None (sin almacenamiento)
+    pub fn battery(&self) -> Result<Option<Battery>, EpbdError> {
+        self.get_meta("CTE_BATERIA").map(|v| v.parse()).transpose()
+    }
+
+    /// Criterio de reparto de la producción común entre unidades (clave `CTE_REPARTO_PRODUCCION_COMUN`)
+    ///
+    /// Si no se define se reparte en proporción a la superficie de referencia de cada unidad
+    pub fn shared_production_allocation(&self) -> Result<SharedProductionAllocation, EpbdError> {
+        self.get_meta("CTE_REPARTO_PRODUCCION_COMUN")
+            .map(|v| v.parse())
+            .unwrap_or_else(|| Ok(SharedProductionAllocation::default()))
+    }
+
+    /// Servicios EPB (clave `CTE_SERVICIOS_EPB`)
+    ///
+    /// Incluye los servicios EPB por defecto y los servicios adicionales (VE, TRA, EQU)
+    /// declarados como EPB en los metadatos (p.e. `CTE_SERVICIOS_EPB: VE, TRA`)
+    ///
+    /// # Errors
+    ///
+    /// * Servicios declarados incorrectos
+    pub fn epb_services(&self) -> Result<Vec<Service>, EpbdError> {
+        let declared = match self.get_meta("CTE_SERVICIOS_EPB") {
+            Some(services) => services
+                .split(',')
+                .map(|s| s.trim().parse::<Service>())
+                .collect::<Result<Vec<Service>, _>>()?,
+            None => Vec::new(),
+        };
+        Ok(SERVICES
+            .iter()
+            .cloned()
+            .filter(|s| s.is_epb_by_default() || declared.contains(s))
+            .collect())
+    }
+
+    /// Componentes con los consumos EPB de servicios no declarados como EPB convertidos a consumos no EPB
+    ///
+    /// Los consumos de los servicios adicionales (VE, TRA, EQU) solo son EPB cuando se declaran como tales
+    /// (ver `epb_services`) y, en otro caso, se consideran consumos no EPB (NEPB)
+    ///
+    /// # Errors
+    ///
+    /// * Servicios declarados incorrectos
+    pub fn with_nepb_services(&self) -> Result<Components, EpbdError> {
+        let epb_services = self.epb_services()?;
+        let mut components = self.clone();
+        for c in components.cdata.iter_mut().filter(|c| {
+            c.ctype == CType::CONSUMO
+                && c.csubtype == CSubtype::EPB
+                && !epb_services.contains(&c.service)
+        }) {
+            c.csubtype = CSubtype::NEPB;
+            components.warnings.push(Warning::new(
+                WarningCode::CONSUMO_NEPB,
+                format!(
+                    "Consumo del servicio {} no declarado como EPB (CTE_SERVICIOS_EPB), se considera no EPB",
+                    c.service
+                ),
+                Some(c.to_string()),
+            ));
+        }
+        Ok(components)
+    }
+
+    /// Tarifas de los vectores energéticos definidas en los metadatos (clave `CTE_TARIFA`)
+    ///
+    /// Se puede definir una tarifa para cada vector energético
+    ///
+    /// # Errors
+    ///
+    /// * Definición incorrecta de alguna tarifa
+    /// * Más de una tarifa para un mismo vector energético
+    pub fn tariffs(&self) -> Result<Vec<Tariff>, EpbdError> {
+        let mut tariffs: Vec<Tariff> = Vec::new();
+        for meta in self.cmeta.iter().filter(|m| m.key == "CTE_TARIFA") {
+            let tariff: Tariff = meta.value.parse()?;
+            if tariffs.iter().any(|t| t.carrier == tariff.carrier) {
+                return Err(EpbdError::WrongInput(format!(
+                    "Se ha definido más de una tarifa para el vector {}",
+                    tariff.carrier
+                )));
+            }
+            tariffs.push(tariff);
+        }
+        Ok(tariffs)
+    }
+
+    /// Fecha de inicio del periodo de cálculo (clave `CTE_FECHA_INICIO`)
+    ///
+    /// Si no se define se considera un periodo de cálculo sin 29 de febrero que comienza el 1 de enero
+    pub fn start_date(&self) -> Result<StartDate, EpbdError> {
+        self.get_meta("CTE_FECHA_INICIO")
+            .map(|v| v.parse())
+            .unwrap_or_else(|| Ok(StartDate::default()))
+    }
+
+    /// Resolución temporal de los pasos de cálculo (clave `CTE_RESOLUCION`)
+    ///
+    /// Si no se declara, se deduce del número de pasos de cálculo (anual, mensual u horaria)
+    /// y se devuelve None cuando no corresponde a ninguna de ellas.
+    ///
+    /// # Errors
+    ///
+    /// * Resolución temporal o fecha de inicio incorrectas
+    /// * Componentes con un número de pasos de cálculo que no corresponde a la resolución declarada
+    pub fn time_resolution(&self) -> Result<Option<TimeResolution>, EpbdError> {
+        let num_steps = match self.cdata.first() {
+            Some(c) => c.values.len(),
+            None => return Ok(None),
+        };
+        let resolution = match self.get_meta("CTE_RESOLUCION") {
+            Some(value) => value.parse::<TimeResolution>()?,
+            None => return Ok(TimeResolution::from_num_steps(num_steps)),
+        };
+        let start = self.start_date()?;
+        let expected = resolution.num_steps(start.days_in_period());
+        if let Some(c) = self.cdata.iter().find(|c| c.values.len() != expected) {
+            return Err(EpbdError::WrongInput(format!(
+                "El componente \"{}\" tiene {} pasos de cálculo y la resolución temporal {} con fecha de inicio {} requiere {}",
+                c,
+                c.values.len(),
+                resolution,
+                start,
+                expected
+            )));
+        }
+        Ok(Some(resolution))
+    }
+
+    /// Agrega los componentes a una resolución temporal menor (p.e. de horaria a mensual o anual)
+    ///
+    /// Los valores de los pasos de cálculo de cada intervalo de la nueva resolución se suman.
+    /// Los meses se ordenan desde el mes de la fecha de inicio del periodo de cálculo.
+    /// Se actualiza la resolución temporal de los metadatos (clave `CTE_RESOLUCION`).
+    ///
+    /// # Errors
+    ///
+    /// * Resolución temporal de los componentes desconocida o incoherente
+    /// * Resolución temporal de destino no menor que la de los componentes
+    pub fn aggregate(&self, resolution: TimeResolution) -> Result<Self, EpbdError> {
+        let current = self.time_resolution()?.ok_or_else(|| {
+            EpbdError::WrongInput(
+                "No se puede determinar la resolución temporal de los componentes".into(),
+            )
+        })?;
+        let start = self.start_date()?;
+        let bins = current.aggregation_bins(resolution, &start)?;
+        let num_bins = resolution.num_steps(start.days_in_period());
+        if self.cdata[0].values.len() != bins.len() {
+            // Resolución deducida y periodo con un número de días distinto al de la fecha de inicio
+            return Err(EpbdError::WrongInput(format!(
+                "El número de pasos de cálculo ({}) no corresponde a la resolución temporal {} con fecha de inicio {}",
+                self.cdata[0].values.len(),
+                current,
+                start
+            )));
+        }
+
+        let mut newcomponents = self.clone();
+        for c in &mut newcomponents.cdata {
+            let mut values = vec![0.0; num_bins];
+            for (value, bin) in c.values.iter().zip(&bins) {
+                values[*bin] += value;
+            }
+            c.values = values;
+        }
+        newcomponents.set_meta("CTE_RESOLUCION", &resolution.to_string());
+        Ok(newcomponents)
+    }
+
+    /// Identificadores de los generadores de todos los vectores energéticos
+    ///
+    /// Ver production_by_generator
+    pub fn generators(&self) -> HashSet<String> {
+        let carriers: HashSet<_> = self.cdata.iter().map(|c| c.carrier).collect();
+        carriers
+            .iter()
+            .flat_map(|carrier| {
+                production_by_generator(self.cdata.iter().filter(|c| c.carrier == *carrier))
+                    .into_iter()
+                    .map(|(gen, _)| gen)
+            })
+            .collect()
+    }
+
+    /// Prioridad de uso de la energía producida por cada generador (clave `CTE_PRIORIDAD_PRODUCCION`)
+    ///
+    /// Lista de identificadores de generadores u orígenes de la producción (INSITU, COGENERACION),
+    /// separados por comas y en orden decreciente de prioridad.
+    /// P.e.: `#META CTE_PRIORIDAD_PRODUCCION: PV_CUBIERTA, INSITU, COGENERACION`
+    ///
+    /// Un origen incluye todos los generadores de ese origen no indicados anteriormente.
+    /// Si no se define, se devuelve una lista vacía (sin prioridades)
+    pub fn production_priority(&self) -> Result<Vec<String>, EpbdError> {
+        let priority = match self.get_meta("CTE_PRIORIDAD_PRODUCCION") {
+            Some(value) => value,
+            None => return Ok(Vec::new()),
+        };
+        let generators = self.generators();
+        priority
+            .split(',')
+            .map(|v| {
+                let v = v.trim();
+                match v.parse::<CSubtype>() {
+                    Ok(CSubtype::INSITU) | Ok(CSubtype::COGENERACION) => Ok(v.to_string()),
+                    _ if generators.contains(v) => Ok(v.to_string()),
+                    _ => Err(EpbdError::ParseError(format!(
+                        "prioridad de la producción \"{}\"",
+                        priority
+                    ))),
+                }
+            })
+            .collect()
+    }
+
     /// Filtra Componentes relacionados con un servicio EPB
     ///
     /// 1. Se seleccionan todos los consumos y producciones asignados al servicio
     /// 2. Se toman las producciones eléctricas
-    /// 3. Reparto de las producciones eléctricas en proporción al consumo del servicio respecto al consumo EPB
+    /// 3. Las producciones eléctricas asignadas a un servicio (distinto de NDEF) se usan primero en ese servicio
+    /// 4. Reparto del resto de las producciones eléctricas en proporción al consumo no cubierto del servicio
+    ///    respecto al consumo EPB no cubierto y entre generadores según la prioridad de la producción
+    ///    (ver método production_priority)
+    ///
+    /// La producción eléctrica usada en servicios EPB se corrige con el factor de coincidencia de cargas
+    /// definido en los metadatos (ver método load_matching), igual que en el balance del edificio.
+    /// Como la producción repartida ya está corregida, se fija un factor unitario en los componentes filtrados.
+    /// El reparto no considera el almacenamiento eléctrico (ver método battery), que se aplica en el balance
+    /// de los componentes filtrados.
     ///
     /// *Nota*: los componentes deben estar normalizados (ver método normalize) para asegurar que:
     /// - los consumos de MEDIOAMBIENTE de un servicio ya están equilibrados
-    /// - las producciones eléctricas no pueden ser asignadas a un servicio
+    ///
+    /// # Errors
+    ///
+    /// * Factor de coincidencia de cargas incorrecto
+    /// * Prioridad de la producción incorrecta
     #[allow(non_snake_case)]
-    pub fn filter_by_epb_service(&self, service: Service) -> Self {
+    pub fn filter_by_epb_service(&self, service: Service) -> Result<Self, EpbdError> {
         let num_steps = self.cdata[0].values.len(); // Pasos de cálculo
         let cdata = self.cdata.iter(); // Componentes
 
@@ -180,41 +672,80 @@ impl Components {
 
         // Si hay consumo y producción de electricidad, se reparte el consumo
         if E_srv_el_an > 0.0 && E_pr_el_an > 0.0 {
-            // Energía eléctrica consumida en usos EPB
+            // Energía eléctrica producida y consumida en usos EPB, corregida por f_match_t,
+            // para los servicios con producción asignada y para el resto (reparto genérico), por generador
+            let E_pr_el_used_EPus = ProducedUsedEPus::new(
+                cdata.clone().filter(|c| c.carrier == Carrier::ELECTRICIDAD),
+                &self.load_matching()?,
+                &self.production_priority()?,
+            )?;
+
+            // Energía eléctrica consumida en usos EPB y en el servicio srv
             let E_EPus_el_t_tot = E_EPus_el_t
                 .clone()
                 .fold(vec![0.0; num_steps], |acc, e| vecvecsum(&acc, &e.values));
-
-            // Fracción del consumo EPB que representa el servicio srv
             let E_srv_el_t_tot = E_srv_el_t
                 .clone()
                 .fold(vec![0.0; num_steps], |acc, e| vecvecsum(&acc, &e.values));
-            let f_srv_t = E_srv_el_t_tot
-                .iter()
-                .zip(&E_EPus_el_t_tot)
-                .map(|(v, t)| if v.abs() < f32::EPSILON { 0.0 } else { v / t })
-                .collect::<Vec<_>>();
+
+            // Fracción del consumo EPB no cubierto por producción asignada que representa el servicio srv
+            let f_srv_t =
+                E_pr_el_used_EPus.generic_share(service, &E_EPus_el_t_tot, &E_srv_el_t_tot);
 
             // Repartimos la producción eléctrica
+            let pr_el_i = production_by_generator(E_pr_el_t);
 
-            // Energía eléctrica producida y consumida en usos EPB, corregida por f_match_t
-            let f_match_t = vec![1.0; num_steps]; // TODO: implementar f_match_t
-            let E_pr_el_t_tot = E_pr_el_t
-                .clone()
-                .fold(vec![0.0; num_steps], |acc, e| vecvecsum(&acc, &e.values));
-            let E_pr_el_used_EPus_t =
-                vecvecmul(&f_match_t, &vecvecmin(&E_EPus_el_t_tot, &E_pr_el_t_tot));
+            // Producción anual de cada generador i asignada a cada servicio
+            let mut E_pr_el_i_srv_an = HashMap::<(&str, Service), f32>::new();
+            for (gen, c) in &pr_el_i {
+                *E_pr_el_i_srv_an.entry((gen, c.service)).or_default() += vecsum(&c.values);
+            }
 
-            // Para cada generador i
-            for mut E_pr_el_i in E_pr_el_t.cloned() {
-                // Fracción de la producción total que corresponde al generador i
-                let f_pr_el_i: f32 = E_pr_el_i.values.iter().sum::<f32>() / E_pr_el_an;
+            // Producción de cada componente usada por su servicio asignado, en proporción a la producción
+            // del componente, y producción anual no usada por el servicio asignado (disponible para el reparto genérico)
+            let mut E_pr_el_i_rem_an = HashMap::<&str, f32>::new();
+            let pr_el_i_used: Vec<(Vec<f32>, f32)> = pr_el_i
+                .iter()
+                .map(|(gen, c)| {
+                    let c_an = vecsum(&c.values);
+                    let E_pr_srv_an = E_pr_el_i_srv_an[&(gen.as_str(), c.service)];
+                    let used_t = match E_pr_el_used_EPus
+                        .byservice
+                        .get(&c.service)
+                        .and_then(|used_srv| used_srv.get(gen))
+                    {
+                        Some(used_t) if E_pr_srv_an > 0.0 => veckmul(used_t, c_an / E_pr_srv_an),
+                        _ => vec![0.0; num_steps],
+                    };
+                    let rem_an = c_an - vecsum(&used_t);
+                    *E_pr_el_i_rem_an.entry(gen).or_default() += rem_an;
+                    (used_t, rem_an)
+                })
+                .collect();
+
+            // Para cada componente de producción del generador i
+            for ((gen, c), (used_t, rem_an)) in pr_el_i.iter().zip(pr_el_i_used) {
+                let mut E_pr_el_i = (*c).clone();
+
+                // Producción asignada al servicio srv y usada por él
+                let E_pr_el_i_srv_t = if c.service == service {
+                    used_t
+                } else {
+                    vec![0.0; num_steps]
+                };
 
-                // Reparto proporcional a la producción del generador i y al consumo del servicio srv
-                E_pr_el_i.values = (&E_pr_el_used_EPus_t)
+                // Reparto genérico, proporcional a la producción del componente no usada por su servicio asignado
+                // y al consumo del servicio srv
+                let f_pr_el_i = if E_pr_el_i_rem_an[gen.as_str()] > 0.0 {
+                    rem_an / E_pr_el_i_rem_an[gen.as_str()]
+                } else {
+                    0.0
+                };
+                E_pr_el_i.values = E_pr_el_used_EPus.generic[gen]
                     .iter()
                     .zip(&f_srv_t)
-                    .map(|(v, f_srv)| v * f_pr_el_i * f_srv)
+                    .zip(&E_pr_el_i_srv_t)
+                    .map(|((v, f_srv), v_srv)| v * f_pr_el_i * f_srv + v_srv)
                     .collect();
                 E_pr_el_i.service = service;
                 E_pr_el_i.comment = format!(
@@ -229,24 +760,52 @@ impl Components {
         let mut newcomponents = Self {
             cdata: cdata_srv,
             cmeta,
+            ..Default::default()
         };
         newcomponents.set_meta("CTE_SERVICIO", &service.to_string());
+        // La producción repartida ya incluye el efecto del factor de coincidencia de cargas
+        if self.has_meta("CTE_FMATCH") {
+            newcomponents.set_meta("CTE_FMATCH", &LoadMatching::default().to_string());
+        }
 
-        newcomponents
+        Ok(newcomponents)
     }
 
-    /// Asegura que la energía eléctrica producida no tiene un uso que no sea NDEF
+    /// Filtra Componentes relacionados con un conjunto de servicios EPB
     ///
-    /// Esta restricción es propia de la implementación y de cómo hace el reparto de la producción,
-    /// solamente en base al consumo de cada servicio y sin tener en cuenta si se define un destino
-    ///XXX: *Esta restricción debería eliminarse*
-    fn force_ndef_use_for_electricity_production(&mut self) {
-        // Localiza componentes de energía procedente del medioambiente
-        for component in &mut self.cdata {
-            if component.carrier == Carrier::ELECTRICIDAD && component.ctype == CType::PRODUCCION {
-                component.service = Service::NDEF
+    /// Reúne los componentes filtrados para cada servicio (ver método filter_by_epb_service), de modo que
+    /// la producción eléctrica se reparte entre los servicios del conjunto igual que en el filtrado por servicio.
+    /// El metadato `CTE_SERVICIO` recoge la lista de servicios.
+    ///
+    /// # Errors
+    ///
+    /// * Lista de servicios vacía
+    /// * Errores del filtrado de cada servicio
+    pub fn filter_by_epb_services(&self, services: &[Service]) -> Result<Self, EpbdError> {
+        let mut unique: Vec<Service> = Vec::new();
+        for service in services {
+            if !unique.contains(service) {
+                unique.push(*service);
             }
         }
+        let services = unique;
+        let (first, rest) = services.split_first().ok_or_else(|| {
+            EpbdError::WrongInput(
+                "No se han indicado servicios para filtrar los componentes".into(),
+            )
+        })?;
+        let mut newcomponents = self.filter_by_epb_service(*first)?;
+        for &service in rest {
+            let components_srv = self.filter_by_epb_service(service)?;
+            newcomponents.cdata.extend(components_srv.cdata);
+        }
+        let services_list = services
+            .iter()
+            .map(Service::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+        newcomponents.set_meta("CTE_SERVICIO", &services_list);
+        Ok(newcomponents)
     }
 
     /// Asegura que la energía MEDIOAMBIENTE consumida está equilibrada por una producción in situ
@@ -265,8 +824,6 @@ impl Components {
         // Identifica servicios
         let services: HashSet<_> = envcomps.iter().map(|c| c.service).collect();
 
-        // Asegura que la producción eléctrica no tiene un uso definido (es NDEF)
-
         // Genera componentes de consumo no compensados con producción
         let mut balancecomps: Vec<Component> = services
             .iter()
@@ -326,10 +883,48 @@ impl Components {
             .collect::<Option<Vec<_>>>()
             .unwrap_or_else(Vec::new);
         // Agrega componentes no compensados
+        for c in &balancecomps {
+            self.warnings.push(Warning::new(
+                WarningCode::PRODUCCION_MEDIOAMBIENTE,
+                format!(
+                    "Producción de MEDIOAMBIENTE añadida para equilibrar el consumo del servicio {}",
+                    c.service
+                ),
+                Some(c.to_string()),
+            ));
+        }
         self.cdata.append(&mut balancecomps);
     }
 }
 
+/// Componentes de producción de un vector energético y su generador
+///
+/// Cada componente de producción corresponde a un generador, identificado por la etiqueta
+/// `CTEEPBD_GENERADOR=<id>` de su comentario o, si no se indica, por su origen y su número de orden
+/// entre los componentes de ese origen (p.e. `INSITU1`, `INSITU2`, `COGENERACION1`).
+/// Los componentes con el mismo identificador corresponden a un mismo generador.
+///
+/// * `cr_list` - componentes de un vector energético
+pub(crate) fn production_by_generator<'a, I>(cr_list: I) -> Vec<(String, &'a Component)>
+where
+    I: IntoIterator<Item = &'a Component>,
+{
+    let mut count = HashMap::<CSubtype, usize>::new();
+    cr_list
+        .into_iter()
+        .filter(|c| c.ctype == CType::PRODUCCION)
+        .map(|c| {
+            let num = count.entry(c.csubtype).or_insert(0);
+            *num += 1;
+            let gen = c
+                .generator_tag()
+                .map(str::to_string)
+                .unwrap_or_else(|| format!("{}{}", c.csubtype, num));
+            (gen, c)
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -341,21 +936,21 @@ ELECTRICIDAD, CONSUMO, EPB, REF, 16.39, 13.11, 8.20, 7.38, 4.10, 4.92, 6.56, 5.7
 ELECTRICIDAD, CONSUMO, EPB, CAL, 16.39, 13.11, 8.20, 7.38, 4.10, 4.92, 6.56, 5.74, 4.10, 6.56, 9.84, 13.11
 MEDIOAMBIENTE, CONSUMO, EPB, CAL, 6.39, 3.11, 8.20, 17.38, 4.10, 4.92, 6.56, 5.74, 4.10, 6.56, 9.84, 3.11";
 
-    // Se han puesto las producciones eléctricas a servicio NDEF y compensado consumos de MEDIOAMBIENTE
+    // Se mantiene el servicio de las producciones eléctricas y se compensan consumos de MEDIOAMBIENTE
     const TCOMPSRES1: &str = "#META CTE_AREAREF: 100.5
-ELECTRICIDAD, PRODUCCION, INSITU, NDEF, 8.20, 6.56, 4.10, 3.69, 2.05, 2.46, 3.28, 2.87, 2.05, 3.28, 4.92, 6.56
+ELECTRICIDAD, PRODUCCION, INSITU, CAL, 8.20, 6.56, 4.10, 3.69, 2.05, 2.46, 3.28, 2.87, 2.05, 3.28, 4.92, 6.56
 ELECTRICIDAD, CONSUMO, EPB, REF, 16.39, 13.11, 8.20, 7.38, 4.10, 4.92, 6.56, 5.74, 4.10, 6.56, 9.84, 13.11
 ELECTRICIDAD, CONSUMO, EPB, CAL, 16.39, 13.11, 8.20, 7.38, 4.10, 4.92, 6.56, 5.74, 4.10, 6.56, 9.84, 13.11
 MEDIOAMBIENTE, CONSUMO, EPB, CAL, 6.39, 3.11, 8.20, 17.38, 4.10, 4.92, 6.56, 5.74, 4.10, 6.56, 9.84, 3.11
 MEDIOAMBIENTE, PRODUCCION, INSITU, CAL, 6.39, 3.11, 8.20, 17.38, 4.10, 4.92, 6.56, 5.74, 4.10, 6.56, 9.84, 3.11 # Equilibrado de consumo sin producción declarada";
 
-    // La producción se debe repartir al 50% entre los usos EPB
+    // La producción asignada a calefacción se usa primero en ese servicio
     const TCOMPSRES2: &str = "#META CTE_AREAREF: 100.5
 #META CTE_SERVICIO: CAL
 ELECTRICIDAD, CONSUMO, EPB, CAL, 16.39, 13.11, 8.20, 7.38, 4.10, 4.92, 6.56, 5.74, 4.10, 6.56, 9.84, 13.11
 MEDIOAMBIENTE, CONSUMO, EPB, CAL, 6.39, 3.11, 8.20, 17.38, 4.10, 4.92, 6.56, 5.74, 4.10, 6.56, 9.84, 3.11
 MEDIOAMBIENTE, PRODUCCION, INSITU, CAL, 6.39, 3.11, 8.20, 17.38, 4.10, 4.92, 6.56, 5.74, 4.10, 6.56, 9.84, 3.11 # Equilibrado de consumo sin producción declarada
-ELECTRICIDAD, PRODUCCION, INSITU, CAL, 4.10, 3.28, 2.05, 1.85, 1.02, 1.23, 1.64, 1.43, 1.02, 1.64, 2.46, 3.28 #  Producción eléctrica reasignada al servicio";
+ELECTRICIDAD, PRODUCCION, INSITU, CAL, 8.20, 6.56, 4.10, 3.69, 2.05, 2.46, 3.28, 2.87, 2.05, 3.28, 4.92, 6.56 #  Producción eléctrica reasignada al servicio";
 
     // La producción se debe repartir al 50% entre los usos EPB y sin excesos
     const TCOMPS2: &str = "#META CTE_AREAREF: 1.0
@@ -378,6 +973,67 @@ ELECTRICIDAD, PRODUCCION, INSITU, CAL, 1.00, 2.00, 1.00 #  Producción eléctric
         assert_eq!(tcomps.to_string(), TCOMPS1);
     }
 
+    #[test]
+    fn tcomponents_validate() {
+        assert!(Components::validate(TCOMPS1).is_ok());
+
+        let tcomps = "#META CTE_AREAREF: 1.0
+#META CTE_SINVALOR
+ELECTRICIDAD, CONSUMO, EPB, CAL, 1.00, 2.00, 1.00
+ELECTRICIDAD, CONSUMO, INSITU, CAL, 1.00, 2.00, 1.00
+GASNATURAL, PRODUCCION, INSITU, CAL, 1.00, 2.00, 1.00
+GASNATURAL, CONSUMO, EPB, XXX, 1.00, 2.00, 1.00
+ELECTRICIDAD, CONSUMO, EPB, CAL, 1.00, -2.00, 1.00, 1.00
+ELECTRICIDAD, CONSUMO, EPB, 1.00, 2.00, 1,0O
+ELECTRICIDA, CONSUMO, EPB, CAL, 1.00, 2.00, 1.00 # Vector desconocido";
+        let report = Components::validate(tcomps);
+        assert!(!report.is_ok());
+        assert_eq!(
+            report.to_string(),
+            "ERROR: línea 2: metadato mal formado, debe tener el formato \"#META CLAVE: valor\"
+ERROR: línea 4, columna 1: combinación no admitida de vector energético, tipo y subtipo: ELECTRICIDAD, CONSUMO, INSITU
+ERROR: línea 5, columna 1: combinación no admitida de vector energético, tipo y subtipo: GASNATURAL, PRODUCCION, INSITU
+ERROR: línea 6, columna 27: servicio \"XXX\" desconocido
+AVISO: línea 7, columna 40: valor negativo (-2.00)
+ERROR: línea 7: el componente tiene 4 pasos de cálculo y el de la línea 3 tiene 3
+AVISO: línea 8, columna 29: componente sin servicio (formato antiguo), se asigna al servicio NDEF
+ERROR: línea 8, columna 43: valor \"0O\" no numérico
+ERROR: línea 9, columna 1: vector energético \"ELECTRICIDA\" desconocido (los vectores de usuario llevan el prefijo USUARIO_)"
+        );
+        assert_eq!(report.errors().count(), 7);
+        assert_eq!(report.warnings().count(), 2);
+
+        // El error de interpretación incluye todos los errores con su línea
+        let err = tcomps.parse::<Components>().unwrap_err().to_string();
+        assert!(err.contains("línea 6, columna 27"));
+        assert!(!err.contains("AVISO"));
+
+        // Los vectores de usuario llevan prefijo
+        let report = Components::validate("USUARIO_HIDROGENO, CONSUMO, EPB, CAL, 1.00, 2.00");
+        assert!(report.is_ok());
+        assert_eq!(report.warnings().count(), 0);
+        let report = Components::validate(
+            "USUARIO_HIDROGENO_VERDE_DE_LARGO_NOMBRE, CONSUMO, EPB, CAL, 1.00, 2.00",
+        );
+        assert!(report.to_string().contains("demasiado largo"));
+
+        // Los errores de resolución temporal se indican en la línea de su metadato
+        let report = Components::validate(
+            "# Datos mensuales (CTE_RESOLUCION)
+#META CTE_FECHA_INICIO: 2021-03-01
+#META CTE_RESOLUCION: MENSUAL
+ELECTRICIDAD, CONSUMO, EPB, CAL, 1.00, 2.00",
+        );
+        assert_eq!(report.errors().next().unwrap().line, 3);
+        let report = Components::validate(
+            "# Datos mensuales (CTE_FECHA_INICIO)
+#META CTE_RESOLUCION: MENSUAL
+#META CTE_FECHA_INICIO: 2021-02-31
+ELECTRICIDAD, CONSUMO, EPB, CAL, 1.00, 2.00",
+        );
+        assert_eq!(report.errors().next().unwrap().line, 3);
+    }
+
     #[test]
     fn tcomponents_normalize() {
         let tcompsnorm = TCOMPS1.parse::<Components>().unwrap().normalize();
@@ -390,7 +1046,8 @@ ELECTRICIDAD, PRODUCCION, INSITU, CAL, 1.00, 2.00, 1.00 #  Producción eléctric
             .parse::<Components>()
             .unwrap()
             .normalize()
-            .filter_by_epb_service(Service::CAL);
+            .filter_by_epb_service(Service::CAL)
+            .unwrap();
         assert_eq!(tcompsnormfilt.to_string(), TCOMPSRES2);
     }
 
@@ -400,7 +1057,8 @@ ELECTRICIDAD, PRODUCCION, INSITU, CAL, 1.00, 2.00, 1.00 #  Producción eléctric
             .parse::<Components>()
             .unwrap()
             .normalize()
-            .filter_by_epb_service(Service::CAL);
+            .filter_by_epb_service(Service::CAL)
+            .unwrap();
         assert_eq!(tcompsnormfilt.to_string(), TCOMPSRES3);
     }
 }
@@ -33,19 +33,31 @@ Utilidades para el manejo de balances energéticos para el CTE:
 - generación y transformación de factores de paso
     - wfactors_from_str
     - wfactors_from_loc
+    - wfactors_from_library (registro de bibliotecas de factores de paso, WFactorsRegistry)
     - wfactors_to_nearby
+- verificación del cumplimiento del DB-HE 2019 (HE0 y HE4)
+    - check_compliance
+- calificación energética
+    - energy_rating
+    - incorpora_calificacion
 - salida/visualización de balances
     - balance_to_plain
     - balance_to_XML
 */
 
 use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::borrow::Borrow;
 use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::path::Path;
+use std::str;
 
 use crate::{
     error::EpbdError,
     types::*,
-    vecops::{vecvecmin, vecvecsum},
+    vecops::{vecvecdif, vecvecsum},
     Balance, Components, Factors, UserWF,
 };
 
@@ -64,13 +76,7 @@ pub const CTE_LOCS: [&str; 4] = ["PENINSULA", "BALEARES", "CANARIAS", "CEUTAMELI
 // CTE_LOCALIZACION -> str
 
 /// Vectores considerados dentro del perímetro NEARBY (a excepción de la ELECTRICIDAD in situ).
-pub const CTE_NRBY: [Carrier; 5] = [
-    Carrier::BIOMASA,
-    Carrier::BIOMASADENSIFICADA,
-    Carrier::RED1,
-    Carrier::RED2,
-    Carrier::MEDIOAMBIENTE,
-]; // Ver B.23. Solo biomasa sólida
+pub const CTE_NRBY: [Carrier; 5] = NEARBY_CARRIERS; // Ver B.23. Solo biomasa sólida
 
 /// Factores de paso definibles por el usuario usados por defecto
 pub const CTE_USERWF: UserWF<RenNrenCo2> = UserWF {
@@ -109,7 +115,9 @@ pub static CTE_LOCWF_RITE2014: Lazy<HashMap<&'static str, Factors>> = Lazy::new(
             Factor::new(ELECTRICIDAD, INSITU, SUMINISTRO, A, (1.000, 0.000, 0.000).into(), "Recursos usados para producir electricidad in situ"),
             Factor::new(ELECTRICIDAD, COGENERACION, SUMINISTRO, A, (0.000, 0.000, 0.000).into(), "Recursos usados para suministrar la energía (0 porque se contabiliza el vector que alimenta el cogenerador)"),
             // Factor::new(ELECTRICIDAD, RED, SUMINISTRO, A, (ren, nren, co2), "Recursos usados para el suministro desde la red")
-        ]};
+        ],
+        ..Default::default()
+    };
     let mut wfpen = wf.clone();
     wfpen.set_meta("CTE_LOCALIZACION", "PENINSULA");
     wfpen.wdata.push(Factor::new(
@@ -186,9 +194,9 @@ pub fn wfactors_from_str(
 ///
 /// Usa localización (PENINSULA, CANARIAS, BALEARES, CEUTAMELILLA),
 /// factores de paso de cogeneración, y factores de paso para RED1 y RED2
-pub fn wfactors_from_loc(
+pub fn wfactors_from_loc<K: Borrow<str> + Hash + Eq>(
     loc: &str,
-    locmap: &HashMap<&'static str, Factors>,
+    locmap: &HashMap<K, Factors>,
     user: UserWF<Option<RenNrenCo2>>,
     userdefaults: UserWF<RenNrenCo2>,
 ) -> Result<Factors, EpbdError> {
@@ -200,36 +208,206 @@ pub fn wfactors_from_loc(
         .normalize(&userdefaults)
 }
 
-/// Convierte factores de paso con perímetro "distant" a factores de paso "nearby".
+/// Identificador de la biblioteca de factores de paso reglamentarios usada por defecto
+pub const CTE_WF_LIBRARY_DEFAULT: &str = "RITE2014";
+
+/// Biblioteca de factores de paso reglamentarios
 ///
-/// Los elementos que tiene origen en la RED (!= INSITU, != COGENERACION)
-/// y no están en la lista CTE_NRBY cambian sus factores de paso
-/// de forma que ren' = 0 y nren' = ren + nren.
-/// **ATENCIÓN**: ¡¡La producción eléctrica de la cogeneración entra con (factores ren:0, nren:0)!!
-pub fn wfactors_to_nearby(wfactors: &Factors) -> Factors {
-    let wmeta = wfactors.wmeta.clone();
-    let mut wdata: Vec<Factor> = Vec::new();
-
-    for f in wfactors.wdata.iter().cloned() {
-        if f.source == Source::INSITU
-            || f.source == Source::COGENERACION
-            || CTE_NRBY.contains(&f.carrier)
-        {
-            wdata.push(f)
-        } else {
-            wdata.push(Factor::new(
-                f.carrier,
-                f.source,
-                f.dest,
-                f.step,
-                RenNrenCo2::new(0.0, f.ren + f.nren, f.co2), // ¿Esto es lo que tiene más sentido?
-                format!("Perímetro nearby: {}", f.comment),
+/// Reúne los factores de paso de cada localización definidos en un documento oficial (p.e. el documento
+/// reconocido del RITE de 20/07/2014), identificado por su versión (`CTE_FUENTE`).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WFactorsLibrary {
+    /// Library identifier (e.g. `RITE2014`)
+    pub id: String,
+    /// Library description
+    pub description: String,
+    /// Weighting factors for each location
+    pub locs: HashMap<String, Factors>,
+}
+
+/// Registro de bibliotecas de factores de paso reglamentarios, por identificador
+///
+/// Incluye las bibliotecas predefinidas (ver `WFactorsRegistry::new`) y permite añadir otras,
+/// p.e. cargadas desde un directorio de archivos de factores de paso (ver `WFactorsRegistry::load_dir`),
+/// para usar nuevas versiones oficiales de los factores de paso sin recompilar.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WFactorsRegistry {
+    /// Registered libraries
+    pub libraries: Vec<WFactorsLibrary>,
+}
+
+impl WFactorsRegistry {
+    /// Registro con las bibliotecas predefinidas (`RITE2014`)
+    pub fn new() -> Self {
+        let mut registry = Self::default();
+        registry.insert(WFactorsLibrary {
+            id: "RITE2014".to_string(),
+            description: "Factores de paso del documento reconocido del RITE de 20/07/2014"
+                .to_string(),
+            locs: CTE_LOCWF_RITE2014
+                .iter()
+                .map(|(loc, wf)| (loc.to_string(), wf.clone()))
+                .collect(),
+        });
+        registry
+    }
+
+    /// Biblioteca con el identificador indicado, si existe
+    pub fn get(&self, id: &str) -> Option<&WFactorsLibrary> {
+        self.libraries.iter().find(|l| l.id == id)
+    }
+
+    /// Identificadores de las bibliotecas registradas
+    pub fn ids(&self) -> Vec<&str> {
+        self.libraries.iter().map(|l| l.id.as_str()).collect()
+    }
+
+    /// Añade una biblioteca al registro, sustituyendo a la que tenga el mismo identificador
+    pub fn insert(&mut self, library: WFactorsLibrary) {
+        match self.libraries.iter_mut().find(|l| l.id == library.id) {
+            Some(l) => *l = library,
+            None => self.libraries.push(library),
+        }
+    }
+
+    /// Añade los factores de paso de una localización a partir de su definición en formato de texto
+    ///
+    /// Los factores deben declarar la biblioteca a la que pertenecen (`CTE_FUENTE`) y su localización
+    /// (`CTE_LOCALIZACION`). La descripción de la biblioteca se toma del metadato `CTE_FUENTE_COMENTARIO`.
+    /// Si no existe la biblioteca se crea y si ya existen factores para esa localización se sustituyen.
+    /// Devuelve el identificador de la biblioteca.
+    ///
+    /// # Errors
+    ///
+    /// * Formato incorrecto de los factores de paso
+    /// * Falta la declaración de la biblioteca o la localización
+    pub fn load_str(&mut self, wfactorsstring: &str) -> Result<String, EpbdError> {
+        let wfactors: Factors = wfactorsstring.parse()?;
+        let id = wfactors.get_meta("CTE_FUENTE").ok_or_else(|| {
+            EpbdError::WrongInput("Biblioteca de factores de paso sin CTE_FUENTE".into())
+        })?;
+        let loc = wfactors.get_meta("CTE_LOCALIZACION").ok_or_else(|| {
+            EpbdError::WrongInput(format!(
+                "Factores de paso de la biblioteca {} sin CTE_LOCALIZACION",
+                id
             ))
+        })?;
+        if self.get(&id).is_none() {
+            self.libraries.push(WFactorsLibrary {
+                id: id.clone(),
+                ..Default::default()
+            });
+        }
+        let library = self.libraries.iter_mut().find(|l| l.id == id).unwrap();
+        if let Some(description) = wfactors.get_meta("CTE_FUENTE_COMENTARIO") {
+            library.description = description;
         }
+        library.locs.insert(loc, wfactors);
+        Ok(id)
+    }
+
+    /// Añade las bibliotecas definidas en los archivos de factores de paso (`*.csv`) de un directorio
+    ///
+    /// Cada archivo contiene los factores de paso de una localización de una biblioteca (ver `load_str`).
+    /// Devuelve los identificadores de las bibliotecas cargadas.
+    ///
+    /// # Errors
+    ///
+    /// * Error de lectura del directorio o de algún archivo
+    /// * Formato incorrecto de algún archivo de factores de paso
+    pub fn load_dir<P: AsRef<Path>>(&mut self, dir: P) -> Result<Vec<String>, EpbdError> {
+        let io_err = |path: &Path, e: std::io::Error| {
+            EpbdError::WrongInput(format!(
+                "Lectura incorrecta de \"{}\": {}",
+                path.display(),
+                e
+            ))
+        };
+        let dir = dir.as_ref();
+        let mut paths = std::fs::read_dir(dir)
+            .map_err(|e| io_err(dir, e))?
+            .map(|entry| entry.map(|e| e.path()).map_err(|e| io_err(dir, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+        paths.retain(|p| p.is_file() && p.extension().and_then(|ext| ext.to_str()) == Some("csv"));
+        paths.sort();
+        let mut ids = Vec::new();
+        for path in paths {
+            let content = std::fs::read_to_string(&path).map_err(|e| io_err(&path, e))?;
+            let id = self.load_str(&content).map_err(|e| {
+                EpbdError::WrongInput(format!("Archivo \"{}\": {}", path.display(), e))
+            })?;
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
     }
-    let mut factors = Factors { wmeta, wdata };
-    factors.set_meta("CTE_PERIMETRO", "NEARBY");
-    factors
+}
+
+/// Genera factores de paso a partir de una biblioteca del registro y la localización
+///
+/// Los factores incluyen el identificador de la biblioteca (`CTE_FUENTE`) y la localización (`CTE_LOCALIZACION`)
+///
+/// # Errors
+///
+/// * Biblioteca o localización no definidas en el registro
+/// * Errores en la normalización de los factores de paso
+pub fn wfactors_from_library(
+    id: &str,
+    loc: &str,
+    registry: &WFactorsRegistry,
+    user: UserWF<Option<RenNrenCo2>>,
+    userdefaults: UserWF<RenNrenCo2>,
+) -> Result<Factors, EpbdError> {
+    let library = registry.get(id).ok_or_else(|| {
+        EpbdError::WrongInput(format!(
+            "Biblioteca de factores de paso {} no disponible ({})",
+            id,
+            registry.ids().join(", ")
+        ))
+    })?;
+    let mut wfactors = wfactors_from_loc(loc, &library.locs, user, userdefaults)?;
+    wfactors.set_meta("CTE_FUENTE", id);
+    wfactors.set_meta("CTE_LOCALIZACION", loc);
+    Ok(wfactors)
+}
+
+/// Vectores energéticos suministrados desde las proximidades del edificio (perímetro próximo)
+///
+/// Ver `Factors::nearby_carriers`.
+///
+/// # Errors
+///
+/// * Vectores de usuario o vectores próximos incorrectos o no declarados
+pub fn nearby_carriers(wfactors: &Factors) -> Result<Vec<Carrier>, EpbdError> {
+    wfactors.nearby_carriers()
+}
+
+/// Convierte factores de paso con perímetro "distant" a factores de paso en el perímetro indicado.
+///
+/// Ver `Factors::to_perimeter`.
+///
+/// # Errors
+///
+/// * Perímetro de los factores de paso incorrecto o más restrictivo que el perímetro indicado
+pub fn wfactors_to_perimeter(
+    wfactors: &Factors,
+    perimeter: Perimeter,
+    nearby: &[Carrier],
+) -> Result<Factors, EpbdError> {
+    wfactors.to_perimeter(perimeter, nearby)
+}
+
+/// Convierte factores de paso con perímetro "distant" a factores de paso "nearby".
+///
+/// Equivale a `wfactors_to_perimeter` con el perímetro próximo (NEARBY), sin comprobar el perímetro de partida.
+///
+/// # Errors
+///
+/// * Vectores de usuario o vectores próximos incorrectos o no declarados
+pub fn wfactors_to_nearby(wfactors: &Factors) -> Result<Factors, EpbdError> {
+    let nearby = wfactors.nearby_carriers()?;
+    Ok(wfactors.convert_to_perimeter(Perimeter::NEARBY, Some(&nearby)))
 }
 
 /*
@@ -304,18 +482,16 @@ fn get_used_carriers(cr_list: &Vec<&Component>) -> Vec<Carrier> {
 /// Permite calcular el indicador de HE4 con las siguientes restricciones:
 ///
 /// 1. si hay biomasa (o biomasa densificada), esta y otros vectores insitu o de distrito cubren el 100% de la demanda
-/// 2. no se permite el consumo de electricidad cogenerada para producir ACS (solo la parte térmica) aunque podría provenir de BIOMASA / BIOMASADENSIFICADA
-///     Si se pudiese usar electricidad y existiese cogeneración tendríamos 2 vectores no insitu (BIOMASA, ELECTRICIDAD)
-///     y, si no se usase la parte térmica, no sabríamos si tiene procedencia renovable o no.
+/// 2. solo se permite el consumo de electricidad cogenerada para producir ACS si se define la unidad de cogeneración
+///    (metadato `CTE_COGENERADOR`). En ese caso, la electricidad cogenerada es renovable según la fracción renovable
+///    de sus factores de paso, obtenidos con el reparto del combustible entre la electricidad y el calor cogenerados.
+///    Sin esa definición, si existiese cogeneración tendríamos 2 vectores no insitu (BIOMASA, ELECTRICIDAD)
+///    y, si no se usase la parte térmica, no sabríamos si tiene procedencia renovable o no.
 /// 3. el rendimiento térmico de la contribución renovable de vectores RED1, RED2 y MEDIOAMBIENTE es 1.0. (demanda == consumo)
 /// 4. las únicas aportaciones nearby son biomasa (cualquiera), RED1, RED2, ELECTRICIDAD insitu y MEDIOAMBIENTE (insitu)
 ///
 /// Casos que no podemos calcular:
-/// - Cuando hay electricidad cogenerada
-///     - En este caso sería necesario que la imputación del combustible fuese en función del destino final del consumo,
-///       sea eléctrico o térmico. Alternativamente se podrían modificar los factores de paso, pero parece más complicado. Analizar.
-///       Se podría estudiar hacer un reparto de la producción de combustible para generar electricidad en función del reparto de la
-///       electricidad cogenerada por usos. Pensar qué ocurre con parte exportada
+/// - Cuando hay electricidad cogenerada y no se ha definido la unidad de cogeneración (metadato `CTE_COGENERADOR`)
 /// - Cuando necesitaríamos conocer el % de la demanda anual de ACS satisfecha por el vector BIOMASA y BIOMASADENSIFICADA porque
 ///     - Hay más de un vector BIOMASA o BIOMASADENSIFICADA
 ///     - Hay BIOMASA o BIOMASADENSIFICADA y otro vector que no sea insitu o de distrito.
@@ -332,8 +508,12 @@ pub fn fraccion_renovable_acs_nrb(
     use CType::{CONSUMO, PRODUCCION};
     use Carrier::{BIOMASA, BIOMASADENSIFICADA, ELECTRICIDAD, MEDIOAMBIENTE, RED1, RED2};
 
+    // Factores de paso de la electricidad cogenerada, si se define la unidad de cogeneración
+    // Se obtienen antes de filtrar por servicio, ya que dependen de toda la producción eléctrica por cogeneración
+    let fp_cgn = components.cogen_wfactors(wfactors)?;
+
     // Lista de componentes para ACS y filtrados excluidos de participar en el cálculo de la demanda renovable
-    let components = &components.filter_by_epb_service(Service::ACS);
+    let components = &components.filter_by_epb_service(Service::ACS)?;
     let cr_list: &Vec<&Component> = &components
         .cdata
         .iter()
@@ -355,21 +535,15 @@ pub fn fraccion_renovable_acs_nrb(
         ));
     };
 
-    // Existe cogeneración eléctrica -> caso no soportado -> ERROR
+    // Existe cogeneración eléctrica sin definición de la unidad de cogeneración -> caso no soportado -> ERROR
     //
-    // Si tenemos electricidad cogenerada no sabemos con qué se ha cogenerado ni si se ha imputado todo el combustible correspondiente
-    // ya que este podría ir a otros usos y no a ACS (y no tenemos los factores de paso de electricidad cogenerada)
-    //
-    // TODO: Para poder tenerlo en cuenta tendríamos dos opciones:
-    // - Imputar correctamente los factores de paso de electricidad cogenerada, en lugar de 0.0 (y ver cómo se imputa el combustible en la parte térmica)
-    // - Imputar el consumo de combustible en función del servicio de destino de la electricidad y de la parte térmica. Esto se podría hacer a la
-    //   hora del reparto de electricidad, si se ha marcado el consumo de combustible como destinado a cogeneración eléctrica CTEEPBD_DESTINO_COGEN y uso NDEF.
-    //   Habría que pensar qué ocurre si una parte no se consume y se exporta.
-    // - Habría que ver cómo se imputa (prioridad) el consumo de electricidad in situ y cogenerada.
+    // Si tenemos electricidad cogenerada y no se define la unidad de cogeneración no sabemos con qué se ha cogenerado
+    // ni si se ha imputado todo el combustible correspondiente, ya que este podría ir a otros usos y no a ACS
+    // (y no tenemos los factores de paso de electricidad cogenerada)
     let has_el_cgn = cr_list
         .iter()
         .any(|c| c.ctype == PRODUCCION && c.csubtype == CSubtype::COGENERACION);
-    if has_el_cgn {
+    if has_el_cgn && fp_cgn.is_none() {
         return Err(EpbdError::WrongInput(
             "Uso de electricidad cogenerada".to_string(),
         ));
@@ -463,11 +637,32 @@ pub fn fraccion_renovable_acs_nrb(
         .filter(|c| c.carrier == ELECTRICIDAD)
         .filter(|c| c.ctype == PRODUCCION && c.csubtype == CSubtype::INSITU)
         .fold(vec![0.0; num_steps], |acc, c| vecvecsum(&acc, &c.values));
-    // c. Consumo efectivo de electricidad renovable en ACS (Mínimo entre el consumo y la producción in situ) (consumo == demanda)
-    let Q_el_an_ren: f32 = vecvecmin(&E_EPus_el_t, &E_pr_el_onsite_t).iter().sum();
+    // c. Consumo efectivo de electricidad renovable en ACS (Mínimo entre el consumo y la producción in situ,
+    // corregido por el factor de coincidencia de cargas) (consumo == demanda)
+    let f_match = components.load_matching()?;
+    let Q_el_t_ren = f_match.produced_used(&E_EPus_el_t, &E_pr_el_onsite_t)?;
+    let Q_el_an_ren: f32 = Q_el_t_ren.iter().sum();
+    // d. Consumo de electricidad cogenerada en ACS no cubierto por la producción in situ, renovable según la
+    // fracción renovable de los factores de paso de la electricidad cogenerada
+    let Q_el_cgn_an_ren = match fp_cgn {
+        Some(fp_cgn) => {
+            let E_pr_el_cgn_t = cr_list
+                .iter()
+                .filter(|c| c.carrier == ELECTRICIDAD)
+                .filter(|c| c.ctype == PRODUCCION && c.csubtype == CSubtype::COGENERACION)
+                .fold(vec![0.0; num_steps], |acc, c| vecvecsum(&acc, &c.values));
+            let E_EPus_el_rem_t = vecvecdif(&E_EPus_el_t, &Q_el_t_ren);
+            let E_el_cgn_an: f32 = f_match
+                .produced_used(&E_EPus_el_rem_t, &E_pr_el_cgn_t)?
+                .iter()
+                .sum();
+            E_el_cgn_an * fp_cgn.electricity.rer()
+        }
+        None => 0.0,
+    };
 
     // === Total de demanda renovable ==
-    let Q_an_ren = Q_district_and_env_acs_an_ren + Q_biomass_an_ren + Q_el_an_ren;
+    let Q_an_ren = Q_district_and_env_acs_an_ren + Q_biomass_an_ren + Q_el_an_ren + Q_el_cgn_an_ren;
 
     Ok(Q_an_ren / demanda_anual_acs)
 }
@@ -521,16 +716,533 @@ pub fn incorpora_demanda_renovable_acs_nrb(
     balance
 }
 
+/*
+Verificación del cumplimiento del DB-HE 2019
+--------------------------------------------
+*/
+
+/// Zona climática de invierno (DB-HE 2019, anejo B)
+#[allow(non_camel_case_types)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WinterZone {
+    /// Zona α (solo en Canarias)
+    ALFA,
+    /// Zona A
+    A,
+    /// Zona B
+    B,
+    /// Zona C
+    C,
+    /// Zona D
+    D,
+    /// Zona E
+    E,
+}
+
+impl WinterZone {
+    /// Posición de la zona en las tablas de valores límite
+    fn idx(self) -> usize {
+        self as usize
+    }
+}
+
+impl str::FromStr for WinterZone {
+    type Err = EpbdError;
+
+    /// Admite la zona de invierno o la zona climática completa (p.e. `D3`, `α3` o `ALFA3`)
+    fn from_str(s: &str) -> Result<WinterZone, Self::Err> {
+        let zone = s.trim().trim_end_matches(|c: char| c.is_ascii_digit());
+        match zone.to_uppercase().as_str() {
+            "α" | "Α" | "ALFA" | "ALPHA" => Ok(WinterZone::ALFA),
+            "A" => Ok(WinterZone::A),
+            "B" => Ok(WinterZone::B),
+            "C" => Ok(WinterZone::C),
+            "D" => Ok(WinterZone::D),
+            "E" => Ok(WinterZone::E),
+            _ => Err(EpbdError::ParseError(format!("zona climática \"{}\"", s))),
+        }
+    }
+}
+
+impl fmt::Display for WinterZone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WinterZone::ALFA => write!(f, "α"),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+/// Uso del edificio a efectos del DB-HE 2019
+#[allow(non_camel_case_types)]
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum BuildingUse {
+    /// Uso residencial privado
+    RESIDENCIAL,
+    /// Otros usos, con la carga interna media, CFI [W/m2]
+    TERCIARIO(f32),
+}
+
+impl str::FromStr for BuildingUse {
+    type Err = EpbdError;
+
+    /// Admite `RESIDENCIAL` o `TERCIARIO, CFI` (p.e. `TERCIARIO, 6.5`)
+    fn from_str(s: &str) -> Result<BuildingUse, Self::Err> {
+        let items: Vec<&str> = s.split(',').map(str::trim).collect();
+        match items.as_slice() {
+            ["RESIDENCIAL"] => Ok(BuildingUse::RESIDENCIAL),
+            ["TERCIARIO", cfi] => match cfi.parse::<f32>() {
+                Ok(cfi) if cfi >= 0.0 => Ok(BuildingUse::TERCIARIO(cfi)),
+                _ => Err(EpbdError::ParseError(format!(
+                    "carga interna media del uso del edificio \"{}\"",
+                    s
+                ))),
+            },
+            _ => Err(EpbdError::ParseError(format!("uso del edificio \"{}\"", s))),
+        }
+    }
+}
+
+impl fmt::Display for BuildingUse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildingUse::RESIDENCIAL => write!(f, "RESIDENCIAL"),
+            BuildingUse::TERCIARIO(cfi) => write!(f, "TERCIARIO, {:.2}", cfi),
+        }
+    }
+}
+
+/// Tipo de obra a efectos del DB-HE 2019
+#[allow(non_camel_case_types)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BuildingStatus {
+    /// Edificio nuevo o ampliación
+    NUEVO,
+    /// Edificio existente (cambio de uso o reforma)
+    EXISTENTE,
+}
+
+impl str::FromStr for BuildingStatus {
+    type Err = EpbdError;
+
+    fn from_str(s: &str) -> Result<BuildingStatus, Self::Err> {
+        match s.trim() {
+            "NUEVO" => Ok(BuildingStatus::NUEVO),
+            "EXISTENTE" => Ok(BuildingStatus::EXISTENTE),
+            _ => Err(EpbdError::ParseError(format!("tipo de obra \"{}\"", s))),
+        }
+    }
+}
+
+impl fmt::Display for BuildingStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Valor límite de C_ep,nren [kWh/m2.an] para uso residencial privado (tabla 3.1.a-HE0)
+///
+/// Filas: edificios nuevos y ampliaciones, cambios de uso y reformas. Columnas: zonas α, A, B, C, D y E
+const HE0_CEP_NREN_LIM_RES: [[f32; 6]; 2] = [
+    [20.0, 25.0, 28.0, 32.0, 38.0, 43.0],
+    [40.0, 50.0, 55.0, 70.0, 80.0, 90.0],
+];
+/// Valor límite de C_ep,nren [kWh/m2.an] para otros usos, sin el término 8·CFI (tabla 3.1.b-HE0)
+const HE0_CEP_NREN_LIM_TER: [[f32; 6]; 2] = [
+    [70.0, 55.0, 50.0, 35.0, 20.0, 10.0],
+    [120.0, 100.0, 90.0, 70.0, 55.0, 45.0],
+];
+/// Valor límite de C_ep,tot [kWh/m2.an] para uso residencial privado (tabla 3.2.a-HE0)
+const HE0_CEP_TOT_LIM_RES: [[f32; 6]; 2] = [
+    [40.0, 50.0, 56.0, 64.0, 76.0, 86.0],
+    [55.0, 75.0, 80.0, 90.0, 105.0, 115.0],
+];
+/// Valor límite de C_ep,tot [kWh/m2.an] para otros usos, sin el término 9·CFI (tabla 3.2.b-HE0)
+const HE0_CEP_TOT_LIM_TER: [[f32; 6]; 2] = [
+    [165.0, 155.0, 150.0, 140.0, 130.0, 120.0],
+    [215.0, 190.0, 180.0, 170.0, 155.0, 140.0],
+];
+/// Factor de los valores límite de C_ep,nren para uso residencial privado en territorio extrapeninsular (tabla 3.1.a-HE0)
+const HE0_FACTOR_EXTRAPENINSULAR_RES: f32 = 1.25;
+/// Factor de los valores límite de C_ep,nren para otros usos en territorio extrapeninsular (tabla 3.1.b-HE0)
+const HE0_FACTOR_EXTRAPENINSULAR_TER: f32 = 1.40;
+/// Contribución renovable mínima a la demanda de ACS (HE4 3.1)
+const HE4_FRACCION_REN_MIN: f32 = 0.70;
+/// Contribución renovable mínima a la demanda de ACS con demanda inferior a 5000 l/d (HE4 3.1)
+const HE4_FRACCION_REN_MIN_REDUCIDA: f32 = 0.60;
+/// Demanda diaria de ACS [l/d] por debajo de la que se reduce la contribución renovable mínima (HE4 3.1)
+const HE4_DEMANDA_DIARIA_REDUCIDA: f32 = 5000.0;
+
+/// Valores límite del DB-HE 2019 para C_ep,nren y C_ep,tot [kWh/m2.an] (HE0)
+///
+/// * `zone` - zona climática de invierno
+/// * `building_use` - uso del edificio
+/// * `status` - tipo de obra
+/// * `loc` - localización (ver `CTE_LOCS`). Fuera de la PENINSULA se corrige el límite de C_ep,nren
+pub fn he0_limits(
+    zone: WinterZone,
+    building_use: BuildingUse,
+    status: BuildingStatus,
+    loc: &str,
+) -> (f32, f32) {
+    let row = match status {
+        BuildingStatus::NUEVO => 0,
+        BuildingStatus::EXISTENTE => 1,
+    };
+    let col = zone.idx();
+    let (cep_nren_lim, cep_tot_lim, k_extrapeninsular) = match building_use {
+        BuildingUse::RESIDENCIAL => (
+            HE0_CEP_NREN_LIM_RES[row][col],
+            HE0_CEP_TOT_LIM_RES[row][col],
+            HE0_FACTOR_EXTRAPENINSULAR_RES,
+        ),
+        BuildingUse::TERCIARIO(cfi) => (
+            HE0_CEP_NREN_LIM_TER[row][col] + 8.0 * cfi,
+            HE0_CEP_TOT_LIM_TER[row][col] + 9.0 * cfi,
+            HE0_FACTOR_EXTRAPENINSULAR_TER,
+        ),
+    };
+    let k_loc = if loc == "PENINSULA" {
+        1.0
+    } else {
+        k_extrapeninsular
+    };
+    (k_loc * cep_nren_lim, cep_tot_lim)
+}
+
+/// Contribución renovable mínima a la demanda de ACS del DB-HE 2019 [-] (HE4)
+///
+/// * `demanda_diaria_acs` - demanda diaria de ACS [l/d], si se conoce. Sin ella se usa el límite general
+pub fn he4_limit(demanda_diaria_acs: Option<f32>) -> f32 {
+    match demanda_diaria_acs {
+        Some(demanda) if demanda < HE4_DEMANDA_DIARIA_REDUCIDA => HE4_FRACCION_REN_MIN_REDUCIDA,
+        _ => HE4_FRACCION_REN_MIN,
+    }
+}
+
+/// Datos del edificio para la verificación del cumplimiento del DB-HE 2019
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ComplianceInput {
+    /// Zona climática de invierno
+    pub zone: WinterZone,
+    /// Uso del edificio
+    pub building_use: BuildingUse,
+    /// Tipo de obra
+    pub status: BuildingStatus,
+    /// Demanda anual de ACS [kWh/an], si hay servicio de ACS
+    pub demanda_anual_acs: Option<f32>,
+    /// Demanda diaria de ACS [l/d], si se conoce
+    pub demanda_diaria_acs: Option<f32>,
+}
+
+impl ComplianceInput {
+    /// Datos del edificio definidos en los metadatos de los componentes
+    ///
+    /// Se usan las claves:
+    /// - `CTE_ZONA_CLIMATICA`: zona climática (p.e. `D3`). Si no se define no se verifica el cumplimiento
+    /// - `CTE_USO_EDIFICIO`: `RESIDENCIAL` (por defecto) o `TERCIARIO, CFI`
+    /// - `CTE_TIPO_OBRA`: `NUEVO` (por defecto) o `EXISTENTE`
+    /// - `CTE_ACS_DEMANDA_ANUAL`: demanda anual de ACS [kWh/an]
+    /// - `CTE_ACS_DEMANDA_DIARIA`: demanda diaria de ACS [l/d]
+    ///
+    /// # Errors
+    ///
+    /// * Definición incorrecta de la zona climática, el uso o el tipo de obra
+    pub fn from_components(components: &Components) -> Result<Option<Self>, EpbdError> {
+        let zone = match components.get_meta("CTE_ZONA_CLIMATICA") {
+            Some(zone) => zone.parse()?,
+            None => return Ok(None),
+        };
+        let building_use = match components.get_meta("CTE_USO_EDIFICIO") {
+            Some(building_use) => building_use.parse()?,
+            None => BuildingUse::RESIDENCIAL,
+        };
+        let status = match components.get_meta("CTE_TIPO_OBRA") {
+            Some(status) => status.parse()?,
+            None => BuildingStatus::NUEVO,
+        };
+        Ok(Some(Self {
+            zone,
+            building_use,
+            status,
+            demanda_anual_acs: components.get_meta_f32("CTE_ACS_DEMANDA_ANUAL"),
+            demanda_diaria_acs: components.get_meta_f32("CTE_ACS_DEMANDA_DIARIA"),
+        }))
+    }
+}
+
+/// Comprobación de un indicador frente a su valor límite
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ComplianceCheck {
+    /// Indicator description
+    pub indicator: String,
+    /// Indicator value
+    pub value: f32,
+    /// Limit value
+    pub limit: f32,
+    /// The limit is a minimum value (true) or a maximum value (false)
+    pub is_min: bool,
+    /// Margin to the limit (positive if the limit is met)
+    pub margin: f32,
+}
+
+impl ComplianceCheck {
+    /// Comprobación de un valor máximo
+    fn max(indicator: &str, value: f32, limit: f32) -> Self {
+        Self {
+            indicator: indicator.to_string(),
+            value,
+            limit,
+            is_min: false,
+            margin: limit - value,
+        }
+    }
+
+    /// Comprobación de un valor mínimo
+    fn min(indicator: &str, value: f32, limit: f32) -> Self {
+        Self {
+            indicator: indicator.to_string(),
+            value,
+            limit,
+            is_min: true,
+            margin: value - limit,
+        }
+    }
+
+    /// Indica si se cumple el valor límite
+    pub fn passes(&self) -> bool {
+        self.margin >= 0.0
+    }
+}
+
+impl fmt::Display for ComplianceCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {:.2} {} {:.2} (margen {:.2}): {}",
+            self.indicator,
+            self.value,
+            if self.is_min { ">=" } else { "<=" },
+            self.limit,
+            self.margin,
+            if self.passes() { "CUMPLE" } else { "NO CUMPLE" }
+        )
+    }
+}
+
+/// Informe de verificación del cumplimiento del DB-HE 2019
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ComplianceReport {
+    /// Building data
+    pub input: ComplianceInput,
+    /// Reference area [m2]
+    pub arearef: f32,
+    /// Checks of each indicator
+    pub checks: Vec<ComplianceCheck>,
+    /// Notes on checks that could not be done
+    pub notes: Vec<String>,
+}
+
+impl ComplianceReport {
+    /// Indica si se cumplen todos los valores límite comprobados
+    pub fn passes(&self) -> bool {
+        self.checks.iter().all(ComplianceCheck::passes)
+    }
+}
+
+impl fmt::Display for ComplianceReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ComplianceInput {
+            zone,
+            building_use,
+            status,
+            ..
+        } = self.input;
+        writeln!(
+            f,
+            "Zona climática de invierno: {}, uso: {}, tipo de obra: {}, A_ref = {:.2} [m2]",
+            zone, building_use, status, self.arearef
+        )?;
+        for check in &self.checks {
+            writeln!(f, "{}", check)?;
+        }
+        for note in &self.notes {
+            writeln!(f, "Nota: {}", note)?;
+        }
+        write!(
+            f,
+            "Resultado: {}",
+            if self.passes() { "CUMPLE" } else { "NO CUMPLE" }
+        )
+    }
+}
+
+/// Verifica el cumplimiento de las exigencias del DB-HE 2019 de un balance energético
+///
+/// Se comprueban:
+/// - HE0: C_ep,nren y C_ep,tot (paso B, por m2 de área de referencia) frente a sus valores límite
+/// - HE4: fracción renovable de la demanda de ACS (perímetro próximo) frente a la contribución mínima,
+///   si se conoce la demanda anual de ACS
+///
+/// Las comprobaciones que no se pueden realizar se indican como notas del informe.
+/// La localización de los factores de paso (`CTE_LOCALIZACION`) se usa para corregir los límites de HE0.
+///
+/// No se verifican las exigencias de HE1, que requieren datos de la envolvente térmica.
+pub fn check_compliance(balance: &Balance, input: ComplianceInput) -> ComplianceReport {
+    let mut checks = Vec::new();
+    let mut notes = Vec::new();
+
+    // HE0
+    let loc = balance
+        .wfactors
+        .get_meta("CTE_LOCALIZACION")
+        .unwrap_or_else(|| {
+            notes.push(
+                "localización no definida en los factores de paso, se usan los límites peninsulares"
+                    .to_string(),
+            );
+            "PENINSULA".to_string()
+        });
+    let (cep_nren_lim, cep_tot_lim) =
+        he0_limits(input.zone, input.building_use, input.status, &loc);
+    checks.push(ComplianceCheck::max(
+        "HE0 C_ep,nren [kWh/m2.an]",
+        balance.balance_m2.B.nren,
+        cep_nren_lim,
+    ));
+    checks.push(ComplianceCheck::max(
+        "HE0 C_ep,tot [kWh/m2.an]",
+        balance.balance_m2.B.tot(),
+        cep_tot_lim,
+    ));
+    if (balance.arearef - AREAREF_DEFAULT).abs() < f32::EPSILON {
+        notes.push("área de referencia no definida, se usa el valor por defecto".to_string());
+    }
+
+    // HE4
+    match input.demanda_anual_acs {
+        Some(demanda_anual_acs) if demanda_anual_acs > 0.0 => {
+            match fraccion_renovable_acs_nrb(
+                &balance.components,
+                &balance.wfactors,
+                demanda_anual_acs,
+            ) {
+                Ok(fraccion) => checks.push(ComplianceCheck::min(
+                    "HE4 fracción renovable de la demanda de ACS [-]",
+                    fraccion,
+                    he4_limit(input.demanda_diaria_acs),
+                )),
+                Err(e) => notes.push(format!(
+                    "no se puede calcular la fracción renovable de la demanda de ACS (HE4): {}",
+                    e
+                )),
+            }
+        }
+        _ => notes.push(
+            "demanda anual de ACS no definida, no se verifica la contribución renovable (HE4)"
+                .to_string(),
+        ),
+    }
+
+    ComplianceReport {
+        input,
+        arearef: balance.arearef,
+        checks,
+        notes,
+    }
+}
+
+/*
+Calificación energética
+-----------------------
+*/
+
+/// Calificación energética del edificio a partir del balance energético
+///
+/// Se califican el consumo de energía primaria no renovable y las emisiones de CO2 (paso B, por m2
+/// de área de referencia) con las escalas definidas en los metadatos de los componentes
+/// (claves `CTE_ESCALA_NREN` y `CTE_ESCALA_CO2`). Los límites de las escalas dependen de la zona
+/// climática y del tipo de edificio y se toman del procedimiento oficial de certificación, que no
+/// se incluye en la biblioteca.
+///
+/// # Errors
+///
+/// * Falta alguna de las escalas o su definición es incorrecta
+pub fn energy_rating(balance: &Balance) -> Result<EnergyRating, EpbdError> {
+    let components = &balance.components;
+    let scale = |key: &str| -> Result<RatingScale, EpbdError> {
+        components
+            .get_meta(key)
+            .ok_or_else(|| {
+                EpbdError::WrongInput(format!("escala de calificación no definida ({})", key))
+            })?
+            .parse()
+    };
+    let scale_nren = scale("CTE_ESCALA_NREN")?;
+    let scale_co2 = scale("CTE_ESCALA_CO2")?;
+    Ok(EnergyRating {
+        nren: scale_nren.letter(balance.balance_m2.B.nren),
+        co2: scale_co2.letter(balance.balance_m2.B.co2),
+    })
+}
+
+/// Devuelve balance con la calificación energética incorporada (campo `rating`), si se define alguna escala de calificación
+///
+/// La calificación solo se calcula si los metadatos de los componentes definen alguna de las escalas
+/// (`CTE_ESCALA_NREN` o `CTE_ESCALA_CO2`). Los errores de cálculo de la calificación, como la falta de
+/// una de las escalas, se incorporan a los datos adicionales (clave `error_calificacion`)
+pub fn incorpora_calificacion(mut balance: Balance) -> Balance {
+    let has_scales = ["CTE_ESCALA_NREN", "CTE_ESCALA_CO2"]
+        .iter()
+        .any(|key| balance.components.get_meta(key).is_some());
+    if !has_scales {
+        return balance;
+    }
+    let rating = energy_rating(&balance);
+    let mut map = balance.misc.unwrap_or_default();
+    map.remove("error_calificacion");
+    balance.rating = match rating {
+        Ok(rating) => Some(rating),
+        Err(e) => {
+            map.insert(
+                "error_calificacion".to_string(),
+                format!(
+                    "ERROR: no se puede calcular la calificación energética \"{}\"",
+                    e
+                ),
+            );
+            None
+        }
+    };
+    balance.misc = Some(map);
+    balance
+}
+
 /*
 Utilidades para visualización del balance
 -----------------------------------------
 */
 
+/// Fracción renovable en los perímetros distante, próximo e in situ ("-" si no se ha evaluado)
+fn rer_to_string(rer: &HashMap<Perimeter, f32>) -> String {
+    [Perimeter::DISTANT, Perimeter::NEARBY, Perimeter::ONSITE]
+        .iter()
+        .map(|p| {
+            rer.get(p)
+                .map(|v| format!("{:.2}", v))
+                .unwrap_or_else(|| "-".to_string())
+        })
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
 /// Muestra el balance (paso B) en formato de texto simple.
 pub fn balance_to_plain(balance: &Balance) -> String {
     let Balance {
         k_exp,
         arearef,
+        resolution,
+        perimeter,
         balance_m2,
         ..
     } = balance;
@@ -539,6 +1251,21 @@ pub fn balance_to_plain(balance: &Balance) -> String {
     let tot = balance_m2.B.tot();
     let rer = balance_m2.B.rer();
 
+    // Indicadores adicionales declarados en los factores de paso
+    let extra_indicators = if balance_m2.extra.is_empty() {
+        String::new()
+    } else {
+        format!(
+            "Otros indicadores [ud/m2.an]: {}\n",
+            balance_m2
+                .extra
+                .iter()
+                .map(|e| format!("{} = {:.2}", e.name, e.B))
+                .collect::<Vec<String>>()
+                .join(", ")
+        )
+    };
+
     // Final
     let mut use_byuse = balance_m2
         .used_EPB_byuse
@@ -560,30 +1287,97 @@ pub fn balance_to_plain(balance: &Balance) -> String {
         .collect::<Vec<String>>();
     b_byuse.sort();
 
+    // Fracción renovable por perímetro (total y por uso)
+    let mut rer_byuse = SERVICES
+        .iter()
+        .filter(|s| balance_m2.B_byuse.contains_key(s))
+        .map(|s| {
+            let rer_s: HashMap<Perimeter, f32> = balance_m2
+                .rer_byuse
+                .iter()
+                .filter_map(|(p, v)| v.get(s).map(|r| (*p, *r)))
+                .collect();
+            format!("{}: {}", s, rer_to_string(&rer_s))
+        })
+        .collect::<Vec<String>>();
+    rer_byuse.sort();
+
     let out = format!(
         "Area_ref = {:.2} [m2]
 k_exp = {:.2}
+Resolución temporal = {}
+Perímetro = {}
 C_ep [kWh/m2.an]: ren = {:.1}, nren = {:.1}, tot = {:.1}, RER = {:.2}
 E_CO2 [kg_CO2e/m2.an]: {:.2}
-
+{}
 ** Energía final (todos los vectores) [kWh/m2.an]:
 {}
 
 ** Energía primaria (ren, nren) [kWh/m2.an] y emisiones [kg_CO2e/m2.an] por servicios:
 {}
+
+** Fracción renovable (RER) por perímetro (distante, próximo, in situ):
+TOTAL: {}
+{}
 ",
         arearef,
         k_exp,
+        resolution
+            .map(|r| r.to_string())
+            .unwrap_or_else(|| "-".to_string()),
+        perimeter,
         ren,
         nren,
         tot,
         rer,
         co2,
+        extra_indicators,
         use_byuse.join("\n"),
-        b_byuse.join("\n")
+        b_byuse.join("\n"),
+        rer_to_string(&balance_m2.rer),
+        rer_byuse.join("\n")
     );
+    // Añade el coste de la energía si se han definido tarifas
+    let out = match &balance.costs {
+        Some(costs) => {
+            let mut bycarrier = costs
+                .bycarrier
+                .iter()
+                .map(|(k, v)| {
+                    format!(
+                        "{}: fijo {:.2}, energía {:.2}, compensación {:.2}, total {:.2}",
+                        k, v.fixed, v.energy, v.compensation, v.total
+                    )
+                })
+                .collect::<Vec<String>>();
+            bycarrier.sort();
+            let mut byuse = costs
+                .total_byservice
+                .iter()
+                .map(|(k, v)| format!("{}: {:.2}", k, v))
+                .collect::<Vec<String>>();
+            byuse.sort();
+            if costs.nepb != 0.0 {
+                byuse.push(format!("Usos no EPB: {:.2}", costs.nepb));
+            }
+            format!(
+                "{}
+** Coste de la energía [€/an]: {:.2}
+{}
+
+** Coste de la energía por servicios [€/an]:
+{}
+",
+                out,
+                costs.total,
+                bycarrier.join("\n"),
+                byuse.join("\n")
+            )
+        }
+        None => out,
+    };
     // Añade parámetros de demanda HE4 si existen
-    if let Some(map) = &balance.misc {
+    let out = if let Some(map) = &balance.misc {
         let demanda = map
             .get("demanda_anual_acs")
             .and_then(|v| v.parse::<f32>().and_then(|r| Ok(format!("{:.1}", r))).ok())
@@ -606,6 +1400,29 @@ Porcentaje renovable de la demanda de ACS (perímetro próximo): {} [%]
         )
     } else {
         out
+    };
+    // Añade la calificación energética si se ha calculado
+    let rating_error = balance
+        .misc
+        .as_ref()
+        .and_then(|map| map.get("error_calificacion"));
+    match (&balance.rating, rating_error) {
+        (Some(rating), _) => format!(
+            "{}
+** Calificación energética
+Energía primaria no renovable: {}
+Emisiones de CO2: {}
+",
+            out, rating.nren, rating.co2
+        ),
+        (None, Some(error)) => format!(
+            "{}
+** Calificación energética
+{}
+",
+            out, error
+        ),
+        _ => out,
     }
 }
 
@@ -642,6 +1459,51 @@ pub fn balance_to_xml(balanceobj: &Balance) -> String {
     }
 
     // Formatting
+    let ratingstring = match &balanceobj.rating {
+        Some(rating) => format!(
+            "
+    <Calificacion>
+        <EnergiaPrimariaNoRenovable>{}</EnergiaPrimariaNoRenovable>
+        <EmisionesCO2>{}</EmisionesCO2>
+    </Calificacion>",
+            rating.nren, rating.co2
+        ),
+        None => String::new(),
+    };
+    // Fracción renovable por perímetro (total y por uso), omitiendo los perímetros no evaluados
+    let rer_elements = |rer: &HashMap<Perimeter, f32>| {
+        [
+            (Perimeter::DISTANT, "Distante"),
+            (Perimeter::NEARBY, "Proximo"),
+            (Perimeter::ONSITE, "InSitu"),
+        ]
+        .iter()
+        .filter_map(|(p, tag)| rer.get(p).map(|v| format!("<{}>{:.2}</{}>", tag, v, tag)))
+        .collect::<Vec<String>>()
+        .join("")
+    };
+    let mut rer_byuse = SERVICES
+        .iter()
+        .filter(|s| balance_m2.B_byuse.contains_key(s))
+        .map(|s| {
+            let rer_s: HashMap<Perimeter, f32> = balance_m2
+                .rer_byuse
+                .iter()
+                .filter_map(|(p, v)| v.get(s).map(|r| (*p, *r)))
+                .collect();
+            format!(
+                "\n        <Servicio><Nombre>{}</Nombre>{}</Servicio>",
+                s,
+                rer_elements(&rer_s)
+            )
+        })
+        .collect::<Vec<String>>();
+    rer_byuse.sort();
+    let rerstring = format!(
+        "\n    <RER><!-- fracción renovable (paso B) por perímetro -->\n        <Total>{}</Total>{}\n    </RER>",
+        rer_elements(&balance_m2.rer),
+        rer_byuse.join("")
+    );
     let wmetastring = wmeta
         .iter()
         .map(|m| {
@@ -665,6 +1527,7 @@ pub fn balance_to_xml(balanceobj: &Balance) -> String {
                 nren,
                 co2,
                 comment,
+                ..
             } = f;
             format!("      <Dato><Vector>{}</Vector><Origen>{}</Origen><Destino>{}</Destino><Paso>{}</Paso><ren>{:.3}</ren><nren>{:.3}</nren><co2>{:.3}</co2><Comentario>{}</Comentario></Dato>",
             carrier, source, dest, step, ren, nren, co2, escape_xml(comment))
@@ -739,7 +1602,7 @@ pub fn balance_to_xml(balanceobj: &Balance) -> String {
     <Epm2><!-- C_ep [kWh/m2.an] -->
         <tot>{:.1}</tot>
         <nren>{:.1}</nren>
-    </Epm2>
+    </Epm2>{}{}
 </BalanceEPB>",
         wmetastring,
         wdatastring,
@@ -748,6 +1611,8 @@ pub fn balance_to_xml(balanceobj: &Balance) -> String {
         k_exp,
         arearef,
         ren + nren,
-        nren
+        nren,
+        rerstring,
+        ratingstring
     )
 }
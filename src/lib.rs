@@ -37,17 +37,30 @@ best suited for that purpose.
 
 It also holds the following assumptions:
 
-- constant weighting factors through all timesteps
-- no priority is defined for energy production (average step A weighting factor f_we_el_stepA)
+- constant weighting factors through all timesteps, unless values for each timestep are given
+- no priority is defined for energy production (average step A weighting factor f_we_el_stepA), unless
+  a priority is set for the generators or production origins (`CTE_PRIORIDAD_PRODUCCION` metadata)
 - all on-site produced energy from non cogeneration sources is considered as delivered
-- on-site produced energy is not compensated on a service by service basis, but on a carrier basis
-- unit and constant load matching factor
-
-Some restrictions may be lifted in the future. Specifically:
-
-- implement a load matching factor (f_match_t) following formula B.32 in appendix B
-- allow the imputation to a specific service for produced energy
-- allow setting priorities for energy production
+- the fuel of cogeneration is charged to the fuel carrier and exported cogenerated electricity uses
+  user defined factors, unless a cogeneration unit is defined (`CTE_COGENERADOR` metadata)
+- no electrical storage, unless a battery is defined (`CTE_BATERIA` metadata)
+- on-site produced energy assigned to a service is used first by that service, and the rest is
+  compensated on a carrier basis
+- unit and constant load matching factor, unless a constant value, a value for each timestep
+  or the formula B.32 in appendix B is selected (`CTE_FMATCH` metadata)
+- weighted energy is shared among EPB services using the reverse calculation method (E.3.6),
+  unless the forward calculation method is selected (`CTE_REPARTO_SERVICIOS` metadata)
+- user defined energy carriers, besides the predefined ones, use the `USUARIO_` name prefix and
+  must be declared along with their weighting factors (`CTE_VECTOR_USUARIO` metadata)
+- extra weighting indicators (e.g. cost), besides renewable and non renewable primary energy and
+  CO2 emissions, are declared in the weighting factors (`CTE_INDICADORES` metadata)
+- electric vehicle charging, people transport and built-in appliances services are only
+  EPB services when declared as such (`CTE_SERVICIOS_EPB` metadata)
+- shared production of buildings with several units is allocated to the units by area, by energy
+  use or with fixed coefficients (`CTE_REPARTO_PRODUCCION_COMUN` metadata)
+- energy costs are computed for carriers with a tariff (`CTE_TARIFA` metadata), with fixed charges,
+  prices for the metered delivered energy (EPB and non EPB uses) and feed-in or net-billing compensation
+  of exported energy
 
 Este *crate* proporciona una biblioteca y un programa que **implementa una parte sustancial del
 estándar EN ISO 52000-1**: *Eficiencia energética de los edificios - Evaluación global de la EPB -
@@ -59,17 +72,29 @@ así, adopta nomenclatura y valores por defecto adaptados a ese propósito.
 
 También realiza los siguientes supuestos:
 
-- factores de paso constantes en todo el periodo de cálculo
-- no se definen prioridades para la producción de energía
+- factores de paso constantes en todo el periodo de cálculo, salvo que se indiquen valores para cada paso de cálculo
+- no se definen prioridades para la producción de energía, salvo que se indique un orden de prioridad
+  de los generadores u orígenes de la producción (metadato `CTE_PRIORIDAD_PRODUCCION`)
 - se considera como suministrada toda la energía producida procedente de fuentes distintas a la cogeneración
-- la energía producida in situ se compensa por vector energético y no por servicios
-- factor de coincidencia de cargas igual a la unidad
-
-Algunas restricciones pueden revisarse en el futuro, tales como:
-
-- implementación del factor de coincidencia de cargas según fórmula B.32 del apéndice B
-- imputación de energía generada a servicios específicos
-- fijación de prioridades para la producción de energía
+- el combustible de la cogeneración se imputa al vector que la alimenta y la electricidad cogenerada exportada
+  usa factores de paso definidos por el usuario, salvo que se defina la unidad de cogeneración (metadato `CTE_COGENERADOR`)
+- no hay almacenamiento eléctrico, salvo que se defina una batería (metadato `CTE_BATERIA`)
+- la energía producida in situ asignada a un servicio se usa primero en ese servicio y el resto
+  se compensa por vector energético
+- factor de coincidencia de cargas igual a la unidad, salvo que se indique un valor constante, una serie de valores
+  o el uso de la fórmula B.32 del apéndice B (metadato `CTE_FMATCH`)
+- la energía ponderada se reparte entre servicios EPB con el método inverso (E.3.6), salvo que se indique
+  el método directo (metadato `CTE_REPARTO_SERVICIOS`)
+- los vectores energéticos definidos por el usuario, además de los predefinidos, llevan el prefijo
+  `USUARIO_` y se declaran junto a sus factores de paso (metadato `CTE_VECTOR_USUARIO`)
+- los indicadores de ponderación adicionales (p.e. coste), además de la energía primaria renovable y no
+  renovable y las emisiones de CO2, se declaran en los factores de paso (metadato `CTE_INDICADORES`)
+- los servicios de recarga de vehículos eléctricos, transporte de personas y equipamiento integrado
+  solo son servicios EPB cuando se declaran como tales (metadato `CTE_SERVICIOS_EPB`)
+- la producción común de edificios con varias unidades se reparte entre ellas por superficie, por
+  consumo o con coeficientes fijos (metadato `CTE_REPARTO_PRODUCCION_COMUN`)
+- el coste de la energía se calcula para los vectores con tarifa (metadato `CTE_TARIFA`), con cargo fijo,
+  precios de la energía suministrada medida (usos EPB y no EPB) y venta o compensación de la energía exportada
 
 # Ejemplo
 
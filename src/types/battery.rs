@@ -0,0 +1,195 @@
+// Copyright (c) 2018-2020  Ministerio de Fomento
+//                          Instituto de Ciencias de la Construcción Eduardo Torroja (IETcc-CSIC)
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Author(s): Rafael Villar Burke <pachi@ietcc.csic.es>,
+//            Daniel Jiménez González <dani@ietcc.csic.es>,
+//            Marta Sorribes Gil <msorribes@ietcc.csic.es>
+
+/*!
+Almacenamiento eléctrico
+========================
+
+Tipo Battery y sus traits.
+
+Define un sistema de almacenamiento de energía eléctrica (batería) que desplaza a pasos de cálculo posteriores
+el excedente de la producción eléctrica in situ no usada en servicios EPB.
+*/
+
+use std::fmt;
+use std::str;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::EpbdError;
+
+/// Sistema de almacenamiento eléctrico (batería)
+///
+/// Se puede definir en los metadatos de los componentes con la clave `CTE_BATERIA`,
+/// indicando la capacidad útil [kWh] y los rendimientos de carga y descarga [-].
+/// P.e.: `#META CTE_BATERIA: 10.0, 0.95, 0.95`
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Battery {
+    /// Useful storage capacity [kWh]
+    pub capacity: f32,
+    /// Charge efficiency [0, 1]
+    pub eff_charge: f32,
+    /// Discharge efficiency [0, 1]
+    pub eff_discharge: f32,
+}
+
+/// Flujos de energía de la batería en cada paso de cálculo
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct BatteryFlows {
+    /// Energy taken from the surplus production to charge the battery, in each timestep
+    pub charged: Vec<f32>,
+    /// Energy delivered by the battery to EPB uses, in each timestep
+    pub discharged: Vec<f32>,
+    /// Charge and discharge losses, in each timestep
+    pub losses: Vec<f32>,
+}
+
+impl Battery {
+    /// Flujos de energía de la batería con una regla de gestión simple
+    ///
+    /// En cada paso de cálculo la batería se descarga primero para cubrir el consumo EPB no cubierto
+    /// por la producción in situ y después se carga con el excedente de la producción, hasta su capacidad.
+    /// El estado de carga inicial es el estado de carga final de un primer cálculo anual,
+    /// de modo que el periodo de cálculo se repite de forma cíclica.
+    ///
+    /// * `surplus` - produced energy not used for EPB services, in each timestep
+    /// * `deficit` - energy used for EPB services not covered by the produced energy, in each timestep
+    pub fn dispatch(&self, surplus: &[f32], deficit: &[f32]) -> BatteryFlows {
+        let (soc_end, _) = self.dispatch_from(0.0, surplus, deficit);
+        let (_, flows) = self.dispatch_from(soc_end, surplus, deficit);
+        flows
+    }
+
+    /// Flujos de energía de la batería partiendo del estado de carga `soc` y estado de carga final
+    fn dispatch_from(&self, mut soc: f32, surplus: &[f32], deficit: &[f32]) -> (f32, BatteryFlows) {
+        let mut flows = BatteryFlows::default();
+        for (&surplus, &deficit) in surplus.iter().zip(deficit) {
+            // Descarga
+            let discharged = deficit.max(0.0).min(soc * self.eff_discharge);
+            soc -= discharged / self.eff_discharge;
+            // Carga
+            let charged = surplus
+                .max(0.0)
+                .min((self.capacity - soc).max(0.0) / self.eff_charge);
+            soc += charged * self.eff_charge;
+
+            flows.charged.push(charged);
+            flows.discharged.push(discharged);
+            flows.losses.push(
+                charged * (1.0 - self.eff_charge) + discharged * (1.0 / self.eff_discharge - 1.0),
+            );
+        }
+        (soc, flows)
+    }
+}
+
+impl fmt::Display for Battery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.2}, {:.2}, {:.2}",
+            self.capacity, self.eff_charge, self.eff_discharge
+        )
+    }
+}
+
+impl str::FromStr for Battery {
+    type Err = EpbdError;
+
+    fn from_str(s: &str) -> Result<Battery, Self::Err> {
+        let err = || EpbdError::ParseError(format!("batería \"{}\"", s));
+        let values = s
+            .split(',')
+            .map(|v| v.trim().parse::<f32>())
+            .collect::<Result<Vec<f32>, _>>()
+            .map_err(|_| err())?;
+        match values.as_slice() {
+            [capacity, eff_charge, eff_discharge]
+                if *capacity >= 0.0
+                    && *eff_charge > 0.0
+                    && *eff_charge <= 1.0
+                    && *eff_discharge > 0.0
+                    && *eff_discharge <= 1.0 =>
+            {
+                Ok(Battery {
+                    capacity: *capacity,
+                    eff_charge: *eff_charge,
+                    eff_discharge: *eff_discharge,
+                })
+            }
+            _ => Err(err()),
+        }
+    }
+}
+
+// ========================== Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn tbattery_parse() {
+        let battery: Battery = "10, 0.9, 0.8".parse().unwrap();
+        assert_eq!(
+            battery,
+            Battery {
+                capacity: 10.0,
+                eff_charge: 0.9,
+                eff_discharge: 0.8
+            }
+        );
+        assert_eq!(battery.to_string(), "10.00, 0.90, 0.80");
+        assert!("10, 1.5, 0.8".parse::<Battery>().is_err());
+        assert!("10, 0.9".parse::<Battery>().is_err());
+    }
+
+    #[test]
+    fn tbattery_dispatch() {
+        let battery = Battery {
+            capacity: 5.0,
+            eff_charge: 1.0,
+            eff_discharge: 1.0,
+        };
+        // Se carga hasta la capacidad y se descarga en los pasos siguientes
+        let flows = battery.dispatch(&[8.0, 0.0, 0.0], &[0.0, 3.0, 3.0]);
+        assert_eq!(flows.charged, vec![5.0, 0.0, 0.0]);
+        assert_eq!(flows.discharged, vec![0.0, 3.0, 2.0]);
+        assert_eq!(flows.losses, vec![0.0, 0.0, 0.0]);
+
+        // Pérdidas de carga y descarga
+        let battery = Battery {
+            capacity: 5.0,
+            eff_charge: 0.8,
+            eff_discharge: 0.5,
+        };
+        let flows = battery.dispatch(&[5.0, 0.0], &[0.0, 3.0]);
+        assert_eq!(flows.charged, vec![5.0, 0.0]);
+        assert_eq!(flows.discharged, vec![0.0, 2.0]);
+        assert_eq!(format!("{:.2}", flows.losses[0]), "1.00");
+        assert_eq!(format!("{:.2}", flows.losses[1]), "2.00");
+    }
+}
@@ -0,0 +1,247 @@
+// Copyright (c) 2018-2020  Ministerio de Fomento
+//                          Instituto de Ciencias de la Construcción Eduardo Torroja (IETcc-CSIC)
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Author(s): Rafael Villar Burke <pachi@ietcc.csic.es>,
+//            Daniel Jiménez González <dani@ietcc.csic.es>,
+//            Marta Sorribes Gil <msorribes@ietcc.csic.es>
+
+/*!
+Cogeneración
+============
+
+Tipos Cogeneration, CogenAllocation y CogenWFactors y sus traits.
+
+Define una unidad de cogeneración (consumo de combustible y producción eléctrica y térmica) y el método
+de reparto del combustible entre la electricidad y el calor cogenerados (EN 15316-4-5), que permite obtener
+los factores de paso de la electricidad y del calor cogenerados.
+*/
+
+use std::fmt;
+use std::str;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::EpbdError,
+    types::{Carrier, RenNrenCo2},
+};
+
+/// Factor exergético del calor por defecto (factor de Carnot con T_0 = 10ºC y T_s = 80ºC)
+pub const COGEN_HEAT_EXERGY_FACTOR: f32 = 1.0 - 283.15 / 353.15;
+
+/// Método de reparto del combustible de la cogeneración entre la electricidad y el calor
+#[allow(non_camel_case_types)]
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum CogenAllocation {
+    /// Energy method: in proportion to the electrical and thermal outputs
+    ENERGIA,
+    /// Exergy method: in proportion to the exergy of the outputs, with the given heat exergy factor
+    EXERGIA(f32),
+    /// Power bonus method (EN 15316-4-5): electricity is weighted with the grid electricity factors
+    BONIFICACION,
+}
+
+/// Unidad de cogeneración
+///
+/// Se puede definir en los metadatos de los componentes con la clave `CTE_COGENERADOR`:
+/// `combustible, consumo anual de combustible [kWh], producción eléctrica anual [kWh], producción térmica anual [kWh], método`,
+/// donde el método es `ENERGIA`, `EXERGIA` (con un factor exergético del calor opcional, p.e. `EXERGIA, 0.2`)
+/// o `BONIFICACION`. P.e.: `#META CTE_COGENERADOR: GASNATURAL, 1000.0, 300.0, 550.0, EXERGIA`
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Cogeneration {
+    /// Fuel carrier
+    pub fuel: Carrier,
+    /// Annual fuel input, kWh
+    pub fuel_an: f32,
+    /// Annual electrical output, kWh
+    pub el_an: f32,
+    /// Annual thermal output, kWh
+    pub heat_an: f32,
+    /// Allocation method of the fuel between electricity and heat
+    pub method: CogenAllocation,
+}
+
+/// Factores de paso de la electricidad y del calor cogenerados
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CogenWFactors {
+    /// Weighting factors of cogenerated electricity
+    pub electricity: RenNrenCo2,
+    /// Weighting factors of cogenerated heat
+    pub heat: RenNrenCo2,
+}
+
+impl Cogeneration {
+    /// Factores de paso de la electricidad y del calor cogenerados
+    ///
+    /// Weighting factors of cogenerated electricity and heat, that share the weighted fuel input
+    ///
+    /// * `fp_fuel` - weighting factors of the fuel (supplied from the grid)
+    /// * `fp_el_grid` - weighting factors of electricity supplied from the grid (power bonus method)
+    ///
+    /// # Errors
+    ///
+    /// * Null or negative fuel input, electrical output or thermal output
+    pub fn wfactors(
+        &self,
+        fp_fuel: RenNrenCo2,
+        fp_el_grid: RenNrenCo2,
+    ) -> Result<CogenWFactors, EpbdError> {
+        let el_an = self.el_an;
+        if self.fuel_an <= 0.0 || self.heat_an <= 0.0 || el_an <= 0.0 {
+            return Err(EpbdError::WrongInput(format!(
+                "Cogeneración con consumo de combustible ({:.2}), producción eléctrica ({:.2}) o producción térmica ({:.2}) nulos",
+                self.fuel_an, el_an, self.heat_an
+            )));
+        }
+        // Recursos usados por el combustible consumido
+        let we_fuel = self.fuel_an * fp_fuel;
+        let we_el = match self.method {
+            CogenAllocation::ENERGIA => we_fuel * (el_an / (el_an + self.heat_an)),
+            CogenAllocation::EXERGIA(f_ex) => we_fuel * (el_an / (el_an + f_ex * self.heat_an)),
+            CogenAllocation::BONIFICACION => el_an * fp_el_grid,
+        };
+        Ok(CogenWFactors {
+            electricity: we_el * (1.0 / el_an),
+            heat: (we_fuel - we_el) * (1.0 / self.heat_an),
+        })
+    }
+}
+
+impl fmt::Display for CogenAllocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CogenAllocation::ENERGIA => write!(f, "ENERGIA"),
+            CogenAllocation::EXERGIA(f_ex) => write!(f, "EXERGIA, {:.3}", f_ex),
+            CogenAllocation::BONIFICACION => write!(f, "BONIFICACION"),
+        }
+    }
+}
+
+impl fmt::Display for Cogeneration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}, {:.2}, {:.2}, {:.2}, {}",
+            self.fuel, self.fuel_an, self.el_an, self.heat_an, self.method
+        )
+    }
+}
+
+impl str::FromStr for Cogeneration {
+    type Err = EpbdError;
+
+    fn from_str(s: &str) -> Result<Cogeneration, Self::Err> {
+        let err =
+            || EpbdError::ParseError(format!("definición de la cogeneración \"{}\"", s.trim()));
+        let items: Vec<&str> = s.split(',').map(str::trim).collect();
+        if items.len() < 5 {
+            return Err(err());
+        }
+        let fuel: Carrier = items[0].parse().map_err(|_| err())?;
+        let fuel_an: f32 = items[1].parse().map_err(|_| err())?;
+        let el_an: f32 = items[2].parse().map_err(|_| err())?;
+        let heat_an: f32 = items[3].parse().map_err(|_| err())?;
+        let method = match &items[4..] {
+            ["ENERGIA"] => CogenAllocation::ENERGIA,
+            ["EXERGIA"] => CogenAllocation::EXERGIA(COGEN_HEAT_EXERGY_FACTOR),
+            ["EXERGIA", f_ex] => {
+                let f_ex: f32 = f_ex.parse().map_err(|_| err())?;
+                if !(0.0..=1.0).contains(&f_ex) {
+                    return Err(err());
+                }
+                CogenAllocation::EXERGIA(f_ex)
+            }
+            ["BONIFICACION"] => CogenAllocation::BONIFICACION,
+            _ => return Err(err()),
+        };
+        Ok(Cogeneration {
+            fuel,
+            fuel_an,
+            el_an,
+            heat_an,
+            method,
+        })
+    }
+}
+
+// ========================== Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn tcogeneration_parse() {
+        let cgn = "GASNATURAL, 1000.0, 300.0, 550.0, EXERGIA, 0.2"
+            .parse::<Cogeneration>()
+            .unwrap();
+        assert_eq!(cgn.fuel, Carrier::GASNATURAL);
+        assert_eq!(cgn.method, CogenAllocation::EXERGIA(0.2));
+        assert_eq!(
+            cgn.to_string(),
+            "GASNATURAL, 1000.00, 300.00, 550.00, EXERGIA, 0.200"
+        );
+        assert!("GASNATURAL, 1000.0, 300.0, 550.0, EXERGIA, 2.0"
+            .parse::<Cogeneration>()
+            .is_err());
+        assert!("GASNATURAL, 1000.0, 300.0, 550.0"
+            .parse::<Cogeneration>()
+            .is_err());
+        assert!("GASNATURAL, 1000.0, 300.0, 550.0, OTRO"
+            .parse::<Cogeneration>()
+            .is_err());
+    }
+
+    #[test]
+    fn tcogeneration_wfactors() {
+        let fp_fuel = RenNrenCo2::new(0.0, 1.2, 0.25);
+        let fp_el_grid = RenNrenCo2::new(0.4, 2.0, 0.35);
+        // 1000 kWh de combustible -> 300 kWh eléctricos + 600 kWh térmicos
+        let cgn = "GASNATURAL, 1000.0, 300.0, 600.0, ENERGIA"
+            .parse::<Cogeneration>()
+            .unwrap();
+        let fp = cgn.wfactors(fp_fuel, fp_el_grid).unwrap();
+        assert_eq!(format!("{:.3}", fp.electricity.nren), "1.333");
+        assert_eq!(format!("{:.3}", fp.heat.nren), "1.333");
+
+        let cgn = "GASNATURAL, 1000.0, 300.0, 600.0, EXERGIA, 0.5"
+            .parse::<Cogeneration>()
+            .unwrap();
+        let fp = cgn.wfactors(fp_fuel, fp_el_grid).unwrap();
+        assert_eq!(format!("{:.3}", fp.electricity.nren), "2.000");
+        assert_eq!(format!("{:.3}", fp.heat.nren), "1.000");
+
+        // Bonificación: la electricidad se pondera con los factores de red
+        let cgn = "GASNATURAL, 1000.0, 300.0, 600.0, BONIFICACION"
+            .parse::<Cogeneration>()
+            .unwrap();
+        let fp = cgn.wfactors(fp_fuel, fp_el_grid).unwrap();
+        assert_eq!(fp.electricity, fp_el_grid);
+        assert_eq!(format!("{:.3}", fp.heat.nren), "1.000");
+        assert_eq!(format!("{:.3}", fp.heat.ren), "-0.200");
+
+        let cgn = "GASNATURAL, 1000.0, 0.0, 600.0, BONIFICACION"
+            .parse::<Cogeneration>()
+            .unwrap();
+        assert!(cgn.wfactors(fp_fuel, fp_el_grid).is_err());
+    }
+}
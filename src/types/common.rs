@@ -36,8 +36,12 @@ use crate::{error::EpbdError, types::RenNrenCo2};
 // -------------------- Carrier
 
 /// Vector energético (energy carrier).
+///
+/// Además de los vectores predefinidos, se pueden usar vectores definidos por el usuario (`USUARIO`),
+/// cuyo nombre lleva el prefijo `USUARIO_` (p.e. `USUARIO_HIDROGENO`) y que deben declararse en los
+/// metadatos de los factores de paso (ver `UserCarrier`).
 #[allow(non_camel_case_types)]
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Carrier {
     /// Electricity
     ELECTRICIDAD,
@@ -61,6 +65,8 @@ pub enum Carrier {
     RED1,
     /// Generic energy carrier 2
     RED2,
+    /// User defined energy carrier
+    USUARIO(CarrierName),
 }
 
 impl str::FromStr for Carrier {
@@ -79,14 +85,162 @@ impl str::FromStr for Carrier {
             "GLP" => Ok(Carrier::GLP),
             "RED1" => Ok(Carrier::RED1),
             "RED2" => Ok(Carrier::RED2),
-            _ => Err(EpbdError::ParseError(s.into())),
+            _ => match s.strip_prefix(USER_CARRIER_PREFIX) {
+                Some(name) => name.parse().map(Carrier::USUARIO),
+                None => Err(EpbdError::ParseError(format!(
+                    "vector energético \"{}\" desconocido (los vectores de usuario llevan el prefijo {})",
+                    s, USER_CARRIER_PREFIX
+                ))),
+            },
         }
     }
 }
 
 impl std::fmt::Display for Carrier {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            Carrier::USUARIO(name) => write!(f, "{}{}", USER_CARRIER_PREFIX, name),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+impl Serialize for Carrier {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Carrier {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+// -------------------- CarrierName
+
+/// Prefijo de los vectores energéticos definidos por el usuario (p.e. USUARIO_HIDROGENO)
+pub const USER_CARRIER_PREFIX: &str = "USUARIO_";
+
+/// Longitud máxima del nombre de un vector energético definido por el usuario (sin el prefijo)
+pub const CARRIER_NAME_MAX_LEN: usize = 24;
+
+/// Nombre de un vector energético definido por el usuario, sin el prefijo `USUARIO_` (p.e. HIDROGENO, BIOGAS)
+///
+/// Está formado por letras mayúsculas (A-Z), números y guiones bajos, empieza por una letra
+/// y tiene como máximo `CARRIER_NAME_MAX_LEN` caracteres. Se guarda con tamaño fijo para
+/// que los vectores energéticos sean `Copy`, por lo que los nombres más largos son un error.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CarrierName {
+    len: u8,
+    bytes: [u8; CARRIER_NAME_MAX_LEN],
+}
+
+impl CarrierName {
+    /// Nombre del vector energético
+    pub fn as_str(&self) -> &str {
+        // Solo se construye a partir de caracteres ASCII
+        std::str::from_utf8(&self.bytes[..self.len as usize]).unwrap_or_default()
+    }
+}
+
+impl str::FromStr for CarrierName {
+    type Err = EpbdError;
+
+    fn from_str(s: &str) -> Result<CarrierName, Self::Err> {
+        if s.len() > CARRIER_NAME_MAX_LEN {
+            return Err(EpbdError::ParseError(format!(
+                "nombre de vector energético de usuario \"{}\" demasiado largo ({} caracteres, máximo {})",
+                s,
+                s.chars().count(),
+                CARRIER_NAME_MAX_LEN
+            )));
+        }
+        let is_valid = s.starts_with(|c: char| c.is_ascii_uppercase())
+            && s.chars()
+                .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_');
+        if !is_valid {
+            return Err(EpbdError::ParseError(format!(
+                "nombre de vector energético de usuario \"{}\" (letras mayúsculas, números y guiones bajos, empezando por una letra)",
+                s
+            )));
+        }
+        let mut bytes = [0; CARRIER_NAME_MAX_LEN];
+        bytes[..s.len()].copy_from_slice(s.as_bytes());
+        Ok(CarrierName {
+            len: s.len() as u8,
+            bytes,
+        })
+    }
+}
+
+impl fmt::Display for CarrierName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl fmt::Debug for CarrierName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+// -------------------- UserCarrier
+
+/// Declaración de un vector energético definido por el usuario
+///
+/// Se declara en los metadatos de los factores de paso con la clave `CTE_VECTOR_USUARIO`,
+/// una vez para cada vector, indicando su nombre y, opcionalmente, si pertenece al perímetro
+/// próximo (`NEARBY`) y si se puede producir in situ y exportar (`EXPORTABLE`).
+/// P.e.: `#META CTE_VECTOR_USUARIO: USUARIO_HIDROGENO, NEARBY, EXPORTABLE`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserCarrier {
+    /// User defined energy carrier
+    pub carrier: Carrier,
+    /// The carrier is inside the nearby perimeter
+    pub nearby: bool,
+    /// The carrier can be produced on-site and exported
+    pub exportable: bool,
+}
+
+impl str::FromStr for UserCarrier {
+    type Err = EpbdError;
+
+    fn from_str(s: &str) -> Result<UserCarrier, Self::Err> {
+        let err = || EpbdError::ParseError(format!("vector energético de usuario \"{}\"", s));
+        let items: Vec<&str> = s.split(',').map(str::trim).collect();
+        let carrier = match items[0].parse::<Carrier>() {
+            Ok(carrier @ Carrier::USUARIO(_)) => carrier,
+            _ => return Err(err()),
+        };
+        let mut usercarrier = UserCarrier {
+            carrier,
+            nearby: false,
+            exportable: false,
+        };
+        for item in &items[1..] {
+            match *item {
+                "NEARBY" => usercarrier.nearby = true,
+                "EXPORTABLE" => usercarrier.exportable = true,
+                _ => return Err(err()),
+            }
+        }
+        Ok(usercarrier)
+    }
+}
+
+impl fmt::Display for UserCarrier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.carrier)?;
+        if self.nearby {
+            write!(f, ", NEARBY")?;
+        }
+        if self.exportable {
+            write!(f, ", EXPORTABLE")?;
+        }
+        Ok(())
     }
 }
 
@@ -182,10 +336,16 @@ pub enum Service {
     BAC,
     /// Undefined or generic use
     NDEF,
+    /// Electric vehicle charging
+    VE,
+    /// People transport (lifts, escalators)
+    TRA,
+    /// Built-in appliances
+    EQU,
 }
 
 /// Lista de usos disponibles
-pub const SERVICES: [Service; 9] = [
+pub const SERVICES: [Service; 12] = [
     Service::ACS,
     Service::CAL,
     Service::REF,
@@ -195,8 +355,22 @@ pub const SERVICES: [Service; 9] = [
     Service::DHU,
     Service::BAC,
     Service::NDEF,
+    Service::VE,
+    Service::TRA,
+    Service::EQU,
 ];
 
+impl Service {
+    /// ¿Es un servicio EPB por defecto?
+    ///
+    /// Los servicios de recarga de vehículos eléctricos (VE), transporte de personas (TRA)
+    /// y equipamiento integrado (EQU) solo son EPB si se declaran como tales en los metadatos
+    /// de los componentes (clave `CTE_SERVICIOS_EPB`)
+    pub fn is_epb_by_default(self) -> bool {
+        !matches!(self, Service::VE | Service::TRA | Service::EQU)
+    }
+}
+
 impl str::FromStr for Service {
     type Err = EpbdError;
 
@@ -215,6 +389,9 @@ impl str::FromStr for Service {
             "DHU" => Ok(Service::DHU),
             "BAC" => Ok(Service::BAC),
             "NDEF" => Ok(Service::NDEF),
+            "VE" => Ok(Service::VE),
+            "TRA" => Ok(Service::TRA),
+            "EQU" => Ok(Service::EQU),
             "" => Ok(Service::default()),
             _ => Err(EpbdError::ParseError(s.into())),
         }
@@ -277,14 +454,57 @@ impl fmt::Display for Component {
     }
 }
 
-impl str::FromStr for Component {
-    type Err = EpbdError;
+impl Component {
+    /// Identificador del generador indicado en el comentario con la etiqueta `CTEEPBD_GENERADOR=<id>`
+    ///
+    /// Generator id set in the comment with the `CTEEPBD_GENERADOR=<id>` tag
+    pub fn generator_tag(&self) -> Option<&str> {
+        self.comment
+            .split_whitespace()
+            .find_map(|word| word.strip_prefix("CTEEPBD_GENERADOR="))
+            .filter(|id| !id.is_empty())
+    }
 
-    fn from_str(s: &str) -> Result<Component, Self::Err> {
+    /// Comprueba que la combinación de tipo, subtipo y vector energético de un componente es válida
+    ///
+    /// Los consumos son EPB o NEPB y solo se admite la producción (in situ o por cogeneración) de algunos vectores.
+    /// Devuelve la descripción del error si la combinación no es válida.
+    pub fn check_kind(ctype: CType, csubtype: CSubtype, carrier: Carrier) -> Result<(), String> {
         use self::CSubtype::*;
         use self::CType::*;
-        use self::Carrier::{ELECTRICIDAD, MEDIOAMBIENTE};
+        use self::Carrier::{ELECTRICIDAD, MEDIOAMBIENTE, RED1, RED2, USUARIO};
+
+        let is_valid = match ctype {
+            CONSUMO => matches!(csubtype, EPB | NEPB),
+            // Los vectores de usuario necesitan factores de paso de la producción para su evaluación
+            PRODUCCION => matches!(
+                (csubtype, carrier),
+                (INSITU, ELECTRICIDAD)
+                    | (INSITU, MEDIOAMBIENTE)
+                    | (INSITU, RED1)
+                    | (INSITU, RED2)
+                    | (INSITU, USUARIO(_))
+                    | (COGENERACION, ELECTRICIDAD)
+                    | (COGENERACION, RED1)
+                    | (COGENERACION, RED2)
+                    | (COGENERACION, USUARIO(_))
+            ),
+        };
+        if is_valid {
+            Ok(())
+        } else {
+            Err(format!(
+                "combinación no admitida de vector energético, tipo y subtipo: {}, {}, {}",
+                carrier, ctype, csubtype
+            ))
+        }
+    }
+}
 
+impl str::FromStr for Component {
+    type Err = EpbdError;
+
+    fn from_str(s: &str) -> Result<Component, Self::Err> {
         let items: Vec<&str> = s.trim().splitn(2, '#').map(str::trim).collect();
         let comment = items.get(1).unwrap_or(&"").to_string();
         let items: Vec<&str> = items[0].split(',').map(str::trim).collect();
@@ -302,20 +522,8 @@ impl str::FromStr for Component {
         let csubtype: CSubtype = items[2]
             .parse()
             .map_err(|_| EpbdError::ParseError(items[2].into()))?;
-        let carrier_ok = match ctype {
-            CONSUMO => match csubtype {
-                EPB | NEPB => true,
-                _ => false,
-            },
-            PRODUCCION => match csubtype {
-                INSITU => carrier == ELECTRICIDAD || carrier == MEDIOAMBIENTE,
-                COGENERACION => carrier == ELECTRICIDAD,
-                _ => false,
-            },
-        };
-        if !carrier_ok {
-            return Err(EpbdError::ParseError(s.into()));
-        }
+        Component::check_kind(ctype, csubtype, carrier)
+            .map_err(|_| EpbdError::ParseError(s.into()))?;
         //This accounts for the legacy version, which may not have a service type
         let maybeservice: Result<Service, _> = items[3].parse();
         let (valuesidx, service) = match maybeservice {
@@ -472,6 +680,16 @@ pub struct Factor {
     pub nren: f32,
     /// CO2 emissions for each end use unit of this carrier
     pub co2: f32,
+    /// Weighting factors for each timestep, if they are not constant
+    ///
+    /// When defined, `ren`, `nren` and `co2` hold the average of the timestep values
+    values: Option<Vec<RenNrenCo2>>,
+    /// Extra weighting indicators for each end use unit of this carrier (e.g. cost), in the order
+    /// declared in the weighting factors metadata (see `Factors::indicators`)
+    ///
+    /// Each indicator holds a constant value or a value for each timestep
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    extra: Vec<Vec<f32>>,
     /// Descriptive comment string for the weighting factor
     pub comment: String,
 }
@@ -494,10 +712,29 @@ impl Factor {
             ren,
             nren,
             co2,
+            values: None,
+            extra: Vec::new(),
             comment: comment.into(),
         }
     }
 
+    /// Constructor para factores de paso variables en cada paso de cálculo
+    ///
+    /// Constructor for weighting factors with a value for each timestep.
+    /// The constant factors (`ren`, `nren`, `co2`) are set to the average of the timestep values.
+    pub fn new_series<T: Into<String>>(
+        carrier: Carrier,
+        source: Source,
+        dest: Dest,
+        step: Step,
+        values: Vec<RenNrenCo2>,
+        comment: T,
+    ) -> Self {
+        let mut factor = Self::new(carrier, source, dest, step, RenNrenCo2::default(), comment);
+        factor.set_values_t(values);
+        factor
+    }
+
     /// Obtener los factores de paso como estructura RenNrenCo2
     pub fn factors(&self) -> RenNrenCo2 {
         RenNrenCo2 {
@@ -508,36 +745,144 @@ impl Factor {
     }
 
     /// Copia los factores desde una estructura RenNRenCo2
+    ///
+    /// Los factores pasan a ser constantes en todos los pasos de cálculo y sin indicadores adicionales
     pub fn set_values(&mut self, &values: &RenNrenCo2) {
         self.ren = values.ren;
         self.nren = values.nren;
         self.co2 = values.co2;
+        self.values = None;
+        self.extra.clear();
     }
-}
 
-impl fmt::Display for Factor {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    /// Establece los factores para cada paso de cálculo
+    ///
+    /// Los factores constantes (`ren`, `nren`, `co2`) toman el valor medio de los pasos de cálculo.
+    /// Se eliminan los indicadores adicionales.
+    pub fn set_values_t(&mut self, values: Vec<RenNrenCo2>) {
+        let num_steps = values.len().max(1) as f32;
+        let sum = values.iter().fold(RenNrenCo2::default(), |acc, v| acc + *v);
+        self.ren = sum.ren / num_steps;
+        self.nren = sum.nren / num_steps;
+        self.co2 = sum.co2 / num_steps;
+        self.values = Some(values);
+        self.extra.clear();
+    }
+
+    /// Transforma los factores constantes y, si existen, los de cada paso de cálculo
+    ///
+    /// Apply the `f` conversion to the weighting factors of each timestep, if any, or to the constant factors.
+    /// The constant factors of timestep values are recomputed as averages. Extra indicators are kept.
+    pub fn map_values<F: Fn(RenNrenCo2) -> RenNrenCo2>(&mut self, f: F) {
+        let extra = std::mem::take(&mut self.extra);
+        match self.values.take() {
+            Some(values) => self.set_values_t(values.into_iter().map(f).collect()),
+            None => self.set_values(&f(self.factors())),
+        }
+        self.extra = extra;
+    }
+
+    /// Número de indicadores adicionales del factor de paso
+    pub fn num_extra(&self) -> usize {
+        self.extra.len()
+    }
+
+    /// Valor de los indicadores adicionales (valor medio si varían en cada paso de cálculo)
+    ///
+    /// Extra weighting indicators, in the declared order. Timestep values are averaged.
+    pub fn extra(&self) -> Vec<f32> {
+        self.extra
+            .iter()
+            .map(|v| v.iter().sum::<f32>() / v.len().max(1) as f32)
+            .collect()
+    }
+
+    /// Establece valores constantes para los indicadores adicionales
+    ///
+    /// Set constant values for the extra weighting indicators, in the declared order
+    pub fn set_extra(&mut self, extra: &[f32]) {
+        self.extra = extra.iter().map(|&v| vec![v]).collect();
+    }
+
+    /// Factor de paso del indicador adicional `idx`
+    ///
+    /// Weighting factor that holds the value of the extra indicator `idx` as renewable primary energy (ren),
+    /// with null nren and co2 values, keeping the timestep values, if any. Since the energy balance is linear
+    /// in the weighting factors, the balance with these factors gives the balance of the indicator.
+    /// Undefined indicators take a null value.
+    pub fn indicator(&self, idx: usize) -> Factor {
+        let mut factor = Factor::new(
+            self.carrier,
+            self.source,
+            self.dest,
+            self.step,
+            RenNrenCo2::default(),
+            self.comment.clone(),
+        );
+        match self.extra.get(idx).map(Vec::as_slice) {
+            None | Some([]) => (),
+            Some([value]) => factor.ren = *value,
+            Some(values) => factor.set_values_t(
+                values
+                    .iter()
+                    .map(|&v| RenNrenCo2::new(v, 0.0, 0.0))
+                    .collect(),
+            ),
+        }
+        factor
+    }
+
+    /// Representación del factor de paso con `num_extra` indicadores adicionales en cada paso
+    ///
+    /// String representation of the factor, writing `num_extra` extra indicators for each value.
+    /// Undefined indicators are written as null values.
+    pub fn to_string_with_extra(&self, num_extra: usize) -> String {
         let comment = if self.comment != "" {
             format!(" # {}", self.comment)
         } else {
             "".to_owned()
         };
-        write!(
-            f,
-            "{}, {}, {}, {}, {:.3}, {:.3}, {:.3}{}",
-            self.carrier, self.source, self.dest, self.step, self.ren, self.nren, self.co2, comment
+        let fmt_value = |t: usize, v: &RenNrenCo2| {
+            std::iter::once(format!("{:.3}, {:.3}, {:.3}", v.ren, v.nren, v.co2))
+                .chain((0..num_extra).map(|idx| {
+                    let value = match self.extra.get(idx).map(Vec::as_slice) {
+                        None | Some([]) => 0.0,
+                        Some([value]) => *value,
+                        Some(values) => values.get(t).cloned().unwrap_or_default(),
+                    };
+                    format!("{:.3}", value)
+                }))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let values = match &self.values {
+            None => fmt_value(0, &self.factors()),
+            Some(values) => values
+                .iter()
+                .enumerate()
+                .map(|(t, v)| fmt_value(t, v))
+                .collect::<Vec<_>>()
+                .join(", "),
+        };
+        format!(
+            "{}, {}, {}, {}, {}{}",
+            self.carrier, self.source, self.dest, self.step, values, comment
         )
     }
-}
 
-impl str::FromStr for Factor {
-    type Err = EpbdError;
-
-    fn from_str(s: &str) -> Result<Factor, Self::Err> {
+    /// Interpreta un factor de paso con `num_extra` indicadores adicionales en cada paso
+    ///
+    /// Parse a weighting factor with `num_extra` extra indicators after the ren, nren and co2 values,
+    /// either constant (ren, nren, co2, extra_1, ...) or for each timestep (ren_1, nren_1, co2_1, extra_1_1, ..., ren_2, ...)
+    pub fn from_str_with_extra(s: &str, num_extra: usize) -> Result<Factor, EpbdError> {
+        let num_values = 3 + num_extra;
         let items: Vec<&str> = s.trim().splitn(2, '#').map(str::trim).collect();
         let comment = items.get(1).unwrap_or(&"").to_string();
         let items: Vec<&str> = items[0].split(',').map(str::trim).collect();
-        if items.len() < 7 {
+        // Valores constantes (ren, nren, co2) o para cada paso de cálculo (ren_1, nren_1, co2_1, ren_2, ...)
+        if items.len() < 4 + num_values
+            || items[4..].chunks(num_values).any(|v| v.len() != num_values)
+        {
             return Err(EpbdError::ParseError(s.into()));
         };
         let carrier: Carrier = items[0]
@@ -552,19 +897,75 @@ impl str::FromStr for Factor {
         let step: Step = items[3]
             .parse()
             .map_err(|_| EpbdError::ParseError(items[3].into()))?;
-        let ren: f32 = items[4].parse()?;
-        let nren: f32 = items[5].parse()?;
-        let co2: f32 = items[6].parse()?;
-        Ok(Factor {
-            carrier,
-            source,
-            dest,
-            step,
-            ren,
-            nren,
-            co2,
-            comment,
-        })
+        let chunks: Vec<&[&str]> = items[4..].chunks(num_values).collect();
+        let values = chunks
+            .iter()
+            .map(|v| Ok(RenNrenCo2::new(v[0].parse()?, v[1].parse()?, v[2].parse()?)))
+            .collect::<Result<Vec<RenNrenCo2>, EpbdError>>()?;
+        let extra = (0..num_extra)
+            .map(|idx| {
+                chunks
+                    .iter()
+                    .map(|v| v[3 + idx].parse::<f32>())
+                    .collect::<Result<Vec<f32>, _>>()
+            })
+            .collect::<Result<Vec<Vec<f32>>, _>>()?;
+        let mut factor = if let [value] = values.as_slice() {
+            Factor::new(carrier, source, dest, step, *value, comment)
+        } else {
+            Factor::new_series(carrier, source, dest, step, values, comment)
+        };
+        factor.extra = extra;
+        Ok(factor)
+    }
+
+    /// ¿Son los factores constantes en todos los pasos de cálculo?
+    pub fn is_constant(&self) -> bool {
+        self.values.is_none()
+    }
+
+    /// Factores de paso de cada paso de cálculo, si no son constantes
+    ///
+    /// Weighting factors for each timestep, as declared, or None for constant factors
+    pub fn values_t(&self) -> Option<&[RenNrenCo2]> {
+        self.values.as_deref()
+    }
+
+    /// Factores de paso para cada paso de cálculo
+    ///
+    /// Weighting factors for each of the `num_steps` timesteps
+    ///
+    /// # Errors
+    ///
+    /// * The number of timestep values does not match the number of timesteps
+    pub fn factors_t(&self, num_steps: usize) -> Result<Vec<RenNrenCo2>, EpbdError> {
+        match &self.values {
+            None => Ok(vec![self.factors(); num_steps]),
+            Some(values) if values.len() == num_steps => Ok(values.clone()),
+            Some(values) => Err(EpbdError::WrongInput(format!(
+                "El número de valores del factor de paso '{}, {}, {}, {}' ({}) no coincide con el de pasos de cálculo ({})",
+                self.carrier,
+                self.source,
+                self.dest,
+                self.step,
+                values.len(),
+                num_steps
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Factor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_string_with_extra(self.num_extra()))
+    }
+}
+
+impl str::FromStr for Factor {
+    type Err = EpbdError;
+
+    fn from_str(s: &str) -> Result<Factor, Self::Err> {
+        Factor::from_str_with_extra(s, 0)
     }
 }
 
@@ -620,6 +1021,17 @@ mod tests {
                 .to_string(),
             component2str
         );
+
+        // combinaciones de tipo, subtipo y vector, comunes al análisis y a la validación de componentes
+        use CSubtype::*;
+        use CType::*;
+        assert!(Component::check_kind(CONSUMO, NEPB, Carrier::GASNATURAL).is_ok());
+        assert!(Component::check_kind(PRODUCCION, COGENERACION, Carrier::RED1).is_ok());
+        assert!(Component::check_kind(CONSUMO, INSITU, Carrier::ELECTRICIDAD).is_err());
+        assert!(Component::check_kind(PRODUCCION, INSITU, Carrier::GASNATURAL).is_err());
+        assert!("GASNATURAL, PRODUCCION, INSITU, NDEF, 1.0"
+            .parse::<Component>()
+            .is_err());
     }
 
     #[test]
@@ -632,6 +1044,8 @@ mod tests {
             ren: 0.414,
             nren: 1.954,
             co2: 0.331,
+            values: None,
+            extra: Vec::new(),
             comment: "Electricidad de red paso A".into(),
         };
         let factor1str =
@@ -646,5 +1060,110 @@ mod tests {
             factor2str.parse::<Component>().unwrap().to_string(),
             factor2str
         );
+        // factors for each timestep
+        let factor3str = "ELECTRICIDAD, RED, SUMINISTRO, A, 0.400, 2.000, 0.300, 0.600, 1.000, 0.100 # Electricidad de red horaria";
+        let factor3 = factor3str.parse::<Factor>().unwrap();
+        assert!(!factor3.is_constant());
+        assert_eq!(factor3.values_t().map(|v| v.len()), Some(2));
+        assert!(factor1str.parse::<Factor>().unwrap().values_t().is_none());
+        assert_eq!(factor3.to_string(), factor3str);
+        assert_eq!(
+            factor3.factors().to_string(),
+            "{ ren: 0.500, nren: 1.500, co2: 0.200 }"
+        );
+        assert_eq!(
+            factor3.factors_t(2).unwrap(),
+            vec![
+                RenNrenCo2::new(0.4, 2.0, 0.3),
+                RenNrenCo2::new(0.6, 1.0, 0.1)
+            ]
+        );
+        assert!(factor3.factors_t(12).is_err());
+        assert!(
+            "ELECTRICIDAD, RED, SUMINISTRO, A, 0.400, 2.000, 0.300, 0.600"
+                .parse::<Factor>()
+                .is_err()
+        );
+
+        // factor con indicadores adicionales
+        let factor4str =
+            "ELECTRICIDAD, RED, SUMINISTRO, A, 0.400, 2.000, 0.300, 0.150, 0.010 # Con coste";
+        let factor4 = Factor::from_str_with_extra(factor4str, 2).unwrap();
+        assert_eq!(factor4.factors(), RenNrenCo2::new(0.4, 2.0, 0.3));
+        assert_eq!(factor4.extra(), vec![0.15, 0.01]);
+        assert_eq!(factor4.num_extra(), 2);
+        assert_eq!(factor4.to_string(), factor4str);
+        assert_eq!(
+            factor4.to_string_with_extra(3),
+            "ELECTRICIDAD, RED, SUMINISTRO, A, 0.400, 2.000, 0.300, 0.150, 0.010, 0.000 # Con coste"
+        );
+        assert!(factor4str.parse::<Factor>().is_err());
+        let factor4_coste = factor4.indicator(0);
+        assert_eq!(factor4_coste.factors(), RenNrenCo2::new(0.15, 0.0, 0.0));
+        assert_eq!(factor4.indicator(2).factors(), RenNrenCo2::default());
+        // factor variable en cada paso con indicadores adicionales
+        let factor5str = "ELECTRICIDAD, RED, SUMINISTRO, A, 0.400, 2.000, 0.300, 0.100, 0.600, 1.000, 0.100, 0.200";
+        let factor5 = Factor::from_str_with_extra(factor5str, 1).unwrap();
+        assert!((factor5.extra()[0] - 0.15).abs() < 1e-6);
+        assert_eq!(factor5.to_string_with_extra(1), factor5str);
+        assert_eq!(
+            factor5.indicator(0).factors_t(2).unwrap(),
+            vec![
+                RenNrenCo2::new(0.1, 0.0, 0.0),
+                RenNrenCo2::new(0.2, 0.0, 0.0)
+            ]
+        );
+        // Al fijar nuevos valores se eliminan los indicadores adicionales
+        let mut factor6 = factor5.clone();
+        factor6.set_values(&RenNrenCo2::new(1.0, 0.0, 0.0));
+        assert_eq!(factor6.num_extra(), 0);
+        factor6.set_extra(&[0.5]);
+        assert_eq!(factor6.extra(), vec![0.5]);
+    }
+
+    #[test]
+    fn tusercarrier() {
+        // Nombres de vectores de usuario
+        let carrier: Carrier = "USUARIO_HIDROGENO".parse().unwrap();
+        assert_eq!(carrier, Carrier::USUARIO("HIDROGENO".parse().unwrap()));
+        assert_eq!(carrier.to_string(), "USUARIO_HIDROGENO");
+        assert_eq!(
+            "USUARIO_BIOMASA2".parse::<Carrier>().unwrap().to_string(),
+            "USUARIO_BIOMASA2"
+        );
+        assert_eq!(
+            "GASNATURAL".parse::<Carrier>().unwrap(),
+            Carrier::GASNATURAL
+        );
+        // Sin prefijo no se aceptan nombres desconocidos
+        assert!("HIDROGENO".parse::<Carrier>().is_err());
+        assert!("ELECTRICIDA".parse::<Carrier>().is_err());
+        assert!("USUARIO_hidrogeno".parse::<Carrier>().is_err());
+        assert!("USUARIO_1HIDROGENO".parse::<Carrier>().is_err());
+        assert!("USUARIO_".parse::<Carrier>().is_err());
+        // Nombres demasiado largos son un error, no se truncan
+        let err = "USUARIO_HIDROGENO_VERDE_DE_LARGO_NOMBRE"
+            .parse::<Carrier>()
+            .unwrap_err();
+        assert!(err.to_string().contains("demasiado largo"));
+        assert!("USUARIO_ABCDEFGHIJKLMNOPQRSTUVWX"
+            .parse::<Carrier>()
+            .is_ok());
+
+        // Declaración de vectores de usuario
+        let ucarrier: UserCarrier = "USUARIO_HIDROGENO, NEARBY, EXPORTABLE".parse().unwrap();
+        assert_eq!(ucarrier.carrier, carrier);
+        assert!(ucarrier.nearby);
+        assert!(ucarrier.exportable);
+        assert_eq!(
+            ucarrier.to_string(),
+            "USUARIO_HIDROGENO, NEARBY, EXPORTABLE"
+        );
+        let ucarrier: UserCarrier = "USUARIO_BIOGAS".parse().unwrap();
+        assert!(!ucarrier.nearby);
+        assert!(!ucarrier.exportable);
+        assert!("BIOGAS".parse::<UserCarrier>().is_err());
+        assert!("ELECTRICIDAD, NEARBY".parse::<UserCarrier>().is_err());
+        assert!("USUARIO_BIOGAS, LEJANO".parse::<UserCarrier>().is_err());
     }
 }
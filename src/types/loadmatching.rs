@@ -0,0 +1,233 @@
+// Copyright (c) 2018-2020  Ministerio de Fomento
+//                          Instituto de Ciencias de la Construcción Eduardo Torroja (IETcc-CSIC)
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Author(s): Rafael Villar Burke <pachi@ietcc.csic.es>,
+//            Daniel Jiménez González <dani@ietcc.csic.es>,
+//            Marta Sorribes Gil <msorribes@ietcc.csic.es>
+
+/*!
+Factor de coincidencia de cargas
+================================
+
+Tipo LoadMatching y sus traits.
+
+Define cómo se obtiene el factor de coincidencia de cargas (load matching factor, f_match_t)
+que corrige la energía producida in situ que se usa en los servicios EPB (fórmula 31 de la EN ISO 52000-1).
+*/
+
+use std::fmt;
+use std::str;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::EpbdError,
+    vecops::{vecvecmin, vecvecmul},
+};
+
+/// Exponente k de la fórmula B.32 (cálculo mensual)
+const FMATCH_B32_K: f32 = 2.0;
+
+/// Factor de coincidencia de cargas (load matching factor)
+///
+/// Se puede definir en los metadatos de los componentes con la clave `CTE_FMATCH`:
+/// - `B32`: cálculo según la fórmula B.32 del anexo B de la EN ISO 52000-1
+/// - `0.8`: valor constante para todos los pasos de cálculo
+/// - `0.8, 0.9, ...`: valores para cada paso de cálculo
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum LoadMatching {
+    /// Constant value for all timesteps
+    CONSTANTE(f32),
+    /// User defined values for each timestep
+    SERIE(Vec<f32>),
+    /// Formula B.32, in annex B of the EN ISO 52000-1 standard
+    B32,
+}
+
+impl Default for LoadMatching {
+    fn default() -> Self {
+        LoadMatching::CONSTANTE(1.0)
+    }
+}
+
+impl LoadMatching {
+    /// Factor de coincidencia de cargas para cada paso de cálculo
+    ///
+    /// Load matching factor for each timestep, to be applied as in formula 31:
+    /// E_pr_cr_used_EPus_t = f_match_t * min(E_EPus_cr_t, E_pr_cr_t)
+    ///
+    /// * `used` - energy used for EPB services in each timestep (E_EPus_cr_t)
+    /// * `produced` - energy produced on-site in each timestep (E_pr_cr_t)
+    ///
+    /// # Errors
+    ///
+    /// * Values out of the [0, 1] range or a number of values that does not match the number of timesteps
+    pub fn values(&self, used: &[f32], produced: &[f32]) -> Result<Vec<f32>, EpbdError> {
+        let num_steps = used.len();
+        let values = match self {
+            LoadMatching::CONSTANTE(value) => vec![*value; num_steps],
+            LoadMatching::SERIE(values) => {
+                if values.len() != num_steps {
+                    return Err(EpbdError::WrongInput(format!(
+                        "El número de valores del factor de coincidencia de cargas ({}) no coincide con el de pasos de cálculo ({})",
+                        values.len(),
+                        num_steps
+                    )));
+                }
+                values.clone()
+            }
+            LoadMatching::B32 => used
+                .iter()
+                .zip(produced)
+                .map(|(&used, &produced)| fmatch_b32(used, produced))
+                .collect(),
+        };
+        if values.iter().any(|v| *v < 0.0 || *v > 1.0) {
+            return Err(EpbdError::WrongInput(format!(
+                "Factor de coincidencia de cargas fuera de rango [0.00 - 1.00]: {}",
+                self
+            )));
+        }
+        Ok(values)
+    }
+
+    /// Energía producida in situ y usada en servicios EPB, en cada paso de cálculo (fórmula 31)
+    ///
+    /// Produced energy used for EPB services in each timestep (formula 31), using this load matching factor
+    ///
+    /// * `used` - energy used for EPB services in each timestep (E_EPus_cr_t)
+    /// * `produced` - energy produced on-site in each timestep (E_pr_cr_t)
+    pub fn produced_used(&self, used: &[f32], produced: &[f32]) -> Result<Vec<f32>, EpbdError> {
+        let f_match_t = self.values(used, produced)?;
+        Ok(vecvecmul(&f_match_t, &vecvecmin(used, produced)))
+    }
+}
+
+/// Factor de coincidencia de cargas según la fórmula B.32 de la EN ISO 52000-1
+///
+/// La fórmula B.32 da la fracción de la producción que se usa en servicios EPB en función de
+/// la relación x = E_pr_cr_t / E_EPus_cr_t:
+///
+/// E_pr_cr_used_EPus_t = E_pr_cr_t * (x + 1 - (x^k + 1)^(1/k)) / x
+///
+/// Aquí se expresa de forma que se aplique sobre min(E_EPus_cr_t, E_pr_cr_t) (fórmula 31).
+fn fmatch_b32(used: f32, produced: f32) -> f32 {
+    if used <= 0.0 || produced <= 0.0 {
+        return 1.0;
+    }
+    let k = FMATCH_B32_K;
+    let x = produced / used;
+    let f_pr = (x + 1.0 - (x.powf(k) + 1.0).powf(1.0 / k)) / x;
+    // Fracción de la producción -> fracción de min(E_EPus_cr_t, E_pr_cr_t)
+    f_pr * produced / used.min(produced)
+}
+
+impl fmt::Display for LoadMatching {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadMatching::CONSTANTE(value) => write!(f, "{:.2}", value),
+            LoadMatching::SERIE(values) => write!(
+                f,
+                "{}",
+                values
+                    .iter()
+                    .map(|v| format!("{:.2}", v))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            LoadMatching::B32 => write!(f, "B32"),
+        }
+    }
+}
+
+impl str::FromStr for LoadMatching {
+    type Err = EpbdError;
+
+    fn from_str(s: &str) -> Result<LoadMatching, Self::Err> {
+        let s = s.trim();
+        if s == "B32" {
+            return Ok(LoadMatching::B32);
+        }
+        let values = s
+            .split(',')
+            .map(|v| v.trim().parse::<f32>())
+            .collect::<Result<Vec<f32>, _>>()
+            .map_err(|_| {
+                EpbdError::ParseError(format!("factor de coincidencia de cargas \"{}\"", s))
+            })?;
+        match values.as_slice() {
+            [value] => Ok(LoadMatching::CONSTANTE(*value)),
+            _ => Ok(LoadMatching::SERIE(values)),
+        }
+    }
+}
+
+// ========================== Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn tloadmatching_parse() {
+        assert_eq!("B32".parse::<LoadMatching>().unwrap(), LoadMatching::B32);
+        assert_eq!(
+            "0.8".parse::<LoadMatching>().unwrap(),
+            LoadMatching::CONSTANTE(0.8)
+        );
+        assert_eq!(
+            "1.0, 0.5, 0.75".parse::<LoadMatching>().unwrap(),
+            LoadMatching::SERIE(vec![1.0, 0.5, 0.75])
+        );
+        assert!("B31".parse::<LoadMatching>().is_err());
+        assert_eq!(
+            LoadMatching::SERIE(vec![1.0, 0.5]).to_string(),
+            "1.00, 0.50"
+        );
+    }
+
+    #[test]
+    fn tloadmatching_values() {
+        let used = [10.0, 10.0, 10.0, 0.0];
+        let produced = [0.0, 10.0, 100.0, 10.0];
+        assert_eq!(
+            LoadMatching::default().values(&used, &produced).unwrap(),
+            vec![1.0; 4]
+        );
+        assert!(LoadMatching::SERIE(vec![1.0, 0.5])
+            .values(&used, &produced)
+            .is_err());
+        assert!(LoadMatching::CONSTANTE(1.5)
+            .values(&used, &produced)
+            .is_err());
+
+        let f_match_t = LoadMatching::B32.values(&used, &produced).unwrap();
+        assert_eq!(f_match_t[0], 1.0);
+        assert_eq!(format!("{:.3}", f_match_t[1]), "0.586");
+        assert_eq!(format!("{:.3}", f_match_t[2]), "0.950");
+        assert_eq!(f_match_t[3], 1.0);
+
+        let used_pr = LoadMatching::B32.produced_used(&used, &produced).unwrap();
+        assert_eq!(format!("{:.2}", used_pr[1]), "5.86");
+    }
+}
@@ -31,10 +31,32 @@ Definición de tipos básicos para el cálculo de la eficiencia energética.
 
 */
 
+mod battery;
+mod cogeneration;
 mod common;
+mod loadmatching;
+mod perimeter;
+mod rating;
 mod rennrenco2;
+mod serviceallocation;
+mod sharedproduction;
+mod tariff;
+mod timeresolution;
 mod tmeta;
+mod validation;
+mod warning;
 
+pub use battery::*;
+pub use cogeneration::*;
 pub use common::*;
+pub use loadmatching::*;
+pub use perimeter::*;
+pub use rating::*;
 pub use rennrenco2::*;
+pub use serviceallocation::*;
+pub use sharedproduction::*;
+pub use tariff::*;
+pub use timeresolution::*;
 pub use tmeta::*;
+pub use validation::*;
+pub use warning::*;
@@ -0,0 +1,118 @@
+// Copyright (c) 2018-2020  Ministerio de Fomento
+//                          Instituto de Ciencias de la Construcción Eduardo Torroja (IETcc-CSIC)
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Author(s): Rafael Villar Burke <pachi@ietcc.csic.es>,
+//            Daniel Jiménez González <dani@ietcc.csic.es>,
+//            Marta Sorribes Gil <msorribes@ietcc.csic.es>
+
+/*!
+Perímetro de evaluación
+=======================
+
+Tipo Perimeter y sus traits.
+
+Define el perímetro de evaluación de la energía renovable (EN ISO 52000-1, 9.6.6.2 y anexo B):
+distante, próximo o in situ. La energía renovable de los vectores suministrados desde fuera del perímetro
+no se contabiliza como renovable.
+*/
+
+use std::fmt;
+use std::str;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{error::EpbdError, types::Carrier};
+
+/// Vectores suministrados desde las proximidades del edificio, dentro del perímetro próximo (NEARBY)
+///
+/// Ver EN ISO 52000-1, B.23. Solo se incluye la biomasa sólida.
+pub const NEARBY_CARRIERS: [Carrier; 5] = [
+    Carrier::BIOMASA,
+    Carrier::BIOMASADENSIFICADA,
+    Carrier::RED1,
+    Carrier::RED2,
+    Carrier::MEDIOAMBIENTE,
+];
+
+/// Perímetro de evaluación de la energía renovable
+///
+/// Se registra en los metadatos de los factores de paso con la clave `CTE_PERIMETRO`:
+/// - `DISTANT` (o `DISTANTE`): perímetro distante, se consideran todas las aportaciones renovables
+/// - `NEARBY` (o `PROXIMO`): perímetro próximo, producción in situ y vectores suministrados desde las proximidades
+///   (biomasa, redes de distrito, medioambiente y vectores de usuario próximos)
+/// - `ONSITE` (o `INSITU`): perímetro in situ, solo la producción in situ y la energía del medioambiente
+///
+/// Los perímetros están ordenados de mayor a menor extensión (DISTANT < NEARBY < ONSITE).
+#[allow(non_camel_case_types)]
+#[derive(
+    Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub enum Perimeter {
+    /// Distant perimeter (all renewable contributions)
+    #[default]
+    DISTANT,
+    /// Nearby perimeter (on-site production and nearby carriers)
+    NEARBY,
+    /// On-site perimeter (on-site production and environment energy)
+    ONSITE,
+}
+
+impl fmt::Display for Perimeter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl str::FromStr for Perimeter {
+    type Err = EpbdError;
+
+    fn from_str(s: &str) -> Result<Perimeter, Self::Err> {
+        match s.trim() {
+            "DISTANT" | "DISTANTE" => Ok(Perimeter::DISTANT),
+            "NEARBY" | "PROXIMO" | "PRÓXIMO" => Ok(Perimeter::NEARBY),
+            "ONSITE" | "INSITU" => Ok(Perimeter::ONSITE),
+            _ => Err(EpbdError::ParseError(format!(
+                "perímetro de evaluación \"{}\"",
+                s.trim()
+            ))),
+        }
+    }
+}
+
+// ========================== Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn tperimeter() {
+        assert_eq!("NEARBY".parse::<Perimeter>().unwrap(), Perimeter::NEARBY);
+        assert_eq!("PROXIMO".parse::<Perimeter>().unwrap(), Perimeter::NEARBY);
+        assert_eq!(" INSITU ".parse::<Perimeter>().unwrap(), Perimeter::ONSITE);
+        assert_eq!("DISTANTE".parse::<Perimeter>().unwrap(), Perimeter::DISTANT);
+        assert!("LEJANO".parse::<Perimeter>().is_err());
+        assert_eq!(Perimeter::ONSITE.to_string(), "ONSITE");
+        assert_eq!(Perimeter::default(), Perimeter::DISTANT);
+        assert!(Perimeter::DISTANT < Perimeter::NEARBY && Perimeter::NEARBY < Perimeter::ONSITE);
+    }
+}
@@ -0,0 +1,149 @@
+// Copyright (c) 2018-2020  Ministerio de Fomento
+//                          Instituto de Ciencias de la Construcción Eduardo Torroja (IETcc-CSIC)
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Author(s): Rafael Villar Burke <pachi@ietcc.csic.es>,
+//            Daniel Jiménez González <dani@ietcc.csic.es>,
+//            Marta Sorribes Gil <msorribes@ietcc.csic.es>
+
+/*!
+Calificación energética
+=======================
+
+Tipos RatingLetter, RatingScale y EnergyRating y sus traits.
+
+Define las letras y escalas de calificación energética de los indicadores de energía primaria
+no renovable y emisiones de CO2.
+*/
+
+use std::convert::TryInto;
+use std::fmt;
+use std::str;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::EpbdError;
+
+/// Letra de la calificación energética
+#[allow(non_camel_case_types)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum RatingLetter {
+    /// Clase A
+    A,
+    /// Clase B
+    B,
+    /// Clase C
+    C,
+    /// Clase D
+    D,
+    /// Clase E
+    E,
+    /// Clase F
+    F,
+    /// Clase G
+    G,
+}
+
+impl fmt::Display for RatingLetter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl str::FromStr for RatingLetter {
+    type Err = EpbdError;
+
+    fn from_str(s: &str) -> Result<RatingLetter, Self::Err> {
+        match s.trim() {
+            "A" => Ok(RatingLetter::A),
+            "B" => Ok(RatingLetter::B),
+            "C" => Ok(RatingLetter::C),
+            "D" => Ok(RatingLetter::D),
+            "E" => Ok(RatingLetter::E),
+            "F" => Ok(RatingLetter::F),
+            "G" => Ok(RatingLetter::G),
+            _ => Err(EpbdError::ParseError(format!(
+                "letra de calificación \"{}\"",
+                s
+            ))),
+        }
+    }
+}
+
+/// Escala de calificación energética de un indicador
+///
+/// Contiene los valores límite entre letras consecutivas (A/B, B/C, C/D, D/E, E/F y F/G), en orden creciente.
+/// Se obtienen de la escala oficial de calificación para la zona climática y el tipo de edificio.
+/// P.e. (valores de ejemplo): `#META CTE_ESCALA_NREN: 20.0, 35.0, 55.0, 85.0, 170.0, 200.0`
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RatingScale(pub [f32; 6]);
+
+impl RatingScale {
+    /// Letra que corresponde al valor del indicador
+    ///
+    /// Un valor igual al límite entre dos letras recibe la letra peor
+    pub fn letter(&self, value: f32) -> RatingLetter {
+        use RatingLetter::*;
+        [A, B, C, D, E, F]
+            .iter()
+            .zip(self.0.iter())
+            .find(|(_, &limit)| value < limit)
+            .map_or(G, |(&letter, _)| letter)
+    }
+}
+
+impl str::FromStr for RatingScale {
+    type Err = EpbdError;
+
+    fn from_str(s: &str) -> Result<RatingScale, Self::Err> {
+        let err = || EpbdError::ParseError(format!("escala de calificación \"{}\"", s));
+        let values = s
+            .split(',')
+            .map(|v| v.trim().parse::<f32>())
+            .collect::<Result<Vec<f32>, _>>()
+            .map_err(|_| err())?;
+        let limits: [f32; 6] = values.as_slice().try_into().map_err(|_| err())?;
+        if limits[0] < 0.0 || limits.windows(2).any(|w| w[0] >= w[1]) {
+            return Err(err());
+        }
+        Ok(RatingScale(limits))
+    }
+}
+
+impl fmt::Display for RatingScale {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let limits = self
+            .0
+            .iter()
+            .map(|v| format!("{:.2}", v))
+            .collect::<Vec<_>>()
+            .join(", ");
+        write!(f, "{}", limits)
+    }
+}
+
+/// Calificación energética del edificio
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EnergyRating {
+    /// Rating for non renewable primary energy
+    pub nren: RatingLetter,
+    /// Rating for CO2 emissions
+    pub co2: RatingLetter,
+}
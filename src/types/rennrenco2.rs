@@ -33,12 +33,12 @@ Energy performance type as a tuple to represent energy or emission values.
 use std::fmt;
 use std::ops::{Add, AddAssign, Mul, MulAssign, Sub, SubAssign};
 
-use serde::{Serialize, Deserialize};
+use serde::{Deserialize, Serialize};
 
 use crate::error::EpbdError;
 
 /// Tupla que representa los factores de energía primaria renovable, no renovable y de emisión
-/// 
+///
 /// Energy pairs representing renewable and non renewable energy quantities or factors.
 #[derive(Debug, Copy, Clone, PartialEq, Default, Serialize, Deserialize)]
 pub struct RenNrenCo2 {
@@ -109,23 +109,15 @@ impl std::str::FromStr for RenNrenCo2 {
         let s = s.trim().trim_matches(|c| c == '(' || c == ')');
         if s.starts_with('{') {
             let mut res = RenNrenCo2::default();
-            s.trim_matches(|c| c == '{' || c == '}')
-                .split(',')
-                .map(|s| s.splitn(2, ':').map(str::trim).collect::<Vec<&str>>())
-                .for_each(|v| {
-                    let mut it = v.iter();
-                    let (key, val) = match (it.next(), it.next()) {
-                        (Some(k), Some(v)) => (*k, *v),
-                        _ => ("Error", "0.0"),
-                    };
-                    //let haskey = ["ren", "nren", "co2"].contains(&key);
-                    match (key, f32::from_str(val)) {
-                        ("ren", Ok(v)) => res.ren = v,
-                        ("nren", Ok(v)) => res.nren = v,
-                        ("co2", Ok(v)) => res.co2 = v,
-                        _ => println!("Algo malo pasa con {}", key),
-                    }
-                });
+            for item in s.trim_matches(|c| c == '{' || c == '}').split(',') {
+                let mut it = item.splitn(2, ':').map(str::trim);
+                match (it.next(), it.next().map(f32::from_str)) {
+                    (Some("ren"), Some(Ok(v))) => res.ren = v,
+                    (Some("nren"), Some(Ok(v))) => res.nren = v,
+                    (Some("co2"), Some(Ok(v))) => res.co2 = v,
+                    _ => return Err(EpbdError::ParseError(s.into())),
+                }
+            }
             Ok(res)
         } else {
             let vals = s
@@ -317,7 +309,7 @@ impl MulAssign<f32> for RenNrenCo2 {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use pretty_assertions::{assert_eq};
+    use pretty_assertions::assert_eq;
 
     #[test]
     fn add() {
@@ -434,6 +426,11 @@ mod tests {
                 .unwrap(),
             val
         );
+        // Claves desconocidas o valores no numéricos
+        assert!("{ ren: 1.000, nren: 0.000, coste: 2.000 }"
+            .parse::<RenNrenCo2>()
+            .is_err());
+        assert!("{ ren: 1.000, nren: x }".parse::<RenNrenCo2>().is_err());
     }
 
     #[test]
@@ -0,0 +1,135 @@
+// Copyright (c) 2018-2020  Ministerio de Fomento
+//                          Instituto de Ciencias de la Construcción Eduardo Torroja (IETcc-CSIC)
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Author(s): Rafael Villar Burke <pachi@ietcc.csic.es>,
+//            Daniel Jiménez González <dani@ietcc.csic.es>,
+//            Marta Sorribes Gil <msorribes@ietcc.csic.es>
+
+/*!
+Reparto de resultados por servicios
+===================================
+
+Tipos ServiceAllocation y ExportCredit y sus traits.
+
+Define el método de reparto de la energía ponderada de cada vector energético entre los servicios EPB
+(anexo E de la EN ISO 52000-1).
+*/
+
+use std::fmt;
+use std::str;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::EpbdError;
+
+/// Método de reparto de la energía ponderada entre servicios EPB (anexo E de la EN ISO 52000-1)
+///
+/// Se puede definir en los metadatos de los componentes con la clave `CTE_REPARTO_SERVICIOS`:
+/// - `INVERSO`: método inverso (E.3.6), en proporción al consumo EPB de cada servicio
+/// - `DIRECTO`: método directo, con la energía suministrada y la producción usada por cada servicio
+///   y el reparto de la exportación en proporción al consumo EPB de cada servicio (`DIRECTO, CONSUMO`)
+/// - `DIRECTO, PRODUCCION`: método directo, con el reparto de la exportación en proporción a la
+///   energía producida usada por cada servicio
+#[allow(non_camel_case_types)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum ServiceAllocation {
+    /// Reverse calculation method (E.3.6)
+    #[default]
+    INVERSO,
+    /// Forward calculation method, with the given rule for the allocation of exported energy
+    DIRECTO(ExportCredit),
+}
+
+/// Criterio de reparto entre servicios de la energía exportada (método directo)
+#[allow(non_camel_case_types)]
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ExportCredit {
+    /// In proportion to the energy used by each EPB service
+    CONSUMO,
+    /// In proportion to the produced energy used by each EPB service
+    PRODUCCION,
+}
+
+impl fmt::Display for ServiceAllocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServiceAllocation::INVERSO => write!(f, "INVERSO"),
+            ServiceAllocation::DIRECTO(credit) => write!(f, "DIRECTO, {}", credit),
+        }
+    }
+}
+
+impl fmt::Display for ExportCredit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExportCredit::CONSUMO => write!(f, "CONSUMO"),
+            ExportCredit::PRODUCCION => write!(f, "PRODUCCION"),
+        }
+    }
+}
+
+impl str::FromStr for ServiceAllocation {
+    type Err = EpbdError;
+
+    fn from_str(s: &str) -> Result<ServiceAllocation, Self::Err> {
+        let items: Vec<&str> = s.split(',').map(str::trim).collect();
+        match items.as_slice() {
+            ["INVERSO"] => Ok(ServiceAllocation::INVERSO),
+            ["DIRECTO"] | ["DIRECTO", "CONSUMO"] => {
+                Ok(ServiceAllocation::DIRECTO(ExportCredit::CONSUMO))
+            }
+            ["DIRECTO", "PRODUCCION"] => Ok(ServiceAllocation::DIRECTO(ExportCredit::PRODUCCION)),
+            _ => Err(EpbdError::ParseError(format!(
+                "reparto de resultados por servicios \"{}\"",
+                s.trim()
+            ))),
+        }
+    }
+}
+
+// ========================== Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn tserviceallocation_parse() {
+        assert_eq!(
+            "INVERSO".parse::<ServiceAllocation>().unwrap(),
+            ServiceAllocation::INVERSO
+        );
+        assert_eq!(
+            " DIRECTO ".parse::<ServiceAllocation>().unwrap(),
+            ServiceAllocation::DIRECTO(ExportCredit::CONSUMO)
+        );
+        assert_eq!(
+            "DIRECTO, PRODUCCION".parse::<ServiceAllocation>().unwrap(),
+            ServiceAllocation::DIRECTO(ExportCredit::PRODUCCION)
+        );
+        assert_eq!(
+            ServiceAllocation::DIRECTO(ExportCredit::CONSUMO).to_string(),
+            "DIRECTO, CONSUMO"
+        );
+        assert!("DIRECTO, RED".parse::<ServiceAllocation>().is_err());
+    }
+}
@@ -0,0 +1,205 @@
+// Copyright (c) 2018-2020  Ministerio de Fomento
+//                          Instituto de Ciencias de la Construcción Eduardo Torroja (IETcc-CSIC)
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Author(s): Rafael Villar Burke <pachi@ietcc.csic.es>,
+//            Daniel Jiménez González <dani@ietcc.csic.es>,
+//            Marta Sorribes Gil <msorribes@ietcc.csic.es>
+
+/*!
+Reparto de la producción común entre unidades
+=============================================
+
+Tipo SharedProductionAllocation y sus traits.
+
+Define el criterio de reparto de la producción común de un edificio (p.e. una instalación fotovoltaica
+compartida) entre las unidades que lo componen (p.e. las viviendas de un bloque).
+*/
+
+use std::fmt;
+use std::str;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::EpbdError;
+
+/// Criterio de reparto de la producción común entre unidades
+///
+/// Se puede definir en los metadatos de los componentes comunes con la clave `CTE_REPARTO_PRODUCCION_COMUN`:
+/// - `SUPERFICIE`: en proporción a la superficie de referencia de cada unidad
+/// - `CONSUMO`: en proporción al consumo EPB de cada unidad del vector producido, en cada paso de cálculo
+///   (o a la superficie, en los pasos sin consumo)
+/// - `COEFICIENTES, c_1, c_2, ...`: con coeficientes de reparto fijos para cada unidad, que suman 1.0
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum SharedProductionAllocation {
+    /// In proportion to the reference area of each unit
+    #[default]
+    SUPERFICIE,
+    /// In proportion to the EPB energy use of the produced carrier by each unit, in each timestep
+    CONSUMO,
+    /// Fixed allocation coefficients for each unit
+    COEFICIENTES(Vec<f32>),
+}
+
+impl SharedProductionAllocation {
+    /// Fracción de la producción común asignada a cada unidad, en cada paso de cálculo
+    ///
+    /// * `areas` - reference area of each unit
+    /// * `used` - EPB energy use of the produced carrier by each unit, in each timestep
+    ///
+    /// # Errors
+    ///
+    /// * Number of allocation coefficients different to the number of units or coefficients not adding up to 1.0
+    /// * Null total reference area
+    pub fn shares(&self, areas: &[f32], used: &[Vec<f32>]) -> Result<Vec<Vec<f32>>, EpbdError> {
+        let num_steps = used.first().map(Vec::len).unwrap_or(0);
+        let area_tot: f32 = areas.iter().sum();
+        if area_tot <= 0.0 {
+            return Err(EpbdError::WrongInput(
+                "La superficie de referencia total de las unidades no puede ser nula".to_string(),
+            ));
+        }
+        let area_shares: Vec<f32> = areas.iter().map(|a| a / area_tot).collect();
+        match self {
+            SharedProductionAllocation::SUPERFICIE => {
+                Ok(area_shares.iter().map(|f| vec![*f; num_steps]).collect())
+            }
+            SharedProductionAllocation::COEFICIENTES(coefs) => {
+                let coefs_tot: f32 = coefs.iter().sum();
+                if coefs.len() != areas.len() || (coefs_tot - 1.0).abs() > 0.01 {
+                    return Err(EpbdError::WrongInput(format!(
+                        "Los coeficientes de reparto de la producción común ({}) deben corresponder a las unidades ({}) y sumar 1.0",
+                        coefs.len(),
+                        areas.len()
+                    )));
+                }
+                Ok(coefs.iter().map(|f| vec![*f; num_steps]).collect())
+            }
+            SharedProductionAllocation::CONSUMO => {
+                let used_tot: Vec<f32> = (0..num_steps)
+                    .map(|t| used.iter().map(|u| u[t]).sum())
+                    .collect();
+                Ok(used
+                    .iter()
+                    .zip(&area_shares)
+                    .map(|(used_i, f_area)| {
+                        used_i
+                            .iter()
+                            .zip(&used_tot)
+                            .map(|(u, tot)| if *tot > 0.0 { u / tot } else { *f_area })
+                            .collect()
+                    })
+                    .collect())
+            }
+        }
+    }
+}
+
+impl fmt::Display for SharedProductionAllocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SharedProductionAllocation::SUPERFICIE => write!(f, "SUPERFICIE"),
+            SharedProductionAllocation::CONSUMO => write!(f, "CONSUMO"),
+            SharedProductionAllocation::COEFICIENTES(coefs) => write!(
+                f,
+                "COEFICIENTES, {}",
+                coefs
+                    .iter()
+                    .map(|c| format!("{:.3}", c))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+impl str::FromStr for SharedProductionAllocation {
+    type Err = EpbdError;
+
+    fn from_str(s: &str) -> Result<SharedProductionAllocation, Self::Err> {
+        let err =
+            || EpbdError::ParseError(format!("reparto de la producción común \"{}\"", s.trim()));
+        let items: Vec<&str> = s.split(',').map(str::trim).collect();
+        match items.as_slice() {
+            ["SUPERFICIE"] => Ok(SharedProductionAllocation::SUPERFICIE),
+            ["CONSUMO"] => Ok(SharedProductionAllocation::CONSUMO),
+            ["COEFICIENTES", coefs @ ..] if !coefs.is_empty() => coefs
+                .iter()
+                .map(|c| c.parse::<f32>())
+                .collect::<Result<Vec<f32>, _>>()
+                .map(SharedProductionAllocation::COEFICIENTES)
+                .map_err(|_| err()),
+            _ => Err(err()),
+        }
+    }
+}
+
+// ========================== Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn tsharedproduction_parse() {
+        assert_eq!(
+            "CONSUMO".parse::<SharedProductionAllocation>().unwrap(),
+            SharedProductionAllocation::CONSUMO
+        );
+        let coefs: SharedProductionAllocation = "COEFICIENTES, 0.25, 0.75".parse().unwrap();
+        assert_eq!(
+            coefs,
+            SharedProductionAllocation::COEFICIENTES(vec![0.25, 0.75])
+        );
+        assert_eq!(coefs.to_string(), "COEFICIENTES, 0.250, 0.750");
+        assert!("COEFICIENTES"
+            .parse::<SharedProductionAllocation>()
+            .is_err());
+        assert!("POTENCIA".parse::<SharedProductionAllocation>().is_err());
+    }
+
+    #[test]
+    fn tsharedproduction_shares() {
+        let areas = [100.0, 300.0];
+        let used = [vec![10.0, 0.0], vec![30.0, 0.0]];
+        assert_eq!(
+            SharedProductionAllocation::SUPERFICIE
+                .shares(&areas, &used)
+                .unwrap(),
+            vec![vec![0.25, 0.25], vec![0.75, 0.75]]
+        );
+        // Sin consumo en el segundo paso se reparte por superficie
+        let used = [vec![30.0, 0.0], vec![10.0, 0.0]];
+        assert_eq!(
+            SharedProductionAllocation::CONSUMO
+                .shares(&areas, &used)
+                .unwrap(),
+            vec![vec![0.75, 0.25], vec![0.25, 0.75]]
+        );
+        assert!(SharedProductionAllocation::COEFICIENTES(vec![0.5, 0.4])
+            .shares(&areas, &used)
+            .is_err());
+        assert!(SharedProductionAllocation::COEFICIENTES(vec![1.0])
+            .shares(&areas, &used)
+            .is_err());
+    }
+}
@@ -0,0 +1,377 @@
+// Copyright (c) 2018-2020  Ministerio de Fomento
+//                          Instituto de Ciencias de la Construcción Eduardo Torroja (IETcc-CSIC)
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Author(s): Rafael Villar Burke <pachi@ietcc.csic.es>,
+//            Daniel Jiménez González <dani@ietcc.csic.es>,
+//            Marta Sorribes Gil <msorribes@ietcc.csic.es>
+
+/*!
+Tarifas energéticas
+===================
+
+Tipo Tariff y sus traits.
+
+Define la tarifa de un vector energético (cargo fijo, precio de la energía suministrada por la red
+y compensación de la energía exportada a la red) para calcular el coste anual de la energía.
+*/
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::EpbdError,
+    types::{Carrier, Service},
+    vecops::{vecsum, vecvecsum},
+};
+
+/// Compensación de la energía exportada a la red
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum TariffCompensation {
+    /// Sin compensación de la energía exportada
+    NINGUNA,
+    /// Venta de la energía exportada a un precio [€/kWh], constante o para cada paso de cálculo (feed-in)
+    VENTA(Vec<f32>),
+    /// Compensación de la energía exportada a un precio [€/kWh], constante o para cada paso de cálculo,
+    /// limitada al coste de la energía suministrada en el periodo de cálculo (net-billing)
+    COMPENSACION(Vec<f32>),
+}
+
+/// Tarifa de un vector energético
+///
+/// Se puede definir en los metadatos de los componentes con la clave `CTE_TARIFA`, una para cada vector,
+/// indicando el vector, el cargo fijo anual [€], el precio de la energía suministrada por la red [€/kWh],
+/// constante o para cada paso de cálculo, y, opcionalmente, el precio de venta o de compensación
+/// de la energía exportada a la red [€/kWh], constante o para cada paso de cálculo.
+/// P.e.: `#META CTE_TARIFA: ELECTRICIDAD, 60.0, PRECIO, 0.20, 0.12, COMPENSACION, 0.05`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tariff {
+    /// Energy carrier
+    pub carrier: Carrier,
+    /// Fixed charges for the calculation period [€]
+    pub fixed: f32,
+    /// Price of the energy delivered by the grid [€/kWh], constant or for each timestep
+    pub prices: Vec<f32>,
+    /// Compensation of the energy exported to the grid
+    pub compensation: TariffCompensation,
+}
+
+/// Coste de la energía de un vector energético para el periodo de cálculo
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct CostForCarrier {
+    /// Fixed charges [€]
+    pub fixed: f32,
+    /// Cost of the energy delivered by the grid, in each timestep [€]
+    pub energy_t: Vec<f32>,
+    /// Cost of the energy delivered by the grid [€]
+    pub energy: f32,
+    /// Compensation of the energy exported to the grid [€]
+    pub compensation: f32,
+    /// Total cost (fixed + energy - compensation) [€]
+    pub total: f32,
+    /// Total cost, by EPB service [€]
+    pub total_byservice: HashMap<Service, f32>,
+    /// Total cost of non EPB uses [€]
+    #[serde(default)]
+    pub nepb: f32,
+}
+
+/// Coste de la energía de todos los vectores con tarifa para el periodo de cálculo
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct Costs {
+    /// Costs by carrier [€]
+    pub bycarrier: HashMap<Carrier, CostForCarrier>,
+    /// Total cost [€]
+    pub total: f32,
+    /// Total cost, by EPB service [€]
+    pub total_byservice: HashMap<Service, f32>,
+    /// Total cost of non EPB uses [€]
+    #[serde(default)]
+    pub nepb: f32,
+}
+
+impl Tariff {
+    /// Coste de la energía suministrada y exportada
+    ///
+    /// Se factura la energía medida, es decir, la suministrada por la red tanto a usos EPB como no EPB,
+    /// y la compensación por net-billing se limita al coste de esa energía.
+    /// El coste de la energía se reparte entre servicios EPB y usos no EPB en proporción a su consumo
+    /// en cada paso de cálculo y el cargo fijo y la compensación en proporción a su consumo anual.
+    ///
+    /// * `delivered` - energy delivered by the grid to EPB and non EPB uses, in each timestep
+    /// * `exported` - energy exported to the grid, in each timestep
+    /// * `used_byservice` - energy used by each EPB service, in each timestep
+    /// * `used_nepb` - energy used by non EPB uses, in each timestep
+    ///
+    /// # Errors
+    ///
+    /// * The number of prices does not match the number of timesteps
+    pub fn costs(
+        &self,
+        delivered: &[f32],
+        exported: &[f32],
+        used_byservice: &HashMap<Service, Vec<f32>>,
+        used_nepb: &[f32],
+    ) -> Result<CostForCarrier, EpbdError> {
+        let num_steps = delivered.len();
+        let prices = self.prices_t(&self.prices, num_steps)?;
+        let energy_t: Vec<f32> = delivered.iter().zip(&prices).map(|(e, p)| e * p).collect();
+        let energy = vecsum(&energy_t);
+
+        let compensation = match &self.compensation {
+            TariffCompensation::NINGUNA => 0.0,
+            TariffCompensation::VENTA(prices) => {
+                let prices = self.prices_t(prices, num_steps)?;
+                exported.iter().zip(&prices).map(|(e, p)| e * p).sum()
+            }
+            TariffCompensation::COMPENSACION(prices) => {
+                let prices = self.prices_t(prices, num_steps)?;
+                let compensation: f32 = exported.iter().zip(&prices).map(|(e, p)| e * p).sum();
+                compensation.min(energy)
+            }
+        };
+
+        // Reparto entre servicios EPB y usos no EPB según el consumo total
+        let used_t = used_byservice
+            .values()
+            .fold(vecvecsum(&vec![0.0; num_steps], used_nepb), |acc, v| {
+                vecvecsum(&acc, v)
+            });
+        let used_an = vecsum(&used_t);
+        let share = |used_srv_t: &[f32]| {
+            let energy_srv: f32 = energy_t
+                .iter()
+                .zip(used_srv_t.iter().zip(&used_t))
+                .map(|(cost, (srv, tot))| if *tot > 0.0 { cost * srv / tot } else { 0.0 })
+                .sum();
+            let f_srv = vecsum(used_srv_t) / used_an;
+            energy_srv + (self.fixed - compensation) * f_srv
+        };
+        let mut total_byservice = HashMap::new();
+        let mut nepb = 0.0;
+        if used_an > 0.0 {
+            for (service, used_srv_t) in used_byservice {
+                total_byservice.insert(*service, share(used_srv_t));
+            }
+            nepb = share(used_nepb);
+        }
+
+        Ok(CostForCarrier {
+            fixed: self.fixed,
+            energy_t,
+            energy,
+            compensation,
+            total: self.fixed + energy - compensation,
+            total_byservice,
+            nepb,
+        })
+    }
+
+    /// Precios para cada paso de cálculo, a partir de un precio constante o de un precio para cada paso
+    fn prices_t(&self, prices: &[f32], num_steps: usize) -> Result<Vec<f32>, EpbdError> {
+        match prices {
+            [price] => Ok(vec![*price; num_steps]),
+            _ if prices.len() == num_steps => Ok(prices.to_vec()),
+            _ => Err(EpbdError::WrongInput(format!(
+                "El número de precios de la tarifa de {} ({}) no coincide con el de pasos de cálculo ({})",
+                self.carrier,
+                prices.len(),
+                num_steps
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for TariffCompensation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fmt_prices = |prices: &[f32]| {
+            prices
+                .iter()
+                .map(|p| format!("{:.4}", p))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        match self {
+            TariffCompensation::NINGUNA => write!(f, "NINGUNA"),
+            TariffCompensation::VENTA(prices) => write!(f, "VENTA, {}", fmt_prices(prices)),
+            TariffCompensation::COMPENSACION(prices) => {
+                write!(f, "COMPENSACION, {}", fmt_prices(prices))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Tariff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let prices = self
+            .prices
+            .iter()
+            .map(|p| format!("{:.4}", p))
+            .collect::<Vec<_>>()
+            .join(", ");
+        write!(f, "{}, {:.2}, PRECIO, {}", self.carrier, self.fixed, prices)?;
+        if self.compensation != TariffCompensation::NINGUNA {
+            write!(f, ", {}", self.compensation)?;
+        }
+        Ok(())
+    }
+}
+
+impl str::FromStr for Tariff {
+    type Err = EpbdError;
+
+    fn from_str(s: &str) -> Result<Tariff, Self::Err> {
+        let err = || EpbdError::ParseError(format!("tarifa \"{}\"", s));
+        let items: Vec<&str> = s.split(',').map(str::trim).collect();
+        if items.len() < 4 || items[2] != "PRECIO" {
+            return Err(err());
+        }
+        let carrier: Carrier = items[0].parse().map_err(|_| err())?;
+        let fixed: f32 = items[1].parse().map_err(|_| err())?;
+        // Precios de la energía suministrada y, tras la clave VENTA o COMPENSACION, de la energía exportada
+        let comp_pos = items
+            .iter()
+            .position(|i| *i == "VENTA" || *i == "COMPENSACION");
+        let (prices, compensation) = match comp_pos {
+            Some(pos) => (&items[3..pos], Some((items[pos], &items[pos + 1..]))),
+            None => (&items[3..], None),
+        };
+        let parse_prices = |prices: &[&str]| {
+            if prices.is_empty() {
+                return Err(err());
+            }
+            prices
+                .iter()
+                .map(|p| p.parse::<f32>())
+                .collect::<Result<Vec<f32>, _>>()
+                .map_err(|_| err())
+        };
+        let prices = parse_prices(prices)?;
+        let compensation = match compensation {
+            None => TariffCompensation::NINGUNA,
+            Some(("VENTA", prices)) => TariffCompensation::VENTA(parse_prices(prices)?),
+            Some((_, prices)) => TariffCompensation::COMPENSACION(parse_prices(prices)?),
+        };
+        Ok(Tariff {
+            carrier,
+            fixed,
+            prices,
+            compensation,
+        })
+    }
+}
+
+// ========================== Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn ttariff_parse() {
+        let tariff: Tariff = "ELECTRICIDAD, 60, PRECIO, 0.2, 0.1, COMPENSACION, 0.05"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            tariff,
+            Tariff {
+                carrier: Carrier::ELECTRICIDAD,
+                fixed: 60.0,
+                prices: vec![0.2, 0.1],
+                compensation: TariffCompensation::COMPENSACION(vec![0.05]),
+            }
+        );
+        assert_eq!(
+            tariff.to_string(),
+            "ELECTRICIDAD, 60.00, PRECIO, 0.2000, 0.1000, COMPENSACION, 0.0500"
+        );
+        let tariff: Tariff = "GASNATURAL, 40, PRECIO, 0.07".parse().unwrap();
+        assert_eq!(tariff.compensation, TariffCompensation::NINGUNA);
+        assert!("GASNATURAL, 40, 0.07".parse::<Tariff>().is_err());
+        assert!("GASNATURAL, 40, PRECIO".parse::<Tariff>().is_err());
+        assert!("ELECTRICIDAD, 60, PRECIO, 0.2, VENTA"
+            .parse::<Tariff>()
+            .is_err());
+    }
+
+    #[test]
+    fn ttariff_costs() {
+        let mut used_byservice = HashMap::new();
+        used_byservice.insert(Service::CAL, vec![10.0, 0.0]);
+        used_byservice.insert(Service::ACS, vec![10.0, 10.0]);
+
+        // Precios para cada paso de cálculo y venta de la energía exportada
+        let tariff: Tariff = "ELECTRICIDAD, 30, PRECIO, 0.2, 0.1, VENTA, 0.05"
+            .parse()
+            .unwrap();
+        let costs = tariff
+            .costs(&[20.0, 0.0], &[0.0, 40.0], &used_byservice, &[0.0, 0.0])
+            .unwrap();
+        assert_eq!(costs.energy_t, vec![4.0, 0.0]);
+        assert_eq!(costs.compensation, 2.0);
+        assert_eq!(costs.total, 32.0);
+        // CAL: 2.0 + 28.0 / 3; ACS: 2.0 + 28.0 * 2 / 3
+        assert_eq!(
+            format!("{:.2}", costs.total_byservice[&Service::CAL]),
+            "11.33"
+        );
+        assert_eq!(
+            format!("{:.2}", costs.total_byservice[&Service::ACS]),
+            "20.67"
+        );
+
+        // La compensación se limita al coste de la energía suministrada
+        let tariff: Tariff = "ELECTRICIDAD, 30, PRECIO, 0.2, COMPENSACION, 0.05"
+            .parse()
+            .unwrap();
+        let costs = tariff
+            .costs(&[5.0, 0.0], &[0.0, 40.0], &used_byservice, &[0.0, 0.0])
+            .unwrap();
+        assert_eq!(costs.compensation, 1.0);
+        assert_eq!(costs.total, 30.0);
+
+        // Se factura y reparte el consumo total, incluidos los usos no EPB
+        let costs = tariff
+            .costs(&[15.0, 0.0], &[0.0, 40.0], &used_byservice, &[10.0, 10.0])
+            .unwrap();
+        assert_eq!(costs.energy, 3.0);
+        assert_eq!(costs.compensation, 2.0);
+        assert_eq!(costs.total, 31.0);
+        // CAL: 1.0 + 28.0 / 5; ACS: 1.0 + 28.0 * 2 / 5; no EPB: 1.0 + 28.0 * 2 / 5
+        assert_eq!(
+            format!("{:.2}", costs.total_byservice[&Service::CAL]),
+            "6.60"
+        );
+        assert_eq!(format!("{:.2}", costs.nepb), "12.20");
+        let total_alloc: f32 = costs.total_byservice.values().sum::<f32>() + costs.nepb;
+        assert!((total_alloc - costs.total).abs() < 1e-4);
+
+        // Número incorrecto de precios
+        let tariff: Tariff = "ELECTRICIDAD, 30, PRECIO, 0.2, 0.1, 0.1".parse().unwrap();
+        assert!(tariff
+            .costs(&[5.0, 0.0], &[0.0, 0.0], &used_byservice, &[0.0, 0.0])
+            .is_err());
+    }
+}
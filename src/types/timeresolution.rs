@@ -0,0 +1,337 @@
+// Copyright (c) 2018-2020  Ministerio de Fomento
+//                          Instituto de Ciencias de la Construcción Eduardo Torroja (IETcc-CSIC)
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Author(s): Rafael Villar Burke <pachi@ietcc.csic.es>,
+//            Daniel Jiménez González <dani@ietcc.csic.es>,
+//            Marta Sorribes Gil <msorribes@ietcc.csic.es>
+
+/*!
+Resolución temporal
+===================
+
+Tipos TimeResolution y StartDate y sus traits.
+
+Definen la duración de los pasos de cálculo de los componentes (anual, mensual, horaria o subhoraria)
+y la fecha de inicio del periodo de cálculo, que tiene una duración de un año.
+*/
+
+use std::fmt;
+use std::str;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::EpbdError;
+
+/// Resolución temporal de los pasos de cálculo
+///
+/// Se puede definir en los metadatos de los componentes con la clave `CTE_RESOLUCION`:
+/// - `ANUAL`: un paso de cálculo
+/// - `MENSUAL`: 12 pasos de cálculo
+/// - `HORARIA`: 8760 pasos de cálculo (8784 en periodos con un 29 de febrero)
+/// - `SUBHORARIA_15`: pasos de cálculo de 15 minutos (el número de minutos debe ser divisor de 60)
+#[allow(non_camel_case_types)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TimeResolution {
+    /// Annual timestep
+    ANUAL,
+    /// Monthly timesteps
+    MENSUAL,
+    /// Hourly timesteps
+    HORARIA,
+    /// Subhourly timesteps, with the given duration in minutes
+    SUBHORARIA(u32),
+}
+
+impl TimeResolution {
+    /// Número de pasos de cálculo en un periodo de cálculo de `days` días
+    pub fn num_steps(&self, days: u32) -> usize {
+        match self {
+            TimeResolution::ANUAL => 1,
+            TimeResolution::MENSUAL => 12,
+            TimeResolution::HORARIA => (days * 24) as usize,
+            TimeResolution::SUBHORARIA(minutes) => (days * 24 * 60 / minutes) as usize,
+        }
+    }
+
+    /// Resolución temporal deducida del número de pasos de cálculo
+    ///
+    /// Solo se identifican las resoluciones anual, mensual y horaria
+    pub fn from_num_steps(num_steps: usize) -> Option<Self> {
+        match num_steps {
+            1 => Some(TimeResolution::ANUAL),
+            12 => Some(TimeResolution::MENSUAL),
+            8760 | 8784 => Some(TimeResolution::HORARIA),
+            _ => None,
+        }
+    }
+
+    /// Pasos de cálculo por día (solamente resoluciones horaria y subhoraria)
+    fn steps_per_day(&self) -> Option<usize> {
+        match self {
+            TimeResolution::HORARIA => Some(24),
+            TimeResolution::SUBHORARIA(minutes) => Some((24 * 60 / minutes) as usize),
+            _ => None,
+        }
+    }
+
+    /// Índice del intervalo de la resolución `target` al que pertenece cada paso de cálculo
+    ///
+    /// Para agregar los pasos de cálculo de un periodo de cálculo que comienza en `start` a una resolución menor.
+    /// Los meses se numeran desde el mes de inicio del periodo de cálculo.
+    ///
+    /// # Errors
+    ///
+    /// * La resolución `target` no es menor que la actual
+    pub fn aggregation_bins(
+        &self,
+        target: TimeResolution,
+        start: &StartDate,
+    ) -> Result<Vec<usize>, EpbdError> {
+        let num_steps = self.num_steps(start.days_in_period());
+        let bins = match (self, target) {
+            (_, TimeResolution::ANUAL) => vec![0; num_steps],
+            (TimeResolution::MENSUAL, TimeResolution::MENSUAL) => (0..12).collect(),
+            (_, TimeResolution::MENSUAL) if self.steps_per_day().is_some() => {
+                let steps_per_day = self.steps_per_day().unwrap();
+                let month_by_day = start.month_by_day();
+                (0..num_steps)
+                    .map(|step| month_by_day[step / steps_per_day])
+                    .collect()
+            }
+            (TimeResolution::SUBHORARIA(minutes), TimeResolution::HORARIA) => (0..num_steps)
+                .map(|step| step * (*minutes as usize) / 60)
+                .collect(),
+            (TimeResolution::HORARIA, TimeResolution::HORARIA) => (0..num_steps).collect(),
+            _ => {
+                return Err(EpbdError::WrongInput(format!(
+                    "No se puede agregar la resolución temporal {} a {}",
+                    self, target
+                )))
+            }
+        };
+        Ok(bins)
+    }
+}
+
+impl fmt::Display for TimeResolution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimeResolution::SUBHORARIA(minutes) => write!(f, "SUBHORARIA_{}", minutes),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+impl str::FromStr for TimeResolution {
+    type Err = EpbdError;
+
+    fn from_str(s: &str) -> Result<TimeResolution, Self::Err> {
+        let err = || EpbdError::ParseError(format!("resolución temporal \"{}\"", s));
+        match s.trim() {
+            "ANUAL" => Ok(TimeResolution::ANUAL),
+            "MENSUAL" => Ok(TimeResolution::MENSUAL),
+            "HORARIA" => Ok(TimeResolution::HORARIA),
+            other if other.starts_with("SUBHORARIA_") => {
+                let minutes: u32 = other["SUBHORARIA_".len()..].parse().map_err(|_| err())?;
+                if minutes == 0 || minutes >= 60 || 60 % minutes != 0 {
+                    return Err(err());
+                }
+                Ok(TimeResolution::SUBHORARIA(minutes))
+            }
+            _ => Err(err()),
+        }
+    }
+}
+
+/// Fecha de inicio del periodo de cálculo
+///
+/// Se puede definir en los metadatos de los componentes con la clave `CTE_FECHA_INICIO`,
+/// con el formato `AAAA-MM-DD` (p.e. `2021-01-01`).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StartDate {
+    /// Year
+    pub year: i32,
+    /// Month (1-12)
+    pub month: u32,
+    /// Day of month (1-31)
+    pub day: u32,
+}
+
+impl Default for StartDate {
+    /// Un año sin 29 de febrero que empieza el 1 de enero
+    fn default() -> Self {
+        StartDate {
+            year: 2001,
+            month: 1,
+            day: 1,
+        }
+    }
+}
+
+impl StartDate {
+    /// Número de días del periodo de cálculo de un año que comienza en esta fecha (365 o 366)
+    pub fn days_in_period(&self) -> u32 {
+        // El periodo incluye el 29 de febrero del año de inicio o del año siguiente
+        let includes_feb29 = if self.month <= 2 {
+            is_leap_year(self.year)
+        } else {
+            is_leap_year(self.year + 1)
+        };
+        if includes_feb29 {
+            366
+        } else {
+            365
+        }
+    }
+
+    /// Índice del mes (desde el mes de inicio) de cada día del periodo de cálculo
+    fn month_by_day(&self) -> Vec<usize> {
+        let mut months = Vec::with_capacity(366);
+        let (mut year, mut month, mut day) = (self.year, self.month, self.day);
+        for _ in 0..self.days_in_period() {
+            months.push(((month + 12 - self.month) % 12) as usize);
+            day += 1;
+            if day > days_in_month(year, month) {
+                day = 1;
+                month += 1;
+                if month > 12 {
+                    month = 1;
+                    year += 1;
+                }
+            }
+        }
+        months
+    }
+}
+
+impl fmt::Display for StartDate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+impl str::FromStr for StartDate {
+    type Err = EpbdError;
+
+    fn from_str(s: &str) -> Result<StartDate, Self::Err> {
+        let err = || EpbdError::ParseError(format!("fecha de inicio \"{}\"", s));
+        let items: Vec<&str> = s.trim().split('-').collect();
+        if items.len() != 3 {
+            return Err(err());
+        }
+        let year: i32 = items[0].parse().map_err(|_| err())?;
+        let month: u32 = items[1].parse().map_err(|_| err())?;
+        let day: u32 = items[2].parse().map_err(|_| err())?;
+        if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+            return Err(err());
+        }
+        Ok(StartDate { year, month, day })
+    }
+}
+
+/// ¿Es un año bisiesto?
+fn is_leap_year(year: i32) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+/// Número de días del mes `month` (1-12) del año `year`
+fn days_in_month(year: i32, month: u32) -> u32 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// ========================== Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn ttimeresolution_parse() {
+        assert_eq!(
+            "HORARIA".parse::<TimeResolution>().unwrap(),
+            TimeResolution::HORARIA
+        );
+        assert_eq!(
+            "SUBHORARIA_15".parse::<TimeResolution>().unwrap(),
+            TimeResolution::SUBHORARIA(15)
+        );
+        assert_eq!(TimeResolution::SUBHORARIA(15).to_string(), "SUBHORARIA_15");
+        assert!("SUBHORARIA_7".parse::<TimeResolution>().is_err());
+        assert!("DIARIA".parse::<TimeResolution>().is_err());
+        assert_eq!(TimeResolution::SUBHORARIA(15).num_steps(365), 35040);
+        assert_eq!(
+            TimeResolution::from_num_steps(8784),
+            Some(TimeResolution::HORARIA)
+        );
+    }
+
+    #[test]
+    fn tstartdate() {
+        assert_eq!(
+            "2020-03-01".parse::<StartDate>().unwrap().to_string(),
+            "2020-03-01"
+        );
+        assert!("2021-02-29".parse::<StartDate>().is_err());
+        assert_eq!(StartDate::default().days_in_period(), 365);
+        assert_eq!(
+            "2020-01-01".parse::<StartDate>().unwrap().days_in_period(),
+            366
+        );
+        assert_eq!(
+            "2020-03-01".parse::<StartDate>().unwrap().days_in_period(),
+            365
+        );
+        assert_eq!(
+            "2019-07-01".parse::<StartDate>().unwrap().days_in_period(),
+            366
+        );
+    }
+
+    #[test]
+    fn taggregation_bins() {
+        let start = StartDate::default();
+        let bins = TimeResolution::HORARIA
+            .aggregation_bins(TimeResolution::MENSUAL, &start)
+            .unwrap();
+        assert_eq!(bins.len(), 8760);
+        assert_eq!(bins[31 * 24 - 1], 0);
+        assert_eq!(bins[31 * 24], 1);
+        assert_eq!(bins[8759], 11);
+
+        // El periodo comienza a mitad de mes: el primer y último días se asignan al mismo mes
+        let start: StartDate = "2021-01-15".parse().unwrap();
+        let bins = TimeResolution::HORARIA
+            .aggregation_bins(TimeResolution::MENSUAL, &start)
+            .unwrap();
+        assert_eq!(bins[0], 0);
+        assert_eq!(bins[8759], 0);
+
+        assert!(TimeResolution::MENSUAL
+            .aggregation_bins(TimeResolution::HORARIA, &start)
+            .is_err());
+    }
+}
@@ -0,0 +1,158 @@
+// Copyright (c) 2018-2020  Ministerio de Fomento
+//                          Instituto de Ciencias de la Construcción Eduardo Torroja (IETcc-CSIC)
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Author(s): Rafael Villar Burke <pachi@ietcc.csic.es>,
+//            Daniel Jiménez González <dani@ietcc.csic.es>,
+//            Marta Sorribes Gil <msorribes@ietcc.csic.es>
+
+/*!
+Informe de validación
+=====================
+
+Tipos ValidationIssue y ValidationReport y sus traits.
+
+Recogen los problemas detectados al validar un archivo de datos, localizados por línea y columna,
+para informar de todos ellos a la vez en lugar de detenerse en el primero.
+*/
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// Gravedad de un problema de validación
+#[allow(non_camel_case_types)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Severity {
+    /// Error, impide interpretar los datos
+    ERROR,
+    /// Aviso, los datos se pueden interpretar pero probablemente son incorrectos
+    AVISO,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Problema detectado en la validación de un archivo de datos
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ValidationIssue {
+    /// Gravedad del problema
+    pub severity: Severity,
+    /// Línea del archivo (empezando en 1)
+    pub line: usize,
+    /// Columna de la línea (empezando en 1), si el problema se localiza en un campo concreto
+    pub column: Option<usize>,
+    /// Descripción del problema
+    pub msg: String,
+}
+
+impl fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.column {
+            Some(column) => write!(
+                f,
+                "{}: línea {}, columna {}: {}",
+                self.severity, self.line, column, self.msg
+            ),
+            None => write!(f, "{}: línea {}: {}", self.severity, self.line, self.msg),
+        }
+    }
+}
+
+/// Informe de validación, con la lista de problemas detectados en orden de aparición
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ValidationReport {
+    /// Problemas detectados
+    pub issues: Vec<ValidationIssue>,
+}
+
+impl ValidationReport {
+    /// Añade un error en la línea y columna indicadas
+    pub fn error(&mut self, line: usize, column: Option<usize>, msg: impl Into<String>) {
+        self.issues.push(ValidationIssue {
+            severity: Severity::ERROR,
+            line,
+            column,
+            msg: msg.into(),
+        });
+    }
+
+    /// Añade un aviso en la línea y columna indicadas
+    pub fn warning(&mut self, line: usize, column: Option<usize>, msg: impl Into<String>) {
+        self.issues.push(ValidationIssue {
+            severity: Severity::AVISO,
+            line,
+            column,
+            msg: msg.into(),
+        });
+    }
+
+    /// Errores detectados
+    pub fn errors(&self) -> impl Iterator<Item = &ValidationIssue> {
+        self.issues.iter().filter(|i| i.severity == Severity::ERROR)
+    }
+
+    /// Avisos detectados
+    pub fn warnings(&self) -> impl Iterator<Item = &ValidationIssue> {
+        self.issues.iter().filter(|i| i.severity == Severity::AVISO)
+    }
+
+    /// Indica si no se han detectado errores (puede haber avisos)
+    pub fn is_ok(&self) -> bool {
+        self.errors().next().is_none()
+    }
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lines = self
+            .issues
+            .iter()
+            .map(|i| i.to_string())
+            .collect::<Vec<_>>()
+            .join("\n");
+        write!(f, "{}", lines)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn tvalidationreport() {
+        let mut report = ValidationReport::default();
+        assert!(report.is_ok());
+        report.warning(2, None, "aviso");
+        assert!(report.is_ok());
+        report.error(3, Some(14), "error");
+        assert!(!report.is_ok());
+        assert_eq!(report.errors().count(), 1);
+        assert_eq!(report.warnings().count(), 1);
+        assert_eq!(
+            report.to_string(),
+            "AVISO: línea 2: aviso\nERROR: línea 3, columna 14: error"
+        );
+    }
+}
@@ -0,0 +1,115 @@
+// Copyright (c) 2018-2020  Ministerio de Fomento
+//                          Instituto de Ciencias de la Construcción Eduardo Torroja (IETcc-CSIC)
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Author(s): Rafael Villar Burke <pachi@ietcc.csic.es>,
+//            Daniel Jiménez González <dani@ietcc.csic.es>,
+//            Marta Sorribes Gil <msorribes@ietcc.csic.es>
+
+/*!
+Avisos del cálculo
+==================
+
+Tipos Warning y WarningCode y sus traits.
+
+Registran las correcciones automáticas de los datos de entrada (componentes y factores de paso)
+que se aplican durante el cálculo, para que se puedan revisar los cambios respecto a los datos declarados,
+y los resultados que no se pueden evaluar con los datos declarados.
+*/
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// Tipo de corrección automática de los datos de entrada
+#[allow(non_camel_case_types)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WarningCode {
+    /// Producción de MEDIOAMBIENTE añadida para equilibrar el consumo de un servicio
+    PRODUCCION_MEDIOAMBIENTE,
+    /// Consumo EPB de un servicio no declarado como EPB, considerado como consumo no EPB
+    CONSUMO_NEPB,
+    /// Factor de paso no definido, deducido de otros factores o de los valores por defecto
+    FACTOR_DEDUCIDO,
+    /// Factor de paso definido cuyos valores se sustituyen
+    FACTOR_MODIFICADO,
+    /// Factor de paso definido que se elimina por no usarse
+    FACTOR_ELIMINADO,
+    /// Fracción renovable en el perímetro próximo no evaluada por una definición incorrecta de los vectores próximos
+    RER_PROXIMO_NO_EVALUADO,
+}
+
+impl fmt::Display for WarningCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Aviso de una corrección automática de los datos de entrada
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Warning {
+    /// Tipo de corrección
+    pub code: WarningCode,
+    /// Descripción de la corrección
+    pub msg: String,
+    /// Componente o factor de paso afectado, si lo hay, tras la corrección (o antes, si se elimina)
+    pub item: Option<String>,
+}
+
+impl Warning {
+    /// Constructor
+    pub fn new<T: Into<String>>(code: WarningCode, msg: T, item: Option<String>) -> Self {
+        Self {
+            code,
+            msg: msg.into(),
+            item,
+        }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.item {
+            Some(item) => write!(f, "{}: {} ({})", self.code, self.msg, item),
+            None => write!(f, "{}: {}", self.code, self.msg),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn twarning() {
+        let warning = Warning::new(
+            WarningCode::FACTOR_ELIMINADO,
+            "Factor de paso no usado",
+            Some("GLP, RED, SUMINISTRO, A, 0.030, 1.201, 0.254".into()),
+        );
+        assert_eq!(
+            warning.to_string(),
+            "FACTOR_ELIMINADO: Factor de paso no usado (GLP, RED, SUMINISTRO, A, 0.030, 1.201, 0.254)"
+        );
+        let warning = Warning::new(WarningCode::CONSUMO_NEPB, "Consumo no EPB", None);
+        assert_eq!(warning.to_string(), "CONSUMO_NEPB: Consumo no EPB");
+    }
+}
@@ -39,7 +39,10 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
     error::EpbdError,
-    types::{CSubtype, Carrier, Dest, Factor, Meta, MetaVec, RenNrenCo2, Source, Step},
+    types::{
+        CSubtype, Carrier, CogenWFactors, Cogeneration, Dest, Factor, Meta, MetaVec, Perimeter,
+        RenNrenCo2, Source, Step, UserCarrier, Warning, WarningCode, NEARBY_CARRIERS,
+    },
     Components,
 };
 
@@ -54,9 +57,282 @@ pub struct Factors {
     pub wmeta: Vec<Meta>,
     /// Metadata
     pub wdata: Vec<Factor>,
+    /// Automatic corrections of the declared weighting factors (see `normalize` and `strip`)
+    #[serde(skip)]
+    pub warnings: Vec<Warning>,
 }
 
 impl Factors {
+    /// Nombres de los indicadores adicionales de ponderación (clave `CTE_INDICADORES`)
+    ///
+    /// Names of the extra weighting indicators (e.g. `COSTE, CO2E_CH4, CO2E_N2O`), in the order
+    /// of their values after the ren, nren and co2 values of the weighting factors
+    ///
+    /// # Errors
+    ///
+    /// * Empty or repeated indicator names
+    pub fn indicators(&self) -> Result<Vec<String>, EpbdError> {
+        let names: Vec<String> = match self.get_meta("CTE_INDICADORES") {
+            None => return Ok(Vec::new()),
+            Some(names) => names.split(',').map(|n| n.trim().to_string()).collect(),
+        };
+        let unique: HashSet<_> = names.iter().collect();
+        if names.iter().any(String::is_empty) || unique.len() != names.len() {
+            return Err(EpbdError::WrongInput(format!(
+                "Indicadores adicionales vacíos o repetidos: {}",
+                names.join(", ")
+            )));
+        }
+        Ok(names)
+    }
+
+    /// Factores de paso del indicador adicional `idx`
+    ///
+    /// Weighting factors with the value of the extra indicator `idx` as renewable primary energy
+    /// and null nren and co2 values (see `Factor::indicator`)
+    pub fn indicator_wfactors(&self, idx: usize) -> Factors {
+        Factors {
+            wmeta: self.wmeta.clone(),
+            wdata: self.wdata.iter().map(|f| f.indicator(idx)).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Perímetro de evaluación de los factores de paso (clave `CTE_PERIMETRO`)
+    ///
+    /// Assessment perimeter of the weighting factors. Factors without the metadata use the distant perimeter.
+    pub fn perimeter(&self) -> Result<Perimeter, EpbdError> {
+        self.get_meta("CTE_PERIMETRO")
+            .map(|p| p.parse())
+            .unwrap_or_else(|| Ok(Perimeter::default()))
+    }
+
+    /// Vectores energéticos suministrados desde las proximidades del edificio (perímetro próximo)
+    ///
+    /// Incluye los vectores de la lista `NEARBY_CARRIERS`, los vectores de usuario declarados como próximos
+    /// y los vectores declarados en los metadatos con la clave `CTE_VECTORES_PROXIMOS`
+    /// (p.e. `#META CTE_VECTORES_PROXIMOS: GASNATURAL, RED3`).
+    ///
+    /// # Errors
+    ///
+    /// * Vectores de usuario o vectores próximos incorrectos o no declarados
+    pub fn nearby_carriers(&self) -> Result<Vec<Carrier>, EpbdError> {
+        let mut carriers = NEARBY_CARRIERS.to_vec();
+        // Vectores de usuario declarados como próximos
+        carriers.extend(
+            self.user_carriers()?
+                .iter()
+                .filter(|c| c.nearby)
+                .map(|c| c.carrier),
+        );
+        // Vectores próximos declarados en los metadatos
+        if let Some(list) = self.get_meta("CTE_VECTORES_PROXIMOS") {
+            for c in list.split(',').map(str::trim).filter(|c| !c.is_empty()) {
+                carriers.push(c.parse().map_err(|_| {
+                    EpbdError::ParseError(format!(
+                        "vector próximo \"{}\" en CTE_VECTORES_PROXIMOS",
+                        c
+                    ))
+                })?);
+            }
+        }
+        carriers.sort_unstable();
+        carriers.dedup();
+        self.check_user_carriers(&carriers)?;
+        Ok(carriers)
+    }
+
+    /// Convierte factores de paso con perímetro "distant" a factores de paso en el perímetro indicado.
+    ///
+    /// Los factores con origen fuera del perímetro cambian sus factores de paso
+    /// de forma que ren' = 0 y nren' = ren + nren, es decir, la energía renovable suministrada desde fuera
+    /// del perímetro no se contabiliza como renovable y se mantiene la energía primaria total:
+    /// - perímetro distante (DISTANT): no se modifica ningún factor
+    /// - perímetro próximo (NEARBY): se mantienen los factores con origen INSITU y COGENERACION
+    ///   y los de los vectores de la lista `nearby` (ver `nearby_carriers`)
+    /// - perímetro in situ (ONSITE): se mantienen los factores con origen INSITU y COGENERACION
+    ///   y los del vector MEDIOAMBIENTE
+    ///
+    /// Se registra el perímetro en los metadatos de los factores de paso con la clave `CTE_PERIMETRO`,
+    /// que se recoge en el balance energético.
+    /// **ATENCIÓN**: ¡¡La producción eléctrica de la cogeneración entra con (factores ren:0, nren:0)!!
+    ///
+    /// # Errors
+    ///
+    /// * Perímetro de los factores de paso incorrecto o más restrictivo que el perímetro indicado
+    pub fn to_perimeter(
+        &self,
+        perimeter: Perimeter,
+        nearby: &[Carrier],
+    ) -> Result<Factors, EpbdError> {
+        let current = self.perimeter()?;
+        if current > perimeter {
+            return Err(EpbdError::WrongInput(format!(
+                "No se pueden convertir factores de paso en el perímetro {} al perímetro {}",
+                current, perimeter
+            )));
+        }
+        let onsite = [Carrier::MEDIOAMBIENTE];
+        let kept = match perimeter {
+            Perimeter::DISTANT => None,
+            Perimeter::NEARBY => Some(nearby),
+            Perimeter::ONSITE => Some(&onsite[..]),
+        };
+        Ok(self.convert_to_perimeter(perimeter, kept))
+    }
+
+    /// Convierte los factores de paso de los vectores de red no incluidos en `kept` (ren' = 0, nren' = ren + nren)
+    ///
+    /// Se convierten los factores de cada paso de cálculo, si existen, y se mantienen los indicadores adicionales.
+    pub(crate) fn convert_to_perimeter(
+        &self,
+        perimeter: Perimeter,
+        kept: Option<&[Carrier]>,
+    ) -> Factors {
+        let wdata = self
+            .wdata
+            .iter()
+            .cloned()
+            .map(|f| match kept {
+                Some(kept)
+                    if !(f.source == Source::INSITU
+                        || f.source == Source::COGENERACION
+                        || kept.contains(&f.carrier)) =>
+                {
+                    let mut f = f;
+                    f.map_values(|v| RenNrenCo2 {
+                        ren: 0.0,
+                        nren: v.ren + v.nren,
+                        ..v
+                    });
+                    f.comment = format!("Perímetro {}: {}", perimeter, f.comment);
+                    f
+                }
+                _ => f,
+            })
+            .collect();
+        let mut factors = Factors {
+            wmeta: self.wmeta.clone(),
+            wdata,
+            warnings: self.warnings.clone(),
+        };
+        factors.set_meta("CTE_PERIMETRO", &perimeter.to_string());
+        factors
+    }
+
+    /// Vectores energéticos definidos por el usuario (clave `CTE_VECTOR_USUARIO`)
+    ///
+    /// User defined energy carriers, declared in the metadata (see `UserCarrier`)
+    pub fn user_carriers(&self) -> Result<Vec<UserCarrier>, EpbdError> {
+        self.wmeta
+            .iter()
+            .filter(|m| m.key == "CTE_VECTOR_USUARIO")
+            .map(|m| m.value.parse())
+            .collect()
+    }
+
+    /// Comprueba que los vectores definidos por el usuario de la lista están declarados
+    ///
+    /// Check that the user defined carriers in the list are declared in the metadata
+    pub fn check_user_carriers<'a, I>(&self, carriers: I) -> Result<(), EpbdError>
+    where
+        I: IntoIterator<Item = &'a Carrier>,
+    {
+        let user_carriers = self.user_carriers()?;
+        for carrier in carriers {
+            if let Carrier::USUARIO(_) = carrier {
+                if !user_carriers.iter().any(|c| c.carrier == *carrier) {
+                    return Err(EpbdError::WrongInput(format!(
+                        "Vector energético de usuario {} no declarado en los factores de paso (CTE_VECTOR_USUARIO)",
+                        carrier
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Factores de paso de la electricidad y del calor de una unidad de cogeneración
+    ///
+    /// Weighting factors of cogenerated electricity and heat, from the step A supply factors
+    /// of the fuel and of grid electricity
+    ///
+    /// * `cogen` - cogeneration unit
+    ///
+    /// # Errors
+    ///
+    /// * Missing supply factors of the fuel or of grid electricity
+    /// * Wrong definition of the cogeneration unit (see `Cogeneration::wfactors`)
+    pub fn cogen_wfactors(&self, cogen: &Cogeneration) -> Result<CogenWFactors, EpbdError> {
+        let fp_grid = |carrier: Carrier| {
+            self.wdata
+                .iter()
+                .find(|f| {
+                    f.carrier == carrier
+                        && f.source == Source::RED
+                        && f.dest == Dest::SUMINISTRO
+                        && f.step == Step::A
+                })
+                .map(Factor::factors)
+                .ok_or_else(|| EpbdError::MissingFactor(format!("{}, RED, SUMINISTRO, A", carrier)))
+        };
+        cogen.wfactors(fp_grid(cogen.fuel)?, fp_grid(Carrier::ELECTRICIDAD)?)
+    }
+
+    /// Fija los factores de paso de la electricidad cogenerada exportada según el reparto de la cogeneración
+    ///
+    /// Set the step A weighting factors of exported cogenerated electricity (to the grid and to non EPB uses)
+    /// to the weighting factors of cogenerated electricity. They replace any user defined or default values.
+    pub fn set_cogen_wfactors(&mut self, fp: &CogenWFactors) {
+        let declared = self.wdata.clone();
+        self.wdata.retain(|f| {
+            !(f.carrier == Carrier::ELECTRICIDAD
+                && f.source == Source::COGENERACION
+                && (f.dest == Dest::A_RED || f.dest == Dest::A_NEPB)
+                && f.step == Step::A)
+        });
+        self.update_wfactor(
+            Carrier::ELECTRICIDAD,
+            Source::COGENERACION,
+            Dest::A_RED,
+            Step::A,
+            fp.electricity,
+            "Recursos usados para producir la electricidad cogenerada exportada a la red (reparto del combustible)",
+        );
+        self.update_wfactor(
+            Carrier::ELECTRICIDAD,
+            Source::COGENERACION,
+            Dest::A_NEPB,
+            Step::A,
+            fp.electricity,
+            "Recursos usados para producir la electricidad cogenerada exportada a usos no EPB (reparto del combustible)",
+        );
+        self.warn_wfactor_changes(
+            &declared,
+            "Factor de la electricidad cogenerada exportada obtenido del reparto del combustible (CTE_COGENERADOR)",
+        );
+    }
+
+    /// Registra como avisos los factores de paso añadidos o modificados respecto a la lista `declared`
+    fn warn_wfactor_changes(&mut self, declared: &[Factor], msg: &str) {
+        for f in &self.wdata {
+            let code = match declared.iter().find(|d| {
+                d.carrier == f.carrier
+                    && d.source == f.source
+                    && d.dest == f.dest
+                    && d.step == f.step
+            }) {
+                None => WarningCode::FACTOR_DEDUCIDO,
+                Some(d) if d.factors() != f.factors() || d.values_t() != f.values_t() => {
+                    WarningCode::FACTOR_MODIFICADO
+                }
+                Some(_) => continue,
+            };
+            self.warnings
+                .push(Warning::new(code, msg, Some(f.to_string())));
+        }
+    }
+
     /// Elimina factores no EPB de la lista de factores
     ///
     /// Remove non EPB weighting factors from the factor list
@@ -84,6 +360,29 @@ impl Factors {
         };
     }
 
+    /// Actualiza o establece los valores de un factor de paso para cada paso de cálculo
+    ///
+    /// Update or set the weighting factor values for each timestep (e.g. hourly grid electricity factors)
+    pub fn update_wfactor_series(
+        &mut self,
+        carrier: Carrier,
+        source: Source,
+        dest: Dest,
+        step: Step,
+        values: Vec<RenNrenCo2>,
+        comment: &str,
+    ) {
+        if let Some(factor) = self.wdata.iter_mut().find(|f| {
+            f.carrier == carrier && f.source == source && f.step == step && f.dest == dest
+        }) {
+            factor.set_values_t(values);
+        } else {
+            self.wdata.push(Factor::new_series(
+                carrier, source, dest, step, values, comment,
+            ));
+        };
+    }
+
     /// Asegura que existe un factor de paso. Si ya existe no se modifica
     pub fn ensure_wfactor(
         &mut self,
@@ -104,6 +403,33 @@ impl Factors {
         };
     }
 
+    /// Asegura que existe un factor de paso, con los mismos valores que el factor `base`. Si ya existe no se modifica
+    ///
+    /// Se conservan los valores para cada paso de cálculo del factor `base`, si los tiene
+    fn ensure_wfactor_from(
+        &mut self,
+        carrier: Carrier,
+        source: Source,
+        dest: Dest,
+        step: Step,
+        base: &Factor,
+        comment: &str,
+    ) {
+        if !self
+            .wdata
+            .iter()
+            .any(|f| f.carrier == carrier && f.source == source && f.step == step && f.dest == dest)
+        {
+            let mut factor = base.clone();
+            factor.carrier = carrier;
+            factor.source = source;
+            factor.dest = dest;
+            factor.step = step;
+            factor.comment = comment.to_string();
+            self.wdata.push(factor);
+        };
+    }
+
     /// Actualiza los factores definibles por el usuario (cogen_to_grid, cogen_to_nepb, red1 y red2)
     pub fn set_user_wfactors(mut self, user: UserWF<Option<RenNrenCo2>>) -> Self {
         use Carrier::{ELECTRICIDAD, RED1, RED2};
@@ -149,6 +475,10 @@ impl Factors {
     /// - asegura que factor paso A para suministro de cogeneración es 0.0 (se considera en vector original)
     /// - asegura definición de factores a la red para vectores con exportación
     /// - asegura que existe RED1 | RED2 en suministro
+    /// - asegura definición de factores de exportación de energía térmica a las redes de distrito (RED1 | RED2)
+    ///   si se definen factores para la producción in situ o por cogeneración de esos vectores
+    ///
+    /// Los factores añadidos o modificados se registran como avisos (campo `warnings`).
     ///
     /// TODO: se deberían separar algunos de estos pasos como métodos de CteFactorsExt
     pub fn normalize(mut self, defaults: &UserWF<RenNrenCo2>) -> Result<Self, EpbdError> {
@@ -159,6 +489,11 @@ impl Factors {
 
         // Vectores existentes
         let wf_carriers: HashSet<_> = self.wdata.iter().map(|f| f.carrier).collect();
+        let declared = self.wdata.clone();
+
+        // Los vectores definidos por el usuario deben estar declarados
+        let user_carriers = self.user_carriers()?;
+        self.check_user_carriers(wf_carriers.iter())?;
 
         // Asegura que existe MEDIOAMBIENTE, INSITU, SUMINISTRO, A, 1.0, 0.0
         self.update_wfactor(
@@ -235,25 +570,25 @@ impl Factors {
                             && f.step == Step::A
                             && f.dest == Dest::SUMINISTRO
                     })
-                    .map(|f| f.factors());
+                    .cloned();
 
-                if let Some(factors) = fp_a_input {
+                if let Some(factor) = fp_a_input {
                     // VECTOR, SRC, A_RED, A, ren, nren === VECTOR, SRC, SUMINISTRO, A, ren, nren
-                    self.ensure_wfactor(
+                    self.ensure_wfactor_from(
                         *c,
                         *s,
                         A_RED,
                         A,
-                        factors,
+                        &factor,
                         "Recursos usados para producir la energía exportada a la red",
                     );
                     // VECTOR, SRC, A_NEPB, A, ren, nren == VECTOR, SRC, SUMINISTRO, A, ren, nren
-                    self.ensure_wfactor(
+                    self.ensure_wfactor_from(
                         *c,
                         *s,
                         A_NEPB,
                         A,
-                        factors,
+                        &factor,
                         "Recursos usados para producir la energía exportada a usos no EPB",
                     );
                 }
@@ -291,25 +626,25 @@ impl Factors {
                         && f.dest == Dest::SUMINISTRO
                         && f.step == Step::A
                 })
-                .map(|f| f.factors());
+                .cloned();
 
-            if let Some(factors) = fp_a_red_input {
+            if let Some(factor) = fp_a_red_input {
                 // VECTOR, SRC, A_RED, B, ren, nren == VECTOR, RED, SUMINISTRO, A, ren, nren
-                self.ensure_wfactor(
+                self.ensure_wfactor_from(
                     *c,
                     *s,
                     A_RED,
                     B,
-                    factors,
+                    &factor,
                     "Recursos ahorrados a la red por la energía producida in situ y exportada a la red",
                 );
                 // VECTOR, SRC, A_NEPB, B, ren, nren == VECTOR, RED, SUMINISTRO, A, ren, nren
-                self.ensure_wfactor(
+                self.ensure_wfactor_from(
                     *c,
                     *s,
                     A_NEPB,
                     B,
-                    factors,
+                    &factor,
                     "Recursos ahorrados a la red por la energía producida in situ y exportada a usos no EPB",
                 );
             } else {
@@ -336,9 +671,110 @@ impl Factors {
             "Recursos usados para suministrar energía de la red de distrito 2 (definible por el usuario)",
         );
 
+        // Asegura que existen factores de exportación de las redes de distrito y los vectores de usuario exportables
+        // - producción in situ (p.e. campo solar térmico): se necesita VECTOR, INSITU, SUMINISTRO, A
+        // - cogeneración: se necesita VECTOR, COGENERACION, A_RED, A (energía exportada a la red)
+        // En paso B, se ahorran los recursos usados para suministrar la energía desde la red
+        let other_exp_carriers: Vec<Carrier> = [RED1, RED2]
+            .iter()
+            .cloned()
+            .chain(
+                user_carriers
+                    .iter()
+                    .filter(|c| c.exportable)
+                    .map(|c| c.carrier),
+            )
+            .collect();
+        for c in &other_exp_carriers {
+            let find_factor = |source: Source, dest: Dest| {
+                self.wdata
+                    .iter()
+                    .find(|f| {
+                        f.carrier == *c && f.source == source && f.dest == dest && f.step == A
+                    })
+                    .cloned()
+            };
+            let fp_red = find_factor(RED, SUMINISTRO)
+                .ok_or_else(|| EpbdError::MissingFactor(format!("{}, SUMINISTRO, A", c)))?;
+            let fp_insitu = find_factor(INSITU, SUMINISTRO);
+            let fp_cogen = find_factor(COGENERACION, A_RED);
+
+            if let Some(factor) = fp_insitu {
+                self.ensure_wfactor_from(
+                    *c,
+                    INSITU,
+                    A_RED,
+                    A,
+                    &factor,
+                    "Recursos usados para producir la energía exportada a la red",
+                );
+                self.ensure_wfactor_from(
+                    *c,
+                    INSITU,
+                    A_NEPB,
+                    A,
+                    &factor,
+                    "Recursos usados para producir la energía exportada a usos no EPB",
+                );
+                self.ensure_exported_wfactors_stepB(*c, INSITU, &fp_red);
+            }
+
+            if let Some(factor) = fp_cogen {
+                self.ensure_wfactor(
+                    *c,
+                    COGENERACION,
+                    SUMINISTRO,
+                    A,
+                    RenNrenCo2::new(0.0, 0.0, 0.0),
+                    "Factor de paso generado (el impacto de la cogeneración se tiene en cuenta en el vector de suministro)",
+                );
+                self.ensure_wfactor_from(
+                    *c,
+                    COGENERACION,
+                    A_NEPB,
+                    A,
+                    &factor,
+                    "Recursos usados para producir la energía exportada a usos no EPB",
+                );
+                self.ensure_exported_wfactors_stepB(*c, COGENERACION, &fp_red);
+            }
+        }
+
+        self.warn_wfactor_changes(
+            &declared,
+            "Factor de paso no definido o con valor reglamentario, completado al normalizar los factores",
+        );
         Ok(self)
     }
 
+    /// Asegura que existen los factores de paso B de la energía exportada de una red de distrito o un vector de usuario
+    ///
+    /// VECTOR, SRC, A_RED | A_NEPB, B, ren, nren == VECTOR, RED, SUMINISTRO, A, ren, nren
+    #[allow(non_snake_case)]
+    fn ensure_exported_wfactors_stepB(
+        &mut self,
+        carrier: Carrier,
+        source: Source,
+        fp_red: &Factor,
+    ) {
+        self.ensure_wfactor_from(
+            carrier,
+            source,
+            Dest::A_RED,
+            Step::B,
+            fp_red,
+            "Recursos ahorrados a la red por la energía producida in situ y exportada a la red",
+        );
+        self.ensure_wfactor_from(
+            carrier,
+            source,
+            Dest::A_NEPB,
+            Step::B,
+            fp_red,
+            "Recursos ahorrados a la red por la energía producida in situ y exportada a usos no EPB",
+        );
+    }
+
     /// Elimina factores de paso no usados en los datos de vectores energéticos.
     ///
     /// Elimina los factores:
@@ -346,7 +782,11 @@ impl Factors {
     ///  - de cogeneración si no hay cogeneración
     ///  - para exportación a usos no EPB si no se aparecen en los datos
     ///  - de electricidad in situ si no aparece una producción de ese tipo
+    ///
+    /// Los factores eliminados se registran como avisos (campo `warnings`), salvo los añadidos al normalizar
+    /// los factores, cuyos avisos se descartan.
     pub fn strip(mut self, components: &Components) -> Self {
+        let declared = self.wdata.clone();
         let wf_carriers: HashSet<_> = components.cdata.iter().map(|c| c.carrier).collect();
         // Mantenemos factores para todos los vectores usados
         self.wdata.retain(|f| wf_carriers.contains(&f.carrier));
@@ -371,6 +811,28 @@ impl Factors {
         self.wdata.retain(|f| {
             f.carrier != Carrier::ELECTRICIDAD || f.source != Source::INSITU || has_elec_insitu
         });
+        for f in &declared {
+            if self.wdata.iter().any(|g| {
+                g.carrier == f.carrier
+                    && g.source == f.source
+                    && g.dest == f.dest
+                    && g.step == f.step
+            }) {
+                continue;
+            }
+            let item = f.to_string();
+            let num_warnings = self.warnings.len();
+            self.warnings.retain(|w| {
+                w.code != WarningCode::FACTOR_DEDUCIDO || w.item.as_ref() != Some(&item)
+            });
+            if self.warnings.len() == num_warnings {
+                self.warnings.push(Warning::new(
+                    WarningCode::FACTOR_ELIMINADO,
+                    "Factor de paso no usado por los componentes energéticos",
+                    Some(item),
+                ));
+            }
+        }
         self
     }
 }
@@ -392,10 +854,16 @@ impl fmt::Display for Factors {
             .map(|v| format!("{}", v))
             .collect::<Vec<_>>()
             .join("\n");
+        // Se escriben los indicadores adicionales declarados o, en su defecto, los usados
+        let num_extra = self
+            .indicators()
+            .map(|i| i.len())
+            .unwrap_or(0)
+            .max(self.wdata.iter().map(Factor::num_extra).max().unwrap_or(0));
         let datalines = self
             .wdata
             .iter()
-            .map(|v| format!("{}", v))
+            .map(|v| v.to_string_with_extra(num_extra))
             .collect::<Vec<_>>()
             .join("\n");
         write!(f, "{}\n{}", metalines, datalines)
@@ -416,10 +884,18 @@ impl str::FromStr for Factors {
         let wmeta = metalines
             .map(|e| e.parse())
             .collect::<Result<Vec<Meta>, _>>()?;
-        let wdata = datalines
-            .map(|e| e.parse())
+        let mut factors = Factors {
+            wmeta,
+            ..Default::default()
+        };
+        // Los indicadores adicionales declarados se leen a continuación de ren, nren y co2
+        let num_extra = factors.indicators()?.len();
+        factors.wdata = datalines
+            .map(|e| Factor::from_str_with_extra(e, num_extra))
             .collect::<Result<Vec<Factor>, _>>()?;
-        Ok(Factors { wmeta, wdata })
+        // Los vectores de usuario deben estar declarados (CTE_VECTOR_USUARIO)
+        factors.check_user_carriers(factors.wdata.iter().map(|f| &f.carrier))?;
+        Ok(factors)
     }
 }
 
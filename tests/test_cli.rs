@@ -9,6 +9,17 @@ fn ejemplo_j1_loc() {
         .unwrap();
 }
 
+#[test]
+fn ejemplo_j1_resolution() {
+    assert_cli::Assert::main_binary()
+        .with_args(&["-c", "test_data/ejemploJ1_base.csv", "-l", "PENINSULA"])
+        .stdout()
+        .contains("Resolución temporal: ANUAL, pasos de cálculo: 1")
+        .stdout()
+        .contains("Resolución temporal = ANUAL")
+        .unwrap();
+}
+
 #[test]
 fn ejemplo_j1() {
     assert_cli::Assert::main_binary()
@@ -121,6 +132,22 @@ fn ejemplo_j9() {
         .unwrap();
 }
 
+#[test]
+fn ejemplo_j9_fmatch_b32() {
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            "test_data/ejemploJ9_electr.csv",
+            "-f",
+            "test_data/factores_paso_test.csv",
+            "--fmatch",
+            "B32",
+        ])
+        .stdout()
+        .contains("C_ep [kWh/m2.an]: ren = 919.1, nren = 1203.5, tot = 2122.6, RER = 0.43")
+        .unwrap();
+}
+
 #[test]
 fn ejemplo_testcarriers() {
     assert_cli::Assert::main_binary()
@@ -159,6 +186,38 @@ fn ejemplo_testcarriers_loc_nearby() {
         .unwrap();
 }
 
+#[test]
+fn cte_test_carriers_rer_perimetro() {
+    assert_cli::Assert::main_binary()
+        .with_args(&["-c", "test_data/cte_test_carriers.csv", "-l", "PENINSULA"])
+        .stdout()
+        .contains("** Fracción renovable (RER) por perímetro (distante, próximo, in situ):\nTOTAL: 0.57, 0.47, 0.47\nACS: 0.71, 0.65, 0.65")
+        .unwrap();
+}
+
+#[test]
+fn cte_test_carriers_servicios_perimetro() {
+    // Equivale a --acs_nearby
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            "test_data/cte_test_carriers.csv",
+            "-l",
+            "PENINSULA",
+            "--servicios",
+            "ACS",
+            "--perimetro",
+            "NEARBY",
+        ])
+        .stdout()
+        .contains("** Balance energético (servicios: ACS, perímetro: NEARBY)")
+        .stdout()
+        .contains("Perímetro = NEARBY")
+        .stdout()
+        .contains("C_ep [kWh/m2.an]: ren = 9.2, nren = 4.7, tot = 13.9, RER = 0.66")
+        .unwrap();
+}
+
 #[test]
 fn ejemplo_acs_demanda_ren_con_nepb() {
     assert_cli::Assert::main_binary()
@@ -204,3 +263,23 @@ fn ejemplo_acs_demanda_ren_con_nepb_con_exclusion_aux_meta() {
         .contains("Porcentaje renovable de la demanda de ACS (perímetro próximo): 96.7 [%]")
         .unwrap();
 }
+
+#[test]
+fn ejemplo_j1_biblioteca_factores() {
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            "test_data/ejemploJ1_base.csv",
+            "-l",
+            "PENINSULA",
+            "--dir_factores",
+            "test_data/factores_bibliotecas",
+            "--biblioteca_factores",
+            "EJEMPLO",
+        ])
+        .stdout()
+        .contains("Factores de paso (usuario): PENINSULA (EJEMPLO)")
+        .stdout()
+        .contains("C_ep [kWh/m2.an]: ren = 50.0, nren = 150.0, tot = 200.0, RER = 0.25")
+        .unwrap();
+}
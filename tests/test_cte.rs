@@ -127,6 +127,7 @@ fn get_energydatalist() -> Components {
                 comment: "".into(),
             },
         ],
+        ..Default::default()
     }
 }
 
@@ -625,6 +626,53 @@ fn cte_J7_Co_generator_gas_plus_gas_boiler_kexp_1() {
     ));
 }
 
+#[test]
+fn cte_J7_cogeneration_unit_allocation() {
+    // Unidad de cogeneración: 100 kWh de gas natural -> 47.4 kWh eléctricos + 40 kWh térmicos
+    let mut comps = components_from_file("test_data/ejemploJ7_cogenfuelgasboiler.csv");
+    comps.set_meta("CTE_COGENERADOR", "GASNATURAL, 100.0, 47.4, 40.0, ENERGIA");
+    let FP: Factors = TESTFPJ7.parse().unwrap();
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+
+    // Reparto por energía: 110 kWh nren del combustible -> 1.259 kWh/kWh para electricidad y calor
+    let fp_cgn = bal.cogeneration.unwrap();
+    assert_eq!(format!("{:.3}", fp_cgn.electricity.nren), "1.259");
+    assert_eq!(format!("{:.3}", fp_cgn.heat.nren), "1.259");
+    let fp_exp = bal
+        .wfactors
+        .wdata
+        .iter()
+        .find(|f| {
+            f.carrier == Carrier::ELECTRICIDAD
+                && f.source == Source::COGENERACION
+                && f.dest == Dest::A_RED
+                && f.step == Step::A
+        })
+        .unwrap();
+    assert_eq!(fp_exp.factors(), fp_cgn.electricity);
+    // La electricidad exportada (27.4 kWh) descuenta los recursos asignados a la electricidad cogenerada
+    assert_eq!(format!("{:.1}", bal.balance.A.nren), "249.3");
+
+    // Un indicador adicional con los valores de nren da el mismo balance, incluido el reparto de la cogeneración
+    let FP_nren: Factors = format!(
+        "#META CTE_INDICADORES: NREN\n{}",
+        FP.wdata
+            .iter()
+            .map(|f| format!("{}, {}", f, f.nren))
+            .collect::<Vec<_>>()
+            .join("\n")
+    )
+    .parse()
+    .unwrap();
+    let bal = energy_performance(&comps, &FP_nren, TESTKEXP, 1.0).unwrap();
+    assert_eq!(format!("{:.1}", bal.balance.extra[0].A), "249.3");
+    assert!((bal.balance.extra[0].B - bal.balance.B.nren).abs() < 0.01);
+
+    // Definición incorrecta de la unidad de cogeneración
+    comps.set_meta("CTE_COGENERADOR", "GASNATURAL, 100.0, 47.4");
+    assert!(energy_performance(&comps, &FP, TESTKEXP, 1.0).is_err());
+}
+
 #[test]
 fn cte_J8_Co_generator_biogas_plus_gas_boiler_kexp_1() {
     let comps = components_from_file("test_data/ejemploJ8_cogenbiogasboiler.csv");
@@ -671,6 +719,38 @@ fn cte_J9_electricity_monthly_kexp_1() {
     ));
 }
 
+#[test]
+fn cte_J9_electricity_monthly_fmatch() {
+    let mut comps = components_from_file("test_data/ejemploJ9_electr.csv");
+    let FP: Factors = TESTFPJ.parse().unwrap();
+    let bal_1 = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+
+    // Factor de coincidencia de cargas según fórmula B.32
+    comps.set_meta("CTE_FMATCH", "B32");
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    let bal_el = &bal.balance_cr[&Carrier::ELECTRICIDAD];
+    assert!(bal_el.f_match.iter().all(|f| *f > 0.0 && *f < 1.0));
+    let used_1: f32 = bal_1.balance_cr[&Carrier::ELECTRICIDAD]
+        .produced_used_EPus
+        .iter()
+        .sum();
+    let used: f32 = bal_el.produced_used_EPus.iter().sum();
+    assert!(used < used_1);
+    assert!(bal.balance_m2.B.nren > bal_1.balance_m2.B.nren);
+
+    // Factor de coincidencia de cargas constante
+    comps.set_meta("CTE_FMATCH", "0.5");
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    assert_eq!(
+        bal.balance_cr[&Carrier::ELECTRICIDAD].f_match,
+        vec![0.5; 12]
+    );
+
+    // Serie con número de valores incorrecto
+    comps.set_meta("CTE_FMATCH", "0.5, 0.8");
+    assert!(energy_performance(&comps, &FP, TESTKEXP, 1.0).is_err());
+}
+
 #[test]
 fn cte_test_carriers_kexp_0() {
     let comps = components_from_file("test_data/cte_test_carriers.csv");
@@ -726,8 +806,9 @@ fn cte_new_services_format() {
 #[test]
 fn cte_new_services_format_ACS() {
     // Igual que N_R09, y usamos valores por defecto en función de normalize
-    let comps =
-        components_from_file("test_data/newServicesFormat.csv").filter_by_epb_service(Service::ACS);
+    let comps = components_from_file("test_data/newServicesFormat.csv")
+        .filter_by_epb_service(Service::ACS)
+        .unwrap();
     let FP = get_ctefp_peninsula();
     let bal = energy_performance(&comps, &FP, 0.0, 217.4).unwrap();
     assert!(approx_equal(
@@ -741,13 +822,666 @@ fn cte_new_services_format_ACS() {
 }
 
 #[test]
-fn cte_force_electricity_prod_to_NDEF() {
+fn cte_filter_by_epb_service_fmatch() {
+    // El reparto de la producción entre servicios usa el mismo factor de coincidencia de cargas que el balance
+    let comps = "#META CTE_FMATCH: B32
+ELECTRICIDAD,CONSUMO,EPB,CAL,20,10,5
+ELECTRICIDAD,CONSUMO,EPB,ACS,10,10,10
+ELECTRICIDAD,PRODUCCION,INSITU,NDEF,10,20,40"
+        .parse::<Components>()
+        .unwrap()
+        .normalize();
+    let FP: Factors = TESTFPJ.parse().unwrap();
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    let used_EPus: f32 = bal.balance_cr[&Carrier::ELECTRICIDAD]
+        .produced_used_EPus
+        .iter()
+        .sum();
+
+    let used_srv: f32 = [Service::CAL, Service::ACS]
+        .iter()
+        .map(|srv| {
+            let comps_srv = comps.filter_by_epb_service(*srv).unwrap();
+            let bal_srv = energy_performance(&comps_srv, &FP, TESTKEXP, 1.0).unwrap();
+            bal_srv.balance_cr[&Carrier::ELECTRICIDAD]
+                .produced_used_EPus
+                .iter()
+                .sum::<f32>()
+        })
+        .sum();
+    assert!((used_EPus - used_srv).abs() < 0.01);
+}
+
+#[test]
+fn cte_service_allocation_forward() {
+    // Método directo: sin exportación, los resultados por servicio coinciden con los del balance filtrado
+    let comps = "#META CTE_REPARTO_SERVICIOS: DIRECTO
+ELECTRICIDAD,CONSUMO,EPB,CAL,20,10,5
+ELECTRICIDAD,CONSUMO,EPB,ACS,10,10,10
+ELECTRICIDAD,PRODUCCION,INSITU,ACS,5,5,5
+ELECTRICIDAD,PRODUCCION,INSITU,NDEF,10,10,5"
+        .parse::<Components>()
+        .unwrap()
+        .normalize();
+    let FP: Factors = TESTFPJ.parse().unwrap();
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    for srv in &[Service::CAL, Service::ACS] {
+        let comps_srv = comps.filter_by_epb_service(*srv).unwrap();
+        let bal_srv = energy_performance(&comps_srv, &FP, TESTKEXP, 1.0).unwrap();
+        assert!(approx_equal(bal_srv.balance.B, bal.balance.B_byuse[srv]));
+    }
+
+    // Exportación repartida según la producción usada por cada servicio
+    let comps = "#META CTE_REPARTO_SERVICIOS: DIRECTO, PRODUCCION
+ELECTRICIDAD,CONSUMO,EPB,CAL,10,0
+ELECTRICIDAD,CONSUMO,EPB,ACS,0,10
+ELECTRICIDAD,PRODUCCION,INSITU,NDEF,0,20"
+        .parse::<Components>()
+        .unwrap()
+        .normalize();
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    let bal_el = &bal.balance_cr[&Carrier::ELECTRICIDAD];
+    // La calefacción no usa energía producida y no recibe el efecto de la exportación
+    assert!(approx_equal(
+        bal_el.we_delivered_grid_an,
+        bal.balance.B_byuse[&Service::CAL]
+    ));
+    assert!(approx_equal(
+        bal.balance.A,
+        bal.balance.A_byuse[&Service::CAL] + bal.balance.A_byuse[&Service::ACS]
+    ));
+    assert!(approx_equal(
+        bal.balance.B,
+        bal.balance.B_byuse[&Service::CAL] + bal.balance.B_byuse[&Service::ACS]
+    ));
+
+    // Método inverso: reparto en proporción al consumo de cada servicio
+    let mut comps = comps;
+    comps.set_meta("CTE_REPARTO_SERVICIOS", "INVERSO");
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    assert!(approx_equal(
+        bal.balance.B_byuse[&Service::CAL],
+        bal.balance.B_byuse[&Service::ACS]
+    ));
+
+    // Método de reparto incorrecto
+    comps.set_meta("CTE_REPARTO_SERVICIOS", "DIRECTO, RED");
+    assert!(energy_performance(&comps, &FP, TESTKEXP, 1.0).is_err());
+}
+
+#[test]
+fn cte_district_heating_export() {
+    // Campo solar térmico que exporta a la red de distrito el excedente de producción
+    let comps = "RED1,CONSUMO,EPB,CAL,20,0
+RED1,PRODUCCION,INSITU,NDEF,10,20 # Campo solar térmico"
+        .parse::<Components>()
+        .unwrap()
+        .normalize();
+    let FP = "vector, fuente, uso, step, ren, nren, co2
+ELECTRICIDAD, RED, SUMINISTRO, A, 0.5, 2.0, 0.42
+RED1, RED, SUMINISTRO, A, 0.5, 0.5, 0.1
+RED1, INSITU, SUMINISTRO, A, 1.0, 0.0, 0.0
+RED1, COGENERACION, A_RED, A, 0.0, 1.5, 0.3"
+        .parse::<Factors>()
+        .unwrap()
+        .normalize(&CTE_USERWF)
+        .unwrap();
+    // Factores de exportación generados para la producción in situ y la cogeneración
+    for (source, dest, step) in &[
+        (Source::INSITU, Dest::A_RED, Step::B),
+        (Source::INSITU, Dest::A_NEPB, Step::A),
+        (Source::COGENERACION, Dest::SUMINISTRO, Step::A),
+        (Source::COGENERACION, Dest::A_NEPB, Step::B),
+    ] {
+        assert!(FP.wdata.iter().any(|f| f.carrier == Carrier::RED1
+            && f.source == *source
+            && f.dest == *dest
+            && f.step == *step));
+    }
+
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    let bal_red1 = &bal.balance_cr[&Carrier::RED1];
+    assert!((bal_red1.exported_grid_an - 20.0).abs() < 0.01);
+    assert!(approx_equal(
+        RenNrenCo2::new(35.0, 5.0, 1.0),
+        bal_red1.we_delivered_an
+    ));
+    assert!(approx_equal(
+        RenNrenCo2::new(20.0, 0.0, 0.0),
+        bal_red1.we_exported_an_A
+    ));
+    assert!(approx_equal(RenNrenCo2::new(15.0, 5.0, 1.0), bal.balance.A));
+    assert!(approx_equal(
+        RenNrenCo2::new(25.0, -5.0, -1.0),
+        bal.balance.B
+    ));
+
+    // Calor producido por cogeneración y exportado a la red de distrito
+    let comps = "RED1,CONSUMO,EPB,CAL,20,0
+RED1,PRODUCCION,COGENERACION,NDEF,0,10"
+        .parse::<Components>()
+        .unwrap();
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    assert!(approx_equal(
+        RenNrenCo2::new(0.0, 15.0, 3.0),
+        bal.balance_cr[&Carrier::RED1].we_exported_an_A
+    ));
+
+    // Sin factor de paso de la producción in situ no se puede evaluar la energía suministrada
+    let FP = "vector, fuente, uso, step, ren, nren, co2
+ELECTRICIDAD, RED, SUMINISTRO, A, 0.5, 2.0, 0.42
+RED1, RED, SUMINISTRO, A, 0.5, 0.5, 0.1"
+        .parse::<Factors>()
+        .unwrap()
+        .normalize(&CTE_USERWF)
+        .unwrap();
+    let comps = "RED1,CONSUMO,EPB,CAL,20,0
+RED1,PRODUCCION,INSITU,NDEF,10,0"
+        .parse::<Components>()
+        .unwrap();
+    assert!(energy_performance(&comps, &FP, TESTKEXP, 1.0).is_err());
+
+    // Solo las redes de distrito, la electricidad y el medioambiente admiten producción
+    assert!("GASNATURAL,PRODUCCION,INSITU,NDEF,10,0"
+        .parse::<Components>()
+        .is_err());
+}
+
+#[test]
+fn cte_user_carriers() {
+    // Vectores definidos por el usuario, con sus propios factores de paso
+    let FP = "#META CTE_VECTOR_USUARIO: USUARIO_HIDROGENO, NEARBY, EXPORTABLE
+#META CTE_VECTOR_USUARIO: USUARIO_BIOGAS
+vector, fuente, uso, step, ren, nren, co2
+ELECTRICIDAD, RED, SUMINISTRO, A, 0.5, 2.0, 0.42
+USUARIO_HIDROGENO, RED, SUMINISTRO, A, 0.2, 1.8, 0.3
+USUARIO_HIDROGENO, INSITU, SUMINISTRO, A, 1.0, 0.0, 0.0
+USUARIO_BIOGAS, RED, SUMINISTRO, A, 0.9, 0.1, 0.02"
+        .parse::<Factors>()
+        .unwrap()
+        .normalize(&CTE_USERWF)
+        .unwrap();
+    let user_carriers = FP.user_carriers().unwrap();
+    assert_eq!(user_carriers.len(), 2);
+    assert!(user_carriers[0].nearby && user_carriers[0].exportable);
+    assert!(!user_carriers[1].nearby && !user_carriers[1].exportable);
+    let hidrogeno: Carrier = "USUARIO_HIDROGENO".parse().unwrap();
+    let biogas: Carrier = "USUARIO_BIOGAS".parse().unwrap();
+    // Factores de exportación generados para el vector exportable
+    assert!(FP.wdata.iter().any(|f| f.carrier == hidrogeno
+        && f.source == Source::INSITU
+        && f.dest == Dest::A_RED
+        && f.step == Step::B));
+    assert!(!FP
+        .wdata
+        .iter()
+        .any(|f| f.carrier == biogas && f.source == Source::INSITU));
+
+    // Consumo, producción y exportación de un vector de usuario
+    let comps = "USUARIO_HIDROGENO,CONSUMO,EPB,CAL,20,0
+USUARIO_HIDROGENO,PRODUCCION,INSITU,NDEF,10,20 # Electrolizador
+USUARIO_BIOGAS,CONSUMO,EPB,ACS,10,10"
+        .parse::<Components>()
+        .unwrap()
+        .normalize();
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    let bal_h2 = &bal.balance_cr[&hidrogeno];
+    assert!((bal_h2.delivered_grid_an - 10.0).abs() < 0.01);
+    assert!((bal_h2.exported_grid_an - 20.0).abs() < 0.01);
+    assert!(approx_equal(
+        RenNrenCo2::new(32.0, 18.0, 3.0),
+        bal_h2.we_delivered_an
+    ));
+    assert!(approx_equal(
+        RenNrenCo2::new(18.0, 2.0, 0.4),
+        bal.balance_cr[&biogas].we_delivered_an
+    ));
+    assert!(approx_equal(
+        RenNrenCo2::new(30.0, 20.0, 3.4),
+        bal.balance.A
+    ));
+    assert!(approx_equal(
+        RenNrenCo2::new(46.0, -16.0, -2.6),
+        bal.balance.B
+    ));
+
+    // Perímetro próximo: se mantienen los factores de los vectores de usuario declarados como próximos
+    let FP_nrby = wfactors_to_nearby(&FP).unwrap();
+    let fp_red = |c: Carrier| {
+        FP_nrby
+            .wdata
+            .iter()
+            .find(|f| f.carrier == c && f.source == Source::RED && f.dest == Dest::SUMINISTRO)
+            .unwrap()
+            .factors()
+    };
+    assert!(approx_equal(
+        RenNrenCo2::new(0.2, 1.8, 0.3),
+        fp_red(hidrogeno)
+    ));
+    assert!(approx_equal(
+        RenNrenCo2::new(0.0, 1.0, 0.02),
+        fp_red(biogas)
+    ));
+
+    // Un vector no exportable no puede exportar energía
+    let comps = "USUARIO_BIOGAS,CONSUMO,EPB,CAL,10,0
+USUARIO_BIOGAS,PRODUCCION,INSITU,NDEF,10,10"
+        .parse::<Components>()
+        .unwrap()
+        .normalize();
+    assert!(energy_performance(&comps, &FP, TESTKEXP, 1.0).is_err());
+
+    // Los vectores de usuario llevan prefijo y deben estar declarados
+    assert!("BIOMASA2,CONSUMO,EPB,CAL,10,0"
+        .parse::<Components>()
+        .is_err());
+    let comps = "USUARIO_BIOMASA2,CONSUMO,EPB,CAL,10,0"
+        .parse::<Components>()
+        .unwrap();
+    assert!(energy_performance(&comps, &FP, TESTKEXP, 1.0).is_err());
+    assert!("vector, fuente, uso, step, ren, nren, co2
+ELECTRICIDAD, RED, SUMINISTRO, A, 0.5, 2.0, 0.42
+USUARIO_BIOMASA2, RED, SUMINISTRO, A, 0.9, 0.1, 0.02"
+        .parse::<Factors>()
+        .is_err());
+}
+
+#[test]
+fn cte_production_priority() {
+    let comps = "ELECTRICIDAD,CONSUMO,EPB,NDEF,10,10
+ELECTRICIDAD,PRODUCCION,INSITU,NDEF,10,0
+ELECTRICIDAD,PRODUCCION,COGENERACION,NDEF,5,10
+GASNATURAL,CONSUMO,EPB,NDEF,30,30"
+        .parse::<Components>()
+        .unwrap()
+        .normalize();
+    let FP: Factors = TESTFP.parse().unwrap();
+
+    // Sin prioridad: reparto proporcional a la producción de cada origen en cada paso de cálculo
+    // Paso 1: in situ 10 * 10 / 15 = 6.67 y cogeneración 3.33; paso 2: toda la producción de la cogeneración
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    let bal_el = &bal.balance_cr[&Carrier::ELECTRICIDAD];
+    assert!((bal_el.exported_bygen_an["INSITU1"] - 3.33).abs() < 0.01);
+    assert!((bal_el.exported_bygen_an["COGENERACION1"] - 1.67).abs() < 0.01);
+    assert!(approx_equal(
+        RenNrenCo2 {
+            ren: 3.33,
+            nren: 4.17,
+            co2: 1.37,
+        },
+        bal_el.we_exported_an_A
+    ));
+    // Mismo reparto con la producción asignada al servicio
+    let comps_srv = "ELECTRICIDAD,CONSUMO,EPB,CAL,10,10
+ELECTRICIDAD,PRODUCCION,INSITU,CAL,10,0
+ELECTRICIDAD,PRODUCCION,COGENERACION,CAL,5,10
+GASNATURAL,CONSUMO,EPB,CAL,30,30"
+        .parse::<Components>()
+        .unwrap()
+        .normalize();
+    let bal_srv = energy_performance(&comps_srv, &FP, TESTKEXP, 1.0).unwrap();
+    let bal_el_srv = &bal_srv.balance_cr[&Carrier::ELECTRICIDAD];
+    for gen in &["INSITU1", "COGENERACION1"] {
+        assert!((bal_el_srv.exported_bygen_an[*gen] - bal_el.exported_bygen_an[*gen]).abs() < 0.01);
+    }
+    assert!(approx_equal(
+        bal_el.we_exported_an_A,
+        bal_el_srv.we_exported_an_A
+    ));
+
+    // Primero la producción in situ: la exportación procede de la cogeneración
+    let mut comps_pv = comps.clone();
+    comps_pv.set_meta("CTE_PRIORIDAD_PRODUCCION", "INSITU, COGENERACION");
+    let bal = energy_performance(&comps_pv, &FP, TESTKEXP, 1.0).unwrap();
+    let bal_el = &bal.balance_cr[&Carrier::ELECTRICIDAD];
+    assert_eq!(bal_el.exported_bygen_an["INSITU1"], 0.0);
+    assert_eq!(bal_el.exported_bygen_an["COGENERACION1"], 5.0);
+    assert!(approx_equal(
+        RenNrenCo2 {
+            ren: 0.0,
+            nren: 12.5,
+            co2: 4.1,
+        },
+        bal_el.we_exported_an_A
+    ));
+
+    // Primero la cogeneración: la exportación procede de la producción in situ
+    let mut comps_cgn = comps.clone();
+    comps_cgn.set_meta("CTE_PRIORIDAD_PRODUCCION", "COGENERACION");
+    let bal = energy_performance(&comps_cgn, &FP, TESTKEXP, 1.0).unwrap();
+    let bal_el = &bal.balance_cr[&Carrier::ELECTRICIDAD];
+    assert_eq!(bal_el.exported_bygen_an["INSITU1"], 5.0);
+    assert_eq!(bal_el.exported_bygen_an["COGENERACION1"], 0.0);
+    assert!(approx_equal(
+        RenNrenCo2 {
+            ren: 5.0,
+            nren: 0.0,
+            co2: 0.0,
+        },
+        bal_el.we_exported_an_A
+    ));
+
+    // Origen incorrecto
+    let mut comps_err = comps;
+    comps_err.set_meta("CTE_PRIORIDAD_PRODUCCION", "INSITU, EPB");
+    assert!(energy_performance(&comps_err, &FP, TESTKEXP, 1.0).is_err());
+}
+
+/// Energía exportada ponderada por generador y destino (fórmulas 23-26)
+#[test]
+fn cte_weighted_exported_by_generator() {
+    let comps = "ELECTRICIDAD,CONSUMO,EPB,NDEF,10,10
+ELECTRICIDAD,PRODUCCION,INSITU,NDEF,10,0
+ELECTRICIDAD,PRODUCCION,COGENERACION,NDEF,5,10"
+        .parse::<Components>()
+        .unwrap()
+        .normalize();
+    let FP: Factors = TESTFP.parse().unwrap();
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    let bal_el = &bal.balance_cr[&Carrier::ELECTRICIDAD];
+    let we_pv = &bal_el.we_exported_bygen["INSITU1"];
+    let we_cgn = &bal_el.we_exported_bygen["COGENERACION1"];
+
+    // Toda la energía se exporta a la red
+    assert!((we_pv.exported_grid_an - 3.33).abs() < 0.01);
+    assert_eq!(we_pv.exported_nEPB_an, 0.0);
+    assert!((we_cgn.exported_grid_an - 1.67).abs() < 0.01);
+    assert_eq!(we_cgn.fpA_grid, RenNrenCo2::new(0.0, 2.5, 0.82));
+    assert_eq!(we_cgn.fpB_grid, RenNrenCo2::new(0.5, 2.0, 0.42));
+    assert!(approx_equal(
+        RenNrenCo2::new(3.33, 0.0, 0.0),
+        we_pv.we_exported_grid_an_A
+    ));
+    assert!(approx_equal(
+        RenNrenCo2::new(-1.67, 6.67, 1.4),
+        we_pv.we_exported_grid_an_AB
+    ));
+
+    // La suma de los generadores coincide con el total del vector
+    assert!(approx_equal(
+        we_pv.we_exported_grid_an_A + we_cgn.we_exported_grid_an_A,
+        bal_el.we_exported_an_A
+    ));
+    assert!(approx_equal(
+        we_pv.we_exported_grid_an_AB + we_cgn.we_exported_grid_an_AB,
+        bal_el.we_exported_grid_an_AB
+    ));
+}
+
+#[test]
+fn cte_balance_by_generator() {
+    let comps = "ELECTRICIDAD,CONSUMO,EPB,NDEF,10,10
+ELECTRICIDAD,PRODUCCION,INSITU,NDEF,10,0 # Paneles en cubierta CTEEPBD_GENERADOR=PV_CUBIERTA
+ELECTRICIDAD,PRODUCCION,INSITU,NDEF,5,10 # Paneles en fachada CTEEPBD_GENERADOR=PV_FACHADA
+ELECTRICIDAD,PRODUCCION,INSITU,NDEF,2,2"
+        .parse::<Components>()
+        .unwrap()
+        .normalize();
+    let FP: Factors = TESTFP.parse().unwrap();
+
+    let mut comps_prio = comps.clone();
+    comps_prio.set_meta("CTE_PRIORIDAD_PRODUCCION", "PV_FACHADA, INSITU");
+    let bal = energy_performance(&comps_prio, &FP, TESTKEXP, 1.0).unwrap();
+    let bal_el = &bal.balance_cr[&Carrier::ELECTRICIDAD];
+    assert_eq!(bal_el.generators.len(), 3);
+    assert_eq!(bal_el.generators["INSITU3"], CSubtype::INSITU);
+    assert_eq!(bal_el.produced_bygen_an["PV_FACHADA"], 15.0);
+    assert_eq!(bal_el.exported_bygen_an["PV_FACHADA"], 0.0);
+    // PV_CUBIERTA e INSITU3 comparten el segundo nivel de prioridad
+    assert!((bal_el.exported_bygen_an["PV_CUBIERTA"] - 5.83).abs() < 0.01);
+    assert!((bal_el.exported_bygen_an["INSITU3"] - 3.17).abs() < 0.01);
+
+    // Generador inexistente
+    let mut comps_err = comps.clone();
+    comps_err.set_meta("CTE_PRIORIDAD_PRODUCCION", "PV_TEJADO");
+    assert!(energy_performance(&comps_err, &FP, TESTKEXP, 1.0).is_err());
+
+    // Generador con distintos orígenes
+    let comps_err = "ELECTRICIDAD,CONSUMO,EPB,NDEF,10,10
+ELECTRICIDAD,PRODUCCION,INSITU,NDEF,10,0 # CTEEPBD_GENERADOR=GEN
+ELECTRICIDAD,PRODUCCION,COGENERACION,NDEF,5,10 # CTEEPBD_GENERADOR=GEN"
+        .parse::<Components>()
+        .unwrap();
+    assert!(energy_performance(&comps_err, &FP, TESTKEXP, 1.0).is_err());
+}
+
+/// Factores de paso con valores para cada paso de cálculo
+#[test]
+fn cte_balance_timestep_wfactors() {
+    let comps = "ELECTRICIDAD,CONSUMO,EPB,NDEF,10,10
+ELECTRICIDAD,PRODUCCION,INSITU,NDEF,0,20"
+        .parse::<Components>()
+        .unwrap()
+        .normalize();
+    let FP = "vector, fuente, uso, step, ren, nren
+ELECTRICIDAD, RED, SUMINISTRO, A, 0.5, 2.0, 0.4, 0.0, 1.0, 0.1
+ELECTRICIDAD, INSITU, SUMINISTRO, A, 1.0, 0.0, 0.0"
+        .parse::<Factors>()
+        .unwrap()
+        .normalize(&CTE_USERWF)
+        .unwrap();
+    // Los factores derivados conservan los valores para cada paso de cálculo
+    let fpB = FP
+        .wdata
+        .iter()
+        .find(|f| f.source == Source::INSITU && f.dest == Dest::A_RED && f.step == Step::B)
+        .unwrap();
+    assert_eq!(
+        fpB.to_string(),
+        "ELECTRICIDAD, INSITU, A_RED, B, 0.500, 2.000, 0.400, 0.000, 1.000, 0.100 # Recursos ahorrados a la red por la energía producida in situ y exportada a la red"
+    );
+
+    let bal = energy_performance(&comps, &FP, 1.0, 1.0).unwrap();
+    let bal_el = &bal.balance_cr[&Carrier::ELECTRICIDAD];
+    // Suministro de red solo en el primer paso y exportación solo en el segundo
+    assert!(approx_equal(
+        RenNrenCo2::new(25.0, 20.0, 4.0),
+        bal_el.we_delivered_an
+    ));
+    assert!(approx_equal(
+        RenNrenCo2::new(10.0, 0.0, 0.0),
+        bal_el.we_exported_an_A
+    ));
+    assert!(approx_equal(
+        RenNrenCo2::new(25.0, 10.0, 3.0),
+        bal.balance.B
+    ));
+
+    // Resultados ponderados para cada paso de cálculo
+    assert_eq!(bal_el.we_delivered.len(), 2);
+    assert!(approx_equal(
+        RenNrenCo2::new(5.0, 20.0, 4.0),
+        bal_el.we_delivered[0]
+    ));
+    assert!(approx_equal(
+        RenNrenCo2::new(20.0, 0.0, 0.0),
+        bal_el.we_delivered[1]
+    ));
+    assert!(approx_equal(
+        RenNrenCo2::new(0.0, 10.0, 1.0),
+        bal_el.we_exported[1]
+    ));
+    let sum_t = |v: &[RenNrenCo2]| v.iter().fold(RenNrenCo2::default(), |acc, e| acc + *e);
+    assert!(approx_equal(
+        sum_t(&bal_el.we_delivered),
+        bal_el.we_delivered_an
+    ));
+    assert!(approx_equal(
+        sum_t(&bal_el.we_exported_A),
+        bal_el.we_exported_an_A
+    ));
+    assert!(approx_equal(
+        sum_t(&bal_el.we_exported),
+        bal_el.we_exported_an
+    ));
+    assert!(approx_equal(sum_t(&bal.balance.B_t), bal.balance.B));
+    assert!(approx_equal(sum_t(&bal.balance.A_t), bal.balance.A));
+
+    // Número de valores distinto al de pasos de cálculo
+    let comps = "ELECTRICIDAD,CONSUMO,EPB,NDEF,10,10,10"
+        .parse::<Components>()
+        .unwrap();
+    assert!(energy_performance(&comps, &FP, 1.0, 1.0).is_err());
+}
+
+/// Resolución temporal declarada y agregación de datos horarios
+#[test]
+fn cte_time_resolution() {
+    let hourly = |num_steps: usize| vec!["1.0"; num_steps].join(",");
+    let compsstr = format!(
+        "#META CTE_RESOLUCION: HORARIA
+#META CTE_FECHA_INICIO: 2021-01-01
+ELECTRICIDAD,CONSUMO,EPB,CAL,{}",
+        hourly(8760)
+    );
+    let comps = compsstr.parse::<Components>().unwrap();
+    assert_eq!(
+        comps.time_resolution().unwrap(),
+        Some(TimeResolution::HORARIA)
+    );
+
+    // Agregación a datos mensuales y anuales
+    let comps_m = comps.aggregate(TimeResolution::MENSUAL).unwrap();
+    assert_eq!(comps_m.get_meta("CTE_RESOLUCION").unwrap(), "MENSUAL");
+    assert_eq!(comps_m.cdata[0].values.len(), 12);
+    assert_eq!(comps_m.cdata[0].values[0], 744.0);
+    assert_eq!(comps_m.cdata[0].values[1], 672.0);
+    let comps_a = comps_m.aggregate(TimeResolution::ANUAL).unwrap();
+    assert_eq!(comps_a.cdata[0].values, vec![8760.0]);
+    assert!(comps_a.aggregate(TimeResolution::MENSUAL).is_err());
+
+    // El balance informa de la resolución temporal
+    let FP: Factors = TESTFP.parse().unwrap();
+    let bal = energy_performance(&comps_m, &FP, TESTKEXP, 1.0).unwrap();
+    assert_eq!(bal.resolution, Some(TimeResolution::MENSUAL));
+
+    // Año bisiesto: 8784 pasos de cálculo
+    let compsstr = format!(
+        "#META CTE_RESOLUCION: HORARIA
+#META CTE_FECHA_INICIO: 2020-01-01
+ELECTRICIDAD,CONSUMO,EPB,CAL,{}",
+        hourly(8760)
+    );
+    assert!(compsstr.parse::<Components>().is_err());
+
+    // Número de pasos de cálculo distinto al declarado
+    assert!("#META CTE_RESOLUCION: MENSUAL
+ELECTRICIDAD,CONSUMO,EPB,CAL,1.0,2.0"
+        .parse::<Components>()
+        .is_err());
+
+    // Resolución deducida del número de pasos de cálculo
+    let comps = "ELECTRICIDAD,CONSUMO,EPB,CAL,1.0,2.0"
+        .parse::<Components>()
+        .unwrap();
+    assert_eq!(comps.time_resolution().unwrap(), None);
+    assert!(comps.aggregate(TimeResolution::ANUAL).is_err());
+}
+
+/// Almacenamiento eléctrico (batería)
+#[test]
+fn cte_balance_battery() {
+    let comps = "#META CTE_BATERIA: 20.0, 0.9, 0.9
+ELECTRICIDAD,CONSUMO,EPB,NDEF,0,10
+ELECTRICIDAD,PRODUCCION,INSITU,NDEF,10,0"
+        .parse::<Components>()
+        .unwrap()
+        .normalize();
+    let FP: Factors = TESTFP.parse().unwrap();
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    let bal_el = &bal.balance_cr[&Carrier::ELECTRICIDAD];
+    // El excedente del primer paso se almacena y se usa en el segundo
+    assert_eq!(bal_el.stored, vec![10.0, 0.0]);
+    assert_eq!(format!("{:.2}", bal_el.discharged[1]), "8.10");
+    assert_eq!(format!("{:.2}", bal_el.storage_losses_an), "1.90");
+    assert_eq!(bal_el.exported_an, 0.0);
+    assert_eq!(bal_el.exported_bygen_an["INSITU1"], 0.0);
+    assert_eq!(format!("{:.2}", bal_el.delivered_grid_an), "1.90");
+
+    // Sin batería se exporta el excedente
+    let mut comps_nobat = comps.clone();
+    comps_nobat.cmeta.clear();
+    let bal = energy_performance(&comps_nobat, &FP, TESTKEXP, 1.0).unwrap();
+    let bal_el = &bal.balance_cr[&Carrier::ELECTRICIDAD];
+    assert_eq!(bal_el.stored_an, 0.0);
+    assert_eq!(bal_el.exported_an, 10.0);
+    assert_eq!(bal_el.delivered_grid_an, 10.0);
+
+    // Definición incorrecta
+    let mut comps_err = comps;
+    comps_err.set_meta("CTE_BATERIA", "20.0, 1.5, 0.9");
+    assert!(energy_performance(&comps_err, &FP, TESTKEXP, 1.0).is_err());
+}
+
+#[test]
+fn cte_production_keeps_service() {
     let comps = "ELECTRICIDAD,CONSUMO,EPB,CAL,20
 ELECTRICIDAD,PRODUCCION,INSITU,CAL,40"
         .parse::<Components>()
         .unwrap()
         .normalize();
-    assert_eq!(comps.cdata[1].service, Service::NDEF);
+    assert_eq!(comps.cdata[1].service, Service::CAL);
+}
+
+/// La producción asignada a un servicio se usa primero en ese servicio
+#[test]
+fn cte_production_assigned_to_service() {
+    let comps = "ELECTRICIDAD,CONSUMO,EPB,ACS,10
+ELECTRICIDAD,CONSUMO,EPB,CAL,10
+ELECTRICIDAD,PRODUCCION,INSITU,ACS,10"
+        .parse::<Components>()
+        .unwrap()
+        .normalize();
+    let prod_an = |c: &Components| -> f32 {
+        c.cdata
+            .iter()
+            .filter(|c| c.ctype == CType::PRODUCCION)
+            .map(|c| c.values.iter().sum::<f32>())
+            .sum()
+    };
+    let comps_acs = comps.filter_by_epb_service(Service::ACS).unwrap();
+    assert_eq!(format!("{:.2}", prod_an(&comps_acs)), "10.00");
+    let comps_cal = comps.filter_by_epb_service(Service::CAL).unwrap();
+    assert_eq!(format!("{:.2}", prod_an(&comps_cal)), "0.00");
+
+    let FP: Factors = TESTFP.parse().unwrap();
+    let fraccion_ren_acs = fraccion_renovable_acs_nrb(&comps, &FP, 10.0).unwrap();
+    assert_eq!(format!("{:.2}", fraccion_ren_acs), "1.00");
+
+    // Generador asignado a ACS y generador genérico: el sobrante del generador asignado (10 - 5 = 5) se reparte
+    // con la producción genérica (10), en proporción a la producción no usada por el servicio asignado
+    let prod = |c: &Components| -> Vec<String> {
+        c.cdata
+            .iter()
+            .filter(|c| c.ctype == CType::PRODUCCION)
+            .map(|c| format!("{:.2}", c.values.iter().sum::<f32>()))
+            .collect()
+    };
+    let comps = "ELECTRICIDAD,CONSUMO,EPB,ACS,5
+ELECTRICIDAD,CONSUMO,EPB,CAL,10
+ELECTRICIDAD,PRODUCCION,INSITU,ACS,10 # CTEEPBD_GENERADOR=PVACS
+ELECTRICIDAD,PRODUCCION,INSITU,NDEF,10 # CTEEPBD_GENERADOR=PVGEN"
+        .parse::<Components>()
+        .unwrap()
+        .normalize();
+    let comps_acs = comps.filter_by_epb_service(Service::ACS).unwrap();
+    assert_eq!(prod(&comps_acs), vec!["5.00", "0.00"]);
+    let comps_cal = comps.filter_by_epb_service(Service::CAL).unwrap();
+    assert_eq!(prod(&comps_cal), vec!["3.33", "6.67"]);
+
+    // Mismo generador con producción asignada a ACS y producción genérica
+    let comps = "ELECTRICIDAD,CONSUMO,EPB,ACS,5
+ELECTRICIDAD,CONSUMO,EPB,CAL,10
+ELECTRICIDAD,PRODUCCION,INSITU,ACS,10 # CTEEPBD_GENERADOR=PV
+ELECTRICIDAD,PRODUCCION,INSITU,NDEF,10 # CTEEPBD_GENERADOR=PV"
+        .parse::<Components>()
+        .unwrap()
+        .normalize();
+    let comps_acs = comps.filter_by_epb_service(Service::ACS).unwrap();
+    assert_eq!(prod(&comps_acs), vec!["5.00", "0.00"]);
+    let comps_cal = comps.filter_by_epb_service(Service::CAL).unwrap();
+    assert_eq!(prod(&comps_cal), vec!["3.33", "6.67"]);
 }
 
 #[test]
@@ -898,6 +1632,27 @@ MEDIOAMBIENTE,CONSUMO,EPB,ACS,60"
     assert_eq!(format!("{:.2}", fraccion_ren_acs), "0.60");
 }
 
+/// Electricidad cogenerada con biocarburante (100kWh demanda ACS)
+#[test]
+fn cte_ACS_demanda_ren_cogen() {
+    let mut comps = "ELECTRICIDAD,CONSUMO,EPB,ACS,30
+BIOCARBURANTE,CONSUMO,EPB,NDEF,100
+ELECTRICIDAD,PRODUCCION,COGENERACION,NDEF,47.4"
+        .parse::<Components>()
+        .unwrap()
+        .normalize();
+    let FP: Factors = TESTFPJ8.parse().unwrap();
+    // Sin definir la unidad de cogeneración no se puede calcular
+    assert!(fraccion_renovable_acs_nrb(&comps, &FP, 100.0).is_err());
+    // La electricidad cogenerada es renovable según sus factores de paso (1.0 / 1.1)
+    comps.set_meta(
+        "CTE_COGENERADOR",
+        "BIOCARBURANTE, 100.0, 47.4, 40.0, ENERGIA",
+    );
+    let fraccion_ren_acs = fraccion_renovable_acs_nrb(&comps, &FP, 100.0).unwrap();
+    assert_eq!(format!("{:.3}", fraccion_ren_acs), "0.273");
+}
+
 /// Bomba de calor (SCOP=2.5) + 10kWh PV (100kWh demanda ACS)
 #[test]
 fn cte_ACS_demanda_ren_bdc_60ma_10pv() {
@@ -997,3 +1752,814 @@ fn cte_ACS_demanda_ren_excluye_aux() {
     let fraccion_ren_acs = fraccion_renovable_acs_nrb(&comps, &FP, 4549.0).unwrap();
     assert_eq!(format!("{:.3}", fraccion_ren_acs), "0.967");
 }
+
+#[test]
+fn cte_extra_indicators() {
+    // Indicadores adicionales (coste y emisiones de CH4 como CO2e) declarados en los factores de paso
+    let FPstr = "#META CTE_INDICADORES: COSTE, CO2E_CH4
+vector, fuente, uso, step, ren, nren, co2, coste, co2e_ch4
+ELECTRICIDAD, RED, SUMINISTRO, A, 0.5, 2.0, 0.42, 0.15, 0.01
+ELECTRICIDAD, INSITU, SUMINISTRO, A, 1.0, 0.0, 0.0, 0.0, 0.0
+GASNATURAL, RED, SUMINISTRO, A, 0.0, 1.1, 0.22, 0.07, 0.005";
+    let FP = FPstr
+        .parse::<Factors>()
+        .unwrap()
+        .normalize(&CTE_USERWF)
+        .unwrap();
+    assert_eq!(FP.indicators().unwrap(), vec!["COSTE", "CO2E_CH4"]);
+    // Los factores de exportación generados mantienen los indicadores adicionales
+    let fp_exp = FP
+        .wdata
+        .iter()
+        .find(|f| {
+            f.carrier == Carrier::ELECTRICIDAD
+                && f.source == Source::INSITU
+                && f.dest == Dest::A_RED
+                && f.step == Step::B
+        })
+        .unwrap();
+    assert_eq!(fp_exp.extra(), vec![0.15, 0.01]);
+    // Lectura y escritura con los indicadores adicionales
+    let FP_roundtrip = FP.to_string().parse::<Factors>().unwrap();
+    assert_eq!(FP_roundtrip.to_string(), FP.to_string());
+
+    let comps = "ELECTRICIDAD,CONSUMO,EPB,CAL,100
+ELECTRICIDAD,PRODUCCION,INSITU,NDEF,120
+GASNATURAL,CONSUMO,EPB,ACS,50"
+        .parse::<Components>()
+        .unwrap();
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    // Los indicadores adicionales no modifican el balance de energía primaria y emisiones
+    assert!(approx_equal(
+        RenNrenCo2::new(100.0, 55.0, 11.0),
+        bal.balance.A
+    ));
+    let extra = &bal.balance.extra;
+    assert_eq!(extra.len(), 2);
+    assert_eq!(extra[0].name, "COSTE");
+    assert_eq!(extra[1].name, "CO2E_CH4");
+    assert!((extra[0].A - 3.5).abs() < 0.01);
+    assert!((extra[1].A - 0.25).abs() < 0.01);
+    assert!((extra[0].B - 0.5).abs() < 0.01);
+    assert!((extra[1].B - 0.05).abs() < 0.01);
+    assert!((extra[0].B_byuse[&Service::ACS] - 3.5).abs() < 0.01);
+    assert!(balance_to_plain(&bal).contains("COSTE = 0.50, CO2E_CH4 = 0.05"));
+
+    // Indicadores con valores en cada paso de cálculo
+    let FP = "#META CTE_INDICADORES: COSTE
+vector, fuente, uso, step, ren, nren, co2, coste
+ELECTRICIDAD, RED, SUMINISTRO, A, 0.5, 2.0, 0.42, 0.10, 0.5, 2.0, 0.42, 0.30"
+        .parse::<Factors>()
+        .unwrap()
+        .normalize(&CTE_USERWF)
+        .unwrap();
+    let comps = "ELECTRICIDAD,CONSUMO,EPB,CAL,10,20"
+        .parse::<Components>()
+        .unwrap();
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 2.0).unwrap();
+    assert!((bal.balance.extra[0].A - 7.0).abs() < 0.01);
+    assert!((bal.balance_m2.extra[0].B - 3.5).abs() < 0.01);
+
+    // Número de indicadores sin límite fijo, con nombres no vacíos y sin repetir
+    let FP = "#META CTE_INDICADORES: A, B, C, D, E
+ELECTRICIDAD, RED, SUMINISTRO, A, 0.5, 2.0, 0.42, 1.0, 2.0, 3.0, 4.0, 5.0"
+        .parse::<Factors>()
+        .unwrap();
+    assert_eq!(FP.indicators().unwrap().len(), 5);
+    assert_eq!(FP.wdata[0].extra(), vec![1.0, 2.0, 3.0, 4.0, 5.0]);
+    assert!("#META CTE_INDICADORES: COSTE, COSTE"
+        .parse::<Factors>()
+        .is_err());
+
+    // El número de valores de los factores debe coincidir con el de indicadores declarados
+    assert!("#META CTE_INDICADORES: COSTE
+ELECTRICIDAD, RED, SUMINISTRO, A, 0.5, 2.0, 0.42"
+        .parse::<Factors>()
+        .is_err());
+}
+
+#[test]
+fn cte_energy_costs() {
+    // Tarifas de electricidad (con precios por paso de cálculo y venta de excedentes) y gas natural
+    let comps = "#META CTE_TARIFA: ELECTRICIDAD, 30, PRECIO, 0.2, 0.1, VENTA, 0.05
+#META CTE_TARIFA: GASNATURAL, 20, PRECIO, 0.07
+ELECTRICIDAD,CONSUMO,EPB,CAL,10,0
+ELECTRICIDAD,CONSUMO,EPB,ACS,10,10
+ELECTRICIDAD,PRODUCCION,INSITU,NDEF,0,50
+GASNATURAL,CONSUMO,EPB,CAL,100,0"
+        .parse::<Components>()
+        .unwrap();
+    let FP: Factors = TESTFPJ.parse().unwrap();
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    let costs = bal.costs.as_ref().unwrap();
+    let cost_el = &costs.bycarrier[&Carrier::ELECTRICIDAD];
+    assert!((cost_el.energy - 4.0).abs() < 0.01);
+    assert!((cost_el.compensation - 2.0).abs() < 0.01);
+    assert!((cost_el.total - 32.0).abs() < 0.01);
+    assert!((costs.bycarrier[&Carrier::GASNATURAL].total - 27.0).abs() < 0.01);
+    assert!((costs.total - 59.0).abs() < 0.01);
+    assert!((costs.total_byservice[&Service::CAL] - 38.33).abs() < 0.01);
+    assert!((costs.total_byservice[&Service::ACS] - 20.67).abs() < 0.01);
+    assert!(balance_to_plain(&bal).contains("** Coste de la energía [€/an]: 59.00"));
+
+    // Se factura la energía suministrada por la red a usos no EPB y el coste se reparte también con ellos
+    let comps = "#META CTE_TARIFA: ELECTRICIDAD, 30, PRECIO, 0.2, COMPENSACION, 0.05
+ELECTRICIDAD,CONSUMO,EPB,CAL,10,0
+ELECTRICIDAD,CONSUMO,NEPB,NDEF,10,10
+ELECTRICIDAD,PRODUCCION,INSITU,NDEF,0,50"
+        .parse::<Components>()
+        .unwrap();
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    let bal_el = &bal.balance_cr[&Carrier::ELECTRICIDAD];
+    assert_eq!(bal_el.delivered_grid_an, 10.0);
+    assert_eq!(bal_el.delivered_grid_nEPB_an, 10.0);
+    let costs = bal.costs.as_ref().unwrap();
+    let cost_el = &costs.bycarrier[&Carrier::ELECTRICIDAD];
+    // 20 kWh medidos a 0.2 €/kWh, con compensación de 40 kWh x 0.05 €/kWh
+    assert!((cost_el.energy - 4.0).abs() < 0.01);
+    assert!((cost_el.compensation - 2.0).abs() < 0.01);
+    assert!((cost_el.total - 32.0).abs() < 0.01);
+    // CAL: 2.0 + 28.0 / 3; no EPB: 2.0 + 28.0 * 2 / 3
+    assert!((costs.total_byservice[&Service::CAL] - 11.33).abs() < 0.01);
+    assert!((costs.nepb - 20.67).abs() < 0.01);
+    assert!(balance_to_plain(&bal).contains("Usos no EPB: 20.67"));
+
+    // Sin tarifas no se calcula el coste
+    let comps = "ELECTRICIDAD,CONSUMO,EPB,CAL,10,0"
+        .parse::<Components>()
+        .unwrap();
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    assert!(bal.costs.is_none());
+
+    // Solo se admite una tarifa por vector
+    let comps = "#META CTE_TARIFA: ELECTRICIDAD, 30, PRECIO, 0.2
+#META CTE_TARIFA: ELECTRICIDAD, 20, PRECIO, 0.1
+ELECTRICIDAD,CONSUMO,EPB,CAL,10,0"
+        .parse::<Components>()
+        .unwrap();
+    assert!(energy_performance(&comps, &FP, TESTKEXP, 1.0).is_err());
+}
+
+#[test]
+fn cte_additional_services() {
+    // Recarga de vehículos eléctricos con producción fotovoltaica
+    let comps_str = "ELECTRICIDAD,CONSUMO,EPB,CAL,10
+ELECTRICIDAD,CONSUMO,EPB,VE,10 # Punto de recarga
+ELECTRICIDAD,PRODUCCION,INSITU,NDEF,20";
+    let FP: Factors = TESTFPJ.parse().unwrap();
+
+    // Si no se declara como servicio EPB, la recarga es un consumo no EPB
+    let comps = comps_str.parse::<Components>().unwrap();
+    assert_eq!(comps.cdata[1].service, Service::VE);
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    let bal_el = &bal.balance_cr[&Carrier::ELECTRICIDAD];
+    assert!(!bal.balance.used_EPB_byuse.contains_key(&Service::VE));
+    assert!((bal_el.exported_nEPB_an - 10.0).abs() < 0.01);
+
+    // Declarada como servicio EPB, la recarga usa la producción in situ como servicio EPB
+    let comps = format!("#META CTE_SERVICIOS_EPB: VE\n{}", comps_str)
+        .parse::<Components>()
+        .unwrap();
+    assert!(comps.epb_services().unwrap().contains(&Service::VE));
+    assert!(!comps.epb_services().unwrap().contains(&Service::TRA));
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    let bal_el = &bal.balance_cr[&Carrier::ELECTRICIDAD];
+    assert!((bal.balance.used_EPB_byuse[&Service::VE] - 10.0).abs() < 0.01);
+    assert!(bal_el.exported_an.abs() < 0.01);
+    assert!(balance_to_plain(&bal).contains("VE: 10.00"));
+
+    // Los nuevos servicios no tienen nombres alternativos y los servicios declarados deben existir
+    for service in &SERVICES {
+        assert_eq!(service.to_string().parse::<Service>().unwrap(), *service);
+    }
+    for alias in &["EVCHARGING", "TRANSPORT", "APPLIANCES"] {
+        assert!(alias.parse::<Service>().is_err());
+    }
+    let comps = format!("#META CTE_SERVICIOS_EPB: VE, XX\n{}", comps_str)
+        .parse::<Components>()
+        .unwrap();
+    assert!(energy_performance(&comps, &FP, TESTKEXP, 1.0).is_err());
+}
+
+#[test]
+fn cte_building_units() {
+    // Bloque de dos viviendas con instalación fotovoltaica común repartida según el consumo
+    let shared = "#META CTE_REPARTO_PRODUCCION_COMUN: CONSUMO
+ELECTRICIDAD,PRODUCCION,INSITU,NDEF,20,20 # CTEEPBD_GENERADOR=PVCOMUN"
+        .parse::<Components>()
+        .unwrap();
+    let units = vec![
+        BuildingUnit {
+            id: "1A".to_string(),
+            components: "ELECTRICIDAD,CONSUMO,EPB,CAL,30,0".parse().unwrap(),
+            arearef: 100.0,
+        },
+        BuildingUnit {
+            id: "1B".to_string(),
+            components: "ELECTRICIDAD,CONSUMO,EPB,ACS,10,0".parse().unwrap(),
+            arearef: 100.0,
+        },
+    ];
+    let FP: Factors = TESTFPJ.parse().unwrap();
+    let bal = energy_performance_units(&shared, &units, &FP, TESTKEXP).unwrap();
+
+    assert_eq!(bal.units.len(), 2);
+    let (id_a, bal_a) = &bal.units[0];
+    let (_, bal_b) = &bal.units[1];
+    assert_eq!(id_a, "1A");
+    let el_a = &bal_a.balance_cr[&Carrier::ELECTRICIDAD];
+    let el_b = &bal_b.balance_cr[&Carrier::ELECTRICIDAD];
+    // Paso 1: reparto según el consumo (15 y 5); paso 2: sin consumo, reparto según la superficie
+    assert!((el_a.delivered_grid_an - 15.0).abs() < 0.01);
+    assert!((el_b.delivered_grid_an - 5.0).abs() < 0.01);
+    assert!((el_a.exported_an - 10.0).abs() < 0.01);
+    assert!((el_b.exported_an - 10.0).abs() < 0.01);
+    assert!((el_a.produced_bygen_an["PVCOMUN"] - 25.0).abs() < 0.01);
+
+    // Edificio completo: todas las unidades con la superficie total
+    let el_bld = &bal.building.balance_cr[&Carrier::ELECTRICIDAD];
+    assert!((bal.building.arearef - 200.0).abs() < 0.01);
+    assert!((el_bld.delivered_grid_an - 20.0).abs() < 0.01);
+    assert!((el_bld.exported_an - 20.0).abs() < 0.01);
+    assert!(approx_equal(
+        bal_a.balance.B + bal_b.balance.B,
+        bal.building.balance.B
+    ));
+    assert!(approx_equal(
+        (bal_a.balance.B + bal_b.balance.B) * (1.0 / 200.0),
+        bal.building.balance_m2.B
+    ));
+
+    // Reparto por superficie (25% y 75%), con tarifa solo en la primera unidad.
+    // El edificio agrega los balances de las unidades, que no comparten la producción sobrante de otra unidad
+    let shared_area = "ELECTRICIDAD,PRODUCCION,INSITU,NDEF,20,20 # CTEEPBD_GENERADOR=PVCOMUN"
+        .parse::<Components>()
+        .unwrap();
+    let units_area = vec![
+        BuildingUnit {
+            id: "1A".to_string(),
+            components: "#META CTE_TARIFA: ELECTRICIDAD, 30, PRECIO, 0.2
+ELECTRICIDAD,CONSUMO,EPB,CAL,30,0"
+                .parse()
+                .unwrap(),
+            arearef: 100.0,
+        },
+        BuildingUnit {
+            id: "1B".to_string(),
+            components: "ELECTRICIDAD,CONSUMO,EPB,ACS,10,0".parse().unwrap(),
+            arearef: 300.0,
+        },
+    ];
+    let bal = energy_performance_units(&shared_area, &units_area, &FP, TESTKEXP).unwrap();
+    let (_, bal_a) = &bal.units[0];
+    let (_, bal_b) = &bal.units[1];
+    let el_a = &bal_a.balance_cr[&Carrier::ELECTRICIDAD];
+    let el_b = &bal_b.balance_cr[&Carrier::ELECTRICIDAD];
+    assert!((el_a.delivered_grid_an - 25.0).abs() < 0.01);
+    assert!((el_a.exported_an - 5.0).abs() < 0.01);
+    assert!((el_b.delivered_grid_an - 0.0).abs() < 0.01);
+    assert!((el_b.exported_an - 20.0).abs() < 0.01);
+    // Coste con la tarifa de la unidad: 30 + 25 * 0.2
+    assert!((bal_a.costs.as_ref().unwrap().total - 35.0).abs() < 0.01);
+    assert!(bal_b.costs.is_none());
+
+    let el_bld = &bal.building.balance_cr[&Carrier::ELECTRICIDAD];
+    assert!((bal.building.arearef - 400.0).abs() < 0.01);
+    assert!((el_bld.delivered_grid_an - 25.0).abs() < 0.01);
+    assert!((el_bld.exported_an - 25.0).abs() < 0.01);
+    assert!((el_bld.produced_bygen_an["PVCOMUN"] - 40.0).abs() < 0.01);
+    assert!((bal.building.costs.as_ref().unwrap().total - 35.0).abs() < 0.01);
+    let B_units = bal_a.balance.B + bal_b.balance.B;
+    assert!(approx_equal(B_units, bal.building.balance.B));
+    assert!(approx_equal(
+        B_units * (1.0 / 400.0),
+        bal.building.balance_m2.B
+    ));
+    assert!((bal.building.balance.rer[&Perimeter::DISTANT] - B_units.rer()).abs() < 0.001);
+    // RER del edificio a partir de la energía ponderada agregada en cada perímetro, aunque la unidad 1B,
+    // con más exportación que consumo, tenga energía ponderada total negativa:
+    // NEARBY: (7.5 + 20) / ((7.5 + 52.5) + (20 - 40)) = 0.6875
+    assert!(bal_b.balance.B.tot() < 0.0);
+    let B_nrb_units = bal_a.balance.B_perimeter[&Perimeter::NEARBY]
+        + bal_b.balance.B_perimeter[&Perimeter::NEARBY];
+    assert!(approx_equal(
+        B_nrb_units,
+        bal.building.balance.B_perimeter[&Perimeter::NEARBY]
+    ));
+    assert!((bal.building.balance.rer[&Perimeter::NEARBY] - 0.6875).abs() < 0.001);
+    assert!(
+        (bal.building.balance.B_byuse[&Service::CAL].nren
+            - bal_a.balance.B_byuse[&Service::CAL].nren)
+            .abs()
+            < 0.01
+    );
+
+    // Reparto con coeficientes fijos
+    let shared_coefs = "#META CTE_REPARTO_PRODUCCION_COMUN: COEFICIENTES, 0.5, 0.5
+ELECTRICIDAD,PRODUCCION,INSITU,NDEF,20,20"
+        .parse::<Components>()
+        .unwrap();
+    let bal = energy_performance_units(&shared_coefs, &units_area, &FP, TESTKEXP).unwrap();
+    let el_a = &bal.units[0].1.balance_cr[&Carrier::ELECTRICIDAD];
+    let el_bld = &bal.building.balance_cr[&Carrier::ELECTRICIDAD];
+    assert!((el_a.delivered_grid_an - 20.0).abs() < 0.01);
+    assert!((el_bld.delivered_grid_an - 20.0).abs() < 0.01);
+    assert!((el_bld.exported_an - 20.0).abs() < 0.01);
+    assert!(approx_equal(
+        bal.units[0].1.balance.B + bal.units[1].1.balance.B,
+        bal.building.balance.B
+    ));
+
+    // Errores: sin unidades, consumos comunes y coeficientes de reparto incorrectos
+    assert!(energy_performance_units(&shared, &[], &FP, TESTKEXP).is_err());
+    let shared_cons = "ELECTRICIDAD,CONSUMO,EPB,ILU,5,5"
+        .parse::<Components>()
+        .unwrap();
+    assert!(energy_performance_units(&shared_cons, &units, &FP, TESTKEXP).is_err());
+    let shared_coefs = "#META CTE_REPARTO_PRODUCCION_COMUN: COEFICIENTES, 0.5, 0.2
+ELECTRICIDAD,PRODUCCION,INSITU,NDEF,20,20"
+        .parse::<Components>()
+        .unwrap();
+    assert!(energy_performance_units(&shared_coefs, &units, &FP, TESTKEXP).is_err());
+}
+
+#[test]
+fn cte_balance_warnings() {
+    // Bomba de calor sin producción de MEDIOAMBIENTE declarada y recarga de vehículo eléctrico
+    let comps = "ELECTRICIDAD,CONSUMO,EPB,CAL,10
+MEDIOAMBIENTE,CONSUMO,EPB,CAL,20
+ELECTRICIDAD,CONSUMO,EPB,VE,5"
+        .parse::<Components>()
+        .unwrap()
+        .normalize();
+    let FP: Factors = TESTFPJ
+        .parse::<Factors>()
+        .unwrap()
+        .normalize(&CTE_USERWF)
+        .unwrap()
+        .strip(&comps);
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    let codes: Vec<_> = bal.warnings.iter().map(|w| w.code).collect();
+
+    // Producción de MEDIOAMBIENTE añadida y consumo de recarga considerado no EPB
+    assert!(codes.contains(&WarningCode::PRODUCCION_MEDIOAMBIENTE));
+    assert!(codes.contains(&WarningCode::CONSUMO_NEPB));
+    assert_eq!(
+        bal.warnings
+            .iter()
+            .find(|w| w.code == WarningCode::PRODUCCION_MEDIOAMBIENTE)
+            .unwrap()
+            .item
+            .as_deref(),
+        Some("MEDIOAMBIENTE, PRODUCCION, INSITU, CAL, 20.00 # Equilibrado de consumo sin producción declarada")
+    );
+    // Factores declarados no usados (p.e. GASNATURAL) y eliminados
+    assert!(bal
+        .warnings
+        .iter()
+        .any(|w| w.code == WarningCode::FACTOR_ELIMINADO
+            && w.item
+                .as_deref()
+                .unwrap()
+                .starts_with("GASNATURAL, RED, SUMINISTRO, A")));
+    // Los factores añadidos al normalizar y luego eliminados no generan avisos
+    assert!(!bal.warnings.iter().any(|w| w
+        .item
+        .as_deref()
+        .unwrap_or_default()
+        .starts_with("RED1")));
+    // Factores deducidos al normalizar
+    assert!(bal
+        .warnings
+        .iter()
+        .any(|w| w.code == WarningCode::FACTOR_DEDUCIDO
+            && w.item
+                .as_deref()
+                .unwrap()
+                .starts_with("MEDIOAMBIENTE, INSITU, A_RED, A")));
+
+    // Sin correcciones no hay avisos
+    let comps = "ELECTRICIDAD,CONSUMO,EPB,CAL,10"
+        .parse::<Components>()
+        .unwrap()
+        .normalize();
+    let FP = Factors {
+        wmeta: vec![],
+        wdata: vec![Factor::new(
+            Carrier::ELECTRICIDAD,
+            Source::RED,
+            Dest::SUMINISTRO,
+            Step::A,
+            RenNrenCo2::new(0.5, 2.0, 0.42),
+            "",
+        )],
+        ..Default::default()
+    };
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    assert!(bal.warnings.is_empty());
+}
+
+#[test]
+fn cte_compliance() {
+    // Vivienda nueva en zona D3 con bomba de calor para calefacción y ACS
+    let comps = "#META CTE_ZONA_CLIMATICA: D3
+#META CTE_ACS_DEMANDA_ANUAL: 2000
+ELECTRICIDAD,CONSUMO,EPB,CAL,1000
+MEDIOAMBIENTE,CONSUMO,EPB,CAL,2000
+ELECTRICIDAD,CONSUMO,EPB,ACS,500
+MEDIOAMBIENTE,CONSUMO,EPB,ACS,1500"
+        .parse::<Components>()
+        .unwrap()
+        .normalize();
+    let FP = get_ctefp_peninsula();
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 100.0).unwrap();
+    let input = ComplianceInput::from_components(&comps).unwrap().unwrap();
+    assert_eq!(input.zone, WinterZone::D);
+    assert_eq!(input.building_use, BuildingUse::RESIDENCIAL);
+    assert_eq!(input.status, BuildingStatus::NUEVO);
+
+    let report = check_compliance(&bal, input);
+    assert_eq!(report.checks.len(), 3);
+    // C_ep,nren = 1500 * 1.954 / 100 = 29.31 <= 38.0
+    assert!((report.checks[0].value - 29.31).abs() < 0.01);
+    assert!((report.checks[0].limit - 38.0).abs() < 0.01);
+    assert!((report.checks[0].margin - 8.69).abs() < 0.01);
+    // C_ep,tot = (1500 * (0.414 + 1.954) + 3500) / 100 = 70.52 <= 76.0
+    assert!((report.checks[1].value - 70.52).abs() < 0.01);
+    assert!((report.checks[1].limit - 76.0).abs() < 0.01);
+    // Fracción renovable de la demanda de ACS = 1500 / 2000 = 0.75 >= 0.70
+    assert!((report.checks[2].value - 0.75).abs() < 0.01);
+    assert!((report.checks[2].limit - 0.70).abs() < 0.01);
+    assert!(report.passes());
+    assert!(report.to_string().ends_with("Resultado: CUMPLE"));
+
+    // Edificio terciario existente: límites con la carga interna media
+    let input = ComplianceInput {
+        zone: "α3".parse().unwrap(),
+        building_use: "TERCIARIO, 5.0".parse().unwrap(),
+        status: BuildingStatus::EXISTENTE,
+        demanda_anual_acs: None,
+        demanda_diaria_acs: None,
+    };
+    assert_eq!(input.zone, WinterZone::ALFA);
+    assert_eq!(
+        he0_limits(input.zone, input.building_use, input.status, "PENINSULA"),
+        (160.0, 260.0)
+    );
+    // En territorio extrapeninsular se corrige el límite de C_ep,nren
+    assert_eq!(
+        he0_limits(input.zone, input.building_use, input.status, "CANARIAS"),
+        (224.0, 260.0)
+    );
+    let report = check_compliance(&bal, input);
+    assert_eq!(report.checks.len(), 2);
+    assert_eq!(report.notes.len(), 1);
+    assert!(report.passes());
+
+    // Contribución renovable mínima reducida para demandas de ACS inferiores a 5000 l/d
+    assert!((he4_limit(Some(4000.0)) - 0.60).abs() < 0.01);
+    assert!((he4_limit(None) - 0.70).abs() < 0.01);
+
+    // Sin zona climática no se verifica el cumplimiento y con datos incorrectos hay error
+    assert!(ComplianceInput::from_components(&Components::default())
+        .unwrap()
+        .is_none());
+    let comps = "#META CTE_ZONA_CLIMATICA: D3
+#META CTE_USO_EDIFICIO: TERCIARIO
+ELECTRICIDAD,CONSUMO,EPB,CAL,1000"
+        .parse::<Components>()
+        .unwrap();
+    assert!(ComplianceInput::from_components(&comps).is_err());
+}
+
+#[test]
+fn cte_he0_limits() {
+    use BuildingStatus::{EXISTENTE, NUEVO};
+    let zone = |z: &str| z.parse::<WinterZone>().unwrap();
+    let res = BuildingUse::RESIDENCIAL;
+    let ter = BuildingUse::TERCIARIO(0.0);
+    let lims = |z: &str, u: BuildingUse, s: BuildingStatus| he0_limits(zone(z), u, s, "PENINSULA");
+
+    // Tablas 3.1.a-HE0 y 3.2.a-HE0: uso residencial privado
+    assert_eq!(lims("α3", res, NUEVO), (20.0, 40.0));
+    assert_eq!(lims("B3", res, NUEVO), (28.0, 56.0));
+    assert_eq!(lims("E1", res, NUEVO), (43.0, 86.0));
+    assert_eq!(lims("A3", res, EXISTENTE), (50.0, 75.0));
+    assert_eq!(lims("C2", res, EXISTENTE), (70.0, 90.0));
+    assert_eq!(lims("D3", res, EXISTENTE), (80.0, 105.0));
+    assert_eq!(lims("E1", res, EXISTENTE), (90.0, 115.0));
+
+    // Tablas 3.1.b-HE0 y 3.2.b-HE0: otros usos, sin los términos de la carga interna media
+    assert_eq!(lims("A3", ter, NUEVO), (55.0, 155.0));
+    assert_eq!(lims("C2", ter, NUEVO), (35.0, 140.0));
+    assert_eq!(lims("E1", ter, NUEVO), (10.0, 120.0));
+    assert_eq!(lims("B3", ter, EXISTENTE), (90.0, 180.0));
+    assert_eq!(lims("D3", ter, EXISTENTE), (55.0, 155.0));
+    assert_eq!(
+        he0_limits(zone("C2"), BuildingUse::TERCIARIO(2.5), NUEVO, "PENINSULA"),
+        (55.0, 162.5)
+    );
+
+    // Territorio extrapeninsular: C_ep,nren x 1.25 (residencial privado) y x 1.40 (otros usos)
+    assert_eq!(he0_limits(zone("C2"), res, NUEVO, "BALEARES"), (40.0, 64.0));
+    assert_eq!(
+        he0_limits(zone("A3"), res, EXISTENTE, "CANARIAS"),
+        (62.5, 75.0)
+    );
+    assert_eq!(
+        he0_limits(zone("C2"), ter, NUEVO, "BALEARES"),
+        (49.0, 140.0)
+    );
+    assert_eq!(
+        he0_limits(zone("α3"), ter, NUEVO, "CEUTAMELILLA"),
+        (98.0, 165.0)
+    );
+}
+
+#[test]
+fn cte_energy_rating() {
+    // Escalas de los metadatos (los límites dependen de la zona climática y del tipo de edificio)
+    let comps = "#META CTE_ESCALA_NREN: 20.0, 35.0, 55.0, 85.0, 170.0, 200.0
+#META CTE_ESCALA_CO2: 4.0, 8.0, 12.0, 18.0, 35.0, 42.0
+ELECTRICIDAD,CONSUMO,EPB,CAL,1500
+GASNATURAL,CONSUMO,EPB,ACS,3000"
+        .parse::<Components>()
+        .unwrap();
+    let FP = get_ctefp_peninsula();
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 100.0).unwrap();
+    // nren = (1500 * 1.954 + 3000 * 1.190) / 100 = 65.01 -> D
+    // co2 = (1500 * 0.331 + 3000 * 0.252) / 100 = 12.53 -> D
+    let rating = energy_rating(&bal).unwrap();
+    assert_eq!(rating.nren, RatingLetter::D);
+    assert_eq!(rating.co2, RatingLetter::D);
+
+    let bal = incorpora_calificacion(bal);
+    assert_eq!(bal.rating, Some(rating));
+    assert!(balance_to_plain(&bal).contains("Energía primaria no renovable: D"));
+    assert!(balance_to_xml(&bal).contains("<EmisionesCO2>D</EmisionesCO2>"));
+    assert!(serde_json::to_string(&bal).unwrap().contains("\"rating\""));
+
+    // Límites de la escala: un valor igual al límite recibe la letra peor
+    let scale: RatingScale = "20.0, 35.0, 55.0, 85.0, 170.0, 200.0".parse().unwrap();
+    assert_eq!(scale.letter(0.0), RatingLetter::A);
+    assert_eq!(scale.letter(20.0), RatingLetter::B);
+    assert_eq!(scale.letter(199.9), RatingLetter::F);
+    assert_eq!(scale.letter(250.0), RatingLetter::G);
+    assert_eq!(
+        scale.to_string(),
+        "20.00, 35.00, 55.00, 85.00, 170.00, 200.00"
+    );
+    // Escalas incorrectas: número de límites o límites no crecientes
+    assert!("20.0, 35.0, 55.0".parse::<RatingScale>().is_err());
+    assert!("20.0, 35.0, 30.0, 85.0, 170.0, 200.0"
+        .parse::<RatingScale>()
+        .is_err());
+
+    // Sin escalas no hay calificación y energy_rating devuelve un error
+    let comps = "#META CTE_ZONA_CLIMATICA: D3
+ELECTRICIDAD,CONSUMO,EPB,CAL,1500"
+        .parse::<Components>()
+        .unwrap();
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 100.0).unwrap();
+    assert!(energy_rating(&bal).is_err());
+    let bal = incorpora_calificacion(bal);
+    assert!(bal.rating.is_none());
+    assert!(!bal
+        .misc
+        .unwrap_or_default()
+        .contains_key("error_calificacion"));
+
+    // Los errores de cálculo, como la falta de una de las escalas, se incorporan a los datos adicionales
+    let comps = "#META CTE_ESCALA_NREN: 20.0, 35.0, 55.0, 85.0, 170.0, 200.0
+ELECTRICIDAD,CONSUMO,EPB,CAL,1500"
+        .parse::<Components>()
+        .unwrap();
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 100.0).unwrap();
+    assert!(energy_rating(&bal).is_err());
+    let bal = incorpora_calificacion(bal);
+    assert!(bal.rating.is_none());
+    assert!(bal.misc.unwrap().contains_key("error_calificacion"));
+}
+
+#[test]
+fn cte_wfactors_registry() {
+    let user_wf = UserWF {
+        red1: None,
+        red2: None,
+        cogen_to_grid: None,
+        cogen_to_nepb: None,
+    };
+    // Biblioteca predefinida
+    let mut registry = WFactorsRegistry::new();
+    assert_eq!(registry.ids(), vec![CTE_WF_LIBRARY_DEFAULT]);
+    let FP =
+        wfactors_from_library("RITE2014", "PENINSULA", &registry, user_wf, CTE_USERWF).unwrap();
+    assert_eq!(FP.get_meta("CTE_FUENTE").unwrap(), "RITE2014");
+    assert_eq!(FP.get_meta("CTE_LOCALIZACION").unwrap(), "PENINSULA");
+    assert_eq!(FP.to_string(), get_ctefp_peninsula().to_string());
+
+    // Bibliotecas cargadas desde un directorio
+    let ids = registry.load_dir("test_data/factores_bibliotecas").unwrap();
+    assert_eq!(ids, vec!["EJEMPLO"]);
+    assert_eq!(registry.ids(), vec!["RITE2014", "EJEMPLO"]);
+    let library = registry.get("EJEMPLO").unwrap();
+    assert_eq!(library.locs.len(), 2);
+    assert!(library.description.contains("valores no oficiales"));
+    let FP = wfactors_from_library("EJEMPLO", "CANARIAS", &registry, user_wf, CTE_USERWF).unwrap();
+    assert_eq!(FP.get_meta("CTE_FUENTE").unwrap(), "EJEMPLO");
+    let comps = "ELECTRICIDAD,CONSUMO,EPB,CAL,100"
+        .parse::<Components>()
+        .unwrap();
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    assert!(approx_equal(
+        RenNrenCo2::new(20.0, 250.0, 60.0),
+        bal.balance_m2.B
+    ));
+
+    // Bibliotecas o localizaciones no disponibles
+    assert!(wfactors_from_library("XX", "PENINSULA", &registry, user_wf, CTE_USERWF).is_err());
+    assert!(wfactors_from_library("EJEMPLO", "BALEARES", &registry, user_wf, CTE_USERWF).is_err());
+    // Factores sin biblioteca o localización
+    assert!(registry
+        .load_str("ELECTRICIDAD, RED, SUMINISTRO, A, 0.5, 1.5, 0.25")
+        .is_err());
+    assert!(registry
+        .load_str("#META CTE_FUENTE: OTRA\nELECTRICIDAD, RED, SUMINISTRO, A, 0.5, 1.5, 0.25")
+        .is_err());
+    assert!(registry.load_dir("test_data/no_existe").is_err());
+}
+
+#[test]
+fn cte_perimeter() {
+    let comps = "ELECTRICIDAD,CONSUMO,EPB,CAL,10
+BIOCARBURANTE,CONSUMO,EPB,CAL,10
+BIOMASA,CONSUMO,EPB,ACS,10
+MEDIOAMBIENTE,CONSUMO,EPB,ACS,10"
+        .parse::<Components>()
+        .unwrap()
+        .normalize();
+    let FP: Factors = TESTFP.parse().unwrap();
+    let nearby = nearby_carriers(&FP).unwrap();
+    let bal_perimeter = |perimeter, wfactors: &Factors| {
+        let wfactors = wfactors_to_perimeter(wfactors, perimeter, &nearby).unwrap();
+        energy_performance(&comps, &wfactors, TESTKEXP, 1.0).unwrap()
+    };
+
+    // Perímetro distante: no cambian los factores de paso
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    assert_eq!(bal.perimeter, Perimeter::DISTANT);
+    assert!(approx_equal(
+        RenNrenCo2::new(36.03, 21.34, 5.08),
+        bal.balance.B
+    ));
+    // Perímetro próximo: la biomasa y el medioambiente siguen siendo renovables
+    let bal = bal_perimeter(Perimeter::NEARBY, &FP);
+    assert_eq!(bal.perimeter, Perimeter::NEARBY);
+    assert!(approx_equal(
+        RenNrenCo2::new(20.03, 37.34, 5.08),
+        bal.balance.B
+    ));
+    // Perímetro in situ: solo el medioambiente es renovable
+    let bal = bal_perimeter(Perimeter::ONSITE, &FP);
+    assert_eq!(bal.perimeter, Perimeter::ONSITE);
+    assert!(approx_equal(
+        RenNrenCo2::new(10.0, 47.37, 5.08),
+        bal.balance.B
+    ));
+    assert!(bal.wfactors.has_meta("CTE_PERIMETRO"));
+
+    // Vectores próximos declarados en los metadatos de los factores de paso
+    let mut FP_nrb = FP.clone();
+    FP_nrb.set_meta("CTE_VECTORES_PROXIMOS", "BIOCARBURANTE");
+    let nearby_meta = nearby_carriers(&FP_nrb).unwrap();
+    assert!(nearby_meta.contains(&Carrier::BIOCARBURANTE));
+    let wfactors = wfactors_to_perimeter(&FP_nrb, Perimeter::NEARBY, &nearby_meta).unwrap();
+    let bal = energy_performance(&comps, &wfactors, TESTKEXP, 1.0).unwrap();
+    assert!(approx_equal(
+        RenNrenCo2::new(31.03, 26.34, 5.08),
+        bal.balance.B
+    ));
+    FP_nrb.set_meta("CTE_VECTORES_PROXIMOS", "CARBONCILLO");
+    assert!(nearby_carriers(&FP_nrb).is_err());
+    assert!(wfactors_to_nearby(&FP_nrb).is_err());
+
+    // No se pueden ampliar factores de paso de un perímetro más restrictivo
+    let FP_onsite = wfactors_to_perimeter(&FP, Perimeter::ONSITE, &nearby).unwrap();
+    assert!(wfactors_to_perimeter(&FP_onsite, Perimeter::NEARBY, &nearby).is_err());
+
+    // Conjunto de servicios en el perímetro próximo
+    let comps_acs = comps.filter_by_epb_services(&[Service::ACS]).unwrap();
+    let wfactors = wfactors_to_perimeter(&FP, Perimeter::NEARBY, &nearby).unwrap();
+    let bal = energy_performance(&comps_acs, &wfactors, TESTKEXP, 1.0).unwrap();
+    assert!(approx_equal(
+        RenNrenCo2::new(20.03, 0.34, 0.18),
+        bal.balance.B
+    ));
+    let comps_all = comps
+        .filter_by_epb_services(&[Service::CAL, Service::ACS, Service::CAL])
+        .unwrap();
+    assert_eq!(comps_all.get_meta("CTE_SERVICIO").unwrap(), "CAL, ACS");
+    let bal_all = energy_performance(&comps_all, &FP, TESTKEXP, 1.0).unwrap();
+    assert!(approx_equal(
+        RenNrenCo2::new(36.03, 21.34, 5.08),
+        bal_all.balance.B
+    ));
+    assert!(comps.filter_by_epb_services(&[]).is_err());
+
+    // La conversión de perímetro mantiene los factores de cada paso de cálculo y los indicadores adicionales
+    let is_el_red = |f: &Factor| {
+        f.carrier == Carrier::ELECTRICIDAD
+            && f.source == Source::RED
+            && f.dest == Dest::SUMINISTRO
+            && f.step == Step::A
+    };
+    let mut FP_t = FP.clone();
+    let fp_el = FP_t.wdata.iter_mut().find(|f| is_el_red(f)).unwrap();
+    fp_el.set_values_t(vec![
+        RenNrenCo2::new(0.5, 2.0, 0.4),
+        RenNrenCo2::new(0.3, 1.5, 0.3),
+    ]);
+    fp_el.set_extra(&[0.15]);
+    let FP_t_nrb = wfactors_to_perimeter(&FP_t, Perimeter::NEARBY, &nearby).unwrap();
+    let fp_el_nrb = FP_t_nrb.wdata.iter().find(|f| is_el_red(f)).unwrap();
+    let values_t = fp_el_nrb.values_t().unwrap();
+    assert_eq!(values_t.len(), 2);
+    assert!(approx_equal(RenNrenCo2::new(0.0, 2.5, 0.4), values_t[0]));
+    assert!(approx_equal(RenNrenCo2::new(0.0, 1.8, 0.3), values_t[1]));
+    assert!(approx_equal(
+        RenNrenCo2::new(0.0, 2.15, 0.35),
+        fp_el_nrb.factors()
+    ));
+    assert_eq!(fp_el_nrb.extra(), vec![0.15]);
+}
+
+#[test]
+fn cte_rer_perimeter() {
+    let comps = "ELECTRICIDAD,CONSUMO,EPB,CAL,10
+BIOCARBURANTE,CONSUMO,EPB,CAL,10
+BIOMASA,CONSUMO,EPB,ACS,10
+MEDIOAMBIENTE,CONSUMO,EPB,ACS,10"
+        .parse::<Components>()
+        .unwrap()
+        .normalize();
+    let FP: Factors = TESTFP.parse().unwrap();
+    let approx = |expected: f32, got: Option<&f32>| (expected - *got.unwrap()).abs() < 0.01;
+
+    // RER del edificio y por servicios en cada perímetro
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    let rer = &bal.balance.rer;
+    assert!(approx(bal.balance.B.rer(), rer.get(&Perimeter::DISTANT)));
+    assert!(approx(0.628, rer.get(&Perimeter::DISTANT)));
+    assert!(approx(0.349, rer.get(&Perimeter::NEARBY)));
+    assert!(approx(0.174, rer.get(&Perimeter::ONSITE)));
+    let rer_byuse = |p: Perimeter, s: Service| bal.balance.rer_byuse[&p].get(&s);
+    assert!(approx(0.983, rer_byuse(Perimeter::DISTANT, Service::ACS)));
+    assert!(approx(0.983, rer_byuse(Perimeter::NEARBY, Service::ACS)));
+    assert!(approx(0.491, rer_byuse(Perimeter::ONSITE, Service::ACS)));
+    assert!(approx(0.432, rer_byuse(Perimeter::DISTANT, Service::CAL)));
+    assert!(approx(0.0, rer_byuse(Perimeter::NEARBY, Service::CAL)));
+    assert!(approx(0.0, rer_byuse(Perimeter::ONSITE, Service::CAL)));
+    assert_eq!(bal.balance_m2.rer, bal.balance.rer);
+
+    // Con factores de paso en el perímetro próximo no se evalúa el perímetro distante
+    let nearby = nearby_carriers(&FP).unwrap();
+    let FP_nrb = wfactors_to_perimeter(&FP, Perimeter::NEARBY, &nearby).unwrap();
+    let bal = energy_performance(&comps, &FP_nrb, TESTKEXP, 1.0).unwrap();
+    assert!(!bal.balance.rer.contains_key(&Perimeter::DISTANT));
+    assert!(approx(0.349, bal.balance.rer.get(&Perimeter::NEARBY)));
+    assert!(approx(0.174, bal.balance.rer.get(&Perimeter::ONSITE)));
+
+    // Con vectores próximos incorrectos en los metadatos no se evalúa el perímetro próximo y se avisa
+    let mut FP_err = FP.clone();
+    FP_err.set_meta("CTE_VECTORES_PROXIMOS", "CARBONCILLO");
+    let bal = energy_performance(&comps, &FP_err, TESTKEXP, 1.0).unwrap();
+    assert!(!bal.balance.rer.contains_key(&Perimeter::NEARBY));
+    assert!(bal
+        .warnings
+        .iter()
+        .any(|w| w.code == WarningCode::RER_PROXIMO_NO_EVALUADO));
+    assert!(approx(0.628, bal.balance.rer.get(&Perimeter::DISTANT)));
+    assert!(approx(0.174, bal.balance.rer.get(&Perimeter::ONSITE)));
+
+    // Factores de paso variables en cada paso de cálculo
+    let comps_t = "ELECTRICIDAD,CONSUMO,EPB,CAL,10,10
+BIOMASA,CONSUMO,EPB,ACS,10,10"
+        .parse::<Components>()
+        .unwrap();
+    let mut FP_t = FP.clone();
+    let fp_el = FP_t
+        .wdata
+        .iter_mut()
+        .find(|f| f.carrier == Carrier::ELECTRICIDAD && f.source == Source::RED)
+        .unwrap();
+    fp_el.set_values_t(vec![
+        RenNrenCo2::new(0.5, 2.0, 0.42),
+        RenNrenCo2::new(1.0, 1.5, 0.3),
+    ]);
+    let bal = energy_performance(&comps_t, &FP_t, TESTKEXP, 1.0).unwrap();
+    // ren = 10 * (0.5 + 1.0) + 20 * 1.003 = 35.06; tot = 35.06 + 10 * (2.0 + 1.5) + 20 * 0.034 = 70.74
+    assert!(approx(0.496, bal.balance.rer.get(&Perimeter::DISTANT)));
+    // Sin la electricidad de red (ren = 20.06)
+    assert!(approx(0.284, bal.balance.rer.get(&Perimeter::NEARBY)));
+    assert!(approx(0.0, bal.balance.rer.get(&Perimeter::ONSITE)));
+}